@@ -0,0 +1,119 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Benchmarks for the hot paths of reading a CAN log: parsing SavvyCAN CSV
+// records, sorting the messages of a multi-bus log, and expanding alerts over a
+// long route. Run with "cargo bench".
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use csv::StringRecord;
+use make_cabana_route::input::{
+    expand_alerts, read_can_messages_from_reader, Alert, AlertStatus, CANMessage, ReadOptions,
+    ALERT_INTERVAL,
+};
+use make_cabana_route::Nanos;
+
+// Number of buses, and messages on each, in the synthetic log
+const BUSES: usize = 3;
+const MESSAGES_PER_BUS: usize = 20_000;
+
+fn bench_parse_from(c: &mut Criterion) {
+    let records = [
+        (
+            "classic",
+            vec![
+                "1000", "123", "false", "Rx", "0", "8", "01", "02", "03", "04", "05", "06", "07",
+                "08",
+            ],
+        ),
+        (
+            "no_direction",
+            vec!["1000", "123", "false", "0", "2", "DE", "AD"],
+        ),
+        (
+            "extended",
+            vec!["1000", "18DAF110", "true", "Tx", "1", "3", "02", "10", "03"],
+        ),
+        (
+            "fd",
+            [
+                vec!["1000", "123", "false", "Rx", "0", "64"],
+                vec!["AA"; 64],
+            ]
+            .concat(),
+        ),
+    ];
+    let mut group = c.benchmark_group("parse_from");
+    for (name, fields) in records {
+        let record = StringRecord::from(fields);
+        group.bench_function(name, |b| {
+            b.iter(|| CANMessage::parse_from(black_box(&record), 0).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// A SavvyCAN CSV log with messages from several buses, where each bus is written
+// to the log 5ms later than the one before so the rows are out of timestamp order
+// (as for a logger with a buffer for each bus)
+fn multi_bus_log() -> Vec<u8> {
+    let mut log = String::from("Time Stamp,ID,Extended,Dir,Bus,LEN,D1,D2,D3,D4,D5,D6,D7,D8\n");
+    for idx in 0..MESSAGES_PER_BUS {
+        for bus in 0..BUSES {
+            let ts_us = 1_000_000 + idx * 1000 + (idx % 7) * 100 - bus * 5000;
+            log.push_str(&format!(
+                "{},{:X},false,Rx,{},8,{:02X},00,00,00,00,00,00,00\n",
+                ts_us,
+                0x100 + idx % 32,
+                bus,
+                idx % 256
+            ));
+        }
+    }
+    log.into_bytes()
+}
+
+fn bench_read_can_messages(c: &mut Criterion) {
+    let log = multi_bus_log();
+    c.bench_function("read_can_messages/multi_bus", |b| {
+        b.iter(|| {
+            read_can_messages_from_reader(black_box(&log[..]), None, &ReadOptions::default())
+                .unwrap()
+        })
+    });
+}
+
+fn bench_expand_alerts(c: &mut Criterion) {
+    // A gap alert every minute of a 24 hour route
+    const MINUTE: Nanos = 60_000_000_000;
+    let alerts: Vec<Alert> = (0..24 * 60)
+        .flat_map(|minute| {
+            [
+                Alert {
+                    timestamp: minute * MINUTE,
+                    status: AlertStatus::Critical,
+                    message: Some("Possible lost CAN messages".to_string()),
+                },
+                Alert {
+                    timestamp: minute * MINUTE + 1_000_000_000,
+                    status: AlertStatus::Normal,
+                    message: None,
+                },
+            ]
+        })
+        .collect();
+    c.bench_function("expand_alerts/24h", |b| {
+        b.iter_batched(
+            || alerts.clone(),
+            |alerts| expand_alerts(alerts, ALERT_INTERVAL),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_parse_from,
+    bench_read_can_messages,
+    bench_expand_alerts
+);
+criterion_main!(benches);
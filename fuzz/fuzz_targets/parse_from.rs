@@ -0,0 +1,19 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Fuzz the SavvyCAN CSV record parser with arbitrary input. Parsing may fail,
+// but it should never panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use make_cabana_route::input::CANMessage;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(data);
+    for record in reader.records().flatten() {
+        let _ = CANMessage::parse_from(&record, 0);
+    }
+});
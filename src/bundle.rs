@@ -0,0 +1,113 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Writing routes to a single zip or tar archive (see --bundle), with the same
+// paths as in the data directory so that it unpacks to a data directory again.
+// Zip archives can be encrypted with a password (see --bundle-password-file).
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::prelude::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use tracing::info;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BundleFormat {
+    Zip,
+    Tar,
+}
+
+impl BundleFormat {
+    // A bundle with a .tar extension is a tar archive, anything else is a zip
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("tar") => BundleFormat::Tar,
+            _ => BundleFormat::Zip,
+        }
+    }
+}
+
+// Read a bundle password from the first line of a file
+pub fn read_bundle_password(path: &Path) -> Result<String> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read bundle password file {:?}", path))?;
+    let password = contents.lines().next().unwrap_or("");
+    if password.is_empty() {
+        bail!("Bundle password file {:?} is empty", path);
+    }
+    Ok(password.to_string())
+}
+
+// Paths of the files under dir, relative to it. These are sorted so the
+// archive is in the same order each time.
+fn files_under(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {:?}", dir))?
+        .collect::<io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = PathBuf::from(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            files.extend(
+                files_under(&entry.path())?
+                    .into_iter()
+                    .map(|f| name.join(f)),
+            );
+        } else {
+            files.push(name);
+        }
+    }
+    Ok(files)
+}
+
+// Write all the files under dir to a new archive at bundle_path. Each file is
+// copied into the archive as it's added, rather than read into memory. If a
+// password is given each file in the zip is encrypted with AES-256 (tar has no
+// encryption). Returns the number of files written.
+pub fn write_bundle(dir: &Path, bundle_path: &Path, password: Option<&str>) -> Result<usize> {
+    let format = BundleFormat::from_path(bundle_path);
+    if password.is_some() && format != BundleFormat::Zip {
+        bail!("Only zip bundles can be encrypted, not {:?}", bundle_path);
+    }
+    let files = files_under(dir)?;
+    info!("Writing {} files to {:?}...", files.len(), bundle_path);
+
+    let f = File::create(bundle_path)
+        .with_context(|| format!("Failed to create bundle {:?}", bundle_path))?;
+    let mut writer = BufWriter::new(f);
+    match format {
+        BundleFormat::Zip => {
+            let mut zip = zip::ZipWriter::new(&mut writer);
+            // Nearly all of a route is compressed already (the videos, and the
+            // logs unless --compression none), so files are stored as they are
+            let options: zip::write::FileOptions<()> = zip::write::FileOptions::default()
+                .compression_method(zip::CompressionMethod::Stored);
+            let options = match password {
+                Some(password) => options.with_aes_encryption(zip::AesMode::Aes256, password),
+                None => options,
+            };
+            for name in &files {
+                let path = dir.join(name);
+                let mode = fs::metadata(&path)?.permissions().mode();
+                zip.start_file(name.to_string_lossy(), options.unix_permissions(mode))?;
+                io::copy(&mut File::open(&path)?, &mut zip)
+                    .with_context(|| format!("Failed to add {:?} to bundle", path))?;
+            }
+            zip.finish()?;
+        }
+        BundleFormat::Tar => {
+            let mut tar = tar::Builder::new(&mut writer);
+            for name in &files {
+                tar.append_path_with_name(dir.join(name), name)
+                    .with_context(|| format!("Failed to add {:?} to bundle", name))?;
+            }
+            tar.finish()?;
+        }
+    }
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write bundle {:?}", bundle_path))?;
+    Ok(files.len())
+}
@@ -0,0 +1,2577 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// The make_cabana_route command line tool. This is in the library so that the
+// Python module (see python.rs) can run conversions the same way.
+use crate::bundle::{read_bundle_password, write_bundle, BundleFormat};
+use crate::dbc::{
+    export_signals, find_signal_alerts, find_signal_edges, synthesize_car_states, AlertRule,
+    CarStateSignals, Dbc, EdgeMarkerRule,
+};
+#[cfg(target_os = "linux")]
+use crate::input::record_can_messages_socketcan;
+use crate::input::{
+    check_can_flags, dedup_timestamps, drop_static_can_ids, epoch_start_time, expand_markers,
+    find_missing_can_messages, find_silent_can_ids, generate_can_messages, iter_can_messages,
+    merge_timeline, read_alerts, read_can_logs, read_locations, read_markers, CANMessage,
+    CanFilter, CanLog, DecimateCanMessages, FlagCheck, GeneratorSpec, InputError, LogDate,
+    LogFormat, LogInput, ReadOptions, ReorderCanMessages, TsMode, TsUnit,
+};
+use crate::interrupt::{handle_interrupt, interrupted};
+use crate::log_capnp::sentinel::SentinelType;
+use crate::qlog::{CanDecimator, DeviceType, InitDataFields, LogCompression, QlogWriter};
+use crate::route::{concat_routes, dump_route_can, verify_route};
+use crate::stats::{route_stats, AlignmentReport, RouteStats};
+use crate::sync::find_sync_lag;
+use crate::video::{Camera, SegmentVideoEncoder, SegmentVideoKind, SourceVideo};
+use crate::Nanos;
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Local, Utc};
+use clap::{Parser, Subcommand};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use itertools::{merge, process_results, Itertools};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, Permissions};
+use std::io::{BufWriter, Write};
+use std::net::TcpStream;
+use std::os::unix::prelude::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, info_span, warn};
+use tracing_subscriber::EnvFilter;
+
+// Size of the buffer for writing each event with --replay-to, larger than
+// any event that's written
+const REPLAY_BUFFER_SIZE: usize = 1 << 20;
+
+// Each CAN event can span up to this long (effectively, giving all those messages the same timestamp)
+const CAN_EVENT_TIME: Nanos = Duration::from_millis(10).as_nanos() as Nanos;
+
+// The qlog keeps at most one CAN message per bus and ID in this interval
+const QLOG_CAN_INTERVAL: Nanos = Duration::from_secs(1).as_nanos() as Nanos;
+
+// A route longer than this is almost certainly a mistake (i.e. a timestamp offset
+// that's wrong, or a log with a corrupt timestamp), so it's rejected unless
+// --max-segments is set
+const MAX_ROUTE_DURATION: Nanos = Duration::from_secs(24 * 60 * 60).as_nanos() as Nanos;
+
+// With --privacy, the driver camera video is blurred with this ffmpeg filter
+// (a box blur with a radius of 20 pixels, applied 3 times)
+const PRIVACY_BLUR_FILTER: &str = "boxblur=20:3";
+
+// With --low-memory, CAN messages are put back in timestamp order within a
+// window of this many messages
+const CAN_REORDER_WINDOW: usize = 1000;
+
+// --auto-sync-signal searches this far either side of the YAML sync point, in
+// steps of AUTO_SYNC_STEP
+const AUTO_SYNC_MAX_LAG: Nanos = Duration::from_secs(2).as_nanos() as Nanos;
+const AUTO_SYNC_STEP: Nanos = Duration::from_millis(100).as_nanos() as Nanos;
+
+// openpilot's layout of segment directories named <route>--<segment>
+const DEFAULT_OUTPUT_LAYOUT: &str = "{route}--{segment}/{file}";
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Path of YAML file with route information
+    #[arg(short, long, default_value = "routes.yml")]
+    yaml_path: PathBuf,
+
+    #[command(flatten)]
+    options: ConvertOptions,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+// Options for converting logs into routes, set on the command line or by the
+// Python module (see python.rs). When it's deserialized, any options that
+// aren't set have the same defaults as on the command line.
+#[derive(clap::Args, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConvertOptions {
+    /// Path to generate Cabana data directory
+    #[arg(short, long, default_value = "data_dir")]
+    pub data_dir: PathBuf,
+
+    /// Overwrite existing video files if found
+    #[arg(short, long)]
+    pub overwrite: bool,
+
+    /// Format of the CAN log files (savvycan, candump, asc, trc, gvret, vehiclespy, busmaster or mf4). If not set, guessed from the file extension.
+    #[arg(long)]
+    #[serde(deserialize_with = "from_arg_str_opt")]
+    pub format: Option<LogFormat>,
+
+    /// Unit of the timestamps in SavvyCAN CSV logs (us, ms or s). If not set, guessed from the first few messages.
+    #[arg(long)]
+    pub ts_unit: Option<TsUnit>,
+
+    /// How the timestamps in SavvyCAN CSV logs are written: absolute, or delta for the time
+    /// since the message before (added up from the start of each log file)
+    #[arg(long, default_value = "absolute")]
+    #[serde(deserialize_with = "from_arg_str")]
+    pub ts_mode: TsMode,
+
+    /// Date that BUSMASTER logs were recorded on (YYYY-MM-DD, or mtime for the date each log
+    /// file was modified), so their times of day are absolute timestamps
+    #[arg(long)]
+    #[serde(deserialize_with = "from_arg_str_opt")]
+    pub log_date: Option<LogDate>,
+
+    /// Skip any rows in the CAN logs that can't be parsed, instead of stopping with an error
+    #[arg(long)]
+    pub skip_bad_rows: bool,
+
+    /// Check that each row of a SavvyCAN CSV log has exactly as many data fields as its length
+    /// field says, and stop with an error if not. By default any extra fields are read as data.
+    #[arg(long)]
+    pub strict_fields: bool,
+
+    /// Reverse the order of the data bytes in every CAN message, for loggers that write them
+    /// backwards. By default the data bytes are in the order they were sent on the bus.
+    #[arg(long, conflicts_with = "socketcan")]
+    pub reverse_data_bytes: bool,
+
+    /// Write a route with one empty segment if there are no CAN messages (i.e. a log with only
+    /// a header, or everything filtered out), instead of stopping with an error
+    #[arg(long)]
+    pub allow_empty: bool,
+
+    /// Stop with an error if any CAN messages have flags that disagree with their ID or data
+    /// (i.e. a standard ID above 0x7FF), instead of only warning about them
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Only include CAN messages from these bus numbers (comma separated). Default is all buses.
+    #[arg(long, value_delimiter = ',')]
+    pub bus: Vec<u8>,
+
+    /// Renumber buses as the logs are read, as comma separated FROM:TO pairs (i.e. 1:0,2:1,3:2).
+    /// Other buses keep their numbers. --bus applies to the new numbers.
+    #[arg(long, value_delimiter = ',', value_parser = parse_bus_remap)]
+    pub remap_bus: Vec<(u8, u8)>,
+
+    /// Bus index written to the route for each bus, as comma separated FROM:TO pairs (i.e.
+    /// 2:0,0:2 to show bus 2 first in Cabana). Unlike --remap-bus this only changes the route,
+    /// not --bus or --stats. Other buses keep their numbers.
+    #[arg(long, value_delimiter = ',', value_parser = parse_bus_remap)]
+    pub output_bus: Vec<(u8, u8)>,
+
+    /// Only include CAN messages with these IDs (hex, comma separated or repeated). Default is all IDs.
+    #[arg(long, value_delimiter = ',', value_parser = parse_can_id)]
+    pub include_id: Vec<u32>,
+
+    /// Exclude CAN messages with these IDs (hex, comma separated or repeated).
+    #[arg(long, value_delimiter = ',', value_parser = parse_can_id)]
+    pub exclude_id: Vec<u32>,
+
+    /// Thin out CAN IDs to a lower rate in the route, as ID:HZ pairs (hex ID, comma separated or
+    /// repeated, i.e. 1d0:10). The most recent message for the ID in each 1/HZ window is kept,
+    /// others are dropped. Gaps, --stats and alerts are still found from all the messages.
+    #[arg(long, value_delimiter = ',', value_parser = parse_decimate)]
+    pub decimate: Vec<(u32, f64)>,
+
+    /// Only convert the route from this time (seconds, or HH:MM:SS), measured from the start the
+    /// route would otherwise have. The route is moved to start from here.
+    #[arg(long, value_parser = parse_time_arg)]
+    #[serde(deserialize_with = "from_seconds_opt")]
+    pub start: Option<Nanos>,
+
+    /// Only convert the route up to this time (seconds, or HH:MM:SS), measured the same as --start
+    #[arg(long, value_parser = parse_time_arg)]
+    #[serde(deserialize_with = "from_seconds_opt")]
+    pub end: Option<Nanos>,
+
+    /// Stream CAN messages from the log instead of loading them all into memory. The log is
+    /// read twice, and messages are only reordered within a small window (fine unless
+    /// messages in the log are very out of order).
+    #[arg(long)]
+    pub low_memory: bool,
+
+    /// Record live routes from SocketCAN interfaces until Ctrl-C is pressed, with each YAML
+    /// logfile (and extra_logfiles) naming an interface (i.e. can0) instead of a log file
+    #[arg(long, conflicts_with_all = ["low_memory", "dry_run"])]
+    #[cfg_attr(not(target_os = "linux"), arg(hide = true))]
+    pub socketcan: bool,
+
+    /// Show an alert for any gap longer than this many milliseconds with no CAN messages
+    #[arg(long, default_value_t = 500)]
+    pub gap_threshold_ms: u64,
+
+    /// Also show an alert when any periodic CAN ID stops arriving for more than 5x its usual period
+    #[arg(long)]
+    pub detect_id_dropout: bool,
+
+    /// Drop the CAN messages of any ID whose data never changes in the log, except its first
+    /// message, so the route only has IDs with changing signals
+    #[arg(long)]
+    pub only_ids_with_changes: bool,
+
+    /// Repeat each alert at this interval in milliseconds, so it stays visible during playback
+    #[arg(long, default_value_t = 100)]
+    pub alert_interval_ms: u64,
+
+    /// Insert a video thumbnail (shown in the Cabana timeline) at this interval, in milliseconds
+    #[arg(long, default_value_t = 5000)]
+    pub thumbnail_interval_ms: u64,
+
+    /// Also write each segment's thumbnails as JPEG files, with a thumbnails.json index of
+    /// them, for timelines that don't read the logs (always written with
+    /// --connect-compatible)
+    #[arg(long)]
+    pub thumbnail_manifest: bool,
+
+    /// Keep CAN messages from before the video starts, by starting the route earlier and
+    /// padding the start of the video with black frames
+    #[arg(long)]
+    pub pad_video_start: bool,
+
+    /// Keep CAN messages from before the video starts by moving them to the start of the
+    /// route, instead of dropping them
+    #[arg(long, conflicts_with_all = ["pad_video_start", "start"])]
+    pub clamp_early: bool,
+
+    /// Move the video this many milliseconds later (or earlier, if negative) relative to the
+    /// CAN log. Applied after the video is synced using the YAML sync section.
+    #[arg(long, default_value_t = 0, allow_hyphen_values = true)]
+    pub video_offset_ms: i64,
+
+    /// Find the video offset automatically instead, by matching the motion in the video
+    /// against this DBC speed signal (MESSAGE.SIGNAL, or SIGNAL if the name is unique)
+    #[arg(long)]
+    pub auto_sync_signal: Option<String>,
+
+    /// Timestamp video frames at this constant frame rate, instead of from the timestamps in
+    /// the video (i.e. for a camera that misreports its frame rate, so the video drifts)
+    #[arg(long)]
+    pub fps: Option<f64>,
+
+    /// Correct video timestamps that go backwards (i.e. between recordings in a dashcam video)
+    /// by moving the rest of the video later, instead of dropping frames until they catch up
+    #[arg(long)]
+    pub monotonize_video: bool,
+
+    /// Spread out CAN messages that have identical timestamps evenly over the interval up to
+    /// the next timestamp, so they're strictly ordered for smoother playback
+    #[arg(long)]
+    pub dedup_timestamps: bool,
+
+    /// Stop writing the route after this many segments, truncating it
+    #[arg(long)]
+    pub max_segments: Option<i64>,
+
+    /// Length of each route segment in seconds. Real openpilot routes (and comma Connect) use
+    /// 60 second segments.
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub segment_seconds: u64,
+
+    /// Write statistics for the CAN messages in each route (rates, intervals and gaps) to this
+    /// JSON file, as a list with one entry per route
+    #[arg(long)]
+    pub stats: Option<PathBuf>,
+
+    /// Write a CSV file next to each route's launch script counting the CAN messages and video
+    /// frames in each second of the route, marking seconds that have one but not the other
+    #[arg(long)]
+    pub alignment_report: bool,
+
+    /// Write a JSON file next to each route's launch script with the log timestamp and wall
+    /// clock time (if known) that the route starts at, to convert times in the route back
+    #[arg(long)]
+    pub timebase: bool,
+
+    /// Write the routes to this zip file (or tar file, with a .tar extension) instead of
+    /// --data-dir. The archive has the same files as a data directory would.
+    #[arg(long, conflicts_with_all = ["dry_run", "export_signals", "replay_to"])]
+    pub bundle: Option<PathBuf>,
+
+    /// Encrypt the files in a zip --bundle with AES-256, using the password on the first line
+    /// of this file (so it isn't in the command line)
+    #[arg(long, requires = "bundle")]
+    pub bundle_password_file: Option<PathBuf>,
+
+    /// Don't show progress bars or any messages except errors (and the output of --dry-run or
+    /// verify)
+    #[arg(long)]
+    pub quiet: bool,
+
+    /// Write the progress of the conversion to stdout as JSON, one object per line with the
+    /// phase ("reading", "writing", "done" or "bundling"), log, segment and percent
+    #[arg(long, conflicts_with_all = ["dry_run", "replay_to"])]
+    pub json_progress: bool,
+
+    /// Read the CAN logs and videos and print a summary of the route, without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Write the values of signals decoded with the log's DBC file to this CSV file, instead of
+    /// writing a route. Only one log can be exported at a time.
+    #[arg(long, conflicts_with_all = ["dry_run", "low_memory", "socketcan"])]
+    pub export_signals: Option<PathBuf>,
+
+    /// Signals to write with --export-signals (MESSAGE.SIGNAL, or SIGNAL if the name is unique;
+    /// comma separated or repeated). Default is every signal in the DBC file.
+    #[arg(long, value_delimiter = ',', requires = "export_signals")]
+    pub signal: Vec<String>,
+
+    /// Instead of writing a route, stream its events to stdout (-) or a TCP address (HOST:PORT)
+    /// in real time, as if they were arriving live. For testing tools that read live events.
+    #[arg(long, conflicts_with_all = ["dry_run", "export_signals", "socketcan", "stats"])]
+    pub replay_to: Option<String>,
+
+    /// Speed for --replay-to, as a multiple of real time (i.e. 2 streams twice as fast)
+    #[arg(long, default_value_t = 1.0, requires = "replay_to", value_parser = parse_replay_speed)]
+    pub replay_speed: f64,
+
+    /// Only write the full resolution fcamera.hevc video for each segment, not the low
+    /// resolution qcamera.ts (used by comma connect's web replay)
+    #[arg(long)]
+    pub no_qcamera: bool,
+
+    /// Re-encode HEVC videos even when their packets could be copied into the segment videos
+    /// as they are (which is much faster, and keeps the original quality and frame rate)
+    #[arg(long)]
+    pub reencode_video: bool,
+
+    /// Compression for the rlog and qlog files (none, bz2 or zstd). Cabana and openpilot
+    /// tools expect bz2, none is useful for debugging and zstd is much faster to write.
+    #[arg(long, default_value = "bz2")]
+    #[serde(deserialize_with = "from_arg_str")]
+    pub compression: LogCompression,
+
+    /// Only write the full rate rlog for each segment, not the decimated qlog
+    #[arg(long)]
+    pub no_qlog: bool,
+
+    /// Make routes that a (self-hosted) comma Connect server will accept: always write
+    /// bz2 compressed rlogs and qlogs, and fill any gaps in the route with empty segments
+    #[arg(long, conflicts_with_all = ["no_qlog", "compression"])]
+    pub connect_compatible: bool,
+
+    /// Where each segment's files are written in the output directory. {route} is the
+    /// route name, {segment} the segment number, {dongle} the --dongle-id and {file} the
+    /// file's name (rlog.bz2, fcamera.hevc, etc.). Cabana and comma Connect only find
+    /// routes in the default openpilot layout.
+    #[arg(long, default_value = DEFAULT_OUTPUT_LAYOUT)]
+    pub output_layout: String,
+
+    /// Ignore any videos in the YAML file, and make routes from only the CAN logs
+    #[arg(long)]
+    pub can_only: bool,
+
+    /// Make routes that are safer to share: blur any driver camera video, and leave out the
+    /// GPS locations
+    #[arg(long)]
+    pub privacy: bool,
+
+    /// Car name for the route's carParams, overrides the car names in the YAML file
+    #[arg(long)]
+    pub car: Option<String>,
+
+    /// DBC file for every log, overrides any dbc in the YAML file. This decodes signals for
+    /// alert_rules and car_state, and is opened by the route's launch script in Cabana.
+    #[arg(long)]
+    pub dbc: Option<PathBuf>,
+
+    /// Version string for the route's initData (default is the make_cabana_route version)
+    #[arg(long, default_value = concat!("make_cabana_route ", env!("CARGO_PKG_VERSION")))]
+    pub openpilot_version: String,
+
+    /// Git commit for the route's initData
+    #[arg(long, default_value = "0000000000000000000000000000000000000000")]
+    pub git_commit: String,
+
+    /// Git branch for the route's initData
+    #[arg(long, default_value = "make_cabana_route")]
+    pub git_branch: String,
+
+    /// Git remote for the route's initData
+    #[arg(long, default_value = "make_cabana_route")]
+    pub git_remote: String,
+
+    /// Device type for the route's initData (unknown, neo, tici, tizi or pc)
+    #[arg(long, default_value = "pc")]
+    #[serde(deserialize_with = "from_arg_str")]
+    pub device_type: DeviceType,
+
+    /// Dongle ID of the device that the route claims to be recorded on, 16 hex digits. This is
+    /// written in the route's initData, and can be used in --output-layout as {dongle}.
+    #[arg(long, default_value = "0000000000000000", value_parser = parse_dongle_id)]
+    pub dongle_id: String,
+
+    /// Wall clock time that the route starts (RFC 3339, i.e. 2023-01-01T00:00:00Z), for
+    /// initData and the route name. By default this comes from the log.
+    #[arg(long)]
+    pub start_time: Option<DateTime<Utc>>,
+
+    /// Optional filter. If set, only process logs containing this string.
+    pub filter_by: Option<String>,
+}
+
+// Commands that work on routes already in the data directory, instead of
+// converting the logs in the YAML file
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Join two routes in --data-dir into a new route, i.e. for a drive where the logger was
+    /// restarted. The second route's segments are renumbered and their timestamps moved to
+    /// follow the first route.
+    Concat {
+        /// Name of the first route (the segment directory names, without the --<segment> part)
+        first: String,
+        /// Name of the route to add after it
+        second: String,
+        /// Name of the joined route, which must not exist yet
+        output: String,
+    },
+    /// Check that a route in --data-dir is well formed, printing a report for each segment.
+    /// Exits with an error if any problems are found.
+    Verify {
+        /// Name of the route (the segment directory names, without the --<segment> part)
+        route: String,
+    },
+    /// Write the CAN frames in a route in --data-dir to a SavvyCAN CSV file, with the
+    /// timestamps of the original log if the route was written with --timebase
+    DumpCan {
+        /// Name of the route (the segment directory names, without the --<segment> part)
+        route: String,
+        /// Only dump this segment of the route
+        #[arg(long)]
+        segment: Option<i64>,
+        /// Timebase file for the route's timestamps, by default the one in --data-dir for the
+        /// route (if any)
+        #[arg(long)]
+        timebase_file: Option<PathBuf>,
+        /// CSV file to write, instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+// Parse a hex CAN ID from the command line, with or without 0x prefix
+fn parse_can_id(arg: &str) -> Result<u32> {
+    let hex = arg
+        .strip_prefix("0x")
+        .or_else(|| arg.strip_prefix("0X"))
+        .unwrap_or(arg);
+    u32::from_str_radix(hex, 16).with_context(|| format!("Invalid CAN ID {}", arg))
+}
+
+// Parse a time from the command line, either in seconds or as [HH:]MM:SS (the
+// seconds can have a fractional part in either case)
+fn parse_time_arg(arg: &str) -> Result<Nanos> {
+    let mut secs = 0.0;
+    for part in arg.split(':') {
+        let value: f64 = part
+            .parse()
+            .with_context(|| format!("Invalid time {}", arg))?;
+        secs = secs * 60.0 + value;
+    }
+    if arg.split(':').count() > 3 || secs < 0.0 {
+        bail!("Invalid time {}", arg);
+    }
+    Ok((secs * 1e9).round() as Nanos)
+}
+
+// Parse a FROM:TO pair of bus numbers from the command line
+fn parse_bus_remap(arg: &str) -> Result<(u8, u8)> {
+    let (from, to) = arg
+        .split_once(':')
+        .with_context(|| format!("Bus remapping {} should be FROM:TO", arg))?;
+    let bus = |b: &str| -> Result<u8> {
+        b.parse()
+            .with_context(|| format!("Invalid bus number {}", b))
+    };
+    Ok((bus(from)?, bus(to)?))
+}
+
+// Dongle IDs are 16 lowercase hex digits, the same as openpilot devices
+fn parse_dongle_id(arg: &str) -> Result<String> {
+    if arg.len() != 16 || !arg.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Invalid dongle ID {}, should be 16 hex digits", arg);
+    }
+    Ok(arg.to_lowercase())
+}
+
+fn parse_replay_speed(arg: &str) -> Result<f64> {
+    let speed: f64 = arg
+        .parse()
+        .with_context(|| format!("Invalid replay speed {}", arg))?;
+    if !speed.is_finite() || speed <= 0.0 {
+        bail!("Invalid replay speed {}, should be more than 0", arg);
+    }
+    Ok(speed)
+}
+
+// Parse an ID:HZ pair for --decimate
+fn parse_decimate(arg: &str) -> Result<(u32, f64)> {
+    let (id, rate) = arg
+        .split_once(':')
+        .with_context(|| format!("Decimation {} should be ID:HZ", arg))?;
+    let rate: f64 = rate
+        .parse()
+        .with_context(|| format!("Invalid rate {}", rate))?;
+    if !rate.is_finite() || rate <= 0.0 {
+        bail!("Invalid rate {}, should be more than 0 Hz", rate);
+    }
+    Ok((parse_can_id(id)?, rate))
+}
+
+impl Default for ConvertOptions {
+    // The command line defaults, so they're only set in one place
+    fn default() -> Self {
+        #[derive(Parser)]
+        struct Defaults {
+            #[command(flatten)]
+            options: ConvertOptions,
+        }
+        Defaults::parse_from(["make_cabana_route"]).options
+    }
+}
+
+impl ConvertOptions {
+    // Check the values that the command line parsers check, for options that
+    // weren't set from the command line
+    fn check(&self) -> Result<()> {
+        if self.segment_seconds == 0 {
+            bail!("segment_seconds should be at least 1");
+        }
+        parse_dongle_id(&self.dongle_id)?;
+        if !self.replay_speed.is_finite() || self.replay_speed <= 0.0 {
+            bail!(
+                "Invalid replay speed {}, should be more than 0",
+                self.replay_speed
+            );
+        }
+        for (_, rate) in &self.decimate {
+            if !rate.is_finite() || *rate <= 0.0 {
+                bail!("Invalid rate {}, should be more than 0 Hz", rate);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Deserialize an option that's written the same as on the command line, i.e.
+// compression = "zstd"
+fn from_arg_str<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn from_arg_str_opt<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .transpose()
+}
+
+// Deserialize a time in seconds, i.e. start = 90.5
+fn from_seconds_opt<'de, D>(deserializer: D) -> std::result::Result<Option<Nanos>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(secs) if !secs.is_finite() || secs < 0.0 => {
+            Err(de::Error::custom(format!("Invalid time {}", secs)))
+        }
+        secs => Ok(secs.map(|secs| (secs * 1e9).round() as Nanos)),
+    }
+}
+
+// One log to convert into a route, as written in the YAML file
+#[derive(Deserialize, Debug)]
+pub struct LogInfo {
+    car: String,
+    fingerprint: String,
+    route_timestamp: Option<DateTime<Local>>,
+    logfile: PathBuf,
+    // Any more log files to merge with logfile into the same route
+    #[serde(default)]
+    extra_logfiles: Vec<PathBuf>,
+    video: Option<PathBuf>,
+    sync: Option<LogSyncInfo>,
+    // Videos from the wide road and driver cameras, as well as the road camera video
+    #[serde(default)]
+    extra_cameras: Vec<CameraVideo>,
+    // GPX or NMEA log of GPS fixes, to add location events to the route
+    gps: Option<PathBuf>,
+    gps_sync: Option<GpsSyncInfo>,
+    // CSV file of "timestamp,label" markers to show on the timeline
+    markers: Option<PathBuf>,
+    // JSON or CSV file of alerts from another tool, shown with the alerts found
+    // from the CAN messages (see read_alerts())
+    alerts: Option<PathBuf>,
+    // DBC file used to decode signals for alert_rules, and opened with the route
+    // by the launch script
+    dbc: Option<PathBuf>,
+    #[serde(default)]
+    alert_rules: Vec<AlertRule>,
+    // DBC signals to add a marker for each time they turn on or off
+    #[serde(default)]
+    edge_markers: Vec<EdgeMarkerRule>,
+    // DBC signals to decode into carState events
+    car_state: Option<CarStateSignals>,
+    // Synthetic CAN messages to add to the log's messages
+    #[serde(default)]
+    generate: Vec<GeneratorSpec>,
+}
+
+// Video from another camera, to include in the route alongside the road camera video
+#[derive(Deserialize, Debug)]
+struct CameraVideo {
+    camera: Camera,
+    video: PathBuf,
+    // If not set, the same sync values as the road camera video
+    sync: Option<LogSyncInfo>,
+}
+
+impl LogInfo {
+    // A log with only a CAN log file and optionally a road camera video, for
+    // convert() callers that don't have a YAML file
+    pub fn new(car: &str, fingerprint: &str, logfile: PathBuf, video: Option<PathBuf>) -> Self {
+        LogInfo {
+            car: car.to_string(),
+            fingerprint: fingerprint.to_string(),
+            route_timestamp: None,
+            logfile,
+            extra_logfiles: vec![],
+            video,
+            sync: None,
+            extra_cameras: vec![],
+            gps: None,
+            gps_sync: None,
+            markers: None,
+            alerts: None,
+            dbc: None,
+            alert_rules: vec![],
+            edge_markers: vec![],
+            car_state: None,
+            generate: vec![],
+        }
+    }
+
+    // Convert relative paths (relative to the directory relative_to) to absolute
+    // ones, return an error if paths don't exist. If live_can is set, the log
+    // files are SocketCAN interface names and are left as they are.
+    fn canonicalise_paths(&mut self, relative_to: &Path, live_can: bool) -> Result<()> {
+        let relative_to = relative_to
+            .canonicalize()
+            .with_context(|| format!("Failed to canonicalize path {:?}", relative_to))?;
+
+        if !live_can {
+            self.logfile = relative_to.join(&self.logfile);
+
+            // Check logfile exists
+            self.logfile
+                .metadata()
+                .with_context(|| format!("Failed to read log file metadata: {:?}", self.logfile))?;
+
+            for logfile in &mut self.extra_logfiles {
+                *logfile = relative_to.join(logfile.as_path());
+                logfile
+                    .metadata()
+                    .with_context(|| format!("Failed to read log file metadata: {:?}", logfile))?;
+            }
+        }
+
+        if let Some(dbc) = &self.dbc {
+            let dbc = relative_to.join(dbc);
+            dbc.metadata()
+                .with_context(|| format!("Failed to read DBC file metadata: {:?}", dbc))?;
+            self.dbc = Some(dbc);
+        }
+
+        if let Some(gps) = &self.gps {
+            let gps = relative_to.join(gps);
+            gps.metadata()
+                .with_context(|| format!("Failed to read GPS log metadata: {:?}", gps))?;
+            self.gps = Some(gps);
+        }
+
+        if let Some(markers) = &self.markers {
+            let markers = relative_to.join(markers);
+            markers
+                .metadata()
+                .with_context(|| format!("Failed to read markers file metadata: {:?}", markers))?;
+            self.markers = Some(markers);
+        }
+
+        if let Some(alerts) = &self.alerts {
+            let alerts = relative_to.join(alerts);
+            alerts
+                .metadata()
+                .with_context(|| format!("Failed to read alerts file metadata: {:?}", alerts))?;
+            self.alerts = Some(alerts);
+        }
+
+        if let Some(video) = &self.video {
+            let video = relative_to.join(video);
+            // Check video exists
+            video
+                .metadata()
+                .with_context(|| format!("Failed to read video metadata: {:?}", video))?;
+            self.video = Some(video);
+        }
+
+        for camera in &mut self.extra_cameras {
+            camera.video = relative_to.join(&camera.video);
+            camera
+                .video
+                .metadata()
+                .with_context(|| format!("Failed to read video metadata: {:?}", camera.video))?;
+        }
+
+        Ok(())
+    }
+
+    // Give the nanosecond offset to subtract from a GPS fix time (nanoseconds since
+    // the Unix epoch), to convert it into a route timestamp. Without gps_sync the
+    // CAN log has to have absolute timestamps, and the two clocks are assumed to
+    // agree.
+    fn gps_ts_offs(&self, can_ts_offs: Nanos) -> Result<Nanos> {
+        match &self.gps_sync {
+            Some(sync) => {
+                let gps_ns = sync.gps_time.timestamp() * 1_000_000_000
+                    + sync.gps_time.timestamp_subsec_nanos() as i64;
+                Ok(gps_ns - (sync.log_us * 1000 - can_ts_offs))
+            }
+            None if epoch_start_time(can_ts_offs).is_some() => Ok(can_ts_offs),
+            None => bail!("CAN log doesn't have absolute timestamps, gps_sync needs to be set"),
+        }
+    }
+
+    // Routes are identified in openpilot by their timestamp.
+    //
+    // If route_timestamp is set in the YAML file, use this. Otherwise, use the
+    // start time of the log if it has absolute timestamps (log_start), or
+    // the modification date of the video file or the log file..
+    fn route_timestamp(&self, log_start: Option<SystemTime>) -> DateTime<Local> {
+        if let Some(ts) = self.route_timestamp {
+            ts
+        } else if let Some(log_start) = log_start {
+            log_start.into()
+        } else if let Some(video) = &self.video {
+            video
+                .metadata()
+                .expect("video file should already exist")
+                .modified()
+                .expect("video file checked already")
+                .into()
+        } else {
+            self.logfile
+                .metadata()
+                .expect("logfile checked already")
+                .modified()
+                .expect("logfile checked already")
+                .into()
+        }
+    }
+
+    // Routes are named after the route timestamp
+    //
+    // See replay Route::parseRoute() in openpilot for the regex that resolves the route name.
+    //
+    // The full name of a route also starts with the 16 character hex dongle ID of
+    // the device, i.e. "0123456789abcdef|2022-12-17--09-35-30". This is left out
+    // of the directory names (unless --output-layout includes it), as Cabana
+    // finds local routes without it. It's written in initData instead.
+    fn route_name(&self, log_start: Option<SystemTime>) -> String {
+        self.route_timestamp(log_start)
+            .format("%Y-%m-%d--%H-%M-%S")
+            .to_string()
+    }
+
+    // The full route name, with the dongle ID, as openpilot and Cabana name routes
+    fn full_route_name(&self, log_start: Option<SystemTime>, dongle_id: &str) -> String {
+        format!("{}|{}", dongle_id, self.route_name(log_start))
+    }
+
+    // Segment directories in the data directory are the route name plus a suffix
+    // for the segment number, or as set by --output-layout
+    fn segment_dir_path(
+        &self,
+        data_dir: &Path,
+        log_start: Option<SystemTime>,
+        segment_idx: i64,
+        args: &ConvertOptions,
+    ) -> PathBuf {
+        let segment_dir = args
+            .output_layout
+            .strip_suffix("/{file}")
+            .expect("output layout checked already")
+            .replace("{dongle}", &args.dongle_id)
+            .replace("{route}", &self.route_name(log_start))
+            .replace("{segment}", &segment_idx.to_string());
+        data_dir.join(segment_dir)
+    }
+
+    // All the video files for this route, with their camera and sync values
+    fn videos(&self) -> Result<Vec<(Camera, &Path, &LogSyncInfo)>> {
+        let mut result = vec![];
+        if let (Some(video), Some(sync)) = (&self.video, &self.sync) {
+            result.push((Camera::Road, video.as_path(), sync));
+        }
+        for camera in &self.extra_cameras {
+            if result.iter().any(|(c, _, _)| *c == camera.camera) {
+                bail!(
+                    "Log {:?} has more than one {:?} camera video",
+                    self.logfile,
+                    camera.camera
+                );
+            }
+            let sync = camera
+                .sync
+                .as_ref()
+                .or(self.sync.as_ref())
+                .with_context(|| {
+                    format!("Video {:?} requires a sync section to match", camera.video)
+                })?;
+            result.push((camera.camera, camera.video.as_path(), sync));
+        }
+        Ok(result)
+    }
+
+    // Forget the videos and their sync sections, so the route starts from the first
+    // CAN message instead of the start of the video
+    fn drop_videos(&mut self) {
+        self.video = None;
+        self.sync = None;
+        self.extra_cameras.clear();
+    }
+
+    // Forget the GPS log, so the route has no locations
+    fn drop_gps(&mut self) {
+        self.gps = None;
+        self.gps_sync = None;
+    }
+
+    // All the log files for this route
+    fn logfiles(&self) -> Vec<PathBuf> {
+        let mut result = vec![self.logfile.clone()];
+        result.extend(self.extra_logfiles.iter().cloned());
+        result
+    }
+
+    fn log_matches(&self, filter_by: &str) -> bool {
+        /* Match log info on either the video file name (if any), log file name,
+
+        */
+        let log_match = self
+            .logfile
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .contains(filter_by);
+
+        let video_match = self
+            .video
+            .as_ref()
+            .and_then(|p| p.file_name())
+            .and_then(|o| o.to_str())
+            .map(|s| s.contains(filter_by))
+            .unwrap_or(false);
+
+        log_match || video_match || self.fingerprint.contains(filter_by)
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+struct LogSyncInfo {
+    video_s: f64,
+    log_us: i64,
+}
+
+impl LogSyncInfo {
+    /* Give the nanosecond offset to subtract from a CAN log timestamp, to
+    convert it into the pts timestamp of the video.
+    */
+    fn can_ts_offs(&self) -> Nanos {
+        // The video timestamp that corresponds to log timestamp
+        let video_us = (self.video_s * 1_000_000.0) as i64;
+        // The log timestamp that corresponds to video timestamp 0:00
+        let log_us_at_zero = self.log_us - video_us;
+
+        log_us_at_zero * 1000
+    }
+}
+
+// A GPS fix time and the CAN log timestamp at the same moment, similar to
+// LogSyncInfo for a video
+#[derive(Deserialize, PartialEq, Debug)]
+struct GpsSyncInfo {
+    gps_time: DateTime<Utc>,
+    log_us: i64,
+}
+
+pub fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Messages go to stderr, at info level unless RUST_LOG is set (i.e.
+    // RUST_LOG=debug for more detail, or RUST_LOG=warn for only warnings).
+    // --quiet only shows errors, whatever RUST_LOG is.
+    let filter = match args.options.quiet {
+        true => EnvFilter::new("error"),
+        false => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .without_time()
+        .init();
+
+    // The first Ctrl-C ends the route after the segment being written (or
+    // stops recording, with --socketcan)
+    handle_interrupt();
+
+    run(&args)
+}
+
+fn run(args: &Args) -> Result<()> {
+    if let Some(command) = &args.command {
+        ffmpeg::init().context("Failed to initialise ffmpeg")?;
+        return run_command(command, &args.options);
+    }
+
+    let f = std::fs::File::open(&args.yaml_path)
+        .with_context(|| format!("Failed to read file at path {:?}", &args.yaml_path))?;
+    let logs: Vec<LogInfo> = serde_yaml::from_reader(f)
+        .with_context(|| format!("Failed to read YAML data from {:?}", args.yaml_path))?;
+    // Paths in the YAML file are relative to it
+    let yaml_dir = args
+        .yaml_path
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize path {:?}", args.yaml_path))?
+        .parent()
+        .expect("YAML file should always have a parent directory.")
+        .to_path_buf();
+    convert(logs, &yaml_dir, &args.options)
+}
+
+// Convert each of the logs into a route, the same as the command line tool does
+// for the logs in its YAML file. Relative paths in the logs are relative to
+// base_dir.
+pub fn convert(mut logs: Vec<LogInfo>, base_dir: &Path, args: &ConvertOptions) -> Result<()> {
+    ffmpeg::init().context("Failed to initialise ffmpeg")?;
+    args.check()?;
+
+    // Check the DBC file is good before converting anything with it. Any dbc
+    // files in the YAML file are checked as each log is converted.
+    let dbc_path = match &args.dbc {
+        Some(dbc_path) => {
+            Dbc::from_path(dbc_path)?;
+            Some(
+                dbc_path
+                    .canonicalize()
+                    .with_context(|| format!("Failed to canonicalize path {:?}", dbc_path))?,
+            )
+        }
+        None => None,
+    };
+
+    if args.connect_compatible && args.segment_seconds != 60 {
+        bail!("comma Connect only accepts routes with 60 second segments (--segment-seconds 60)");
+    }
+    check_output_layout(&args.output_layout)?;
+    if args.connect_compatible && args.output_layout != DEFAULT_OUTPUT_LAYOUT {
+        bail!("comma Connect only accepts routes with the default --output-layout");
+    }
+
+    // Fix up paths, this will also error out early if any files are not found
+    for info in &mut logs {
+        if args.can_only {
+            info.drop_videos();
+        }
+        if args.privacy {
+            info.drop_gps();
+        }
+        // The route is named after --start-time, not any route_timestamp in the file
+        if let Some(start_time) = args.start_time {
+            info.route_timestamp = Some(start_time.into());
+        }
+        info.canonicalise_paths(base_dir, args.socketcan)?;
+        if let Some(dbc_path) = &dbc_path {
+            info.dbc = Some(dbc_path.clone());
+        }
+    }
+
+    let logs: Vec<&LogInfo> = logs
+        .iter()
+        .filter(|info| {
+            args.filter_by
+                .as_deref()
+                .is_none_or(|f| info.log_matches(f))
+        })
+        .collect();
+    if args.export_signals.is_some() && logs.len() > 1 {
+        bail!(
+            "--export-signals writes a single log's signals, pass a filter to choose one of the {} \
+             logs",
+            logs.len()
+        );
+    }
+
+    // Read the password before converting anything, in case it's missing
+    let bundle_password = args
+        .bundle_password_file
+        .as_deref()
+        .map(read_bundle_password)
+        .transpose()?;
+    if let (Some(bundle), Some(_)) = (&args.bundle, &bundle_password) {
+        if BundleFormat::from_path(bundle) != BundleFormat::Zip {
+            bail!("Only zip bundles can be encrypted, not {:?}", bundle);
+        }
+    }
+
+    // With --bundle the routes are written to a new directory next to the
+    // bundle, which is removed once it's been archived
+    let data_dir = match &args.bundle {
+        Some(bundle) => {
+            let mut staging_dir = bundle.clone().into_os_string();
+            staging_dir.push(".tmp");
+            let staging_dir = PathBuf::from(staging_dir);
+            if staging_dir.try_exists()? {
+                bail!(
+                    "{:?} already exists, remove it to write the bundle {:?}",
+                    staging_dir,
+                    bundle
+                );
+            }
+            staging_dir
+        }
+        None => args.data_dir.clone(),
+    };
+
+    let mut all_stats = vec![];
+    for info in logs {
+        all_stats.extend(process_log(info, args, &data_dir)?);
+        if interrupted() && !args.socketcan {
+            warn!("Interrupted, not converting any more logs");
+            break;
+        }
+    }
+
+    if let Some(bundle) = &args.bundle {
+        if data_dir.try_exists()? {
+            write_json_progress(args, "bundling", None, None, None);
+            write_bundle(&data_dir, bundle, bundle_password.as_deref())?;
+            fs::remove_dir_all(&data_dir)
+                .with_context(|| format!("Failed to remove {:?}", data_dir))?;
+        } else {
+            warn!("No routes were written, so there's no bundle");
+        }
+    }
+
+    if let Some(stats_path) = &args.stats {
+        let f = File::create(stats_path)
+            .with_context(|| format!("Failed to create stats file {:?}", stats_path))?;
+        serde_json::to_writer_pretty(f, &all_stats)
+            .with_context(|| format!("Failed to write stats file {:?}", stats_path))?;
+    }
+
+    Ok(())
+}
+
+fn run_command(command: &Command, args: &ConvertOptions) -> Result<()> {
+    match command {
+        Command::Concat {
+            first,
+            second,
+            output,
+        } => concat_routes(&args.data_dir, first, second, output, segment_nanos(args)),
+        Command::Verify { route } => {
+            let checks = verify_route(&args.data_dir, route)?;
+            let mut problems = 0;
+            for check in &checks {
+                let status = match check.problems.is_empty() {
+                    true => "OK",
+                    false => "FAILED",
+                };
+                println!(
+                    "Segment {}: {} events, videos [{}]: {}",
+                    check.number,
+                    check.events,
+                    check.videos.join(", "),
+                    status
+                );
+                for problem in &check.problems {
+                    println!("  {}", problem);
+                }
+                problems += check.problems.len();
+            }
+            if problems > 0 {
+                bail!("Route {} has {} problems", route, problems);
+            }
+            println!("Route {} passed ({} segments)", route, checks.len());
+            Ok(())
+        }
+        Command::DumpCan {
+            route,
+            segment,
+            timebase_file,
+            output,
+        } => {
+            let timebase = match timebase_file {
+                Some(path) => Some(read_timebase(path)?),
+                None => find_timebase(&args.data_dir, route)?,
+            };
+            let (ts_offset, ts_unit) = match &timebase {
+                Some(timebase) => (timebase.ts_offset, timebase.ts_unit),
+                None => {
+                    info!(
+                        "No timebase file for route {}, timestamps start from 0",
+                        route
+                    );
+                    (0, None)
+                }
+            };
+            // Unless the unit is known, write microseconds the same as SavvyCAN
+            let ts_unit = args.ts_unit.or(ts_unit).unwrap_or(TsUnit::Micros);
+            let writer: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(
+                    File::create(path).with_context(|| format!("Failed to create {:?}", path))?,
+                )),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let count =
+                dump_route_can(&args.data_dir, route, *segment, ts_offset, ts_unit, writer)?;
+            info!("Wrote {} CAN frames", count);
+            Ok(())
+        }
+    }
+}
+
+// Entry in the --stats file for one route
+#[derive(Serialize)]
+struct RouteStatsEntry {
+    route: String,
+    fingerprint: String,
+    #[serde(flatten)]
+    stats: RouteStats,
+}
+
+// Open a source video, with --fps and --monotonize-video applied
+fn open_video(video_path: &Path, camera: Camera, args: &ConvertOptions) -> Result<SourceVideo> {
+    let mut sv = SourceVideo::new(video_path, camera)?;
+    if let Some(fps) = args.fps {
+        sv.set_fps(fps);
+    }
+    if args.monotonize_video {
+        sv.set_monotonize();
+    }
+    Ok(sv)
+}
+
+// Find the offset to move the video by so its motion best matches the speed
+// signal values
+fn find_video_offset(
+    video_path: &Path,
+    video_start: Nanos,
+    speed: &[(Nanos, f64)],
+    args: &ConvertOptions,
+) -> Result<Nanos> {
+    info!("Finding video offset from motion in {video_path:?}...");
+    let motion = open_video(video_path, Camera::Road, args)?.frame_motion(video_start)?;
+    let (offset, correlation) = find_sync_lag(speed, &motion, AUTO_SYNC_MAX_LAG, AUTO_SYNC_STEP)
+        .context("Video and speed signal don't overlap enough to find the video offset")?;
+    let offset_ms = offset / 1_000_000;
+    info!(
+        "Found video offset {}ms (correlation {:.2}), pass --video-offset-ms={} to reuse it",
+        offset_ms, correlation, offset_ms
+    );
+    Ok(offset)
+}
+
+// Length of each route segment, see --segment-seconds
+fn segment_nanos(args: &ConvertOptions) -> Nanos {
+    args.segment_seconds as Nanos * 1_000_000_000
+}
+
+fn init_data_fields(args: &ConvertOptions) -> InitDataFields {
+    InitDataFields {
+        version: args.openpilot_version.clone(),
+        git_commit: args.git_commit.clone(),
+        git_branch: args.git_branch.clone(),
+        git_remote: args.git_remote.clone(),
+        device_type: args.device_type,
+        dongle_id: args.dongle_id.clone(),
+    }
+}
+
+// Each segment needs its own directory, so the files can keep the names that the
+// openpilot tools expect
+fn check_output_layout(layout: &str) -> Result<()> {
+    let segment_dir = layout
+        .strip_suffix("/{file}")
+        .ok_or(anyhow!("--output-layout should end with /{{file}}"))?;
+    if !segment_dir.contains("{segment}") {
+        bail!("--output-layout needs {{segment}} before /{{file}}");
+    }
+    if segment_dir.contains("{file}") {
+        bail!("--output-layout can only have {{file}} at the end");
+    }
+    if segment_dir.starts_with('/') || segment_dir.split('/').any(|p| p.is_empty() || p == "..") {
+        bail!("--output-layout should be a relative path inside the output directory");
+    }
+    Ok(())
+}
+
+// Fail if any options are set that need all the CAN messages in memory, for a
+// route streamed with the given mode argument
+fn check_streaming_args(info: &LogInfo, args: &ConvertOptions, mode: &str) -> Result<()> {
+    if args.detect_id_dropout {
+        bail!("--detect-id-dropout isn't supported with {}", mode);
+    }
+    if !info.alert_rules.is_empty() {
+        bail!("alert_rules aren't supported with {}", mode);
+    }
+    if !info.edge_markers.is_empty() {
+        bail!("edge_markers aren't supported with {}", mode);
+    }
+    if info.car_state.is_some() {
+        bail!("car_state isn't supported with {}", mode);
+    }
+    if !info.generate.is_empty() {
+        bail!("generate isn't supported with {}", mode);
+    }
+    if args.pad_video_start {
+        bail!("--pad-video-start isn't supported with {}", mode);
+    }
+    if args.auto_sync_signal.is_some() {
+        bail!("--auto-sync-signal isn't supported with {}", mode);
+    }
+    if args.dedup_timestamps {
+        bail!("--dedup-timestamps isn't supported with {}", mode);
+    }
+    if args.start.is_some() || args.end.is_some() {
+        bail!("--start and --end aren't supported with {}", mode);
+    }
+    if args.stats.is_some() {
+        bail!("--stats isn't supported with {}", mode);
+    }
+    if args.only_ids_with_changes {
+        bail!("--only-ids-with-changes isn't supported with {}", mode);
+    }
+    Ok(())
+}
+
+// If no CAN messages were read from the log (without --allow-empty), say which
+// options could have left none of them
+fn empty_log_hint(e: impl Into<anyhow::Error>) -> anyhow::Error {
+    let e = e.into();
+    match e.downcast_ref::<InputError>() {
+        Some(InputError::EmptyLog(_)) => e.context(
+            "CAN log is empty, or none of its messages are left after --bus, --include-id, \
+             --exclude-id, --start and --end (pass --allow-empty to write an empty route anyway)",
+        ),
+        _ => e,
+    }
+}
+
+// Warn when an empty route will be written, with --allow-empty
+fn warn_if_empty(info: &LogInfo, count: usize) {
+    if count == 0 {
+        warn!(
+            "No CAN messages in {:?}, writing an empty route",
+            info.logfiles()
+        );
+    }
+}
+
+// Warn about any CAN messages with inconsistent flags, or fail if strict
+fn report_flag_check(flag_check: &FlagCheck, strict: bool) -> Result<()> {
+    let warnings = flag_check.warnings();
+    if strict && !warnings.is_empty() {
+        bail!(
+            "CAN log has messages with inconsistent flags:\n{}",
+            warnings.join("\n")
+        );
+    }
+    for warning in warnings {
+        warn!("{}", warning);
+    }
+    Ok(())
+}
+
+// Print a summary of the route that would be written for this log
+fn dry_run(
+    info: &LogInfo,
+    args: &ConvertOptions,
+    options: &ReadOptions,
+    sync_ts_offs: Option<Nanos>,
+    gap_threshold: Nanos,
+) -> Result<()> {
+    let CanLog {
+        mut messages,
+        ts_offset: can_ts_offs,
+        buses,
+        duration,
+        ts_unit,
+    } = read_can_logs(&info.logfiles(), args.format, sync_ts_offs, options)
+        .map_err(empty_log_hint)?;
+    if let Some(read_progress) = &options.progress {
+        read_progress.finish_and_clear();
+    }
+    let duplicates = dedup_timestamps(&mut messages, false);
+    report_flag_check(&check_can_flags(&messages), args.strict)?;
+
+    let mut route_end = duration;
+    let duration_s = route_end as f64 / 1e9;
+
+    println!("Route for {:?}", info.logfiles());
+    println!("  Timestamp offset: {}ns", can_ts_offs);
+    if let Some(ts_unit) = ts_unit {
+        println!("  Timestamp unit: {:?}", ts_unit);
+    }
+    if let Some(log_start) = args.start_time.map(SystemTime::from) {
+        println!(
+            "  Start time (--start-time): {}",
+            DateTime::<Local>::from(log_start)
+        );
+    } else if let Some(log_start) = epoch_start_time(can_ts_offs) {
+        println!("  Start time: {}", DateTime::<Local>::from(log_start));
+    }
+    println!("  CAN messages: {}", messages.len());
+    println!("  Duration: {:.3}s", duration_s);
+    println!("  Duplicate timestamps: {}", duplicates);
+
+    let mut id_counts: BTreeMap<(u8, u32), usize> = BTreeMap::new();
+    for m in &messages {
+        *id_counts.entry((m.bus_no, m.can_id)).or_default() += 1;
+    }
+    println!("  Buses: {}", buses.iter().join(", "));
+    println!("  CAN IDs: {}", id_counts.len());
+    for ((bus, can_id), count) in &id_counts {
+        let rate = match duration_s {
+            d if d > 0.0 => format!("{:.1}Hz", *count as f64 / d),
+            _ => "-".to_string(),
+        };
+        println!(
+            "    bus {} 0x{:x}: {} messages, {}",
+            bus, can_id, count, rate
+        );
+    }
+
+    let gaps = find_missing_can_messages(&messages, gap_threshold);
+    println!(
+        "  Gaps over {}ms: {}",
+        args.gap_threshold_ms,
+        gaps.len() / 2
+    );
+    // Each gap has an alert at the start and a Normal alert at the end
+    for (start, end) in gaps.iter().tuples() {
+        println!(
+            "    {:.3}s to {:.3}s",
+            start.timestamp as f64 / 1e9,
+            end.timestamp as f64 / 1e9
+        );
+    }
+
+    for (camera, video_path, sync) in info.videos()? {
+        let video = SourceVideo::new(video_path, camera)?.info()?;
+        let video_start = sync.can_ts_offs() - can_ts_offs + args.video_offset_ms * 1_000_000;
+        route_end = route_end.max(video_start + video.duration_ns);
+        println!("  {:?} camera video {:?}", camera, video_path);
+        println!(
+            "    Starts at {:.3}s, duration {:.3}s",
+            video_start as f64 / 1e9,
+            video.duration_ns as f64 / 1e9
+        );
+        println!("    Frames: {}, {:.2}fps", video.frames, video.fps);
+        if let Some(fps) = args.fps {
+            println!("    Timestamped at {:.2}fps (--fps)", fps);
+        }
+    }
+
+    println!("  Segments: {}", route_end / segment_nanos(args) + 1);
+    info!("Dry run, nothing written");
+    Ok(())
+}
+
+// Write the log's decoded signals to a CSV file, see --export-signals
+fn export_log_signals(
+    info: &LogInfo,
+    args: &ConvertOptions,
+    options: &ReadOptions,
+    sync_ts_offs: Option<Nanos>,
+    dbc: &Dbc,
+    path: &Path,
+) -> Result<()> {
+    let can_log = read_can_logs(&info.logfiles(), args.format, sync_ts_offs, options)
+        .map_err(empty_log_hint)?;
+    if let Some(read_progress) = &options.progress {
+        read_progress.finish_and_clear();
+    }
+    report_flag_check(&check_can_flags(&can_log.messages), args.strict)?;
+
+    let names = match args.signal.is_empty() {
+        true => dbc.signal_names(),
+        false => args.signal.clone(),
+    };
+    let f =
+        File::create(path).with_context(|| format!("Failed to create signals file {:?}", path))?;
+    let rows = export_signals(dbc, &names, &can_log.messages, f)
+        .with_context(|| format!("Failed to write signals file {:?}", path))?;
+    info!(
+        "Wrote {} rows of {} signals to {:?}",
+        rows,
+        names.len(),
+        path
+    );
+    Ok(())
+}
+
+// CAN messages for the route, either loaded in memory or streamed from the log
+type CanInputs = Box<dyn Iterator<Item = CANMessage>>;
+
+// Start recording from the SocketCAN interfaces named by the log's logfile and
+// extra_logfiles. Returns the messages as they arrive, and the offset of the
+// start of the route (the wall clock time that recording started).
+#[cfg(target_os = "linux")]
+fn record_socketcan(info: &LogInfo, options: &ReadOptions) -> Result<(CanInputs, Nanos)> {
+    let interfaces: Vec<String> = info
+        .logfiles()
+        .iter()
+        .map(|i| i.to_string_lossy().into_owned())
+        .collect();
+    let (can_ts_offs, messages) = record_can_messages_socketcan(&interfaces, options)?;
+    info!(
+        "Recording from {}, press Ctrl-C to stop",
+        interfaces.join(", ")
+    );
+    // There's no first pass to find gaps, and an error reading an interface
+    // ends the recording rather than losing the route so far
+    let can_inputs = ReorderCanMessages::new(messages, CAN_REORDER_WINDOW)
+        .map_while(|r| r.map_err(|e| warn!("Stopped recording: {:#}", e)).ok());
+    Ok((Box::new(can_inputs), can_ts_offs))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn record_socketcan(_info: &LogInfo, _options: &ReadOptions) -> Result<(CanInputs, Nanos)> {
+    bail!("--socketcan is only supported on Linux");
+}
+
+// Returns the route's statistics, if --stats is set
+fn process_log(
+    info: &LogInfo,
+    args: &ConvertOptions,
+    data_dir: &Path,
+) -> Result<Option<RouteStatsEntry>> {
+    let _span = info_span!("log", fingerprint = %info.fingerprint).entered();
+
+    if info.video.is_some() && info.sync.is_none() {
+        bail!("Video {0:?} requires a sync section to match", info.video);
+    }
+
+    if !info.alert_rules.is_empty() && info.dbc.is_none() {
+        bail!("Log {0:?} has alert_rules but no dbc file", info.logfile);
+    }
+
+    if !info.edge_markers.is_empty() && info.dbc.is_none() {
+        bail!("Log {0:?} has edge_markers but no dbc file", info.logfile);
+    }
+
+    if info.car_state.is_some() && info.dbc.is_none() {
+        bail!("Log {0:?} has car_state but no dbc file", info.logfile);
+    }
+
+    if args.auto_sync_signal.is_some() && info.video.is_some() && info.dbc.is_none() {
+        bail!(
+            "--auto-sync-signal needs a dbc file for log {0:?}",
+            info.logfile
+        );
+    }
+    let dbc = info.dbc.as_deref().map(Dbc::from_path).transpose()?;
+
+    let sync_ts_offs = info.sync.as_ref().map(|s| s.can_ts_offs());
+
+    // Progress bars are only shown if stderr is a terminal
+    let progress = match args.quiet || args.json_progress {
+        true => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+        false => MultiProgress::new(),
+    };
+    let log_path = info.logfile.as_path();
+    write_json_progress(args, "reading", Some(log_path), None, None);
+
+    // Read CAN messages, and sort them by timestamp
+    // (not guaranteed from the CSV log, if there are CAN messages from >1 bus)
+    info!("Loading CAN messages {0:?}...", info.logfiles());
+    let options = ReadOptions {
+        filter: CanFilter {
+            buses: (!args.bus.is_empty()).then(|| args.bus.clone()),
+            id_allowlist: (!args.include_id.is_empty())
+                .then(|| HashSet::from_iter(args.include_id.iter().copied())),
+            id_denylist: (!args.exclude_id.is_empty())
+                .then(|| HashSet::from_iter(args.exclude_id.iter().copied())),
+        },
+        ts_unit: args.ts_unit,
+        ts_mode: args.ts_mode,
+        log_date: args.log_date,
+        progress: Some(progress.add(read_progress_bar())),
+        skip_bad_rows: args.skip_bad_rows,
+        strict_fields: args.strict_fields,
+        reverse_data_bytes: args.reverse_data_bytes,
+        keep_early: args.pad_video_start,
+        clamp_early: args.clamp_early,
+        bus_remap: HashMap::from_iter(args.remap_bus.iter().copied()),
+        start: args.start.unwrap_or(0),
+        end: args.end,
+        // Generated messages are added to the log after it's read
+        allow_empty: args.allow_empty || !info.generate.is_empty(),
+        ..Default::default()
+    };
+    if let (Some(start), Some(end)) = (args.start, args.end) {
+        if end <= start {
+            bail!("--end should be later than --start");
+        }
+    }
+    if matches!(args.fps, Some(fps) if !fps.is_finite() || fps <= 0.0) {
+        bail!("--fps should be more than 0");
+    }
+    // Higher CanData.src values mean a frame sent by the panda, see QlogWriter::can_src()
+    if args.output_bus.iter().any(|(_, to)| *to >= 128) {
+        bail!("--output-bus numbers should be less than 128");
+    }
+    let output_bus = HashMap::from_iter(args.output_bus.iter().copied());
+    let gap_threshold = Duration::from_millis(args.gap_threshold_ms).as_nanos() as Nanos;
+    let thumbnail_interval = Duration::from_millis(args.thumbnail_interval_ms).as_nanos() as Nanos;
+    let write_manifest = args.thumbnail_manifest || args.connect_compatible;
+    let alert_interval = Duration::from_millis(args.alert_interval_ms).as_nanos() as Nanos;
+
+    if args.dry_run {
+        dry_run(info, args, &options, sync_ts_offs, gap_threshold)?;
+        return Ok(None);
+    }
+    if let Some(path) = &args.export_signals {
+        let Some(dbc) = &dbc else {
+            bail!(
+                "--export-signals needs a dbc file for log {:?}",
+                info.logfile
+            );
+        };
+        export_log_signals(info, args, &options, sync_ts_offs, dbc, path)?;
+        return Ok(None);
+    }
+
+    // Only set for an in memory log, with --stats
+    let mut stats = None;
+    let mut car_states = vec![];
+    let mut edge_markers = vec![];
+    // Only known from the log when it's read in memory
+    let mut ts_unit = args.ts_unit;
+    // How long the route will be, for --json-progress (only known if the whole
+    // CAN log is read first)
+    let mut expected_end: Option<Nanos> = None;
+    // Error that ended a streamed CAN log early, returned after the route is written
+    let stream_error: Rc<RefCell<Option<anyhow::Error>>> = Rc::default();
+    let (can_inputs, alerts_vec, can_ts_offs, sync_speed): (CanInputs, _, _, _) = if args.socketcan
+    {
+        check_streaming_args(info, args, "--socketcan")?;
+        if !info.videos()?.is_empty() {
+            bail!("Videos aren't supported with --socketcan, pass --can-only to ignore them");
+        }
+        if let Some(read_progress) = &options.progress {
+            read_progress.finish_and_clear();
+        }
+
+        let (can_inputs, can_ts_offs) = record_socketcan(info, &options)?;
+        (can_inputs, vec![], can_ts_offs, None)
+    } else if args.low_memory {
+        let format = args
+            .format
+            .unwrap_or_else(|| LogFormat::from_path(&info.logfile));
+        if !info.extra_logfiles.is_empty() || format != LogFormat::SavvyCan {
+            bail!("--low-memory is only supported for a single SavvyCAN CSV log file");
+        }
+        check_streaming_args(info, args, "--low-memory")?;
+
+        // If there's no sync offset, offset so the first message has timestamp 0
+        // (the same as when the offset isn't passed in, but need to know it here)
+        let can_ts_offs = match sync_ts_offs {
+            Some(offs) => offs,
+            None => match iter_can_messages(&info.logfile, Some(0), &options)?.next() {
+                Some(message) => message.map_err(empty_log_hint)?.timestamp(),
+                None => 0,
+            },
+        };
+
+        // Make a first pass over the log to check it parses and find any gaps,
+        // then stream it again for output
+        let can_stream = || -> Result<_> {
+            Ok(ReorderCanMessages::new(
+                iter_can_messages(&info.logfile, Some(can_ts_offs), &options)?,
+                CAN_REORDER_WINDOW,
+            ))
+        };
+        let mut flag_check = FlagCheck::default();
+        let mut count = 0;
+        let alerts_vec = process_results(can_stream()?, |messages| {
+            let messages = messages.inspect(|m| {
+                flag_check.add(m);
+                count += 1;
+            });
+            find_missing_can_messages(messages, gap_threshold)
+        })
+        .map_err(empty_log_hint)?;
+        warn_if_empty(info, count);
+        report_flag_check(&flag_check, args.strict)?;
+        // The log parsed in the first pass, but could still fail to read now (i.e.
+        // if it was changed in between)
+        let second_pass_error = stream_error.clone();
+        let can_inputs = can_stream()?.map_while(move |r| {
+            r.map_err(|e| *second_pass_error.borrow_mut() = Some(e))
+                .ok()
+        });
+        (Box::new(can_inputs), alerts_vec, can_ts_offs, None)
+    } else {
+        let CanLog {
+            messages: mut can_inputs,
+            ts_offset: can_ts_offs,
+            mut duration,
+            ts_unit: log_ts_unit,
+            ..
+        } = read_can_logs(&info.logfiles(), args.format, sync_ts_offs, &options)
+            .map_err(empty_log_hint)?;
+        ts_unit = log_ts_unit;
+
+        if let Some(read_progress) = &options.progress {
+            read_progress.finish_and_clear();
+        }
+        debug!("read {} can inputs", can_inputs.len());
+        if !info.generate.is_empty() {
+            let generated = generate_can_messages(&info.generate, dbc.as_ref())?;
+            info!("Generated {} CAN messages", generated.len());
+            can_inputs = merge(can_inputs, generated).collect();
+            duration = duration.max(can_inputs.last().map_or(0, |m| m.timestamp()));
+        }
+        expected_end = Some(duration);
+        warn_if_empty(info, can_inputs.len());
+        report_flag_check(&check_can_flags(&can_inputs), args.strict)?;
+
+        if duration > MAX_ROUTE_DURATION && args.max_segments.is_none() {
+            bail!(
+                "CAN log would make a {:.1} hour long route, check the timestamps in the log and \
+                 the sync section (or pass --max-segments to convert the start of it anyway)",
+                duration as f64 / 3.6e12
+            );
+        }
+
+        let duplicates = dedup_timestamps(&mut can_inputs, args.dedup_timestamps);
+        if duplicates > 0 && args.dedup_timestamps {
+            info!(
+                "Spread out {} CAN messages with duplicate timestamps",
+                duplicates
+            );
+        } else if duplicates > 0 {
+            warn!(
+                "{} CAN messages have the same timestamp as the message before, \
+                 pass --dedup-timestamps to spread them out",
+                duplicates
+            );
+        }
+
+        let mut alerts_vec = find_missing_can_messages(&can_inputs, gap_threshold);
+        if args.stats.is_some() {
+            stats = Some(route_stats(&can_inputs, &alerts_vec));
+        }
+        if args.detect_id_dropout {
+            alerts_vec.extend(find_silent_can_ids(&can_inputs));
+            alerts_vec.sort_by_key(|a| a.timestamp);
+        }
+        if let Some(dbc) = &dbc {
+            alerts_vec.extend(find_signal_alerts(dbc, &info.alert_rules, &can_inputs)?);
+            alerts_vec.sort_by_key(|a| a.timestamp);
+            edge_markers = find_signal_edges(dbc, &info.edge_markers, &can_inputs)?;
+        }
+        if let (Some(signals), Some(dbc)) = (&info.car_state, &dbc) {
+            car_states = synthesize_car_states(dbc, signals, &can_inputs)?;
+            info!("Synthesized {} carState events", car_states.len());
+        }
+        let sync_speed = match (&args.auto_sync_signal, &dbc) {
+            (Some(signal), Some(dbc)) => Some(dbc.signal_values(signal, &can_inputs)?),
+            _ => None,
+        };
+        if args.only_ids_with_changes {
+            let static_ids = drop_static_can_ids(&mut can_inputs);
+            if !static_ids.is_empty() {
+                let ids: Vec<String> = static_ids
+                    .iter()
+                    .map(|(bus_no, can_id)| format!("0x{:03X} (bus {})", can_id, bus_no))
+                    .collect();
+                info!(
+                    "Dropped {} CAN IDs with unchanging data: {}",
+                    ids.len(),
+                    ids.join(", ")
+                );
+            }
+        }
+        (
+            Box::new(can_inputs.into_iter()),
+            alerts_vec,
+            can_ts_offs,
+            sync_speed,
+        )
+    };
+
+    let mut alerts_vec = alerts_vec;
+    if let Some(alerts_path) = &info.alerts {
+        let alerts = read_alerts(alerts_path, can_ts_offs)?;
+        info!("Adding {} alerts from {:?}", alerts.len(), alerts_path);
+        alerts_vec.extend(alerts);
+        alerts_vec.sort_by_key(|a| a.timestamp);
+    }
+
+    let can_inputs: CanInputs = if args.decimate.is_empty() {
+        can_inputs
+    } else {
+        let intervals = args
+            .decimate
+            .iter()
+            .map(|(id, rate)| (*id, (1e9 / rate).round().max(1.0) as Nanos))
+            .collect();
+        Box::new(DecimateCanMessages::new(can_inputs, intervals))
+    };
+
+    let skipped_rows = options.skipped_rows.load(Ordering::Relaxed);
+    if skipped_rows > 0 {
+        warn!("Skipped {} bad rows in CAN logs", skipped_rows);
+    }
+
+    let log_start = match args.start_time {
+        Some(start_time) => Some(start_time.into()),
+        None => epoch_start_time(can_ts_offs),
+    };
+    if let (Some(log_start), None) = (log_start, args.start_time) {
+        info!(
+            "CAN log has absolute timestamps, route starts at {}",
+            DateTime::<Local>::from(log_start)
+        );
+    }
+
+    let video_offset = match (&sync_speed, &info.video, sync_ts_offs) {
+        (Some(speed), Some(video_path), Some(sync_ts_offs)) => {
+            find_video_offset(video_path, sync_ts_offs - can_ts_offs, speed, args)?
+        }
+        _ => args.video_offset_ms * 1_000_000,
+    };
+
+    // Each camera video, and how far into the route it starts. With
+    // --pad-video-start the route may start before the video, and the start
+    // also includes any --video-offset-ms.
+    let mut source_videos = vec![];
+    let mut video_properties = vec![];
+    for (camera, video_path, sync) in info.videos()? {
+        info!("Opening {camera:?} camera video {video_path:?}...");
+        let mut sv = open_video(video_path, camera, args)?;
+        if args.privacy && camera == Camera::Driver {
+            info!("Blurring driver camera video");
+            sv.add_filter(PRIVACY_BLUR_FILTER);
+        }
+        let video_start = sync.can_ts_offs() - can_ts_offs + video_offset;
+        // Padding frames can only be encoded, not copied
+        if !args.reencode_video && !args.pad_video_start && sv.can_remux()? {
+            // Copying would leave out the frames before the first keyframe of each
+            // segment, so only copy if every segment starts with a keyframe
+            let skipped_frames: usize = open_video(video_path, camera, args)?
+                .remux_segment_starts(video_start, segment_nanos(args))?
+                .iter()
+                .map(|start| start.skipped_frames)
+                .sum();
+            if skipped_frames == 0 {
+                info!("Copying {camera:?} camera video into the segments without re-encoding it");
+                sv.set_remux();
+            } else {
+                info!(
+                    "Re-encoding {camera:?} camera video, as its keyframes aren't at the start \
+                     of every {}s segment (copying it would leave out {} frames)",
+                    args.segment_seconds, skipped_frames
+                );
+            }
+        }
+        video_properties.push((camera, sv.properties()?));
+
+        if args.pad_video_start && video_start > 0 {
+            info!(
+                "Padding start of {:?} camera video with {:.3}s of black frames",
+                camera,
+                video_start as f64 / 1e9
+            );
+        }
+        expected_end = expected_end.map(|end| end.max(video_start + sv.info()?.duration_ns));
+        source_videos.push((sv, video_start));
+    }
+
+    // If we have video and CAN message inputs, merge them together keeping the
+    // output sorted by timestamp
+    let mut camera_frames = vec![];
+    for (sv, video_start) in &mut source_videos {
+        let padding = if args.pad_video_start {
+            Some(sv.padding_frames(*video_start)?)
+        } else {
+            None
+        };
+        camera_frames.push(
+            padding
+                .into_iter()
+                .flatten()
+                .chain(sv.video_frames(*video_start)?),
+        );
+    }
+    let frames = camera_frames
+        .into_iter()
+        .kmerge_by(|a, b| a.ts_ns < b.ts_ns)
+        // A negative --video-offset-ms can move frames before the start of the route
+        .filter(|f| f.ts_ns >= 0);
+
+    let locations = match &info.gps {
+        Some(gps_path) => read_locations(gps_path, info.gps_ts_offs(can_ts_offs)?)?,
+        None => vec![],
+    };
+    if !locations.is_empty() {
+        info!("Adding {} GPS locations", locations.len());
+    }
+
+    let route_end = args
+        .end
+        .map_or(Nanos::MAX, |end| end - args.start.unwrap_or(0));
+
+    let mut markers = match &info.markers {
+        Some(markers_path) => read_markers(markers_path, can_ts_offs)?,
+        None => vec![],
+    };
+    if !edge_markers.is_empty() {
+        info!("Adding {} signal edge markers", edge_markers.len());
+        markers.extend(edge_markers);
+        markers.sort_by_key(|m| m.timestamp);
+    }
+
+    let mut inputs = merge(
+        merge(
+            merge(
+                merge_timeline(can_inputs, frames, alerts_vec, alert_interval),
+                locations.into_iter().map(LogInput::Location),
+            ),
+            expand_markers(markers),
+        ),
+        car_states.into_iter().map(LogInput::CarState),
+    )
+    // The CAN messages are already clipped to --end, but not the other inputs
+    .take_while(|input| input.timestamp() < route_end)
+    .peekable();
+
+    let segment_nanos = segment_nanos(args);
+    if inputs.peek().map(|i| i.timestamp()).unwrap_or(0) > segment_nanos {
+        bail!("Segments should start from 0, the timestamp offset is set incorrectly");
+    }
+
+    if args.replay_to.is_some() {
+        replay_inputs(inputs, log_start, info, args, &output_bus)?;
+        return match stream_error.take() {
+            Some(e) => Err(e.context("Failed to read CAN log")),
+            None => Ok(None),
+        };
+    }
+
+    // Sort the inputs and group them into segments
+    let segments = inputs.group_by(|input| input.timestamp() / segment_nanos);
+    let mut first_video = true;
+
+    let mut total_can = 0usize;
+    let output_progress = progress.add(
+        ProgressBar::new_spinner().with_style(
+            ProgressStyle::with_template("{spinner} {msg} ({pos} events, {elapsed})")
+                .expect("progress template should be valid"),
+        ),
+    );
+    let mut qlog_decimator = CanDecimator::new(QLOG_CAN_INTERVAL);
+    let mut alignment = args.alignment_report.then(AlignmentReport::default);
+
+    // Each segment's logs are kept open until the next segment starts, so the
+    // last segment can end with EndOfRoute instead of EndOfSegment
+    let mut prev_segment: Option<(SegmentLogs, Nanos)> = None;
+    let mut next_segment_idx = 0;
+
+    // On Ctrl-C, stop writing inputs and finish the segment so the route still
+    // opens. With --socketcan it only stops the recording, and the messages
+    // already recorded are still written.
+    let stop_on_interrupt = !args.socketcan;
+    if stop_on_interrupt && interrupted() {
+        bail!("Interrupted before writing the route");
+    }
+
+    // Start a segment that only has the start and end events
+    let empty_segment = |segment_idx: Nanos| -> Result<(SegmentLogs, Nanos)> {
+        let segment_dir = info.segment_dir_path(data_dir, log_start, segment_idx, args);
+        info!("Writing empty segment {segment_idx} to {segment_dir:?}...");
+        std::fs::create_dir_all(&segment_dir)?;
+        let mut logs = SegmentLogs::create(&segment_dir, args, &output_bus)?;
+        let ts = segment_idx * segment_nanos;
+        logs.write_start(ts, segment_idx, log_start, info, args);
+        Ok((logs, ts))
+    };
+
+    for (segment_idx, inputs) in &segments {
+        if stop_on_interrupt && interrupted() {
+            break;
+        }
+        if let Some(max_segments) = args.max_segments {
+            if segment_idx >= max_segments {
+                warn!(
+                    "Stopping after {} segments (--max-segments), the rest of the log \
+                     isn't in the route",
+                    max_segments
+                );
+                break;
+            }
+        }
+        // Connect expects every segment of a route to be there, so fill any gap
+        // with no inputs (i.e. the logger was paused) with segments that only
+        // have the start and end events
+        let empty_segments = if args.connect_compatible {
+            next_segment_idx..segment_idx
+        } else {
+            0..0
+        };
+        for empty_idx in empty_segments {
+            if let Some((mut logs, last_ts)) = prev_segment.take() {
+                logs.write_sentinel(last_ts, SentinelType::EndOfSegment);
+            }
+            prev_segment = Some(empty_segment(empty_idx)?);
+        }
+        next_segment_idx = segment_idx + 1;
+
+        if let Some((mut logs, last_ts)) = prev_segment.take() {
+            logs.write_sentinel(last_ts, SentinelType::EndOfSegment);
+        }
+        let mut inputs = inputs.peekable();
+
+        // Each camera's frames are numbered separately
+        let mut frame_ids: HashMap<Camera, u32> = HashMap::new();
+
+        let segment_dir = info.segment_dir_path(data_dir, log_start, segment_idx, args);
+
+        info!("Writing segment {segment_idx} to {segment_dir:?}...");
+        output_progress.set_message(format!("Writing segment {segment_idx}"));
+        let percent = expected_end
+            .filter(|end| *end > 0)
+            .map(|end| (segment_idx * segment_nanos) as f64 / end.min(route_end) as f64 * 100.0);
+        write_json_progress(
+            args,
+            "writing",
+            Some(log_path),
+            Some(segment_idx),
+            percent.map(|p| p.min(100.0)),
+        );
+
+        std::fs::create_dir_all(&segment_dir)?;
+
+        let mut logs = SegmentLogs::create(&segment_dir, args, &output_bus)?;
+        let mut segment_videos = vec![];
+        for (camera, properties) in &video_properties {
+            for kind in SegmentVideoKind::for_camera(*camera, !args.no_qcamera) {
+                let seg_video_path = segment_dir.join(kind.file_name());
+                if args.overwrite || !seg_video_path.try_exists()? {
+                    let enc =
+                        SegmentVideoEncoder::new(&seg_video_path, properties, kind, first_video)?;
+                    segment_videos.push((kind, seg_video_path, enc));
+                } else {
+                    // Don't encode new a segment video if the it already exists, as this is the
+                    // slowest and most CPU intensive part (unless --overwrite was passed in)
+                    info!("Skipping existing {seg_video_path:?}");
+                }
+            }
+            first_video = false;
+        }
+
+        let first_ts = inputs.peek().map(|f| f.timestamp()).unwrap_or(0);
+        logs.write_start(first_ts, segment_idx, log_start, info, args);
+
+        let mut last_thumbnail: Nanos = 0;
+        let mut thumbnails = vec![];
+        let mut skipped_frames = 0;
+
+        let mut can_msgs: Vec<CANMessage> = vec![];
+        let mut last_ts = first_ts;
+
+        for input in inputs {
+            if stop_on_interrupt && interrupted() {
+                break;
+            }
+            output_progress.inc(1);
+            if let Some(alignment) = &mut alignment {
+                alignment.add(&input);
+            }
+            last_ts = input.timestamp();
+
+            // Flush the current set of CAN messages to an event
+            // in qlog whenever CAN_EVENT_LEN time has passed
+            if !can_msgs.is_empty() && input.timestamp() - can_msgs[0].timestamp() > CAN_EVENT_TIME
+            {
+                // eprintln!(
+                //     "segment {} write_can {} msgs timestamps {} to {}",
+                //     segment_idx,
+                //     can_msgs.len(),
+                //     can_msgs[0].timestamp(),
+                //     can_msgs.last().unwrap().timestamp(),
+                // );
+                total_can += can_msgs.len();
+                logs.write_can(&can_msgs, &mut qlog_decimator);
+                can_msgs.clear();
+            }
+
+            match input {
+                LogInput::CAN(can_msg) => {
+                    can_msgs.push(can_msg);
+                }
+                LogInput::Frame(ref frame) => {
+                    let ts = input.timestamp();
+
+                    // A remuxed video has to start from a keyframe, so any frames
+                    // before the first keyframe in the segment are left out
+                    let is_key = frame.packet.as_ref().map_or(true, |p| p.is_key());
+                    if !is_key && !frame_ids.contains_key(&frame.camera) {
+                        skipped_frames += 1;
+                        continue;
+                    }
+
+                    let frame_id = frame_ids.entry(frame.camera).or_insert(0);
+                    let is_road = frame.camera == Camera::Road;
+
+                    for (kind, _, encode) in &mut segment_videos {
+                        if kind.camera() == frame.camera {
+                            encode.send_frame(frame)?;
+                        }
+                    }
+
+                    // Thumbnails are only from the road camera
+                    let jpeg = (is_road && ts - last_thumbnail > thumbnail_interval)
+                        .then(|| frame.encode_jpeg());
+                    for log in logs.all() {
+                        log.write_camera_state(ts, frame.camera, *frame_id);
+                        log.write_frame_encode_idx(ts, frame.camera, segment_idx as i32, *frame_id);
+                        if is_road && !args.no_qcamera {
+                            log.write_qcamera_encode_idx(ts, segment_idx as i32, *frame_id);
+                        }
+                        if let Some(jpeg) = &jpeg {
+                            log.write_thumbnail(ts, ts + thumbnail_interval, *frame_id, jpeg);
+                        }
+                    }
+                    if let Some(jpeg) = jpeg {
+                        last_thumbnail = ts;
+                        if write_manifest {
+                            let file = format!("thumbnail_{}.jpg", thumbnails.len());
+                            std::fs::write(segment_dir.join(&file), jpeg)?;
+                            thumbnails.push(ManifestThumbnail {
+                                file,
+                                frame_id: *frame_id,
+                                offset_ms: (ts - segment_idx * segment_nanos) / 1_000_000,
+                                duration_ms: thumbnail_interval / 1_000_000,
+                            });
+                        }
+                    }
+
+                    *frame_id += 1;
+                }
+                LogInput::Alert(ref alert) => {
+                    for log in logs.all() {
+                        log.write_alert(alert);
+                    }
+                }
+                LogInput::Location(ref location) => {
+                    for log in logs.all() {
+                        log.write_gps_location(location);
+                    }
+                }
+                LogInput::Marker(ref marker) => {
+                    for log in logs.all() {
+                        log.write_marker(marker);
+                    }
+                }
+                LogInput::CarState(ref car_state) => {
+                    for log in logs.all() {
+                        log.write_car_state(car_state);
+                    }
+                }
+            }
+        }
+
+        // Flush any final batch of CAN messages
+        logs.write_can(&can_msgs, &mut qlog_decimator);
+        total_can += can_msgs.len();
+
+        if write_manifest {
+            write_thumbnail_manifest(&segment_dir, thumbnails)?;
+        }
+
+        if skipped_frames > 0 {
+            warn!(
+                "Left out {} frames before the first keyframe of segment {}",
+                skipped_frames, segment_idx
+            );
+        }
+
+        for (kind, seg_video_path, encode) in segment_videos {
+            encode.finish()?;
+
+            if !frame_ids.contains_key(&kind.camera()) {
+                // No frames actually got written for this segment, so get rid of the
+                // zero byte video file (otherwise Openpilot complains)
+                warn!("Empty video segment, the CAN log probably runs longer than the video");
+                std::fs::remove_file(seg_video_path)?;
+            }
+        }
+
+        prev_segment = Some((logs, last_ts));
+    }
+
+    // With no inputs at all (see --allow-empty) the route still needs a segment
+    // for Cabana to open
+    if prev_segment.is_none() {
+        prev_segment = Some(empty_segment(0)?);
+    }
+    if let Some((mut logs, last_ts)) = prev_segment {
+        logs.write_sentinel(last_ts, SentinelType::EndOfRoute);
+        if stop_on_interrupt && interrupted() {
+            warn!(
+                "Interrupted, the route ends at {:.3}s instead of the end of the log",
+                last_ts as f64 / 1e9
+            );
+        }
+    }
+    if let Some(e) = stream_error.take() {
+        return Err(e.context("Failed to read CAN log"));
+    }
+
+    output_progress.finish_and_clear();
+    if let Some(read_progress) = &options.progress {
+        read_progress.finish_and_clear();
+    }
+    info!("total can messages {}", total_can);
+
+    // Cabana only finds the segments in the default layout
+    if args.output_layout == DEFAULT_OUTPUT_LAYOUT {
+        write_launch_script(info, data_dir, log_start, &args.dongle_id)?;
+    }
+    if let Some(alignment) = &alignment {
+        write_alignment_report(alignment, info, data_dir)?;
+    }
+    if args.timebase {
+        write_timebase(
+            info,
+            data_dir,
+            log_start,
+            &args.dongle_id,
+            can_ts_offs,
+            ts_unit,
+        )?;
+    }
+    write_json_progress(
+        args,
+        "done",
+        Some(log_path),
+        (next_segment_idx > 0).then_some(next_segment_idx - 1),
+        Some(100.0),
+    );
+
+    Ok(stats.map(|stats| RouteStatsEntry {
+        route: info.full_route_name(log_start, &args.dongle_id),
+        fingerprint: info.fingerprint.clone(),
+        stats,
+    }))
+}
+
+// Stream the inputs to --replay-to as events, paced by their timestamps. These
+// are the same events as in the rlog, except that there are no segments: only
+// the start and end of the route, and no encodeIdx or thumbnail events (which
+// refer to the segment videos).
+fn replay_inputs(
+    inputs: impl Iterator<Item = LogInput>,
+    log_start: Option<SystemTime>,
+    info: &LogInfo,
+    args: &ConvertOptions,
+    output_bus: &HashMap<u8, u8>,
+) -> Result<()> {
+    let replay_to = args.replay_to.as_deref().unwrap_or("-");
+    let writer: Box<dyn Write> = if replay_to == "-" {
+        info!("Replaying route to stdout...");
+        Box::new(std::io::stdout())
+    } else {
+        info!("Replaying route to {}...", replay_to);
+        Box::new(
+            TcpStream::connect(replay_to)
+                .with_context(|| format!("Failed to connect to {}", replay_to))?,
+        )
+    };
+    // Each event is buffered until it's sent, so an error writing it (i.e. the
+    // reader has gone away) comes from flush()
+    let mut log = QlogWriter::from_writer(Box::new(BufWriter::with_capacity(
+        REPLAY_BUFFER_SIZE,
+        writer,
+    )));
+    log.set_src_map(output_bus.clone());
+
+    let mut inputs = inputs.peekable();
+    let first_ts = inputs.peek().map(|i| i.timestamp()).unwrap_or(0);
+    let start = Instant::now();
+    // Wait until it's time to send the event at this timestamp
+    let pace = |ts: Nanos| {
+        let due = ((ts - first_ts).max(0) as f64 / args.replay_speed) as u64;
+        if let Some(wait) = Duration::from_nanos(due).checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+    };
+
+    log.write_init_data(
+        first_ts,
+        log_start.map(|t| t + Duration::from_nanos(first_ts as u64)),
+        &init_data_fields(args),
+    );
+    let car = args.car.as_ref().unwrap_or(&info.car);
+    log.write_car_params(first_ts, car, &info.fingerprint);
+    log.write_sentinel(first_ts, SentinelType::StartOfRoute);
+    log.flush()?;
+
+    let mut frame_ids: HashMap<Camera, u32> = HashMap::new();
+    let mut can_msgs: Vec<CANMessage> = vec![];
+    let mut last_ts = first_ts;
+    for input in inputs {
+        // Ctrl-C ends the replay, with the end of route marker
+        if interrupted() {
+            break;
+        }
+        last_ts = input.timestamp();
+
+        // CAN messages are grouped into events the same as in the route, and
+        // each event is sent at the time of its last message
+        if !can_msgs.is_empty() && last_ts - can_msgs[0].timestamp() > CAN_EVENT_TIME {
+            pace(can_msgs[can_msgs.len() - 1].timestamp());
+            log.write_can(&can_msgs);
+            log.flush()?;
+            can_msgs.clear();
+        }
+
+        if !matches!(input, LogInput::CAN(_)) {
+            pace(last_ts);
+        }
+        match input {
+            LogInput::CAN(can_msg) => {
+                can_msgs.push(can_msg);
+                continue;
+            }
+            LogInput::Frame(ref frame) => {
+                let frame_id = frame_ids.entry(frame.camera).or_insert(0);
+                log.write_camera_state(last_ts, frame.camera, *frame_id);
+                *frame_id += 1;
+            }
+            LogInput::Alert(ref alert) => log.write_alert(alert),
+            LogInput::Location(ref location) => log.write_gps_location(location),
+            LogInput::Marker(ref marker) => log.write_marker(marker),
+            LogInput::CarState(ref car_state) => log.write_car_state(car_state),
+        }
+        log.flush()?;
+    }
+
+    if let Some(last) = can_msgs.last() {
+        pace(last.timestamp());
+        log.write_can(&can_msgs);
+    }
+    log.write_sentinel(last_ts, SentinelType::EndOfRoute);
+    log.flush()
+}
+
+// One line of --json-progress output. Every field is always written (as null if
+// it doesn't apply), so the format stays the same for any phase.
+#[derive(Serialize)]
+struct JsonProgress<'a> {
+    phase: &'a str,
+    log: Option<&'a Path>,
+    segment: Option<i64>,
+    percent: Option<f64>,
+}
+
+fn write_json_progress(
+    args: &ConvertOptions,
+    phase: &str,
+    log: Option<&Path>,
+    segment: Option<i64>,
+    percent: Option<f64>,
+) {
+    if args.json_progress {
+        let progress = JsonProgress {
+            phase,
+            log,
+            segment,
+            percent,
+        };
+        println!(
+            "{}",
+            serde_json::to_string(&progress).expect("progress should serialize")
+        );
+    }
+}
+
+// Progress bar for reading each log file, shows how much of the file has been read
+fn read_progress_bar() -> ProgressBar {
+    ProgressBar::new(0).with_style(
+        ProgressStyle::with_template("{msg} [{wide_bar}] {bytes}/{total_bytes} ({eta})")
+            .expect("progress template should be valid"),
+    )
+}
+
+// Each segment has a full rate rlog, and optionally a qlog with the same events
+// except CAN messages are decimated
+struct SegmentLogs {
+    rlog: QlogWriter,
+    qlog: Option<QlogWriter>,
+}
+
+impl SegmentLogs {
+    // Create the logs in a segment's directory, with CAN buses written as per
+    // --output-bus
+    fn create(
+        segment_dir: &Path,
+        args: &ConvertOptions,
+        output_bus: &HashMap<u8, u8>,
+    ) -> Result<Self> {
+        let log_ext = args.compression.extension();
+        let mut logs = SegmentLogs {
+            rlog: QlogWriter::new(segment_dir.join(format!("rlog{log_ext}")), args.compression)?,
+            qlog: if args.no_qlog {
+                None
+            } else {
+                Some(QlogWriter::new(
+                    segment_dir.join(format!("qlog{log_ext}")),
+                    args.compression,
+                )?)
+            },
+        };
+        for log in logs.all() {
+            log.set_src_map(output_bus.clone());
+        }
+        Ok(logs)
+    }
+
+    fn all(&mut self) -> impl Iterator<Item = &mut QlogWriter> {
+        std::iter::once(&mut self.rlog).chain(self.qlog.as_mut())
+    }
+
+    // Write the events at the start of each segment, and the start of the route
+    // for the first segment
+    fn write_start(
+        &mut self,
+        ts: Nanos,
+        segment_idx: Nanos,
+        log_start: Option<SystemTime>,
+        info: &LogInfo,
+        args: &ConvertOptions,
+    ) {
+        let init_data_fields = init_data_fields(args);
+        for log in self.all() {
+            log.write_init_data(
+                ts,
+                log_start.map(|t| t + Duration::from_nanos(ts as u64)),
+                &init_data_fields,
+            );
+
+            if segment_idx == 0 {
+                let car = args.car.as_ref().unwrap_or(&info.car);
+                log.write_car_params(ts, car, &info.fingerprint);
+                log.write_sentinel(ts, SentinelType::StartOfRoute);
+            }
+            log.write_sentinel(ts, SentinelType::StartOfSegment);
+        }
+    }
+
+    fn write_sentinel(&mut self, ts: Nanos, sentinel_type: SentinelType) {
+        for log in self.all() {
+            log.write_sentinel(ts, sentinel_type);
+        }
+    }
+
+    fn write_can(&mut self, can_msgs: &[CANMessage], decimator: &mut CanDecimator) {
+        self.rlog.write_can(can_msgs);
+        if let Some(qlog) = &mut self.qlog {
+            qlog.write_can(&decimator.decimate(can_msgs));
+        }
+    }
+}
+
+// One thumbnail in a segment's thumbnails.json, see --thumbnail-manifest. Times
+// are from the start of the segment, so the manifest stays the same if the
+// segment is renumbered (see concat_routes()).
+#[derive(Serialize)]
+struct ManifestThumbnail {
+    file: String,
+    frame_id: u32,
+    offset_ms: Nanos,
+    duration_ms: Nanos,
+}
+
+#[derive(Serialize)]
+struct ThumbnailManifest {
+    thumbnails: Vec<ManifestThumbnail>,
+}
+
+fn write_thumbnail_manifest(segment_dir: &Path, thumbnails: Vec<ManifestThumbnail>) -> Result<()> {
+    let path = segment_dir.join("thumbnails.json");
+    let manifest = ThumbnailManifest { thumbnails };
+    let f = File::create(&path).with_context(|| format!("Failed to create {:?}", path))?;
+    serde_json::to_writer_pretty(f, &manifest)
+        .with_context(|| format!("Failed to write thumbnail manifest {:?}", path))
+}
+
+// Contents of the --timebase file. A time in the route (i.e. a Cabana playback
+// position, or an event's logMonoTime) of t nanoseconds is log timestamp
+// ts_offset + t in the CAN log, and wall clock time start_time + t.
+#[derive(Serialize, Deserialize)]
+struct Timebase {
+    route: String,
+    // Log timestamp at the start of the route, in nanoseconds
+    ts_offset: Nanos,
+    // Unit the SavvyCAN CSV timestamps were read in, if known
+    ts_unit: Option<TsUnit>,
+    start_time: Option<DateTime<Utc>>,
+}
+
+// Write the timebase file next to the launch script, named after the log
+fn write_timebase(
+    info: &LogInfo,
+    data_dir: &Path,
+    log_start: Option<SystemTime>,
+    dongle_id: &str,
+    ts_offset: Nanos,
+    ts_unit: Option<TsUnit>,
+) -> Result<()> {
+    let log_name = info.logfile.file_stem().unwrap().to_str().unwrap();
+    let path = data_dir.join(format!("{}-timebase.json", log_name));
+    let timebase = Timebase {
+        route: info.full_route_name(log_start, dongle_id),
+        ts_offset,
+        ts_unit,
+        start_time: log_start.map(DateTime::from),
+    };
+    let f = File::create(&path).with_context(|| format!("Failed to create {:?}", path))?;
+    serde_json::to_writer_pretty(f, &timebase)
+        .with_context(|| format!("Failed to write timebase file {:?}", path))
+}
+
+fn read_timebase(path: &Path) -> Result<Timebase> {
+    let f = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    serde_json::from_reader(f).with_context(|| format!("Invalid timebase file {:?}", path))
+}
+
+// The timebase file written for a route in data_dir, if any. The files are named
+// after the logs, so this has to look in each one for the route name. Only the
+// timestamp part is compared, so the route's directory name also matches.
+fn find_timebase(data_dir: &Path, route: &str) -> Result<Option<Timebase>> {
+    for entry in fs::read_dir(data_dir)
+        .with_context(|| format!("Failed to read data directory {:?}", data_dir))?
+    {
+        let path = entry?.path();
+        let is_timebase = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.ends_with("-timebase.json"));
+        if is_timebase {
+            let timebase = read_timebase(&path)?;
+            if timebase.route.rsplit('|').next() == route.rsplit('|').next() {
+                debug!("Using timebase file {:?}", path);
+                return Ok(Some(timebase));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Write the alignment report next to the launch script, named after the log
+fn write_alignment_report(
+    alignment: &AlignmentReport,
+    info: &LogInfo,
+    data_dir: &Path,
+) -> Result<()> {
+    let log_name = info.logfile.file_stem().unwrap().to_str().unwrap();
+    let path = data_dir.join(format!("{}-alignment.csv", log_name));
+    let f = File::create(&path).with_context(|| format!("Failed to create {:?}", path))?;
+    let problems = alignment.write_csv(f)?;
+    if problems > 0 {
+        warn!(
+            "{} seconds of the route have video frames but no CAN messages, or CAN messages \
+             but no frames, see {:?}",
+            problems, path
+        );
+    } else {
+        info!("Wrote alignment report {:?}", path);
+    }
+    Ok(())
+}
+
+fn write_launch_script(
+    info: &LogInfo,
+    data_dir: &Path,
+    log_start: Option<SystemTime>,
+    dongle_id: &str,
+) -> Result<()> {
+    /* Cabana doesn't have much of a feature for browsing local routes, so generate a
+    launch script based on the CSV log file name.
+
+    The scripts assume 'cabana' on the PATH.
+
+    They can take extra arguments like --dbc <path_to_dbc>. If the log has a DBC
+    file, it's copied next to the script and the script opens it (an extra --dbc
+    argument overrides this one).
+    */
+    let log_name = info.logfile.file_stem().unwrap().to_str().unwrap();
+    let script_name = format!("{}.sh", log_name);
+    let script_path = data_dir.join(script_name);
+    let dbc_arg = match &info.dbc {
+        Some(dbc) => {
+            let dbc_name = format!("{}.dbc", log_name);
+            fs::copy(dbc, data_dir.join(&dbc_name))
+                .with_context(|| format!("Failed to copy DBC file {:?}", dbc))?;
+            format!(" --dbc \"$SCRIPT_DIR/{}\"", dbc_name)
+        }
+        None => String::new(),
+    };
+    let vipc_arg = match info.video {
+        Some(_) => "",
+        _ => "--no-vipc",
+    }; // If there's no video, Cabana won't open the route without this argument
+    {
+        let mut script = File::create(&script_path)?;
+        script.write_all(b"#!/bin/sh\n")?;
+        script.write_all(b"SCRIPT_DIR=\"$(realpath \"$(dirname \"$0\")\")\"\n")?;
+        script.write_all(
+            format!(
+                "cabana {} --data_dir \"$SCRIPT_DIR\"{} $@ \"{}--0\"\n",
+                vipc_arg,
+                dbc_arg,
+                info.full_route_name(log_start, dongle_id),
+            )
+            .as_bytes(),
+        )?;
+    }
+
+    // Make executable
+    fs::set_permissions(&script_path, Permissions::from_mode(0o755))?;
+
+    Ok(())
+}
@@ -0,0 +1,532 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use can_dbc::{ByteOrder, MultiplexIndicator, ValueType};
+use serde::Deserialize;
+
+use crate::input::{Alert, AlertStatus, CANMessage, Marker};
+use crate::Nanos;
+
+// DBC files set this bit in the message ID for extended (29-bit) IDs
+const DBC_EXTENDED_ID_FLAG: u32 = 0x8000_0000;
+
+// carState events are synthesized at 100Hz, the same rate openpilot logs them
+const CAR_STATE_INTERVAL: Nanos = 10_000_000;
+
+// Signals loaded from a DBC file, indexed by CAN ID
+pub struct Dbc {
+    messages: HashMap<u32, Vec<SignalDef>>,
+}
+
+// Everything needed to decode one signal from a message's data
+#[derive(Clone, Debug)]
+pub struct SignalDef {
+    pub message_name: String,
+    pub name: String,
+    start_bit: u64,
+    size: u64,
+    little_endian: bool,
+    signed: bool,
+    factor: f64,
+    offset: f64,
+}
+
+impl Dbc {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let buf =
+            std::fs::read(path).with_context(|| format!("Failed to read DBC file {:?}", path))?;
+        let dbc = can_dbc::DBC::from_slice(&buf)
+            .map_err(|_| anyhow!("Failed to parse DBC file {:?}", path))?;
+
+        let mut messages = HashMap::new();
+        for message in dbc.messages() {
+            let can_id = message.message_id().0 & !DBC_EXTENDED_ID_FLAG;
+            let signals = message
+                .signals()
+                .iter()
+                // Multiplexed signals are only valid for some messages, which
+                // isn't supported here
+                .filter(|s| {
+                    matches!(
+                        s.multiplexer_indicator(),
+                        MultiplexIndicator::Plain | MultiplexIndicator::Multiplexor
+                    )
+                })
+                .map(|s| SignalDef {
+                    message_name: message.message_name().clone(),
+                    name: s.name().clone(),
+                    start_bit: *s.start_bit(),
+                    size: *s.signal_size(),
+                    little_endian: *s.byte_order() == ByteOrder::LittleEndian,
+                    signed: *s.value_type() == ValueType::Signed,
+                    factor: *s.factor(),
+                    offset: *s.offset(),
+                })
+                .collect();
+            messages.insert(can_id, signals);
+        }
+
+        Ok(Dbc { messages })
+    }
+
+    // Find a signal by name, either "MESSAGE.SIGNAL" or just "SIGNAL" if the signal
+    // name is unique in the DBC. Returns the CAN ID of the message and the signal.
+    pub fn find_signal(&self, name: &str) -> Result<(u32, &SignalDef)> {
+        let (message_name, signal_name) = match name.split_once('.') {
+            Some((m, s)) => (Some(m), s),
+            None => (None, name),
+        };
+        let mut found = self.messages.iter().flat_map(|(can_id, signals)| {
+            signals
+                .iter()
+                .filter(|s| {
+                    s.name == signal_name && message_name.is_none_or(|m| s.message_name == m)
+                })
+                .map(move |s| (*can_id, s))
+        });
+        let result = found
+            .next()
+            .ok_or(anyhow!("Signal {} not found in DBC", name))?;
+        if found.next().is_some() {
+            return Err(anyhow!(
+                "Signal name {} is in more than one DBC message, use MESSAGE.SIGNAL",
+                name
+            ));
+        }
+        Ok(result)
+    }
+
+    // Decode every value of the named signal (as for find_signal) in the messages
+    pub fn signal_values(&self, name: &str, messages: &[CANMessage]) -> Result<Vec<(Nanos, f64)>> {
+        let (can_id, signal) = self.find_signal(name)?;
+        Ok(messages
+            .iter()
+            .filter(|m| m.can_id == can_id)
+            .filter_map(|m| Some((m.timestamp(), signal.decode(&m.data)?)))
+            .collect())
+    }
+
+    // Every signal in the DBC as "MESSAGE.SIGNAL", ordered by CAN ID and then as
+    // they are in the DBC
+    pub fn signal_names(&self) -> Vec<String> {
+        let mut messages: Vec<_> = self.messages.iter().collect();
+        messages.sort_by_key(|(can_id, _)| **can_id);
+        messages
+            .into_iter()
+            .flat_map(|(_, signals)| signals)
+            .map(|s| format!("{}.{}", s.message_name, s.name))
+            .collect()
+    }
+
+    // Decode all the signals that the DBC has for this message
+    pub fn decode<'a>(
+        &'a self,
+        message: &'a CANMessage,
+    ) -> impl Iterator<Item = (&'a SignalDef, f64)> + 'a {
+        self.messages
+            .get(&message.can_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s.decode(&message.data).map(|v| (s, v)))
+    }
+}
+
+// Write a CSV table of the named signals (as for find_signal()) decoded from the
+// messages, for analysis outside Cabana. The first column is the time in seconds
+// from the start of the route and the rest are the signals, with one row for
+// each message that has any of them. The other signals' columns are left empty
+// in that row. Returns the number of rows written.
+pub fn export_signals<W: Write>(
+    dbc: &Dbc,
+    names: &[String],
+    messages: &[CANMessage],
+    writer: W,
+) -> Result<usize> {
+    // Columns to fill for each CAN ID
+    let mut columns: HashMap<u32, Vec<(usize, &SignalDef)>> = HashMap::new();
+    for (column, name) in names.iter().enumerate() {
+        let (can_id, signal) = dbc.find_signal(name)?;
+        columns.entry(can_id).or_default().push((column, signal));
+    }
+
+    let mut csv = csv::Writer::from_writer(writer);
+    csv.write_record(std::iter::once("time").chain(names.iter().map(String::as_str)))?;
+    let mut rows = 0;
+    for message in messages {
+        let Some(signals) = columns.get(&message.can_id) else {
+            continue;
+        };
+        let mut row = vec![String::new(); names.len() + 1];
+        row[0] = format!("{:.6}", message.timestamp() as f64 / 1e9);
+        for (column, signal) in signals {
+            if let Some(value) = signal.decode(&message.data) {
+                row[column + 1] = value.to_string();
+            }
+        }
+        csv.write_record(&row)?;
+        rows += 1;
+    }
+    csv.flush()?;
+    Ok(rows)
+}
+
+impl SignalDef {
+    // Decode the physical value of this signal from message data. Returns None if the
+    // data is too short to contain the signal.
+    pub fn decode(&self, data: &[u8]) -> Option<f64> {
+        let bit = |pos: u64| -> Option<u64> {
+            let byte = data.get((pos / 8) as usize)?;
+            Some(((byte >> (pos % 8)) & 1) as u64)
+        };
+
+        let mut raw: u64 = 0;
+        if self.little_endian {
+            // Intel byte order, start_bit is the least significant bit
+            for i in 0..self.size {
+                raw |= bit(self.start_bit + i)? << i;
+            }
+        } else {
+            // Motorola byte order, start_bit is the most significant bit and the
+            // signal continues from bit 7 of the next byte
+            let mut pos = self.start_bit;
+            for _ in 0..self.size {
+                raw = (raw << 1) | bit(pos)?;
+                pos = match pos % 8 {
+                    0 => pos + 15,
+                    _ => pos - 1,
+                };
+            }
+        }
+
+        let value = if self.signed && self.size > 0 && self.size < 64 && raw >> (self.size - 1) != 0
+        {
+            // Sign extend
+            (raw | (u64::MAX << self.size)) as i64 as f64
+        } else if self.signed {
+            raw as i64 as f64
+        } else {
+            raw as f64
+        };
+
+        Some(value * self.factor + self.offset)
+    }
+
+    // Encode a physical value of this signal into message data, the reverse of
+    // decode(). The value is rounded to the nearest raw value, and clamped to the
+    // range of the signal. Returns None if the data is too short to contain the
+    // signal.
+    pub fn encode(&self, data: &mut [u8], value: f64) -> Option<()> {
+        let raw = ((value - self.offset) / self.factor).round();
+        let raw = match self.signed {
+            true => {
+                let max = 2f64.powi(self.size as i32 - 1);
+                raw.clamp(-max, max - 1.0) as i64 as u64
+            }
+            false => raw.clamp(0.0, 2f64.powi(self.size as i32) - 1.0) as u64,
+        };
+
+        let mut set_bit = |pos: u64, value: u64| -> Option<()> {
+            let byte = data.get_mut((pos / 8) as usize)?;
+            let mask = 1 << (pos % 8);
+            *byte = match value & 1 {
+                0 => *byte & !mask,
+                _ => *byte | mask,
+            };
+            Some(())
+        };
+        if self.little_endian {
+            for i in 0..self.size {
+                set_bit(self.start_bit + i, raw >> i)?;
+            }
+        } else {
+            let mut pos = self.start_bit;
+            for i in (0..self.size).rev() {
+                set_bit(pos, raw >> i)?;
+                pos = match pos % 8 {
+                    0 => pos + 15,
+                    _ => pos - 1,
+                };
+            }
+        }
+        Some(())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    #[serde(rename = "<")]
+    Less,
+    #[serde(rename = "<=")]
+    LessOrEqual,
+    #[serde(rename = "==")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+    #[serde(rename = ">=")]
+    GreaterOrEqual,
+    #[serde(rename = ">")]
+    Greater,
+}
+
+impl Comparison {
+    fn test(&self, a: f64, b: f64) -> bool {
+        match self {
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+            Comparison::GreaterOrEqual => a >= b,
+            Comparison::Greater => a > b,
+        }
+    }
+}
+
+// A rule to show an alert whenever a decoded signal meets a condition, i.e.
+// "VehicleSpeed > 120". The message can include {signal} and {value}, which are
+// replaced with the signal name and the value that triggered the alert.
+#[derive(Deserialize, Clone, Debug)]
+pub struct AlertRule {
+    pub signal: String,
+    pub condition: Comparison,
+    pub value: f64,
+    pub status: AlertStatus,
+    pub message: String,
+}
+
+// Decode the signals for each rule, and generate an alert each time the rule's
+// condition becomes true. Only one alert is shown at a time, so when a rule's
+// condition becomes false again while another rule is still true, that rule's
+// alert is shown again instead of an empty Normal alert (which is only added once
+// none of the rules are true). Returned alerts are sorted by timestamp.
+pub fn find_signal_alerts(
+    dbc: &Dbc,
+    rules: &[AlertRule],
+    messages: &[CANMessage],
+) -> Result<Vec<Alert>> {
+    let signals = rules
+        .iter()
+        .map(|rule| dbc.find_signal(&rule.signal))
+        .collect::<Result<Vec<_>>>()?;
+    // The alert for each rule whose condition is currently true
+    let mut active: Vec<Option<Alert>> = vec![None; rules.len()];
+    let mut result = vec![];
+
+    for m in messages {
+        for (i, (rule, (can_id, signal))) in rules.iter().zip(&signals).enumerate() {
+            if m.can_id != *can_id {
+                continue;
+            }
+            let Some(value) = signal.decode(&m.data) else {
+                continue;
+            };
+            let matches = rule.condition.test(value, rule.value);
+            if matches && active[i].is_none() {
+                let msg = rule
+                    .message
+                    .replace("{signal}", &rule.signal)
+                    .replace("{value}", &value.to_string());
+                let alert = Alert {
+                    timestamp: m.timestamp(),
+                    status: rule.status.clone(),
+                    message: Some(msg),
+                };
+                result.push(alert.clone());
+                active[i] = Some(alert);
+            } else if !matches && active[i].is_some() {
+                active[i] = None;
+                // Show the most recent alert that's still active, if any
+                let alert = match active.iter().flatten().max_by_key(|a| a.timestamp) {
+                    Some(alert) => Alert {
+                        timestamp: m.timestamp(),
+                        ..alert.clone()
+                    },
+                    None => Alert {
+                        timestamp: m.timestamp(),
+                        status: AlertStatus::Normal,
+                        message: None,
+                    },
+                };
+                result.push(alert);
+            }
+        }
+    }
+
+    result.sort_by_key(|a| a.timestamp);
+    Ok(result)
+}
+
+// Which changes of a signal an EdgeMarkerRule adds markers for
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Edge {
+    // Off to on
+    Rising,
+    // On to off
+    Falling,
+    #[default]
+    Both,
+}
+
+// A rule to add a marker each time a boolean signal turns on or off, i.e. a
+// blinker. Any value other than 0 is on. The label can include {signal} and
+// {edge}, which are replaced with the signal name and "rising" or "falling".
+#[derive(Deserialize, Clone, Debug)]
+pub struct EdgeMarkerRule {
+    pub signal: String,
+    #[serde(default)]
+    pub edge: Edge,
+    #[serde(default = "default_edge_label")]
+    pub label: String,
+}
+
+fn default_edge_label() -> String {
+    "{signal} {edge}".to_string()
+}
+
+// Decode the signals for each rule, and generate a marker each time the signal
+// changes in the direction(s) the rule is for. The first decoded value of each
+// signal isn't a change. Returned markers are sorted by timestamp.
+pub fn find_signal_edges(
+    dbc: &Dbc,
+    rules: &[EdgeMarkerRule],
+    messages: &[CANMessage],
+) -> Result<Vec<Marker>> {
+    let mut result = vec![];
+
+    for rule in rules {
+        let (can_id, signal) = dbc.find_signal(&rule.signal)?;
+        let mut last_on = None;
+
+        for m in messages.iter().filter(|m| m.can_id == can_id) {
+            let Some(value) = signal.decode(&m.data) else {
+                continue;
+            };
+            let on = value != 0.0;
+            let edge = match (last_on, on) {
+                (Some(false), true) => Some(("rising", Edge::Rising)),
+                (Some(true), false) => Some(("falling", Edge::Falling)),
+                _ => None,
+            };
+            if let Some((name, edge)) = edge {
+                if rule.edge == edge || rule.edge == Edge::Both {
+                    let label = rule
+                        .label
+                        .replace("{signal}", &rule.signal)
+                        .replace("{edge}", name);
+                    result.push(Marker {
+                        timestamp: m.timestamp(),
+                        label: Some(label),
+                    });
+                }
+            }
+            last_on = Some(on);
+        }
+    }
+
+    result.sort_by_key(|m| m.timestamp);
+    Ok(result)
+}
+
+// A DBC signal (MESSAGE.SIGNAL or SIGNAL, as for find_signal) to decode into a
+// carState field. The decoded value is multiplied by factor, i.e. to convert a
+// speed in km/h to m/s.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CarStateSignal {
+    pub signal: String,
+    #[serde(default = "default_factor")]
+    pub factor: f64,
+}
+
+fn default_factor() -> f64 {
+    1.0
+}
+
+// Mapping from carState fields to the DBC signals that they're decoded from.
+// Any fields without a signal are left unset.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct CarStateSignals {
+    // Metres per second
+    pub v_ego: Option<CarStateSignal>,
+    // Degrees
+    pub steering_angle_deg: Option<CarStateSignal>,
+    // Pedal positions, from 0 to 1
+    pub gas: Option<CarStateSignal>,
+    pub brake: Option<CarStateSignal>,
+}
+
+// A synthesized carState event, None for any field which has no signal or
+// hasn't been decoded yet
+#[derive(Clone, Debug, PartialEq)]
+pub struct CarState {
+    pub timestamp: Nanos,
+    pub v_ego: Option<f64>,
+    pub steering_angle_deg: Option<f64>,
+    pub gas: Option<f64>,
+    pub brake: Option<f64>,
+}
+
+// Decode the mapped signals, and generate a carState event every
+// CAR_STATE_INTERVAL from the first decoded value until the last message. Each
+// field has the most recently decoded value of its signal. Messages must be
+// sorted by timestamp.
+pub fn synthesize_car_states(
+    dbc: &Dbc,
+    signals: &CarStateSignals,
+    messages: &[CANMessage],
+) -> Result<Vec<CarState>> {
+    let decode = |mapping: &Option<CarStateSignal>| -> Result<Vec<(Nanos, f64)>> {
+        let Some(mapping) = mapping else {
+            return Ok(vec![]);
+        };
+        Ok(dbc
+            .signal_values(&mapping.signal, messages)?
+            .into_iter()
+            .map(|(ts, value)| (ts, value * mapping.factor))
+            .collect())
+    };
+    let fields = [
+        decode(&signals.v_ego)?,
+        decode(&signals.steering_angle_deg)?,
+        decode(&signals.gas)?,
+        decode(&signals.brake)?,
+    ];
+
+    let Some(start) = fields
+        .iter()
+        .filter_map(|f| f.first())
+        .map(|(ts, _)| *ts)
+        .min()
+    else {
+        return Ok(vec![]);
+    };
+    let end = messages.last().map(|m| m.timestamp()).unwrap_or(start);
+
+    // Index of the next value of each field that hasn't been reached yet
+    let mut next = [0usize; 4];
+    let mut result = vec![];
+    let mut ts = start;
+    while ts <= end {
+        let mut values = [None; 4];
+        for (idx, field) in fields.iter().enumerate() {
+            while next[idx] < field.len() && field[next[idx]].0 <= ts {
+                next[idx] += 1;
+            }
+            values[idx] = next[idx].checked_sub(1).map(|i| field[i].1);
+        }
+        let [v_ego, steering_angle_deg, gas, brake] = values;
+        result.push(CarState {
+            timestamp: ts,
+            v_ego,
+            steering_angle_deg,
+            gas,
+            brake,
+        });
+        ts += CAR_STATE_INTERVAL;
+    }
+
+    Ok(result)
+}
@@ -1,20 +1,66 @@
 // Copyright (c) 2023 Angus Gratton
 // SPDX-License-Identifier: GPL-2.0-or-later
-use std::path::Path;
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Context, Result};
-use itertools::Itertools;
-use serde::Deserialize;
+use indicatif::ProgressBar;
+use itertools::{merge, Itertools};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 
+use crate::dbc::CarState;
 use crate::video::SourceFrame;
 use crate::Nanos;
 
+pub mod alerts;
+pub mod asc;
+pub mod busmaster;
+pub mod candump;
+pub mod error;
+pub mod generate;
+pub mod gps;
+pub mod gvret;
+pub mod markers;
+pub mod mf4;
+#[cfg(target_os = "linux")]
+pub mod socketcan;
+pub mod trc;
+pub mod vehiclespy;
+
+pub use alerts::read_alerts;
+pub use asc::read_can_messages_asc;
+pub use busmaster::read_can_messages_busmaster;
+pub use candump::read_can_messages_candump;
+pub use error::InputError;
+pub use generate::{generate_can_messages, GeneratedSignal, GeneratorSpec, Waveform};
+pub use gps::{read_locations, Location};
+pub use gvret::read_can_messages_gvret;
+pub use markers::{expand_markers, read_markers, Marker};
+pub use mf4::read_can_messages_mf4;
+#[cfg(target_os = "linux")]
+pub use socketcan::record_can_messages_socketcan;
+pub use trc::read_can_messages_trc;
+pub use vehiclespy::read_can_messages_vehiclespy;
+
 // Wrapper enum for all inputs to the route log
-#[derive(Eq)]
 pub enum LogInput {
     CAN(CANMessage),
     Frame(SourceFrame),
     Alert(Alert),
+    Location(Location),
+    Marker(Marker),
+    CarState(CarState),
 }
 
 impl LogInput {
@@ -24,6 +70,9 @@ impl LogInput {
             LogInput::CAN(m) => m.timestamp,
             LogInput::Frame(s) => s.ts_ns,
             LogInput::Alert(s) => s.timestamp,
+            LogInput::Location(l) => l.timestamp,
+            LogInput::Marker(m) => m.timestamp,
+            LogInput::CarState(c) => c.timestamp,
         }
     }
 }
@@ -40,6 +89,12 @@ impl From<SourceFrame> for LogInput {
     }
 }
 
+impl From<Location> for LogInput {
+    fn from(value: Location) -> Self {
+        LogInput::Location(value)
+    }
+}
+
 impl Ord for LogInput {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.timestamp().cmp(&other.timestamp())
@@ -58,6 +113,10 @@ impl PartialEq for LogInput {
     }
 }
 
+// Inputs are only compared by timestamp, so the f64 fields of some of them
+// (which can be NaN) don't matter
+impl Eq for LogInput {}
+
 // Parser for CAN messages from CSV log
 #[derive(Eq, PartialEq, Debug)]
 pub struct CANMessage {
@@ -66,6 +125,35 @@ pub struct CANMessage {
     pub is_extended_id: bool,
     pub bus_no: u8,
     pub data: Vec<u8>,
+    pub is_fd: bool,
+    // Data length code as sent on the bus. This is usually the same as the data
+    // length (see len_for_dlc()), but a classic frame can have a DLC of 9 to 15
+    // with 8 bytes of data, and a remote frame has only the DLC and no data. The
+    // route's CAN events have no DLC, so it isn't written anywhere.
+    pub dlc: u8,
+    // Remote transmission request, these have a length but no data
+    pub is_rtr: bool,
+    // None if the log doesn't record whether the frame was sent or received
+    pub direction: Option<Direction>,
+}
+
+// Whether a CAN frame was received or transmitted by the logging device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+impl FromStr for Direction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Rx" => Ok(Direction::Rx),
+            "Tx" => Ok(Direction::Tx),
+            _ => Err(anyhow!("Invalid direction '{}'", s)),
+        }
+    }
 }
 
 impl Ord for CANMessage {
@@ -81,46 +169,99 @@ impl PartialOrd for CANMessage {
 }
 
 impl CANMessage {
-    pub fn parse_from(record: &csv::StringRecord, ts_offs: Nanos) -> Result<Self> {
+    pub fn parse_from(record: &csv::StringRecord, ts_offs: Nanos) -> Result<Self, InputError> {
+        Self::parse_from_unit(record, ts_offs, TsUnit::Micros, false)
+    }
+
+    // Parse a SavvyCAN CSV record where the timestamp field is in ts_unit. With
+    // strict_fields, the record must have a dlen field and exactly that many data
+    // fields, to catch exports where the columns have shifted.
+    pub fn parse_from_unit(
+        record: &csv::StringRecord,
+        ts_offs: Nanos,
+        ts_unit: TsUnit,
+        strict_fields: bool,
+    ) -> Result<Self, InputError> {
         // in this format, each record has a variable number of fields
         // and we want to concatenate the variable data fields
         let mut fields = record.iter();
+        let invalid_hex = |field, value: &str| InputError::InvalidHex {
+            field,
+            value: value.to_string(),
+        };
 
-        let ts_us: i64 = fields.next().ok_or(anyhow!("Missing ts field"))?.parse()?;
-        let can_id =
-            u32::from_str_radix(fields.next().ok_or(anyhow!("Missing can id field"))?, 16)?;
+        let ts = fields.next().ok_or(InputError::MissingField("ts"))?;
+        let ts_ns = ts_unit.parse_as_nanos(ts)?;
+        let can_id = fields.next().ok_or(InputError::MissingField("can id"))?;
+        let can_id = u32::from_str_radix(can_id, 16).map_err(|_| invalid_hex("can id", can_id))?;
         let is_extended_id = fields
             .next()
-            .ok_or(anyhow!("Missing is_extended_id field"))?
+            .ok_or(InputError::MissingField("is_extended_id"))?
             == "true";
 
-        // SavvyCAN CSV files have a field here for Rx/Tx, skip it if present
+        // SavvyCAN CSV files may have a field here for Rx/Tx
         let maybe_tx_rx = fields.next();
-        let next = if maybe_tx_rx != Some("Tx") && maybe_tx_rx != Some("Rx") {
+        let direction = maybe_tx_rx.and_then(|d| d.parse().ok());
+        let next = if direction.is_none() {
             maybe_tx_rx
         } else {
             fields.next()
         };
 
-        let bus_no = next
-            .ok_or(anyhow!("Missing bus field"))?
-            .parse()
-            .context("Invalid bus field")?;
-        fields.next(); // dlen field, can skip this one
+        let bus = next.ok_or(InputError::MissingField("bus"))?;
+        let bus_no = bus.parse().map_err(|_| InputError::InvalidField {
+            field: "bus",
+            value: bus.to_string(),
+        })?;
+        // Number of data bytes, any mismatch with the data is left for
+        // check_can_flags() to report
+        let len: Option<usize> = match fields.next() {
+            None | Some("") => None,
+            Some(len) => Some(len.parse().map_err(|_| InputError::InvalidField {
+                field: "dlen",
+                value: len.to_string(),
+            })?),
+        };
 
-        // collect the remaining variable number of data fields d1..d8
-        let data = fields
-            .take(8)
-            .map(|d| u8::from_str_radix(d, 16))
-            .try_collect()
-            .context("Error parsing CSV data field")?;
+        // the remaining variable number of data fields d1..dN (more than 8 for
+        // CAN-FD frames), then possibly other columns that some exports add
+        let fields: Vec<&str> = fields.collect();
+        if strict_fields {
+            match len {
+                None => return Err(InputError::MissingField("dlen")),
+                Some(len) if len != fields.len() => {
+                    return Err(InputError::DataFieldCount {
+                        len,
+                        data_fields: fields.len(),
+                    })
+                }
+                _ => (),
+            }
+        }
+        // Only the fields that dlen says are data are read, any after them are ignored
+        let data_fields = match len {
+            Some(len) => &fields[..fields.len().min(len).min(64)],
+            None => &fields[..],
+        };
+        let data: Vec<u8> = data_fields
+            .iter()
+            .map(|d| u8::from_str_radix(d, 16).map_err(|_| invalid_hex("data", d)))
+            .try_collect()?;
+        check_data_len(&data)?;
 
         Ok(CANMessage {
-            timestamp: (ts_us * 1000) as Nanos - ts_offs,
+            timestamp: ts_ns
+                .checked_sub(ts_offs)
+                .ok_or_else(|| InputError::TimestampOutOfRange(ts.to_string()))?,
             can_id,
             is_extended_id,
             bus_no,
+            is_fd: data.len() > 8,
+            dlc: dlc_for_len(len.unwrap_or(data.len())),
             data,
+            // SavvyCAN CSV logs don't mark remote frames
+            is_rtr: false,
+            direction,
         })
     }
 
@@ -129,83 +270,1140 @@ impl CANMessage {
     }
 }
 
+// Return an error if a frame's data isn't a length that can be sent on the bus
+// (up to 8 bytes for classic CAN, or one of the larger CAN-FD lengths)
+pub fn check_data_len(data: &[u8]) -> Result<(), InputError> {
+    match data.len() {
+        0..=8 | 12 | 16 | 20 | 24 | 32 | 48 | 64 => Ok(()),
+        len => Err(InputError::InvalidDataLength(len)),
+    }
+}
+
+// Data length code for this many data bytes, for logs that only record the
+// length. A length that CAN-FD can't send is rounded up to the next DLC.
+pub fn dlc_for_len(len: usize) -> u8 {
+    match len {
+        0..=8 => len as u8,
+        9..=12 => 9,
+        13..=16 => 10,
+        17..=20 => 11,
+        21..=24 => 12,
+        25..=32 => 13,
+        33..=48 => 14,
+        _ => 15,
+    }
+}
+
+// Number of data bytes a data length code means. Classic CAN frames have at most
+// 8 bytes, so a DLC of 9 to 15 also means 8 bytes for them.
+pub fn len_for_dlc(dlc: u8, is_fd: bool) -> usize {
+    match dlc {
+        0..=8 => dlc as usize,
+        _ if !is_fd => 8,
+        9 => 12,
+        10 => 16,
+        11 => 20,
+        12 => 24,
+        13 => 32,
+        14 => 48,
+        _ => 64,
+    }
+}
+
+// CAN messages read from one or more logs, sorted by timestamp, with some
+// details of them so callers don't need to work these out again
+#[derive(Debug, Default)]
+pub struct CanLog {
+    pub messages: Vec<CANMessage>,
+    // Offset that was subtracted from the log timestamps, i.e. the log timestamp
+    // of the start of the route (see epoch_start_time())
+    pub ts_offset: Nanos,
+    // Bus numbers of the messages, after any bus_remap
+    pub buses: BTreeSet<u8>,
+    // Timestamp of the last message, or 0 if there are none
+    pub duration: Nanos,
+    // Unit of the timestamps in a SavvyCAN CSV log, as given in the options or
+    // guessed from the log. None for other formats.
+    pub ts_unit: Option<TsUnit>,
+}
+
+impl CanLog {
+    fn new(messages: Vec<CANMessage>, ts_offset: Nanos) -> Self {
+        CanLog {
+            buses: messages.iter().map(|m| m.bus_no).collect(),
+            duration: messages.last().map(|m| m.timestamp()).unwrap_or(0),
+            messages,
+            ts_offset,
+            ts_unit: None,
+        }
+    }
+}
+
+// Supported formats for CAN log input files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    SavvyCan,
+    Candump,
+    Asc,
+    Trc,
+    Gvret,
+    VehicleSpy,
+    Busmaster,
+    Mf4,
+}
+
+impl LogFormat {
+    // Guess the format of a log file from its extension, defaulting to SavvyCAN CSV.
+    // A compression extension is skipped, i.e. "foo.log.gz" is a candump log.
+    pub fn from_path(path: &Path) -> Self {
+        let path = match Compression::from_path(path) {
+            Compression::None => path,
+            _ => Path::new(path.file_stem().unwrap_or_default()),
+        };
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("log") => LogFormat::Candump,
+            Some("asc") => LogFormat::Asc,
+            Some("trc") => LogFormat::Trc,
+            Some("gvret") => LogFormat::Gvret,
+            Some("mf4") => LogFormat::Mf4,
+            _ => LogFormat::SavvyCan,
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "savvycan" | "csv" => Ok(LogFormat::SavvyCan),
+            "candump" => Ok(LogFormat::Candump),
+            "asc" => Ok(LogFormat::Asc),
+            "trc" => Ok(LogFormat::Trc),
+            "gvret" => Ok(LogFormat::Gvret),
+            "vehiclespy" => Ok(LogFormat::VehicleSpy),
+            "busmaster" | "pcan-explorer" => Ok(LogFormat::Busmaster),
+            "mf4" | "mdf4" => Ok(LogFormat::Mf4),
+            _ => Err(anyhow!("Unknown CAN log format '{}'", s)),
+        }
+    }
+}
+
+// Compression of a log file, from its extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("bz2") => Compression::Bzip2,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+// With the mmap feature, uncompressed logs bigger than this are memory mapped
+// instead of read through a buffer. Smaller logs are read quickly either way.
+#[cfg(feature = "mmap")]
+const MMAP_MIN_LOG_BYTES: u64 = 64 * 1024 * 1024;
+
+// Memory map a big uncompressed log file, if possible. Returns None to read the
+// file normally instead.
+#[cfg(feature = "mmap")]
+fn map_log_file(log_path: &Path, f: &File, len: u64) -> Option<std::io::Cursor<memmap2::Mmap>> {
+    if len < MMAP_MIN_LOG_BYTES || Compression::from_path(log_path) != Compression::None {
+        return None;
+    }
+    // The mapping is only read from. If another program changes the file while
+    // it's being read the messages may be garbled, the same as for a log that's
+    // still being written when it's read normally.
+    match unsafe { memmap2::Mmap::map(f) } {
+        Ok(mmap) => {
+            debug!("Memory mapped {:?}", log_path);
+            Some(std::io::Cursor::new(mmap))
+        }
+        Err(e) => {
+            debug!(
+                "Failed to memory map {:?}, reading it instead: {}",
+                log_path, e
+            );
+            None
+        }
+    }
+}
+
+// Open a log file for reading, decompressing it on the fly if it has a
+// .gz, .bz2 or .zst extension. If progress is set, it's reset to track how
+// much of the file has been read.
+pub fn open_log_file(log_path: &Path, progress: Option<&ProgressBar>) -> Result<Box<dyn BufRead>> {
+    let f = File::open(log_path).map_err(|source| InputError::Io {
+        path: log_path.to_path_buf(),
+        source,
+    })?;
+    let len = f.metadata()?.len();
+    if let Some(progress) = progress {
+        progress.reset();
+        progress.set_length(len);
+        progress.set_message(format!(
+            "Reading {:?}",
+            log_path.file_name().unwrap_or_default()
+        ));
+    }
+
+    #[cfg(feature = "mmap")]
+    if let Some(mapped) = map_log_file(log_path, &f, len) {
+        return Ok(match progress {
+            Some(progress) => Box::new(progress.wrap_read(mapped)),
+            None => Box::new(mapped),
+        });
+    }
+
+    let f: Box<dyn Read> = match progress {
+        Some(progress) => Box::new(progress.wrap_read(f)),
+        None => Box::new(f),
+    };
+    Ok(match Compression::from_path(log_path) {
+        Compression::None => Box::new(BufReader::new(f)),
+        Compression::Gzip => Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(f))),
+        Compression::Bzip2 => Box::new(BufReader::new(bzip2::read::MultiBzDecoder::new(f))),
+        Compression::Zstd => Box::new(BufReader::new(
+            zstd::stream::read::Decoder::new(f)
+                .with_context(|| format!("Failed to read zstd file {:?}", log_path))?,
+        )),
+    })
+}
+
+// Read CAN messages from a log file in any supported format. If format is None,
+// it's guessed from the file extension.
+pub fn read_can_log(
+    log_path: &Path,
+    format: Option<LogFormat>,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog, InputError> {
+    let log = read_log_file(log_path, format, can_ts_offs, options)?;
+    check_not_empty(log, &[log_path], options)
+}
+
+// Return an EmptyLog error if there are no messages in log, unless
+// options.allow_empty is set
+fn check_not_empty(
+    log: CanLog,
+    log_paths: &[impl AsRef<Path>],
+    options: &ReadOptions,
+) -> Result<CanLog, InputError> {
+    if log.messages.is_empty() && !options.allow_empty {
+        let paths = log_paths.iter().map(|p| p.as_ref().to_path_buf());
+        return Err(InputError::EmptyLog(paths.collect()));
+    }
+    Ok(log)
+}
+
+// Same as read_can_log(), but an empty log isn't an error
+fn read_log_file(
+    log_path: &Path,
+    format: Option<LogFormat>,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
+    match format.unwrap_or_else(|| LogFormat::from_path(log_path)) {
+        LogFormat::SavvyCan => read_csv_log_file(log_path, can_ts_offs, options),
+        LogFormat::Candump => read_can_messages_candump(log_path, can_ts_offs, options),
+        LogFormat::Asc => read_can_messages_asc(log_path, can_ts_offs, options),
+        LogFormat::Trc => read_can_messages_trc(log_path, can_ts_offs, options),
+        LogFormat::Gvret => read_can_messages_gvret(log_path, can_ts_offs, options),
+        LogFormat::VehicleSpy => read_can_messages_vehiclespy(log_path, can_ts_offs, options),
+        LogFormat::Busmaster => read_can_messages_busmaster(log_path, can_ts_offs, options),
+        LogFormat::Mf4 => read_can_messages_mf4(log_path, can_ts_offs, options),
+    }
+}
+
+// Read CAN messages from one or more log files and merge them into a single
+// timeline, i.e. a long drive that was logged to more than one file.
+//
+// The files may overlap in time, the messages are interleaved in timestamp order.
+// If can_ts_offs is None then the offset is chosen so the earliest message from
+// any of the files has timestamp 0 (not the first message of each file, so the
+// merged timeline stays continuous).
+//
+// The returned log's ts_offset is the offset that was applied to all the files.
+pub fn read_can_logs(
+    log_paths: &[PathBuf],
+    format: Option<LogFormat>,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog, InputError> {
+    // Read each file with no offset applied, so they all share the same timeline.
+    // Each file's messages come back sorted (and any of them can be empty, as
+    // long as they aren't all).
+    let mut sources = vec![];
+    let mut ts_unit = None;
+    for log_path in log_paths {
+        let log = read_log_file(log_path, format, Some(0), options)?;
+        ts_unit = ts_unit.or(log.ts_unit);
+        sources.push(log.messages.into_iter());
+    }
+
+    let first_ts = sources
+        .iter()
+        .filter_map(|s| s.as_slice().first())
+        .map(|m| m.timestamp())
+        .min();
+    let can_ts_offs = match (can_ts_offs, first_ts) {
+        // Move the start of the route earlier to include every message
+        (Some(offs), Some(first_ts)) if options.keep_early => offs.min(first_ts),
+        (Some(offs), _) => offs,
+        (None, first_ts) => first_ts.unwrap_or(0),
+    };
+    // Clip the start of the route, by moving the offset later
+    let can_ts_offs = can_ts_offs + options.start;
+    let end = options.end.map_or(Nanos::MAX, |end| end - options.start);
+    debug!("route can_ts_offs {}", can_ts_offs);
+
+    let messages = merge_sorted_can(sources)
+        .map(|mut m| {
+            m.timestamp -= can_ts_offs;
+            if options.clamp_early {
+                m.timestamp = m.timestamp.max(0);
+            }
+            m
+        })
+        // Same as for a single log, drop any CAN timestamp that comes before the video
+        // (unless keep_early moved the offset earlier, or clamp_early moved them)
+        .filter(|m| m.timestamp >= 0 && m.timestamp < end)
+        .collect();
+    let mut log = CanLog::new(messages, can_ts_offs);
+    log.ts_unit = ts_unit;
+    check_not_empty(log, log_paths, options)
+}
+
+// Some loggers write absolute timestamps (microseconds since the Unix epoch)
+// rather than timestamps relative to the start of the log. If the log timestamp
+// at the start of the route looks like one of these, return the wall clock time
+// that the route starts.
+pub fn epoch_start_time(can_ts_offs: Nanos) -> Option<SystemTime> {
+    // Any timestamp after 2000-01-01 is assumed to be absolute, a relative
+    // timestamp this large would mean a 30 year long log
+    const MIN_EPOCH_NANOS: Nanos = 946_684_800 * 1_000_000_000;
+
+    (can_ts_offs >= MIN_EPOCH_NANOS)
+        .then(|| SystemTime::UNIX_EPOCH + Duration::from_nanos(can_ts_offs as u64))
+}
+
+// Merge several sources of CAN messages that are each sorted by timestamp into
+// one sorted stream. Messages with the same timestamp come out in the order of
+// their sources.
+pub fn merge_sorted_can<I: Iterator<Item = CANMessage>>(
+    mut sources: Vec<I>,
+) -> impl Iterator<Item = CANMessage> {
+    // Heap holds the next message from each source that isn't exhausted yet
+    let mut heap: BinaryHeap<Reverse<(CANMessage, usize)>> = sources
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(idx, s)| s.next().map(|m| Reverse((m, idx))))
+        .collect();
+
+    std::iter::from_fn(move || {
+        let Reverse((message, idx)) = heap.pop()?;
+        if let Some(next) = sources[idx].next() {
+            heap.push(Reverse((next, idx)));
+        }
+        Some(message)
+    })
+}
+
 pub fn read_can_messages(
     csv_log_path: &Path,
     can_ts_offs: Option<Nanos>,
-) -> Result<Vec<CANMessage>> {
-    eprintln!("Opening CAN log {:?}...", csv_log_path);
+    options: &ReadOptions,
+) -> Result<CanLog, InputError> {
+    let log = read_csv_log_file(csv_log_path, can_ts_offs, options)?;
+    check_not_empty(log, &[csv_log_path], options)
+}
+
+// Same as read_can_messages(), but an empty log isn't an error
+fn read_csv_log_file(
+    csv_log_path: &Path,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
+    info!("Opening CAN log {:?}...", csv_log_path);
+
+    let reader = open_log_file(csv_log_path, options.progress.as_ref())?;
+    parse_csv_can_messages(csv_log_path, reader, can_ts_offs, options)
+}
 
+// Same as read_can_messages(), for a SavvyCAN CSV log that's already in memory
+// (i.e. a &[u8]) or open. The log isn't decompressed, and options.progress
+// isn't updated.
+pub fn read_can_messages_from_reader(
+    reader: impl Read,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog, InputError> {
+    // Used in error messages, in place of the log file's path
+    let name = Path::new("<reader>");
+    let log = parse_csv_can_messages(name, reader, can_ts_offs, options)?;
+    check_not_empty(log, &[name], options)
+}
+
+fn parse_csv_can_messages(
+    csv_log_path: &Path,
+    reader: impl Read,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
     let mut rdr = csv::ReaderBuilder::new()
         .flexible(true)
         .has_headers(true)
-        .from_path(csv_log_path)
-        .with_context(|| format!("Failed to read CSV file {:?}", csv_log_path))?;
+        .from_reader(reader);
 
-    let mut records = rdr.records().peekable();
+    let records = rdr.records().enumerate().map(|(row, rec)| {
+        (
+            row + 1,
+            rec.map_err(|e| anyhow!("Invalid CSV record in file {:?}: {}", csv_log_path, e)),
+        )
+    });
+    let records = accumulate_delta_timestamps(records, options.ts_mode);
+    let (ts_unit, records) = resolve_ts_unit(records, options.ts_unit);
 
-    let can_ts_offs = can_ts_offs.unwrap_or_else(|| match records.peek() {
-        // If no timestamp offset was specified, offset so the first message
-        // has timestamp 0
-        Some(Ok(record)) => match CANMessage::parse_from(record, 0) {
-            Ok(message) => message.timestamp(),
-            _ => 0,
+    let mut log = collect_can_messages(
+        csv_log_path,
+        records,
+        can_ts_offs,
+        options,
+        |record, ts_offs| {
+            CANMessage::parse_from_unit(record, ts_offs, ts_unit, options.strict_fields)
+                .map_err(Into::into)
         },
-        _ => 0,
-    });
+    )?;
+    log.ts_unit = Some(ts_unit);
+    Ok(log)
+}
 
-    eprintln!("can_ts_offs {}", can_ts_offs);
+// Options for reading CAN logs, that apply to any log format unless noted
+#[derive(Clone, Debug, Default)]
+pub struct ReadOptions {
+    pub filter: CanFilter,
+    // Unit of SavvyCAN CSV timestamps. If None, guessed from the log.
+    pub ts_unit: Option<TsUnit>,
+    // Whether SavvyCAN CSV timestamps are absolute, or deltas from the message
+    // before
+    pub ts_mode: TsMode,
+    // If set, updated with progress through each log file as it's read
+    pub progress: Option<ProgressBar>,
+    // Skip any records that fail to parse, instead of failing
+    pub skip_bad_rows: bool,
+    // Count of records skipped with skip_bad_rows, shared by any clones of
+    // these options so it covers all the logs read with them
+    pub skipped_rows: Arc<AtomicUsize>,
+    // Keep messages from before the given can_ts_offs, by returning an earlier
+    // offset if needed (read_can_logs only)
+    pub keep_early: bool,
+    // Move messages from before the start of the route to timestamp 0 (in the
+    // same order), instead of dropping them
+    pub clamp_early: bool,
+    // Only keep messages from this far into the route up to end (if set), and
+    // move them so the route starts from start instead (read_can_logs only)
+    pub start: Nanos,
+    pub end: Option<Nanos>,
+    // Change the bus numbers of messages as they're parsed (before the filter is
+    // applied). Buses that aren't in the map keep their number.
+    pub bus_remap: HashMap<u8, u8>,
+    // Reject SavvyCAN CSV records without exactly dlen data fields, see
+    // CANMessage::parse_from_unit()
+    pub strict_fields: bool,
+    // Reverse the order of each message's data bytes as it's parsed, for loggers
+    // that write them backwards (log files only)
+    pub reverse_data_bytes: bool,
+    // Date of a BUSMASTER log, for its times of day to be absolute timestamps.
+    // If None, they're the time since midnight.
+    pub log_date: Option<LogDate>,
+    // Return a log with no messages (i.e. only a header, or none left after the
+    // filter, start and end), instead of an InputError::EmptyLog
+    pub allow_empty: bool,
+}
 
-    let mut result = records
-        .enumerate()
-        .map(|(row, rec)| match rec {
-            Ok(r) => CANMessage::parse_from(&r, can_ts_offs).with_context(|| {
-                format!(
-                    "Invalid CAN data found in CSV {:?} row {}",
-                    csv_log_path,
-                    row + 1
-                )
-            }),
-            Err(e) => Err(anyhow!(
-                "Invalid CSV record in file {:?}: {}",
-                csv_log_path,
-                e
-            )),
-        })
-        // TODO: For now dropping any CAN timestamp that comes before the video
-        // started. Could conceivably adjust the start earlier instead and have empty video
-        .filter(|r| match r {
-            Ok(m) => m.timestamp >= 0,
-            _ => true,
-        })
-        .collect::<Result<Vec<CANMessage>>>()?;
+// Filter for which CAN messages to keep when reading a log. The default
+// filter keeps all messages.
+#[derive(Clone, Debug, Default)]
+pub struct CanFilter {
+    // If set, only keep messages from these bus numbers
+    pub buses: Option<Vec<u8>>,
+    // If set, only keep messages with these CAN IDs
+    pub id_allowlist: Option<HashSet<u32>>,
+    // If set, drop messages with these CAN IDs (also applies to IDs in the allowlist)
+    pub id_denylist: Option<HashSet<u32>>,
+}
+
+impl CanFilter {
+    pub fn matches(&self, message: &CANMessage) -> bool {
+        let bus_match = match &self.buses {
+            Some(buses) => buses.contains(&message.bus_no),
+            None => true,
+        };
+        let allowed = match &self.id_allowlist {
+            Some(ids) => ids.contains(&message.can_id),
+            None => true,
+        };
+        let denied = match &self.id_denylist {
+            Some(ids) => ids.contains(&message.can_id),
+            None => false,
+        };
+        bus_match && allowed && !denied
+    }
+}
+
+// Logs bigger than this are parsed on all cores, there's no benefit for
+// smaller logs
+const PARALLEL_MIN_LOG_BYTES: u64 = 8 * 1024 * 1024;
+
+// Number of records each parallel parsing pass is given at once
+const PARALLEL_CHUNK_RECORDS: usize = 64 * 1024;
+
+// Common part of reading a CAN log in any format.
+//
+// Takes an iterator of (row number, record) pairs and a function to parse each
+// record into a CANMessage with a given timestamp offset. Returns all the
+// messages that match the filter, sorted by timestamp.
+fn collect_can_messages<R: Send>(
+    log_path: &Path,
+    records: impl Iterator<Item = (usize, Result<R>)>,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+    parse: impl Fn(&R, Nanos) -> Result<CANMessage> + Sync,
+) -> Result<CanLog> {
+    let (can_ts_offs, records) = resolve_can_ts_offs(records, can_ts_offs, &parse);
+    let log_len = std::fs::metadata(log_path).map(|m| m.len()).unwrap_or(0);
+    let mut result = if log_len < PARALLEL_MIN_LOG_BYTES {
+        parse_can_records(log_path, records, can_ts_offs, options.clone(), parse)
+            .collect::<Result<Vec<CANMessage>>>()?
+    } else {
+        parse_can_records_parallel(log_path, records, can_ts_offs, options, parse)?
+    };
     // When the log contains >1 bus of data, the messages can be slightly out
     // of order
     result.sort();
+    Ok(CanLog::new(result, can_ts_offs))
+}
+
+// Lazily parse records into CAN messages, in the same order as the records. Used by
+// collect_can_messages() and iter_can_messages(), after resolve_can_ts_offs().
+fn parse_can_records<R>(
+    log_path: &Path,
+    records: impl Iterator<Item = (usize, Result<R>)>,
+    can_ts_offs: Nanos,
+    options: ReadOptions,
+    parse: impl Fn(&R, Nanos) -> Result<CANMessage>,
+) -> impl Iterator<Item = Result<CANMessage>> {
+    let log_path = log_path.to_path_buf();
+    records.filter_map(move |(row, rec)| {
+        parse_can_record(&log_path, row, rec, can_ts_offs, &options, &parse).filter(|r| match r {
+            Ok(m) => keep_can_message(m, &options),
+            _ => true,
+        })
+    })
+}
+
+// Same result as parse_can_records(), but the records are parsed in chunks spread
+// across all cores. The records themselves are still read in order, on one thread.
+fn parse_can_records_parallel<R: Send>(
+    log_path: &Path,
+    mut records: impl Iterator<Item = (usize, Result<R>)>,
+    can_ts_offs: Nanos,
+    options: &ReadOptions,
+    parse: impl Fn(&R, Nanos) -> Result<CANMessage> + Sync,
+) -> Result<Vec<CANMessage>> {
+    let mut result = vec![];
+    loop {
+        let chunk: Vec<_> = records.by_ref().take(PARALLEL_CHUNK_RECORDS).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let parsed: Vec<Result<CANMessage>> = chunk
+            .into_par_iter()
+            .filter_map(|(row, rec)| {
+                parse_can_record(log_path, row, rec, can_ts_offs, options, &parse)
+            })
+            .collect();
+        // Results are in record order, so any error is the first one in the log
+        for message in parsed {
+            let message = message?;
+            if keep_can_message(&message, options) {
+                result.push(message);
+            }
+        }
+    }
     Ok(result)
 }
 
-#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+// Most records to scan for the first one that parses, when finding the timestamp
+// offset for a log
+const TS_OFFS_SCAN_RECORDS: usize = 1000;
+
+// Return the timestamp offset for a log, and the records including any that were
+// read to find it. If no timestamp offset was specified, offset so the first
+// record that parses (i.e. after a junk header row) has timestamp 0.
+fn resolve_can_ts_offs<R>(
+    mut records: impl Iterator<Item = (usize, Result<R>)>,
+    can_ts_offs: Option<Nanos>,
+    parse: &impl Fn(&R, Nanos) -> Result<CANMessage>,
+) -> (Nanos, impl Iterator<Item = (usize, Result<R>)>) {
+    let mut head = vec![];
+    let can_ts_offs = can_ts_offs.unwrap_or_else(|| {
+        for (row, rec) in records.by_ref().take(TS_OFFS_SCAN_RECORDS) {
+            let parsed = rec
+                .as_ref()
+                .ok()
+                .map(|r| parse(r, 0).map(|m| m.timestamp()));
+            head.push((row, rec));
+            match parsed {
+                Some(Ok(ts)) => return ts,
+                Some(Err(_)) => (),
+                // Error reading the record, the rest of the file may not be readable either
+                None => break,
+            }
+        }
+        0
+    });
+
+    debug!("can_ts_offs {}", can_ts_offs);
+
+    (can_ts_offs, head.into_iter().chain(records))
+}
+
+// Parse a single record. Returns None if the record is invalid and
+// options.skip_bad_rows is set, after logging the error.
+fn parse_can_record<R>(
+    log_path: &Path,
+    row: usize,
+    rec: Result<R>,
+    can_ts_offs: Nanos,
+    options: &ReadOptions,
+    parse: &impl Fn(&R, Nanos) -> Result<CANMessage>,
+) -> Option<Result<CANMessage>> {
+    // Errors reading the record (rather than parsing it) are never skipped, as
+    // the rest of the file may not be readable either
+    let rec = match rec {
+        Ok(rec) => rec,
+        Err(e) => return Some(Err(e)),
+    };
+    let result = parse(&rec, can_ts_offs)
+        .map(|mut message| {
+            if let Some(bus_no) = options.bus_remap.get(&message.bus_no) {
+                message.bus_no = *bus_no;
+            }
+            if options.reverse_data_bytes {
+                message.data.reverse();
+            }
+            if options.clamp_early {
+                message.timestamp = message.timestamp.max(0);
+            }
+            message
+        })
+        .map_err(|source| {
+            InputError::InvalidRow {
+                path: log_path.to_path_buf(),
+                row,
+                source,
+            }
+            .into()
+        });
+    match result {
+        Err(e) if options.skip_bad_rows => {
+            warn!("Skipping bad row: {:#}", e);
+            options.skipped_rows.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+        result => Some(result),
+    }
+}
+
+fn keep_can_message(message: &CANMessage, options: &ReadOptions) -> bool {
+    // Drop any CAN timestamp that comes before the video started. read_can_logs
+    // can adjust the start earlier instead, see ReadOptions::keep_early (or see
+    // ReadOptions::clamp_early to keep them at the start)
+    message.timestamp >= 0 && options.filter.matches(message)
+}
+
+// Iterate the CAN messages in a SavvyCAN CSV log, without reading the whole log
+// into memory. The messages are yielded in the order they appear in the log,
+// so may be slightly out of order if the log has more than one bus (see
+// ReorderCanMessages). If there are none, the only item is an
+// InputError::EmptyLog (unless options.allow_empty is set).
+pub fn iter_can_messages(
+    csv_log_path: &Path,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<impl Iterator<Item = Result<CANMessage>>> {
+    info!("Opening CAN log {:?}...", csv_log_path);
+
+    let rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(true)
+        .from_reader(open_log_file(csv_log_path, options.progress.as_ref())?);
+
+    let path = csv_log_path.to_path_buf();
+    let records = rdr.into_records().enumerate().map(move |(row, rec)| {
+        (
+            row + 1,
+            rec.map_err(|e| anyhow!("Invalid CSV record in file {:?}: {}", path, e)),
+        )
+    });
+    let records = accumulate_delta_timestamps(records, options.ts_mode);
+    let (ts_unit, records) = resolve_ts_unit(records, options.ts_unit);
+    let strict_fields = options.strict_fields;
+    let parse = move |record: &csv::StringRecord, ts_offs| {
+        CANMessage::parse_from_unit(record, ts_offs, ts_unit, strict_fields).map_err(Into::into)
+    };
+    let (can_ts_offs, records) = resolve_can_ts_offs(records, can_ts_offs, &parse);
+
+    let mut messages =
+        parse_can_records(csv_log_path, records, can_ts_offs, options.clone(), parse);
+    // Same as read_can_messages(), the end of an empty log is an EmptyLog error
+    let mut empty = !options.allow_empty;
+    let path = csv_log_path.to_path_buf();
+    Ok(std::iter::from_fn(move || match messages.next() {
+        None if empty => {
+            empty = false;
+            Some(Err(InputError::EmptyLog(vec![path.clone()]).into()))
+        }
+        next => {
+            empty = false;
+            next
+        }
+    }))
+}
+
+// Number of records at the start of a CSV log used to guess the timestamp unit
+const TS_UNIT_SAMPLE_RECORDS: usize = 100;
+
+// Unit of the timestamp field in SavvyCAN CSV logs. SavvyCAN itself writes
+// microseconds, but other tools write milliseconds or seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TsUnit {
+    #[serde(rename = "us")]
+    Micros,
+    #[serde(rename = "ms")]
+    Millis,
+    #[serde(rename = "s")]
+    Seconds,
+}
+
+impl TsUnit {
+    // Parse a timestamp in this unit as nanoseconds. Whole numbers and decimals are
+    // both accepted (i.e. "1678901234.123456789" seconds), any digits past
+    // nanosecond precision are dropped.
+    fn parse_as_nanos(&self, value: &str) -> Result<Nanos, InputError> {
+        let frac_digits = match self {
+            TsUnit::Micros => 3,
+            TsUnit::Millis => 6,
+            TsUnit::Seconds => 9,
+        };
+        let (whole, frac) = value.split_once('.').unwrap_or((value, ""));
+        let frac = frac.get(..frac_digits).unwrap_or(frac);
+        parse_fixed_point(&format!("{}.{}", whole, frac), frac_digits)
+            .map_err(|_| InputError::InvalidTimestamp(value.to_string()))
+    }
+
+    // Write nanoseconds as a timestamp in this unit, the reverse of
+    // parse_as_nanos(). Whole numbers are written without a decimal point, and any
+    // fraction without trailing zeros.
+    pub fn format_nanos(&self, ns: Nanos) -> String {
+        let (nanos_per_unit, frac_digits) = match self {
+            TsUnit::Micros => (1_000, 3),
+            TsUnit::Millis => (1_000_000, 6),
+            TsUnit::Seconds => (1_000_000_000, 9),
+        };
+        let sign = if ns < 0 { "-" } else { "" };
+        let (whole, frac) = (
+            ns.unsigned_abs() / nanos_per_unit,
+            ns.unsigned_abs() % nanos_per_unit,
+        );
+        match frac {
+            0 => format!("{}{}", sign, whole),
+            _ => {
+                let frac = format!("{:0width$}", frac, width = frac_digits);
+                format!("{}{}.{}", sign, whole, frac.trim_end_matches('0'))
+            }
+        }
+    }
+
+    fn seconds_per_unit(&self) -> f64 {
+        match self {
+            TsUnit::Micros => 1e-6,
+            TsUnit::Millis => 1e-3,
+            TsUnit::Seconds => 1.0,
+        }
+    }
+
+    // Guess the unit from a sample of consecutive timestamps. Picks the unit where
+    // the average time between messages is plausible for a CAN bus, defaulting to
+    // microseconds. A busy CAN-FD bus has a message every 50us or so and a log
+    // filtered down to a few IDs has one every 50ms or so, this range is only as
+    // wide as the step between units so that exactly one of them can match.
+    fn detect<'a>(timestamps: impl Iterator<Item = &'a str>) -> Self {
+        let values: Vec<f64> = timestamps.filter_map(|ts| ts.parse().ok()).collect();
+        let (Some(min), Some(max)) = (
+            values.iter().copied().reduce(f64::min),
+            values.iter().copied().reduce(f64::max),
+        ) else {
+            return TsUnit::Micros;
+        };
+        if values.len() < 2 {
+            return TsUnit::Micros;
+        }
+        let interval = (max - min) / (values.len() - 1) as f64;
+
+        [TsUnit::Micros, TsUnit::Millis, TsUnit::Seconds]
+            .into_iter()
+            .find(|unit| (5e-5..5e-2).contains(&(interval * unit.seconds_per_unit())))
+            .unwrap_or(TsUnit::Micros)
+    }
+}
+
+impl FromStr for TsUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "us" => Ok(TsUnit::Micros),
+            "ms" => Ok(TsUnit::Millis),
+            "s" => Ok(TsUnit::Seconds),
+            _ => Err(anyhow!("Unknown timestamp unit '{}'", s)),
+        }
+    }
+}
+
+// How the timestamps in a SavvyCAN CSV log are written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TsMode {
+    // Time since some fixed point, i.e. when logging started
+    #[default]
+    Absolute,
+    // Time since the message before (the first message's is from the start of the
+    // log)
+    Delta,
+}
+
+impl FromStr for TsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "absolute" => Ok(TsMode::Absolute),
+            "delta" => Ok(TsMode::Delta),
+            _ => Err(anyhow!("Unknown timestamp mode '{}'", s)),
+        }
+    }
+}
+
+// Date that a log with time of day timestamps was recorded on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogDate {
+    Date(chrono::NaiveDate),
+    // The date the log file was last modified
+    Mtime,
+}
+
+impl FromStr for LogDate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mtime" => Ok(LogDate::Mtime),
+            _ => Ok(LogDate::Date(
+                chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .with_context(|| format!("Invalid log date '{}' (should be YYYY-MM-DD)", s))?,
+            )),
+        }
+    }
+}
+
+// For TsMode::Delta, replace the timestamp field of each CSV record with the
+// total of the deltas so far (in the same unit), so the records can be parsed
+// the same as absolute timestamps. The total starts from 0 for each log. This
+// runs on the records in order before they're parsed, as parsing may be spread
+// across threads. Any record where the timestamp doesn't parse is left as it
+// is, to fail (or be skipped) when it's parsed.
+fn accumulate_delta_timestamps(
+    records: impl Iterator<Item = (usize, Result<csv::StringRecord>)>,
+    ts_mode: TsMode,
+) -> impl Iterator<Item = (usize, Result<csv::StringRecord>)> {
+    // In billionths of a unit, which for microseconds overflows an i64 after a
+    // couple of hours
+    const FRAC_DIGITS: usize = 9;
+    const SCALE: i128 = 1_000_000_000;
+
+    let mut total: i128 = 0;
+    records.map(move |(row, rec)| {
+        if ts_mode == TsMode::Absolute {
+            return (row, rec);
+        }
+        let rec = rec.map(|rec| {
+            let Some(Ok(delta)) = rec.get(0).map(|ts| parse_fixed_point(ts, FRAC_DIGITS)) else {
+                return rec;
+            };
+            total += delta as i128;
+            let sign = if total < 0 { "-" } else { "" };
+            let ts = format!("{}{}.{:09}", sign, total.abs() / SCALE, total.abs() % SCALE);
+            let mut result = csv::StringRecord::new();
+            result.push_field(&ts);
+            for field in rec.iter().skip(1) {
+                result.push_field(field);
+            }
+            result
+        });
+        (row, rec)
+    })
+}
+
+// Return the timestamp unit of some CSV records, guessing it from the first few
+// records if ts_unit isn't set. Returns the records again, including any that
+// were sampled.
+fn resolve_ts_unit(
+    mut records: impl Iterator<Item = (usize, Result<csv::StringRecord>)>,
+    ts_unit: Option<TsUnit>,
+) -> (
+    TsUnit,
+    impl Iterator<Item = (usize, Result<csv::StringRecord>)>,
+) {
+    let head: Vec<_> = match ts_unit {
+        Some(_) => vec![],
+        None => records.by_ref().take(TS_UNIT_SAMPLE_RECORDS).collect(),
+    };
+    let ts_unit = ts_unit.unwrap_or_else(|| {
+        let ts_unit = TsUnit::detect(
+            head.iter()
+                .filter_map(|(_, rec)| rec.as_ref().ok())
+                .filter_map(|rec| rec.get(0)),
+        );
+        info!("Guessed CSV timestamp unit {:?}", ts_unit);
+        ts_unit
+    });
+    (ts_unit, head.into_iter().chain(records))
+}
+
+// Iterator adapter to put a stream of CAN messages that's only slightly out of
+// order back into timestamp order, without sorting the whole stream in memory.
+//
+// Up to 'window' messages are buffered and the earliest one is yielded each time,
+// so the output is only fully sorted if no message is more than 'window'
+// messages away from its sorted position.
+pub struct ReorderCanMessages<I> {
+    inner: I,
+    buffer: BinaryHeap<Reverse<CANMessage>>,
+    window: usize,
+}
+
+impl<I: Iterator<Item = Result<CANMessage>>> ReorderCanMessages<I> {
+    pub fn new(inner: I, window: usize) -> Self {
+        Self {
+            inner,
+            buffer: BinaryHeap::with_capacity(window),
+            window: window.max(1),
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<CANMessage>>> Iterator for ReorderCanMessages<I> {
+    type Item = Result<CANMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.len() < self.window {
+            match self.inner.next() {
+                Some(Ok(message)) => self.buffer.push(Reverse(message)),
+                Some(Err(e)) => return Some(Err(e)),
+                None => break,
+            }
+        }
+        self.buffer.pop().map(|Reverse(message)| Ok(message))
+    }
+}
+
+// A message waiting to be yielded by DecimateCanMessages
+enum DecimatedSlot {
+    Message(CANMessage),
+    // The latest message in a window that hasn't ended yet, replaced by any
+    // later message for the same ID in the window
+    Held(CANMessage),
+    Dropped,
+}
+
+// Iterator adapter to thin out CAN IDs to a lower rate, for IDs with a minimum
+// interval (in nanoseconds) in 'intervals'. Time is split into windows of this
+// interval, and only the last message of each window is kept for each bus and
+// ID, so the values in the route are the most recent ones. Other IDs are passed
+// through unchanged.
+//
+// Messages must be sorted by timestamp. The last message in a window can't be
+// known until the window ends, so messages after it are buffered until then to
+// keep the output sorted.
+pub struct DecimateCanMessages<I> {
+    inner: I,
+    intervals: HashMap<u32, Nanos>,
+    buffer: VecDeque<DecimatedSlot>,
+    // Index in the stream of the message at the front of the buffer
+    buffer_start: usize,
+    // Held messages, with the end of their window and their index in the stream
+    held: HashMap<(u8, u32), (Nanos, usize)>,
+}
+
+impl<I: Iterator<Item = CANMessage>> DecimateCanMessages<I> {
+    pub fn new(inner: I, intervals: HashMap<u32, Nanos>) -> Self {
+        Self {
+            inner,
+            intervals,
+            buffer: VecDeque::new(),
+            buffer_start: 0,
+            held: HashMap::new(),
+        }
+    }
+
+    fn slot(&mut self, index: usize) -> &mut DecimatedSlot {
+        &mut self.buffer[index - self.buffer_start]
+    }
+
+    // Release the held messages whose window ends by this time
+    fn end_windows(&mut self, timestamp: Nanos) {
+        let ended: Vec<(u8, u32)> = self
+            .held
+            .iter()
+            .filter(|(_, (window_end, _))| *window_end <= timestamp)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in ended {
+            let (_, index) = self.held.remove(&key).unwrap();
+            let slot = self.slot(index);
+            if let DecimatedSlot::Held(message) = std::mem::replace(slot, DecimatedSlot::Dropped) {
+                *slot = DecimatedSlot::Message(message);
+            }
+        }
+    }
+
+    fn push(&mut self, message: CANMessage) {
+        let index = self.buffer_start + self.buffer.len();
+        let Some(interval) = self.intervals.get(&message.can_id) else {
+            self.buffer.push_back(DecimatedSlot::Message(message));
+            return;
+        };
+        let window_end = (message.timestamp.div_euclid(*interval) + 1) * interval;
+        let key = (message.bus_no, message.can_id);
+        if let Some((_, replaced)) = self.held.insert(key, (window_end, index)) {
+            *self.slot(replaced) = DecimatedSlot::Dropped;
+        }
+        self.buffer.push_back(DecimatedSlot::Held(message));
+    }
+}
+
+impl<I: Iterator<Item = CANMessage>> Iterator for DecimateCanMessages<I> {
+    type Item = CANMessage;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.buffer.front() {
+                Some(DecimatedSlot::Held(_)) => (),
+                Some(_) => {
+                    self.buffer_start += 1;
+                    match self.buffer.pop_front() {
+                        Some(DecimatedSlot::Message(message)) => return Some(message),
+                        _ => continue,
+                    }
+                }
+                None if self.held.is_empty() => (),
+                None => unreachable!("held messages are always in the buffer"),
+            }
+            // Need more messages to know what to yield next
+            match self.inner.next() {
+                Some(message) => {
+                    self.end_windows(message.timestamp);
+                    self.push(message);
+                }
+                None if self.buffer.is_empty() => return None,
+                None => self.end_windows(Nanos::MAX),
+            }
+        }
+    }
+}
+
+// Parse a decimal number of seconds (i.e. "123.456789") as an integer number of
+// microseconds.
+fn parse_seconds_as_us(secs: &str) -> Result<i64> {
+    parse_fixed_point(secs, 6)
+}
+
+// Parse a decimal number as an integer scaled up by 10^frac_digits. The two
+// halves are parsed separately as integers, to avoid losing any precision by
+// going through a float.
+fn parse_fixed_point(value: &str, frac_digits: usize) -> Result<i64> {
+    let (whole, frac) = value.split_once('.').unwrap_or((value, ""));
+    if frac.len() > frac_digits || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(anyhow!("Invalid timestamp {}", value));
+    }
+    let whole: i64 = whole.parse()?;
+    let frac: i64 = match frac_digits {
+        0 => 0,
+        _ => format!("{:0<width$}", frac, width = frac_digits).parse()?,
+    };
+    let scale = 10i64.pow(frac_digits as u32);
+    let result = whole.checked_mul(scale).and_then(|whole| {
+        if value.starts_with('-') {
+            whole.checked_sub(frac)
+        } else {
+            whole.checked_add(frac)
+        }
+    });
+    result.ok_or(anyhow!("Timestamp {} out of range", value))
+}
+
+// Convert a log timestamp in microseconds to nanoseconds and apply the offset,
+// returning an error instead of overflowing
+fn us_to_timestamp(ts_us: i64, ts_offs: Nanos) -> Result<Nanos> {
+    ts_us
+        .checked_mul(1000)
+        .and_then(|ts| ts.checked_sub(ts_offs))
+        .ok_or(anyhow!("Timestamp {}us out of range", ts_us))
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum AlertStatus {
     Normal,
     UserPrompt,
     Critical,
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 pub struct Alert {
     pub timestamp: Nanos,
     pub status: AlertStatus,
     pub message: Option<String>,
 }
 
-// Scan the CAN messages for gaps that may indicate faults in the CAN logging
-pub fn find_missing_can_messages(messages: &[CANMessage]) -> Vec<Alert> {
+// Scan the CAN messages for gaps longer than threshold, that may indicate faults
+// in the CAN logging
+//
+// Takes any iterator of messages (or references to them), so it can be used
+// either on a slice of messages or on a stream of them from iter_can_messages()
+pub fn find_missing_can_messages<M: Borrow<CANMessage>>(
+    messages: impl IntoIterator<Item = M>,
+    threshold: Nanos,
+) -> Vec<Alert> {
     let mut result = vec![];
-    let mut last_timestamp = messages.first().map(|m| m.timestamp()).unwrap_or(0);
-    const MISSING_THRESHOLD: Nanos = 500_000_000;
+    let mut prev_timestamp = None;
 
     for m in messages {
-        if m.timestamp() - last_timestamp > MISSING_THRESHOLD {
+        let m = m.borrow();
+        let last_timestamp = prev_timestamp
+            .replace(m.timestamp())
+            .unwrap_or(m.timestamp());
+        if m.timestamp() - last_timestamp > threshold {
             let msg = format!(
                 "Possible lost CAN messages.\nGap of {:.3}s with no message",
                 (m.timestamp() - last_timestamp) as f64 / 1_000_000_000.0
@@ -221,42 +1419,329 @@ pub fn find_missing_can_messages(messages: &[CANMessage]) -> Vec<Alert> {
                 timestamp: m.timestamp(),
             });
         }
-        last_timestamp = m.timestamp();
     }
     result
 }
 
+// Count the messages that have the same timestamp as the message before them
+// (common for loggers with millisecond resolution), which can make Cabana's
+// playback stutter. If spread is set, each run of identical timestamps is also
+// spread evenly over the interval up to the next timestamp, so the messages are
+// strictly ordered. Messages must be sorted by timestamp.
+pub fn dedup_timestamps(messages: &mut [CANMessage], spread: bool) -> usize {
+    let mut duplicates = 0;
+    let mut start = 0;
+    while start < messages.len() {
+        let ts = messages[start].timestamp;
+        let len = messages[start..]
+            .iter()
+            .take_while(|m| m.timestamp == ts)
+            .count();
+        duplicates += len - 1;
+
+        if spread && len > 1 {
+            // The last run in the log has nothing after it, so is spread 1ns apart
+            let step = match messages.get(start + len) {
+                Some(next) => (next.timestamp - ts) / len as Nanos,
+                None => 1,
+            };
+            // A run that doesn't fit before the next timestamp is left as it is
+            if step > 0 {
+                for (idx, m) in messages[start..start + len].iter_mut().enumerate() {
+                    m.timestamp = ts + idx as Nanos * step;
+                }
+            }
+        }
+        start += len;
+    }
+    duplicates
+}
+
+// Scan the CAN messages for periodic IDs which stop arriving for a while, even if
+// other messages are still being logged (i.e. a fault in a single sensor or ECU.)
+//
+// Each ID on each bus has its usual period learned from the median interval
+// between its messages, and any interval longer than DROPOUT_PERIODS times that is
+// reported. Returned alerts are sorted by timestamp. If more than one ID is
+// silent at once, the alert only clears once none of them are silent.
+pub fn find_silent_can_ids(messages: &[CANMessage]) -> Vec<Alert> {
+    const DROPOUT_PERIODS: Nanos = 5;
+    // IDs with fewer intervals than this aren't considered periodic
+    const MIN_INTERVALS: usize = 10;
+
+    let mut timestamps: BTreeMap<(u8, u32), Vec<Nanos>> = BTreeMap::new();
+    for m in messages {
+        timestamps
+            .entry((m.bus_no, m.can_id))
+            .or_default()
+            .push(m.timestamp());
+    }
+
+    // Start and end of each silence, the end is (timestamp, true, None)
+    let mut edges: Vec<(Nanos, bool, Option<String>)> = vec![];
+
+    for ((bus_no, can_id), timestamps) in timestamps {
+        let mut intervals: Vec<Nanos> = timestamps.windows(2).map(|w| w[1] - w[0]).collect();
+        if intervals.len() < MIN_INTERVALS {
+            continue;
+        }
+        let mid = intervals.len() / 2;
+        let median = *intervals.select_nth_unstable(mid).1;
+        if median == 0 {
+            continue;
+        }
+
+        for w in timestamps.windows(2) {
+            let gap = w[1] - w[0];
+            if gap > median * DROPOUT_PERIODS {
+                let msg = format!(
+                    "ID 0x{:03X} (bus {}) silent for {:.1}s",
+                    can_id,
+                    bus_no,
+                    gap as f64 / 1_000_000_000.0
+                );
+                edges.push((w[0], false, Some(msg)));
+                edges.push((w[1], true, None));
+            }
+        }
+    }
+
+    // Sort starts before ends at the same timestamp, so an ID going silent just
+    // as another one returns doesn't clear the alert in between
+    edges.sort_by_key(|(timestamp, is_end, _)| (*timestamp, *is_end));
+    let mut silent = 0;
+    let mut result = vec![];
+    for (timestamp, is_end, message) in edges {
+        if is_end {
+            silent -= 1;
+            if silent > 0 {
+                continue;
+            }
+        } else {
+            silent += 1;
+        }
+        result.push(Alert {
+            status: if is_end {
+                AlertStatus::Normal
+            } else {
+                AlertStatus::Critical
+            },
+            message,
+            timestamp,
+        });
+    }
+    result
+}
+
+// Drop the messages of any ID whose data never changes in the whole log, except
+// for its first message, so only the IDs with changing signals are left. IDs are
+// separate on each bus. Returns the (bus, ID) of each ID that was dropped, sorted.
+pub fn drop_static_can_ids(messages: &mut Vec<CANMessage>) -> Vec<(u8, u32)> {
+    // Whether each ID's data has changed, and its first message's data
+    let mut ids: BTreeMap<(u8, u32), (bool, &[u8])> = BTreeMap::new();
+    for m in messages.iter() {
+        let (changed, data) = ids.entry((m.bus_no, m.can_id)).or_insert((false, &m.data));
+        *changed |= *data != m.data.as_slice();
+    }
+    let static_ids: BTreeSet<(u8, u32)> = ids
+        .into_iter()
+        .filter(|(_, (changed, _))| !changed)
+        .map(|(id, _)| id)
+        .collect();
+
+    let mut kept = HashSet::new();
+    messages.retain(|m| {
+        let id = (m.bus_no, m.can_id);
+        !static_ids.contains(&id) || kept.insert(id)
+    });
+    static_ids.into_iter().collect()
+}
+
+// Ways that a CAN message's flags can disagree with its ID or data, which suggest
+// the log is corrupt or its columns were misread
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FlagProblem {
+    // Not marked as an extended ID, but the ID doesn't fit in 11 bits
+    StandardIdTooLarge,
+    // More than 8 bytes of data, which only CAN-FD frames can have
+    LongDataNotFd,
+    // CAN-FD has no remote frames
+    FdRemoteFrame,
+    // Remote frames have a length but no data
+    RemoteFrameWithData,
+    // The DLC is more than 15, or doesn't match the number of data bytes
+    DlcMismatch,
+}
+
+impl fmt::Display for FlagProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FlagProblem::StandardIdTooLarge => "a standard ID larger than 0x7FF",
+            FlagProblem::LongDataNotFd => "more than 8 data bytes but not marked as CAN-FD",
+            FlagProblem::FdRemoteFrame => "both CAN-FD and remote frame flags set",
+            FlagProblem::RemoteFrameWithData => "data bytes despite being remote frames",
+            FlagProblem::DlcMismatch => "a DLC that doesn't match their data length",
+        })
+    }
+}
+
+impl CANMessage {
+    pub fn flag_problems(&self) -> Vec<FlagProblem> {
+        let mut result = vec![];
+        if !self.is_extended_id && self.can_id > 0x7FF {
+            result.push(FlagProblem::StandardIdTooLarge);
+        }
+        if !self.is_fd && self.data.len() > 8 {
+            result.push(FlagProblem::LongDataNotFd);
+        }
+        if self.is_fd && self.is_rtr {
+            result.push(FlagProblem::FdRemoteFrame);
+        }
+        if self.is_rtr && !self.data.is_empty() {
+            result.push(FlagProblem::RemoteFrameWithData);
+        }
+        // Data that's too long for a classic frame is already LongDataNotFd
+        let data_mismatch = !self.is_rtr
+            && (self.is_fd || self.data.len() <= 8)
+            && len_for_dlc(self.dlc, self.is_fd) != self.data.len();
+        if self.dlc > 15 || data_mismatch {
+            result.push(FlagProblem::DlcMismatch);
+        }
+        result
+    }
+}
+
+// Number of messages found with each FlagProblem, and the first of them
+#[derive(Debug, Default)]
+pub struct FlagCheck {
+    problems: BTreeMap<FlagProblem, (usize, Nanos, u8, u32)>,
+}
+
+impl FlagCheck {
+    pub fn add(&mut self, message: &CANMessage) {
+        for problem in message.flag_problems() {
+            self.problems
+                .entry(problem)
+                .or_insert((0, message.timestamp, message.bus_no, message.can_id))
+                .0 += 1;
+        }
+    }
+
+    // One line describing each problem found
+    pub fn warnings(&self) -> Vec<String> {
+        self.problems
+            .iter()
+            .map(|(problem, (count, timestamp, bus_no, can_id))| {
+                format!(
+                    "{} CAN messages have {} (first is ID 0x{:03X} on bus {} at {:.3}s)",
+                    count,
+                    problem,
+                    can_id,
+                    bus_no,
+                    *timestamp as f64 / 1e9
+                )
+            })
+            .collect()
+    }
+}
+
+// Check the flags of every message, like find_missing_can_messages() this takes
+// messages or references to them
+pub fn check_can_flags<M: Borrow<CANMessage>>(messages: impl IntoIterator<Item = M>) -> FlagCheck {
+    let mut result = FlagCheck::default();
+    for m in messages {
+        result.add(m.borrow());
+    }
+    result
+}
+
+// Default interval for expand_alerts() to repeat alerts at
+pub const ALERT_INTERVAL: Nanos = 100_000_000; // 100ms
+
+// Most alerts expand_alerts() will generate, for alerts spanning a long route
+const MAX_EXPANDED_ALERTS: usize = 1_000_000;
+
 /* Takes a list of individual alerts and expands them to cover the whole video
- * time span, with one alert each 100ms. Each alert is repeated until the next
- * alert starts (recall some alerts have message None).
+ * time span, with one alert each interval. Each alert is repeated until the next
+ * alert starts (recall some alerts have message None), and the last alert
+ * appears once.
+ *
+ * Consecutive alerts with the same status and message (i.e. the same problem
+ * found by more than one check) are repeated as one alert, from the first of
+ * them until the next different alert, so there are no extra events where each
+ * of them would have started its repeats again.
  *
- * This is necessary so they display in Cabana during playback.
+ * This is necessary so they display in Cabana during playback. If the alerts
+ * span so long that this would be more than MAX_EXPANDED_ALERTS, they're
+ * repeated less often instead.
  */
-pub fn expand_alerts(alerts: Vec<Alert>) -> Vec<LogInput> {
-    let first_ts = match alerts.first() {
-        Some(first) => first.timestamp,
-        _ => 0,
-    };
-    let last_ts = match alerts.last() {
-        Some(last) => last.timestamp,
-        _ => first_ts,
+pub fn expand_alerts(alerts: Vec<Alert>, interval: Nanos) -> Vec<LogInput> {
+    let (Some(first), Some(last)) = (alerts.first(), alerts.last()) else {
+        return vec![];
     };
+    let span = last.timestamp - first.timestamp;
+    let interval = interval.max(span / MAX_EXPANDED_ALERTS as Nanos).max(1);
 
     let mut result = vec![];
 
-    let mut ts = first_ts;
-
     let mut peekable = alerts.into_iter().peekable();
 
     while let Some(alert) = peekable.next() {
-        let next_at = peekable.peek().map(|a| a.timestamp).unwrap_or(last_ts);
-        while ts < next_at {
+        let mut run_end = alert.timestamp;
+        while let Some(same) =
+            peekable.next_if(|a| a.status == alert.status && a.message == alert.message)
+        {
+            run_end = same.timestamp;
+        }
+
+        // Every alert appears at least once, at its own timestamp
+        let mut ts = alert.timestamp;
+        let next_at = peekable.peek().map(|a| a.timestamp).unwrap_or(run_end);
+        let end = next_at.max(run_end + 1);
+        while ts < end {
             let mut new_alert = alert.clone();
             new_alert.timestamp = ts;
             result.push(LogInput::Alert(new_alert));
-            ts += 100_000_000; // 100ms
+            ts += interval;
         }
     }
 
     result
 }
+
+// Merge CAN messages, video frames and alerts into the timeline of inputs for a
+// route, sorted by timestamp. The CAN messages and frames must each already be
+// sorted by timestamp. Alerts are expanded as per expand_alerts(), repeated at
+// alert_interval.
+pub fn merge_timeline(
+    can_messages: impl Iterator<Item = CANMessage>,
+    frames: impl Iterator<Item = SourceFrame>,
+    alerts: Vec<Alert>,
+    alert_interval: Nanos,
+) -> impl Iterator<Item = LogInput> {
+    let can_messages = can_messages.map(LogInput::CAN);
+    let frames = frames.map(LogInput::Frame);
+    merge(
+        merge(can_messages, frames),
+        expand_alerts(alerts, alert_interval),
+    )
+}
+
+// Same as merge_timeline(), for inputs that are all in memory and may not be
+// sorted yet
+pub fn build_timeline(
+    mut can_messages: Vec<CANMessage>,
+    mut frames: Vec<SourceFrame>,
+    mut alerts: Vec<Alert>,
+) -> Vec<LogInput> {
+    can_messages.sort();
+    frames.sort_by_key(|f| f.ts_ns);
+    alerts.sort_by_key(|a| a.timestamp);
+    merge_timeline(
+        can_messages.into_iter(),
+        frames.into_iter(),
+        alerts,
+        ALERT_INTERVAL,
+    )
+    .collect()
+}
@@ -0,0 +1,116 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Reader for alerts from another tool, to show in the route alongside the
+// alerts found from the CAN messages. Either a JSON array of alerts:
+//
+// [{"timestamp": 12500000000, "status": "Critical", "message": "Overheating"},
+//  {"timestamp": 20000000000, "status": "Normal", "message": null}]
+//
+// or a CSV file with one "timestamp,status,message" per line:
+//
+// 12.5,critical,Overheating
+// 20.0,normal,
+//
+// Timestamps are in the same clock as the CAN log. In JSON they're in
+// nanoseconds, in CSV they're in seconds (with a decimal point) or nanoseconds
+// (a whole number), the same as markers. The status is Normal, UserPrompt or
+// Critical, and an alert with no message ends the alert before it.
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::info;
+
+use super::{open_log_file, parse_fixed_point, Alert, AlertStatus, Compression};
+use crate::Nanos;
+
+// JSON files have a .json extension (before any compression extension), any
+// other file is CSV
+fn is_json(path: &Path) -> bool {
+    let path = match Compression::from_path(path) {
+        Compression::None => path,
+        _ => Path::new(path.file_stem().unwrap_or_default()),
+    };
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+fn parse_status(status: &str) -> Result<AlertStatus> {
+    match status.to_lowercase().as_str() {
+        "normal" => Ok(AlertStatus::Normal),
+        "userprompt" | "user_prompt" => Ok(AlertStatus::UserPrompt),
+        "critical" => Ok(AlertStatus::Critical),
+        _ => bail!(
+            "Invalid alert status '{}', should be Normal, UserPrompt or Critical",
+            status
+        ),
+    }
+}
+
+fn parse_alert(record: &csv::StringRecord) -> Result<Alert> {
+    let ts = record
+        .get(0)
+        .ok_or(anyhow!("Missing timestamp field"))?
+        .trim();
+    let timestamp: Nanos = if ts.contains('.') {
+        parse_fixed_point(ts, 9)?
+    } else {
+        ts.parse()
+            .with_context(|| format!("Invalid timestamp {}", ts))?
+    };
+    let status = parse_status(record.get(1).ok_or(anyhow!("Missing status field"))?.trim())?;
+    let message = record
+        .get(2)
+        .map(str::trim)
+        .filter(|m| !m.is_empty())
+        .map(str::to_string);
+    Ok(Alert {
+        timestamp,
+        status,
+        message,
+    })
+}
+
+fn read_alerts_csv(path: &Path) -> Result<Vec<Alert>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(open_log_file(path, None)?);
+
+    let mut alerts = vec![];
+    for (row, record) in rdr.records().enumerate() {
+        let record = record.map_err(|e| anyhow!("Invalid CSV record in file {:?}: {}", path, e))?;
+        if row == 0 && record.get(0).map(str::trim) == Some("timestamp") {
+            continue;
+        }
+        alerts.push(
+            parse_alert(&record)
+                .with_context(|| format!("Error parsing {:?} row {}", path, row + 1))?,
+        );
+    }
+    Ok(alerts)
+}
+
+// Read the alerts from a JSON or CSV file, applying the same timestamp offset
+// as the CAN log. Returned alerts are sorted by timestamp, any from before the
+// start of the route are dropped.
+pub fn read_alerts(path: &Path, ts_offs: Nanos) -> Result<Vec<Alert>> {
+    info!("Opening alerts file {:?}...", path);
+
+    let mut alerts: Vec<Alert> = if is_json(path) {
+        serde_json::from_reader(open_log_file(path, None)?)
+            .with_context(|| format!("Failed to read alerts from {:?}", path))?
+    } else {
+        read_alerts_csv(path)?
+    };
+
+    for alert in &mut alerts {
+        alert.timestamp = alert
+            .timestamp
+            .checked_sub(ts_offs)
+            .ok_or(anyhow!("Timestamp {} out of range", alert.timestamp))?;
+    }
+    alerts.sort_by_key(|a| a.timestamp);
+    alerts.retain(|a| a.timestamp >= 0);
+    Ok(alerts)
+}
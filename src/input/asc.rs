@@ -0,0 +1,157 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Parser for Vector CANalyzer/CANoe ASCII logs (.asc). After a short header, each
+// CAN frame is a line like:
+//
+//    0.123456 1  123x            Rx   d 8 DE AD BE EF 00 11 22 33
+//
+// Lines for other event types (error frames, statistics, etc.) are skipped. This
+// includes CAN-FD frames, which have a different line format.
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
+use tracing::info;
+
+use super::{
+    collect_can_messages, len_for_dlc, open_log_file, parse_seconds_as_us, us_to_timestamp,
+    CANMessage, CanLog, ReadOptions,
+};
+use crate::Nanos;
+
+impl CANMessage {
+    // Parse a single ASC frame line. radix is 16 or 10, depending on the 'base'
+    // set in the header of the file, and applies to both ID and data bytes.
+    pub fn parse_asc_line(line: &str, ts_offs: Nanos, radix: u32) -> Result<Self> {
+        let mut fields = line.split_whitespace();
+
+        let ts_us = parse_seconds_as_us(fields.next().ok_or(anyhow!("Missing ts field"))?)?;
+
+        // ASC channel numbers are used as the bus number as-is
+        let bus_no = fields
+            .next()
+            .ok_or(anyhow!("Missing channel field"))?
+            .parse()
+            .context("Invalid channel field")?;
+
+        let id = fields.next().ok_or(anyhow!("Missing can id field"))?;
+        let (id, is_extended_id) = match id.strip_suffix(['x', 'X']) {
+            Some(id) => (id, true),
+            None => (id, false),
+        };
+        let can_id = u32::from_str_radix(id, radix).context("Invalid can id field")?;
+
+        let direction = fields
+            .next()
+            .ok_or(anyhow!("Missing direction field"))?
+            .parse()?;
+
+        let (data, dlc, is_rtr) = match fields.next() {
+            Some("d") => {
+                let dlc = u8::from_str_radix(
+                    fields.next().ok_or(anyhow!("Missing data length field"))?,
+                    radix,
+                )
+                .context("Invalid data length field")?;
+                // A DLC of 9 to 15 still has 8 bytes of data
+                let len = len_for_dlc(dlc, false);
+                let data: Vec<u8> = fields
+                    .take(len)
+                    .map(|d| u8::from_str_radix(d, radix))
+                    .try_collect()
+                    .context("Error parsing ASC data field")?;
+                if data.len() != len {
+                    return Err(anyhow!("Expected {} data bytes, found {}", len, data.len()));
+                }
+                (data, dlc, false)
+            }
+            // Remote frame, has a length (in newer logs) but no data
+            Some("r") => {
+                let dlc = match fields.next() {
+                    Some(dlc) => u8::from_str_radix(dlc, radix).context("Invalid DLC field")?,
+                    None => 0,
+                };
+                (vec![], dlc, true)
+            }
+            _ => return Err(anyhow!("Missing data field")),
+        };
+
+        Ok(CANMessage {
+            timestamp: us_to_timestamp(ts_us, ts_offs)?,
+            can_id,
+            is_extended_id,
+            bus_no,
+            data,
+            is_fd: false,
+            dlc,
+            is_rtr,
+            direction: Some(direction),
+        })
+    }
+}
+
+// CAN frame lines have a numeric channel followed by ID and direction. Anything
+// else in the body of the log is some other kind of event.
+fn is_asc_frame_line(line: &str) -> bool {
+    let fields: Vec<&str> = line.split_whitespace().take(4).collect();
+    fields.len() == 4
+        && fields[1].bytes().all(|b| b.is_ascii_digit())
+        && (fields[3] == "Rx" || fields[3] == "Tx")
+}
+
+pub fn read_can_messages_asc(
+    log_path: &Path,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
+    info!("Opening ASC log {:?}...", log_path);
+
+    let mut lines = open_log_file(log_path, options.progress.as_ref())?
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            (
+                idx + 1,
+                line.with_context(|| format!("Failed to read ASC file {:?}", log_path)),
+            )
+        })
+        .peekable();
+
+    // Read the header block, the only part we care about is the number base
+    let mut radix = 16;
+    while let Some((_, Ok(line))) = lines.peek() {
+        let line = line.trim();
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("base") => {
+                radix = match words.next() {
+                    Some("hex") => 16,
+                    Some("dec") => 10,
+                    b => return Err(anyhow!("Unknown base {:?} in ASC file {:?}", b, log_path)),
+                };
+            }
+            Some("date") | Some("internal") | Some("no") | Some("//") | None => (),
+            Some("Begin") => {
+                lines.next();
+                break;
+            }
+            _ => break,
+        }
+        lines.next();
+    }
+
+    let frames = lines.filter(|(_, line)| match line {
+        Ok(l) => is_asc_frame_line(l),
+        _ => true,
+    });
+
+    collect_can_messages(
+        log_path,
+        frames,
+        can_ts_offs,
+        options,
+        |line: &String, ts_offs| CANMessage::parse_asc_line(line, ts_offs, radix),
+    )
+}
@@ -0,0 +1,277 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Parser for the whitespace separated hex logs written by BUSMASTER (and text
+// exports in the same layout, i.e. from PCAN-Explorer). The header is a block of
+// lines wrapped in '***', the last naming the columns:
+//
+// ***<Time><Tx/Rx><Channel><CAN ID><Type><DLC><DataBytes>***
+//
+// followed by one line per message:
+//
+// 14:49:30:7234 Rx 1 0x0C1 s 8 00 01 02 03 04 05 06 07
+//
+// Timestamps are the time of day, HH:MM:SS:mmm, with any number of digits of
+// fractional seconds after the last ':'. These start again from 0 at midnight, so
+// a log that runs past midnight keeps counting up into the next day. With
+// ReadOptions::log_date the times of day are on that date (in the local time
+// zone), making them absolute timestamps.
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use itertools::Itertools;
+use tracing::info;
+
+use super::{
+    check_data_len, collect_can_messages, dlc_for_len, open_log_file, CANMessage, CanLog, LogDate,
+    ReadOptions,
+};
+use crate::Nanos;
+
+const NANOS_PER_DAY: Nanos = 86_400 * 1_000_000_000;
+
+// Column layout of BUSMASTER logs, used if the header doesn't name the columns
+const DEFAULT_COLUMNS: [&str; 7] = [
+    "time",
+    "tx/rx",
+    "channel",
+    "can id",
+    "type",
+    "dlc",
+    "databytes",
+];
+
+// Index of each field's column in the log
+struct Columns {
+    time: usize,
+    can_id: usize,
+    direction: Option<usize>,
+    channel: Option<usize>,
+    msg_type: Option<usize>,
+    dlc: Option<usize>,
+    // The data bytes are the rest of the line from here
+    data: usize,
+}
+
+impl Columns {
+    fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self> {
+        let names: Vec<String> = names.into_iter().map(str::to_lowercase).collect();
+        let find = |keys: &[&str]| names.iter().position(|n| keys.contains(&n.as_str()));
+        let columns = Columns {
+            time: find(&["time"]).ok_or(anyhow!("Missing Time column"))?,
+            can_id: find(&["can id", "id"]).ok_or(anyhow!("Missing CAN ID column"))?,
+            direction: find(&["tx/rx", "rx/tx", "direction"]),
+            channel: find(&["channel", "bus"]),
+            msg_type: find(&["type"]),
+            dlc: find(&["dlc", "length"]),
+            data: find(&["databytes", "data"]).ok_or(anyhow!("Missing DataBytes column"))?,
+        };
+        if columns.data + 1 != names.len() {
+            return Err(anyhow!("DataBytes should be the last column"));
+        }
+        Ok(columns)
+    }
+
+    // Column names from a header line like "***<Time><Tx/Rx>...***"
+    fn from_header(line: &str) -> Option<Result<Self>> {
+        let names = line
+            .trim_matches('*')
+            .strip_prefix('<')?
+            .strip_suffix('>')?;
+        Some(Self::from_names(names.split("><")))
+    }
+}
+
+// Parse a HH:MM:SS:mmm (or HH:MM:SS.mmm) time of day as the time since midnight
+fn parse_time_of_day(time: &str) -> Option<Nanos> {
+    let (hms, frac) = time.rsplit_once([':', '.'])?;
+    let (h, m, s) = hms.split(':').collect_tuple()?;
+    let secs: Nanos = h.parse::<Nanos>().ok()? * 3600
+        + m.parse::<Nanos>().ok()? * 60
+        + s.parse::<Nanos>().ok()?;
+    if frac.is_empty() || frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let frac_nanos = frac.parse::<Nanos>().ok()? * 10_i64.pow(9 - frac.len() as u32);
+    Some(secs * 1_000_000_000 + frac_nanos)
+}
+
+// Unix time of the midnight (in the local time zone) that the log's times of day
+// count from, if a date was given
+fn log_midnight(log_path: &Path, log_date: Option<&LogDate>) -> Result<Option<Nanos>> {
+    let date: NaiveDate = match log_date {
+        None => return Ok(None),
+        Some(LogDate::Date(date)) => *date,
+        Some(LogDate::Mtime) => {
+            let mtime = std::fs::metadata(log_path)
+                .and_then(|m| m.modified())
+                .with_context(|| format!("Failed to read modified time of {:?}", log_path))?;
+            DateTime::<Local>::from(mtime).date_naive()
+        }
+    };
+    let midnight = date.and_hms_opt(0, 0, 0).expect("midnight is a valid time");
+    let midnight = Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .ok_or(anyhow!("No midnight on {} in the local time zone", date))?;
+    Ok(Some(midnight.timestamp() * 1_000_000_000))
+}
+
+// Parse a single message line. day_start is the timestamp of the midnight that
+// the line's time of day counts from.
+fn parse_busmaster_line(
+    line: &str,
+    day_start: Nanos,
+    ts_offs: Nanos,
+    columns: &Columns,
+) -> Result<CANMessage> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let field = |idx: usize, desc: &str| -> Result<&str> {
+        fields
+            .get(idx)
+            .copied()
+            .ok_or(anyhow!("Missing {} field", desc))
+    };
+
+    let time = field(columns.time, "time")?;
+    let time_of_day =
+        parse_time_of_day(time).ok_or_else(|| anyhow!("Invalid time field {}", time))?;
+    let timestamp = (day_start + time_of_day)
+        .checked_sub(ts_offs)
+        .ok_or(anyhow!("Timestamp {} out of range", time))?;
+
+    let id = field(columns.can_id, "can id")?;
+    let can_id = u32::from_str_radix(id.trim_start_matches("0x"), 16)
+        .with_context(|| format!("Invalid can id field {}", id))?;
+
+    // Channels are numbered from 1
+    let bus_no = match columns.channel {
+        Some(idx) => {
+            let channel: u8 = field(idx, "channel")?
+                .parse()
+                .context("Invalid channel field")?;
+            channel.saturating_sub(1)
+        }
+        None => 0,
+    };
+
+    // s for a standard ID or x for extended, with an r for a remote frame
+    let msg_type = columns
+        .msg_type
+        .map(|idx| field(idx, "type"))
+        .transpose()?
+        .unwrap_or_default()
+        .to_lowercase();
+    let is_extended_id = msg_type.contains('x') || can_id > 0x7FF;
+    let is_rtr = msg_type.contains('r');
+
+    let direction = columns
+        .direction
+        .map(|idx| field(idx, "direction")?.parse())
+        .transpose()?;
+
+    let dlc: Option<u8> = match columns.dlc {
+        Some(idx) => Some(field(idx, "dlc")?.parse().context("Invalid dlc field")?),
+        None => None,
+    };
+    let data: Vec<u8> = match is_rtr {
+        true => vec![],
+        false => fields
+            .iter()
+            .skip(columns.data)
+            .map(|d| u8::from_str_radix(d.trim_start_matches("0x"), 16))
+            .try_collect()
+            .context("Error parsing data field")?,
+    };
+    check_data_len(&data)?;
+
+    Ok(CANMessage {
+        timestamp,
+        can_id,
+        is_extended_id,
+        bus_no,
+        is_fd: data.len() > 8,
+        dlc: dlc.unwrap_or(dlc_for_len(data.len())),
+        data,
+        is_rtr,
+        direction,
+    })
+}
+
+pub fn read_can_messages_busmaster(
+    log_path: &Path,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
+    info!("Opening BUSMASTER log {:?}...", log_path);
+
+    let mut lines = open_log_file(log_path, options.progress.as_ref())?
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            (
+                idx + 1,
+                line.with_context(|| format!("Failed to read BUSMASTER log {:?}", log_path)),
+            )
+        })
+        .peekable();
+
+    // Read the header block, for the column names
+    let mut columns = None;
+    while let Some((_, Ok(line))) = lines.peek() {
+        let line = line.trim();
+        if !line.starts_with("***") && !line.is_empty() {
+            break;
+        }
+        if let Some(header) = Columns::from_header(line) {
+            columns = Some(header.with_context(|| format!("Invalid columns in {:?}", log_path))?);
+        }
+        lines.next();
+    }
+    let columns = match columns {
+        Some(columns) => columns,
+        None => Columns::from_names(DEFAULT_COLUMNS)?,
+    };
+
+    // Count the days as the lines are read in order, as parsing them may be spread
+    // across threads. A time of day more than 12 hours earlier than the one before
+    // is the next day.
+    let mut day_start = log_midnight(log_path, options.log_date.as_ref())?.unwrap_or(0);
+    let mut last_time_of_day = 0;
+    let lines = lines
+        .filter(|(_, line)| match line {
+            Ok(l) => {
+                let l = l.trim();
+                !l.is_empty() && !l.starts_with("***") && !l.starts_with(';')
+            }
+            _ => true,
+        })
+        .map(|(row, line)| {
+            let line = line.map(|l| {
+                let time_of_day = l
+                    .split_whitespace()
+                    .nth(columns.time)
+                    .and_then(parse_time_of_day);
+                if let Some(time_of_day) = time_of_day {
+                    if time_of_day < last_time_of_day - NANOS_PER_DAY / 2 {
+                        day_start += NANOS_PER_DAY;
+                    }
+                    last_time_of_day = time_of_day;
+                }
+                (day_start, l)
+            });
+            (row, line)
+        });
+
+    collect_can_messages(
+        log_path,
+        lines,
+        can_ts_offs,
+        options,
+        |(day_start, line): &(Nanos, String), ts_offs| {
+            parse_busmaster_line(line, *day_start, ts_offs, &columns)
+        },
+    )
+}
@@ -0,0 +1,152 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Parser for the log format written by Linux SocketCAN 'candump -L', i.e.
+// lines like:
+//
+// (1678901234.123456) can0 123#DEADBEEF
+//
+// or for CAN-FD frames:
+//
+// (1678901234.123456) can0 123##1DEADBEEF
+//
+// or for remote frames (optionally with the length after the R):
+//
+// (1678901234.123456) can0 123#R
+//
+// Classic frames with a DLC of 9 to 15 have it after the 8 data bytes, i.e.
+// 123#0011223344556677_F
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+
+use super::{
+    check_data_len, collect_can_messages, dlc_for_len, open_log_file, parse_seconds_as_us,
+    us_to_timestamp, CANMessage, CanLog, ReadOptions,
+};
+use crate::Nanos;
+
+impl CANMessage {
+    pub fn parse_candump_line(line: &str, ts_offs: Nanos) -> Result<Self> {
+        let mut fields = line.split_whitespace();
+
+        let ts_field = fields.next().ok_or(anyhow!("Missing ts field"))?;
+        let ts_us = parse_candump_timestamp(ts_field)
+            .with_context(|| format!("Invalid timestamp field {}", ts_field))?;
+
+        // Bus number is the numeric suffix of the interface name, i.e. can0 -> 0
+        let interface = fields.next().ok_or(anyhow!("Missing interface field"))?;
+        let bus_no = interface
+            .trim_start_matches(|c: char| !c.is_ascii_digit())
+            .parse()
+            .with_context(|| format!("Can't find bus number in interface name {}", interface))?;
+
+        let frame = fields.next().ok_or(anyhow!("Missing frame field"))?;
+        let (id, data) = frame
+            .split_once('#')
+            .ok_or(anyhow!("Invalid frame field {}", frame))?;
+
+        let can_id = u32::from_str_radix(id, 16).context("Invalid can id field")?;
+        // candump always prints standard IDs as 3 hex digits, and extended IDs as 8
+        let is_extended_id = id.len() > 3;
+
+        // CAN-FD frames have a second '#' followed by a single hex digit of flags
+        let (is_fd, data) = match data.strip_prefix('#') {
+            Some(fd_data) => (
+                true,
+                fd_data.get(1..).ok_or(anyhow!("Missing CAN-FD flags"))?,
+            ),
+            None => (false, data),
+        };
+
+        // Remote frames have no data, only a length after the R (if there is one).
+        // A classic frame with a DLC above 8 has the DLC after its data, i.e.
+        // 123#0011223344556677_F
+        let is_rtr = !is_fd && data.starts_with('R');
+        let (data, dlc) = if is_rtr {
+            let dlc = match &data[1..] {
+                "" => 0,
+                len => len.parse().context("Invalid remote frame length")?,
+            };
+            (vec![], dlc)
+        } else {
+            let (data, raw_dlc) = match data.split_once('_') {
+                Some((data, dlc)) => (data, Some(dlc)),
+                None => (data, None),
+            };
+            let data = parse_hex_bytes(data).context("Error parsing candump data field")?;
+            let dlc = match raw_dlc {
+                Some(dlc) => u8::from_str_radix(dlc, 16).context("Invalid DLC")?,
+                None => dlc_for_len(data.len()),
+            };
+            (data, dlc)
+        };
+        check_data_len(&data)?;
+
+        Ok(CANMessage {
+            timestamp: us_to_timestamp(ts_us, ts_offs)?,
+            can_id,
+            is_extended_id,
+            bus_no,
+            data,
+            is_fd,
+            dlc,
+            is_rtr,
+            // candump -L logs don't record the direction
+            direction: None,
+        })
+    }
+}
+
+// Timestamps are "(seconds.microseconds)"
+fn parse_candump_timestamp(field: &str) -> Result<i64> {
+    let ts = field
+        .strip_prefix('(')
+        .and_then(|ts| ts.strip_suffix(')'))
+        .ok_or(anyhow!("Timestamp should be in parentheses"))?;
+    parse_seconds_as_us(ts)
+}
+
+// Parse a string of concatenated hex bytes, i.e. "DEADBEEF"
+fn parse_hex_bytes(hex: &str) -> Result<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|s| s.len() == 2)
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                .ok_or(anyhow!("Invalid hex data {}", hex))
+        })
+        .collect()
+}
+
+pub fn read_can_messages_candump(
+    log_path: &Path,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
+    info!("Opening candump log {:?}...", log_path);
+
+    let lines = open_log_file(log_path, options.progress.as_ref())?
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            (
+                idx + 1,
+                line.with_context(|| format!("Failed to read candump file {:?}", log_path)),
+            )
+        })
+        // Skip any blank lines
+        .filter(|(_, line)| !matches!(line, Ok(l) if l.trim().is_empty()));
+
+    collect_can_messages(
+        log_path,
+        lines,
+        can_ts_offs,
+        options,
+        |line: &String, ts_offs| CANMessage::parse_candump_line(line, ts_offs),
+    )
+}
@@ -0,0 +1,59 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Errors from reading CAN logs, for library users that need to tell the kinds of
+// failure apart. CANMessage::parse_from(), read_can_messages(), read_can_log()
+// and read_can_logs() return these directly. The readers for each format return
+// anyhow errors, which contain one of these wherever the problem was one of these
+// kinds, and anything else is returned as an Other.
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum InputError {
+    #[error("Missing {0} field")]
+    MissingField(&'static str),
+    #[error("Invalid {field} field '{value}'")]
+    InvalidField { field: &'static str, value: String },
+    #[error("Invalid hex in {field} field '{value}'")]
+    InvalidHex { field: &'static str, value: String },
+    // Only with --strict-fields
+    #[error("dlen field is {len} but there are {data_fields} data fields")]
+    DataFieldCount { len: usize, data_fields: usize },
+    #[error("Invalid CAN data length {0}")]
+    InvalidDataLength(usize),
+    #[error("Invalid timestamp {0}")]
+    InvalidTimestamp(String),
+    #[error("Timestamp {0} out of range")]
+    TimestampOutOfRange(String),
+    // A row that failed to parse, source is the reason
+    #[error("Invalid CAN data found in {path:?} row {row}")]
+    InvalidRow {
+        path: PathBuf,
+        row: usize,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to open {path:?}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    // No CAN messages in the logs for a route, or none left after filtering them
+    // Unless ReadOptions::allow_empty is set
+    #[error("No CAN messages in {0:?}")]
+    EmptyLog(Vec<PathBuf>),
+    // Any other problem reading a log, i.e. a corrupt compressed file
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+// Errors from the readers for each format are anyhow errors, keep any of these
+// that they contain as it is
+impl From<anyhow::Error> for InputError {
+    fn from(e: anyhow::Error) -> Self {
+        e.downcast().unwrap_or_else(InputError::Other)
+    }
+}
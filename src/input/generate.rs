@@ -0,0 +1,221 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Synthetic CAN messages from the generate section of a log entry, i.e. a sine
+// wave speed signal at 50Hz, for making demo routes or testing a Cabana setup
+// without a vehicle. Each generator sends one CAN ID at a fixed rate, with the
+// data set byte by byte or from DBC signals. The messages are added to the
+// messages read from the log, and written to the route the same way.
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use super::{check_data_len, dlc_for_len, CANMessage};
+use crate::dbc::{Dbc, SignalDef};
+use crate::Nanos;
+
+// How a value changes over the time that a generator runs. t is the time since
+// the generator started, in seconds.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Waveform {
+    Constant(f64),
+    Sine {
+        amplitude: f64,
+        #[serde(default)]
+        offset: f64,
+        // Seconds
+        period: f64,
+    },
+    // high for the first half of each period, then low
+    Square {
+        low: f64,
+        high: f64,
+        period: f64,
+    },
+    // Straight line from one value at the start to another at the end
+    Ramp {
+        from: f64,
+        to: f64,
+    },
+    // Counts up by one each message, from 0 back to 0 after max
+    Counter {
+        #[serde(default = "default_counter_max")]
+        max: u64,
+    },
+}
+
+fn default_counter_max() -> u64 {
+    255
+}
+
+impl Waveform {
+    fn value(&self, t: f64, index: u64, duration: f64) -> f64 {
+        match self {
+            Waveform::Constant(value) => *value,
+            Waveform::Sine {
+                amplitude,
+                offset,
+                period,
+            } => offset + amplitude * (std::f64::consts::TAU * t / period).sin(),
+            Waveform::Square { low, high, period } => match t % period < period / 2.0 {
+                true => *high,
+                false => *low,
+            },
+            Waveform::Ramp { from, to } => match duration > 0.0 {
+                true => from + (to - from) * t / duration,
+                false => *from,
+            },
+            Waveform::Counter { max } => (index % (max + 1)) as f64,
+        }
+    }
+}
+
+// A DBC signal (MESSAGE.SIGNAL or SIGNAL, as for Dbc::find_signal) and its value
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct GeneratedSignal {
+    pub signal: String,
+    pub value: Waveform,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct GeneratorSpec {
+    // If not set, the ID of the message that the signals are in
+    pub id: Option<u32>,
+    #[serde(default)]
+    pub bus: u8,
+    // IDs over 0x7FF are always extended
+    #[serde(default)]
+    pub extended: bool,
+    // Messages per second
+    pub rate: f64,
+    // Seconds from the start of the route to the first message
+    #[serde(default)]
+    pub start: f64,
+    // Seconds
+    pub duration: f64,
+    // Number of data bytes
+    #[serde(default = "default_len")]
+    pub len: usize,
+    // Value of each data byte, from byte 0. The values are rounded and clamped to
+    // 0-255, and any bytes after these are 0.
+    #[serde(default)]
+    pub bytes: Vec<Waveform>,
+    // Signals are set after the bytes, so take precedence where they overlap
+    #[serde(default)]
+    pub signals: Vec<GeneratedSignal>,
+}
+
+fn default_len() -> usize {
+    8
+}
+
+impl GeneratorSpec {
+    fn check(&self) -> Result<()> {
+        if !self.rate.is_finite() || self.rate <= 0.0 {
+            bail!("Invalid rate {}, should be more than 0 Hz", self.rate);
+        }
+        if !self.duration.is_finite() || self.duration < 0.0 {
+            bail!("Invalid duration {}", self.duration);
+        }
+        if !self.start.is_finite() || self.start < 0.0 {
+            bail!("Invalid start {}", self.start);
+        }
+        check_data_len(&vec![0; self.len])?;
+        if self.bytes.len() > self.len {
+            bail!(
+                "{} bytes are set, but the message is only {} bytes long",
+                self.bytes.len(),
+                self.len
+            );
+        }
+        for waveform in self
+            .bytes
+            .iter()
+            .chain(self.signals.iter().map(|s| &s.value))
+        {
+            match waveform {
+                Waveform::Sine { period, .. } | Waveform::Square { period, .. }
+                    if !period.is_finite() || *period <= 0.0 =>
+                {
+                    bail!("Invalid period {}", period)
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    // The CAN ID to send, and the definitions of the signals (which all have to be
+    // in the message with that ID)
+    fn resolve_signals<'a>(&self, dbc: Option<&'a Dbc>) -> Result<(u32, Vec<&'a SignalDef>)> {
+        let mut can_id = self.id;
+        let mut defs = vec![];
+        for signal in &self.signals {
+            let dbc = dbc.ok_or(anyhow!("Generating signals needs a dbc file"))?;
+            let (signal_id, def) = dbc.find_signal(&signal.signal)?;
+            match can_id {
+                Some(id) if id != signal_id => bail!(
+                    "Signal {} is in message 0x{:X}, not 0x{:X}",
+                    signal.signal,
+                    signal_id,
+                    id
+                ),
+                _ => can_id = Some(signal_id),
+            }
+            defs.push(def);
+        }
+        let can_id = can_id.ok_or(anyhow!("Generator needs an id or some signals"))?;
+        Ok((can_id, defs))
+    }
+}
+
+// Generate the messages for each spec, with timestamps from the start of the
+// route. Returned messages are sorted by timestamp.
+pub fn generate_can_messages(
+    specs: &[GeneratorSpec],
+    dbc: Option<&Dbc>,
+) -> Result<Vec<CANMessage>> {
+    let mut result = vec![];
+    for (idx, spec) in specs.iter().enumerate() {
+        let context = || format!("Invalid generator {}", idx + 1);
+        spec.check().with_context(context)?;
+        let (can_id, defs) = spec.resolve_signals(dbc).with_context(context)?;
+
+        let count = (spec.duration * spec.rate).floor() as u64;
+        for index in 0..count {
+            let t = index as f64 / spec.rate;
+            let mut data = vec![0; spec.len];
+            for (byte, waveform) in data.iter_mut().zip(&spec.bytes) {
+                *byte = waveform
+                    .value(t, index, spec.duration)
+                    .round()
+                    .clamp(0.0, 255.0) as u8;
+            }
+            for (signal, def) in spec.signals.iter().zip(&defs) {
+                let value = signal.value.value(t, index, spec.duration);
+                def.encode(&mut data, value)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Signal {} doesn't fit in {} data bytes",
+                            signal.signal,
+                            spec.len
+                        )
+                    })
+                    .with_context(context)?;
+            }
+            result.push(CANMessage {
+                timestamp: ((spec.start + t) * 1e9).round() as Nanos,
+                can_id,
+                is_extended_id: spec.extended || can_id > 0x7FF,
+                bus_no: spec.bus,
+                is_fd: data.len() > 8,
+                dlc: dlc_for_len(data.len()),
+                data,
+                is_rtr: false,
+                direction: None,
+            });
+        }
+    }
+    result.sort();
+    Ok(result)
+}
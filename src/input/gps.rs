@@ -0,0 +1,284 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Parsers for GPS logs, either GPX files or NMEA 0183 sentence logs. GPX track
+// points look like:
+//
+// <trkpt lat="-37.8136" lon="144.9631"><ele>31.0</ele><time>2022-12-17T09:35:31Z</time></trkpt>
+//
+// and NMEA logs have one sentence per line, of which only RMC (position, speed
+// and course) and GGA (altitude) sentences are used:
+//
+// $GPRMC,093531.00,A,3748.81600,S,14457.78600,E,12.3,45.6,171222,,,A*7C
+// $GPGGA,093531.00,3748.81600,S,14457.78600,E,1,08,1.0,31.0,M,,M,,*4B
+use std::io::{BufRead, Read};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use tracing::info;
+
+use super::{open_log_file, Compression};
+use crate::Nanos;
+
+const METRES_PER_SEC_PER_KNOT: f64 = 1852.0 / 3600.0;
+
+// A GPS fix, to add to the route as a location event
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub timestamp: Nanos,
+    // Time of the fix, in nanoseconds since the Unix epoch
+    pub unix_time_ns: i64,
+    pub latitude: f64,
+    pub longitude: f64,
+    // Metres above sea level
+    pub altitude: Option<f64>,
+    // Metres per second
+    pub speed: Option<f64>,
+    // Degrees clockwise from north
+    pub bearing: Option<f64>,
+}
+
+// Read the fixes from a GPX or NMEA file (NMEA unless the extension is .gpx),
+// sorted by time. Each location's timestamp is the time of the fix in
+// nanoseconds since the Unix epoch, minus ts_offs. Any fixes from before the
+// start of the route are dropped.
+pub fn read_locations(path: &Path, ts_offs: Nanos) -> Result<Vec<Location>> {
+    info!("Opening GPS log {:?}...", path);
+    let mut reader = open_log_file(path, None)?;
+
+    // As for CAN logs, skip any compression extension
+    let name = match Compression::from_path(path) {
+        Compression::None => path,
+        _ => Path::new(path.file_stem().unwrap_or_default()),
+    };
+    let is_gpx = name.extension().and_then(|e| e.to_str()) == Some("gpx");
+    let mut locations = if is_gpx {
+        let mut gpx = String::new();
+        reader
+            .read_to_string(&mut gpx)
+            .with_context(|| format!("Failed to read GPX file {:?}", path))?;
+        parse_gpx(&gpx).with_context(|| format!("Failed to parse GPX file {:?}", path))?
+    } else {
+        parse_nmea(reader).with_context(|| format!("Failed to parse NMEA file {:?}", path))?
+    };
+
+    for location in &mut locations {
+        location.timestamp = location.unix_time_ns - ts_offs;
+    }
+    locations.sort_by_key(|l| l.timestamp);
+    locations.retain(|l| l.timestamp >= 0);
+    Ok(locations)
+}
+
+fn unix_time_ns(time: DateTime<Utc>) -> i64 {
+    time.timestamp() * 1_000_000_000 + time.timestamp_subsec_nanos() as i64
+}
+
+// A minimal parser for the track (and route) points of a GPX file, which
+// doesn't try to handle any other parts of the XML
+fn parse_gpx(gpx: &str) -> Result<Vec<Location>> {
+    let mut result = vec![];
+    let mut rest = gpx;
+    // Whichever kind of point comes next, a file can have both
+    while let Some(start) = ["<trkpt", "<rtept"]
+        .into_iter()
+        .filter_map(|tag| rest.find(tag))
+        .min()
+    {
+        let point = &rest[start..];
+        let name = &point[1..6];
+        let tag_end = point
+            .find('>')
+            .ok_or(anyhow!("Unterminated point element"))?;
+        let tag = &point[..tag_end];
+        // Points are usually elements with children, but could be empty
+        let (body, end) = if tag.ends_with('/') {
+            ("", tag_end + 1)
+        } else {
+            let body_end = point
+                .find(&format!("</{}>", name))
+                .ok_or(anyhow!("Unterminated point element"))?;
+            (&point[tag_end + 1..body_end], body_end)
+        };
+        rest = &point[end..];
+
+        let number = |value: Option<&str>, desc: &str| -> Result<Option<f64>> {
+            value
+                .map(|v| {
+                    v.trim()
+                        .parse()
+                        .with_context(|| format!("Invalid {} {}", desc, v))
+                })
+                .transpose()
+        };
+        let time = xml_element(body, "time").ok_or(anyhow!("Point has no time"))?;
+        let time = DateTime::parse_from_rfc3339(time.trim())
+            .with_context(|| format!("Invalid point time {}", time))?;
+        result.push(Location {
+            timestamp: 0,
+            unix_time_ns: unix_time_ns(time.with_timezone(&Utc)),
+            latitude: number(xml_attr(tag, "lat"), "latitude")?
+                .ok_or(anyhow!("Point has no latitude"))?,
+            longitude: number(xml_attr(tag, "lon"), "longitude")?
+                .ok_or(anyhow!("Point has no longitude"))?,
+            altitude: number(xml_element(body, "ele"), "elevation")?,
+            // GPX 1.0 has speed and course, 1.1 moved them to extensions
+            speed: number(xml_element(body, "speed"), "speed")?,
+            bearing: number(xml_element(body, "course"), "course")?,
+        });
+    }
+    Ok(result)
+}
+
+// Value of the named attribute in an element's start tag
+fn xml_attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    tag.split_whitespace().find_map(|attr| {
+        let value = attr.strip_prefix(name)?.strip_prefix('=')?;
+        let value = value.trim_end_matches(['/', '>']);
+        value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+    })
+}
+
+// Text of the first child element with this name
+fn xml_element<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let start = body.find(&format!("<{}>", name))? + name.len() + 2;
+    let len = body[start..].find(&format!("</{}>", name))?;
+    Some(&body[start..start + len])
+}
+
+fn parse_nmea(reader: impl BufRead) -> Result<Vec<Location>> {
+    let mut result: Vec<Location> = vec![];
+    // GGA sentences have the altitude but no date, so they're matched with the
+    // RMC sentence for the same fix (which may come before or after)
+    let mut last_rmc_time = String::new();
+    let mut last_gga: Option<(String, f64)> = None;
+
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line.context("Failed to read NMEA log")?;
+        let Some(sentence) = nmea_sentence(line.trim()) else {
+            continue;
+        };
+        let fields: Vec<&str> = sentence.split(',').collect();
+        let mut parse = || -> Result<()> {
+            match fields[0].get(2..) {
+                Some("RMC") => {
+                    // Skip sentences with no valid fix
+                    if fields.get(2) != Some(&"A") {
+                        return Ok(());
+                    }
+                    let field = |idx: usize| fields.get(idx).copied().unwrap_or("");
+                    let time = field(1);
+                    let mut location = Location {
+                        timestamp: 0,
+                        unix_time_ns: nmea_time(field(9), time)?,
+                        latitude: nmea_coordinate(field(3), field(4))?,
+                        longitude: nmea_coordinate(field(5), field(6))?,
+                        altitude: None,
+                        speed: nmea_number(field(7))?.map(|knots| knots * METRES_PER_SEC_PER_KNOT),
+                        bearing: nmea_number(field(8))?,
+                    };
+                    if let Some((gga_time, altitude)) = &last_gga {
+                        if gga_time == time {
+                            location.altitude = Some(*altitude);
+                        }
+                    }
+                    result.push(location);
+                    last_rmc_time = time.to_string();
+                }
+                Some("GGA") => {
+                    let time = fields.get(1).copied().unwrap_or("");
+                    let Some(altitude) = nmea_number(fields.get(9).copied().unwrap_or(""))? else {
+                        return Ok(());
+                    };
+                    if time == last_rmc_time {
+                        if let Some(last) = result.last_mut() {
+                            last.altitude = Some(altitude);
+                        }
+                    }
+                    last_gga = Some((time.to_string(), altitude));
+                }
+                _ => (),
+            }
+            Ok(())
+        };
+        parse().with_context(|| format!("Line {}: {}", idx + 1, line))?;
+    }
+    Ok(result)
+}
+
+// Return the body of an NMEA sentence (without the '$' and checksum), or None if
+// the line isn't a sentence or the checksum doesn't match
+fn nmea_sentence(line: &str) -> Option<&str> {
+    let line = line.strip_prefix('$')?;
+    match line.split_once('*') {
+        Some((body, checksum)) => {
+            let expected = u8::from_str_radix(checksum.get(..2)?, 16).ok()?;
+            let actual = body.bytes().fold(0, |sum, b| sum ^ b);
+            (actual == expected).then_some(body)
+        }
+        None => Some(line),
+    }
+}
+
+fn nmea_number(field: &str) -> Result<Option<f64>> {
+    match field {
+        "" => Ok(None),
+        f => Ok(Some(
+            f.parse().with_context(|| format!("Invalid number {}", f))?,
+        )),
+    }
+}
+
+// Convert an NMEA (d)ddmm.mmmm coordinate and hemisphere to decimal degrees
+fn nmea_coordinate(value: &str, hemisphere: &str) -> Result<f64> {
+    let dot = value.find('.').unwrap_or(value.len());
+    if dot < 2 {
+        return Err(anyhow!("Invalid coordinate {}", value));
+    }
+    let degrees: f64 = value[..dot - 2]
+        .parse()
+        .with_context(|| format!("Invalid coordinate {}", value))?;
+    let minutes: f64 = value[dot - 2..]
+        .parse()
+        .with_context(|| format!("Invalid coordinate {}", value))?;
+    let result = degrees + minutes / 60.0;
+    match hemisphere {
+        "N" | "E" => Ok(result),
+        "S" | "W" => Ok(-result),
+        h => Err(anyhow!("Invalid hemisphere {}", h)),
+    }
+}
+
+// Nanoseconds since midnight for an NMEA hhmmss.ss time
+fn nmea_time_of_day(time: &str) -> Option<i64> {
+    let hours: i64 = time.get(0..2)?.parse().ok()?;
+    let minutes: i64 = time.get(2..4)?.parse().ok()?;
+    let seconds: f64 = time.get(4..)?.parse().ok()?;
+    Some((hours * 3600 + minutes * 60) * 1_000_000_000 + (seconds * 1e9).round() as i64)
+}
+
+// Unix time in nanoseconds for an NMEA ddmmyy date and hhmmss.ss time (UTC)
+fn nmea_time(date: &str, time: &str) -> Result<i64> {
+    let invalid = || anyhow!("Invalid date and time {} {}", date, time);
+    let day = date
+        .get(0..2)
+        .and_then(|d| d.parse().ok())
+        .ok_or_else(invalid)?;
+    let month = date
+        .get(2..4)
+        .and_then(|d| d.parse().ok())
+        .ok_or_else(invalid)?;
+    let year: i32 = date
+        .get(4..6)
+        .and_then(|d| d.parse().ok())
+        .ok_or_else(invalid)?;
+    let midnight = NaiveDate::from_ymd_opt(2000 + year, month, day)
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .ok_or_else(invalid)?;
+    let time_of_day = nmea_time_of_day(time).ok_or_else(invalid)?;
+    Ok(unix_time_ns(Utc.from_utc_datetime(&midnight)) + time_of_day)
+}
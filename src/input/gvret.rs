@@ -0,0 +1,159 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Parser for the GVRET binary serial protocol, as logged from GVRET/ESP32RET
+// based CAN loggers (i.e. Macchina). Each CAN frame is:
+//
+// F1 00 <timestamp: u32 LE> <id: u32 LE> <bus << 4 | length> <data...> <checksum>
+//
+// The timestamp is a microsecond counter that wraps around every ~71 minutes.
+// Bit 31 of the ID is set for an extended ID, and bit 30 for a remote frame.
+// Replies to any other GVRET commands in the stream are skipped.
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+
+use super::{
+    collect_can_messages, open_log_file, us_to_timestamp, CANMessage, CanLog, ReadOptions,
+};
+use crate::Nanos;
+
+const GVRET_START: u8 = 0xF1;
+const GVRET_CAN_FRAME: u8 = 0x00;
+
+const ID_EXTENDED: u32 = 1 << 31;
+const ID_RTR: u32 = 1 << 30;
+
+// A frame as read from the log, with the timestamp already extended past any
+// wraparounds of the counter
+struct GvretFrame {
+    ts_us: i64,
+    id: u32,
+    bus_len: u8,
+    data: Vec<u8>,
+}
+
+impl CANMessage {
+    fn parse_gvret_frame(frame: &GvretFrame, ts_offs: Nanos) -> Result<Self> {
+        let is_rtr = frame.id & ID_RTR != 0;
+        Ok(CANMessage {
+            timestamp: us_to_timestamp(frame.ts_us, ts_offs)?,
+            can_id: frame.id & !(ID_EXTENDED | ID_RTR),
+            is_extended_id: frame.id & ID_EXTENDED != 0,
+            bus_no: frame.bus_len >> 4,
+            // Remote frames have a length but no data
+            data: if is_rtr { vec![] } else { frame.data.clone() },
+            is_fd: false,
+            dlc: frame.bus_len & 0x0f,
+            is_rtr,
+            direction: None,
+        })
+    }
+}
+
+// Iterator over the CAN frames in a GVRET binary stream
+struct GvretFrames<R: Read> {
+    reader: R,
+    // Last raw timestamp, and the amount added to account for wraparounds
+    last_ts: Option<u32>,
+    wrap_us: i64,
+}
+
+impl<R: Read> GvretFrames<R> {
+    // Read one byte, or None at the end of the stream
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut buf = [0u8];
+        match self.reader.read_exact(&mut buf) {
+            Ok(()) => Ok(Some(buf[0])),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e).context("Failed to read GVRET log"),
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.reader
+            .read_exact(buf)
+            .context("GVRET log ends part way through a frame")
+    }
+
+    fn read_frame(&mut self) -> Result<Option<GvretFrame>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(GVRET_START) => (),
+                // Not the start of a command, i.e. the rest of a reply we skipped
+                Some(_) => continue,
+            }
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(GVRET_CAN_FRAME) => break,
+                Some(_) => continue,
+            }
+        }
+
+        let mut header = [0u8; 9];
+        self.read_exact(&mut header)?;
+        let ts = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let id = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let bus_len = header[8];
+        let len = (bus_len & 0x0f) as usize;
+        if len > 8 {
+            return Err(anyhow!("Invalid CAN data length {}", len));
+        }
+        // Data is followed by a checksum byte, which GVRET always sets to 0
+        let mut data = vec![0u8; len + 1];
+        self.read_exact(&mut data)?;
+        data.truncate(len);
+
+        if matches!(self.last_ts, Some(last) if ts < last) {
+            self.wrap_us += 1 << 32;
+        }
+        self.last_ts = Some(ts);
+
+        Ok(Some(GvretFrame {
+            ts_us: ts as i64 + self.wrap_us,
+            id,
+            bus_len,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for GvretFrames<R> {
+    type Item = Result<GvretFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+pub fn read_can_messages_gvret(
+    log_path: &Path,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
+    info!("Opening GVRET log {:?}...", log_path);
+
+    let frames = GvretFrames {
+        reader: open_log_file(log_path, options.progress.as_ref())?,
+        last_ts: None,
+        wrap_us: 0,
+    }
+    .enumerate()
+    .map(|(idx, frame)| {
+        (
+            idx + 1,
+            frame.with_context(|| format!("Failed to read GVRET file {:?}", log_path)),
+        )
+    });
+
+    collect_can_messages(
+        log_path,
+        frames,
+        can_ts_offs,
+        options,
+        CANMessage::parse_gvret_frame,
+    )
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Parser for a CSV file of user markers to show on the route timeline, one
+// "timestamp,label" per line:
+//
+// 12.5,entered highway
+// 97000000000,hard brake
+//
+// Timestamps are in the same clock as the CAN log, either in seconds (with a
+// decimal point) or in nanoseconds (a whole number). A first line with the
+// heading "timestamp" is skipped.
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+
+use super::{open_log_file, parse_fixed_point, LogInput};
+use crate::Nanos;
+
+// How long each marker's label is shown for, unless the next marker comes first
+const MARKER_DISPLAY_NANOS: Nanos = 2_000_000_000;
+
+// A named point on the timeline. As with Alert, a label of None ends the display
+// of the previous marker.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Marker {
+    pub timestamp: Nanos,
+    pub label: Option<String>,
+}
+
+fn parse_marker(record: &csv::StringRecord, ts_offs: Nanos) -> Result<Marker> {
+    let ts = record
+        .get(0)
+        .ok_or(anyhow!("Missing timestamp field"))?
+        .trim();
+    let ts: Nanos = if ts.contains('.') {
+        parse_fixed_point(ts, 9)?
+    } else {
+        ts.parse()
+            .with_context(|| format!("Invalid timestamp {}", ts))?
+    };
+    let label = record.get(1).ok_or(anyhow!("Missing label field"))?.trim();
+
+    Ok(Marker {
+        timestamp: ts
+            .checked_sub(ts_offs)
+            .ok_or(anyhow!("Timestamp {} out of range", ts))?,
+        label: Some(label.to_string()),
+    })
+}
+
+// Read the markers from a CSV file, applying the same timestamp offset as the
+// CAN log. Returned markers are sorted by timestamp, any from before the start of
+// the route are dropped.
+pub fn read_markers(path: &Path, ts_offs: Nanos) -> Result<Vec<Marker>> {
+    info!("Opening markers file {:?}...", path);
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(open_log_file(path, None)?);
+
+    let mut markers = vec![];
+    for (row, record) in rdr.records().enumerate() {
+        let record = record.map_err(|e| anyhow!("Invalid CSV record in file {:?}: {}", path, e))?;
+        if row == 0 && record.get(0).map(str::trim) == Some("timestamp") {
+            continue;
+        }
+        markers.push(
+            parse_marker(&record, ts_offs)
+                .with_context(|| format!("Error parsing {:?} row {}", path, row + 1))?,
+        );
+    }
+
+    markers.sort_by_key(|m| m.timestamp);
+    markers.retain(|m| m.timestamp >= 0);
+    Ok(markers)
+}
+
+// Like expand_alerts(), repeat each marker every 100ms so it displays during
+// playback. Each marker is shown until the next one, or for at most
+// MARKER_DISPLAY_NANOS, followed by a marker with no label to end it.
+pub fn expand_markers(markers: Vec<Marker>) -> Vec<LogInput> {
+    let mut result = vec![];
+    let mut peekable = markers.into_iter().peekable();
+
+    while let Some(marker) = peekable.next() {
+        let end = marker.timestamp + MARKER_DISPLAY_NANOS;
+        let next_at = peekable.peek().map(|m| m.timestamp);
+        let mut ts = marker.timestamp;
+        while ts < next_at.unwrap_or(end).min(end) {
+            result.push(LogInput::Marker(Marker {
+                timestamp: ts,
+                label: marker.label.clone(),
+            }));
+            ts += 100_000_000; // 100ms
+        }
+        if !matches!(next_at, Some(next) if next <= end) {
+            result.push(LogInput::Marker(Marker {
+                timestamp: end,
+                label: None,
+            }));
+        }
+    }
+
+    result
+}
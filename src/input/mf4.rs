@@ -0,0 +1,632 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Parser for CAN frames in ASAM MDF4 measurement files (.mf4), as written by
+// loggers using the ASAM bus logging layout. The file is a tree of blocks linked
+// by their offsets in the file: the header (HD) links to data groups (DG), each
+// with channel groups (CG) of channels (CN) and a data block of records. CAN
+// frames are in the channel group (usually named CAN_DataFrame) with channels
+// like:
+//
+// Timestamp                 master channel, in seconds from the start time
+// CAN_DataFrame.BusChannel  bus the frame was on, numbered from 1
+// CAN_DataFrame.ID          CAN ID (bit 31 is set for an extended ID)
+// CAN_DataFrame.IDE         1 for an extended ID
+// CAN_DataFrame.DLC         data length code
+// CAN_DataFrame.DataLength  number of data bytes
+// CAN_DataFrame.DataBytes   the data, in the record or variable length (VLSD)
+// CAN_DataFrame.Dir         0 for a received frame, 1 for a sent frame
+// CAN_DataFrame.EDL         1 for a CAN-FD frame
+//
+// Timestamps are made absolute with the start time in the header, i.e.
+// nanoseconds since the Unix epoch. Only the parts of MDF4 that bus logging
+// uses are supported: little endian fixed point and float channels, byte array
+// data, linear conversions of the time, and data in DT, DL, HL and DZ (zlib
+// compressed) blocks. Other channel groups (remote frames, error frames and any
+// other signals) are skipped.
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::{info, warn};
+
+use super::{
+    check_data_len, collect_can_messages, dlc_for_len, len_for_dlc, open_log_file, CANMessage,
+    CanLog, Direction, ReadOptions,
+};
+use crate::Nanos;
+
+// Size of the identification block at the start of the file, the header block
+// follows it
+const ID_BLOCK_LEN: usize = 64;
+
+// Every block starts with an ID ("##HD"), its length and the number of links
+const BLOCK_HEADER_LEN: usize = 24;
+
+// cn_type of the master (time) channel and VLSD channels
+const CN_TYPE_VLSD: u8 = 1;
+const CN_TYPE_MASTER: u8 = 2;
+const CN_SYNC_TIME: u8 = 1;
+
+// cg_flags bit for a channel group of variable length signal data
+const CG_FLAG_VLSD: u16 = 1;
+
+// cc_type of a linear conversion, phys = a * raw + b
+const CC_TYPE_LINEAR: u8 = 1;
+
+// cn_data_type values
+const DATA_TYPE_UINT_LE: u8 = 0;
+const DATA_TYPE_INT_LE: u8 = 2;
+const DATA_TYPE_FLOAT_LE: u8 = 4;
+
+const ID_EXTENDED: u64 = 1 << 31;
+const ID_MASK: u64 = 0x1FFF_FFFF;
+
+fn read_le(data: &[u8], offset: usize, len: usize) -> Option<u64> {
+    let bytes = data.get(offset..offset.checked_add(len)?)?;
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |value, byte| (value << 8) | *byte as u64),
+    )
+}
+
+// A block as found in the file, with its links to other blocks and its data
+struct Block<'a> {
+    id: &'a [u8],
+    links: Vec<u64>,
+    data: &'a [u8],
+}
+
+impl<'a> Block<'a> {
+    // Link number idx, or 0 (no block) if the block doesn't have that many links
+    fn link(&self, idx: usize) -> u64 {
+        self.links.get(idx).copied().unwrap_or(0)
+    }
+
+    fn u64_at(&self, offset: usize) -> Result<u64> {
+        read_le(self.data, offset, 8).ok_or(anyhow!("MDF block data too short"))
+    }
+
+    fn u32_at(&self, offset: usize) -> Result<u32> {
+        Ok(read_le(self.data, offset, 4).ok_or(anyhow!("MDF block data too short"))? as u32)
+    }
+
+    fn u16_at(&self, offset: usize) -> Result<u16> {
+        Ok(read_le(self.data, offset, 2).ok_or(anyhow!("MDF block data too short"))? as u16)
+    }
+
+    fn u8_at(&self, offset: usize) -> Result<u8> {
+        self.data
+            .get(offset)
+            .copied()
+            .ok_or(anyhow!("MDF block data too short"))
+    }
+
+    fn f64_at(&self, offset: usize) -> Result<f64> {
+        Ok(f64::from_bits(self.u64_at(offset)?))
+    }
+}
+
+// Where a channel's value is in each record, and how to read it
+struct Channel {
+    name: String,
+    cn_type: u8,
+    sync_type: u8,
+    data_type: u8,
+    byte_offset: usize,
+    bit_offset: u32,
+    bit_count: u32,
+    // (a, b) of a linear conversion
+    conversion: Option<(f64, f64)>,
+    // SD block (or VLSD channel group) with the data of a VLSD channel
+    data_link: u64,
+}
+
+impl Channel {
+    // Name without any "CAN_DataFrame." prefix
+    fn short_name(&self) -> &str {
+        self.name.rsplit('.').next().unwrap_or_default()
+    }
+
+    // Raw value as an unsigned integer
+    fn uint(&self, record: &[u8]) -> Option<u64> {
+        if self.bit_count == 0 || self.bit_count > 64 {
+            return None;
+        }
+        let len = (self.bit_offset + self.bit_count).div_ceil(8) as usize;
+        if len > 8 {
+            return None;
+        }
+        let raw = read_le(record, self.byte_offset, len)? >> self.bit_offset;
+        Some(match self.bit_count {
+            64 => raw,
+            bits => raw & ((1 << bits) - 1),
+        })
+    }
+
+    // Physical value, with the conversion applied
+    fn value(&self, record: &[u8]) -> Option<f64> {
+        let raw = match (self.data_type, self.bit_count) {
+            (DATA_TYPE_FLOAT_LE, 64) => f64::from_bits(self.uint(record)?),
+            (DATA_TYPE_FLOAT_LE, 32) => f32::from_bits(self.uint(record)? as u32) as f64,
+            (DATA_TYPE_UINT_LE, _) => self.uint(record)? as f64,
+            (DATA_TYPE_INT_LE, bits) => {
+                let shift = 64 - bits;
+                ((self.uint(record)? << shift) as i64 >> shift) as f64
+            }
+            _ => return None,
+        };
+        Some(match self.conversion {
+            Some((a, b)) => a * raw + b,
+            None => raw,
+        })
+    }
+
+    // Fixed length byte array value
+    fn bytes<'r>(&self, record: &'r [u8]) -> Option<&'r [u8]> {
+        let end = self
+            .byte_offset
+            .checked_add((self.bit_count / 8) as usize)?;
+        record.get(self.byte_offset..end)
+    }
+}
+
+struct ChannelGroup {
+    // Offset of the CG block, which VLSD channels link to
+    offset: u64,
+    record_id: u64,
+    flags: u16,
+    record_len: usize,
+    name: Option<String>,
+    channels: Vec<Channel>,
+}
+
+impl ChannelGroup {
+    fn channel(&self, short_name: &str) -> Option<&Channel> {
+        self.channels
+            .iter()
+            .find(|c| c.short_name() == short_name && c.cn_type != CN_TYPE_MASTER)
+    }
+
+    fn is_can_frames(&self) -> bool {
+        self.name.as_deref() == Some("CAN_DataFrame")
+            || self.channels.iter().any(|c| c.name == "CAN_DataFrame")
+    }
+}
+
+// A CAN frame read from the file, with its absolute timestamp
+struct Mf4Frame {
+    // Time channel value, and the timestamp from it (None if it isn't finite or
+    // is out of range)
+    secs: f64,
+    timestamp: Option<Nanos>,
+    can_id: u64,
+    is_extended_id: bool,
+    bus_no: u8,
+    dlc: Option<u8>,
+    data: Vec<u8>,
+    is_fd: bool,
+    direction: Option<Direction>,
+}
+
+impl CANMessage {
+    fn parse_mf4_frame(frame: &Mf4Frame, ts_offs: Nanos) -> Result<Self> {
+        check_data_len(&frame.data)?;
+        let timestamp = frame
+            .timestamp
+            .ok_or(anyhow!("Invalid time {}s", frame.secs))?;
+        Ok(CANMessage {
+            timestamp: timestamp
+                .checked_sub(ts_offs)
+                .ok_or(anyhow!("Timestamp {} out of range", timestamp))?,
+            can_id: (frame.can_id & ID_MASK) as u32,
+            is_extended_id: frame.is_extended_id,
+            bus_no: frame.bus_no,
+            is_fd: frame.is_fd || frame.data.len() > 8,
+            dlc: frame.dlc.unwrap_or(dlc_for_len(frame.data.len())),
+            data: frame.data.clone(),
+            is_rtr: false,
+            direction: frame.direction,
+        })
+    }
+}
+
+struct Mf4File {
+    buf: Vec<u8>,
+}
+
+impl Mf4File {
+    fn block(&self, offset: u64) -> Result<Block<'_>> {
+        let start = offset as usize;
+        let header = start
+            .checked_add(BLOCK_HEADER_LEN)
+            .and_then(|end| self.buf.get(start..end))
+            .ok_or(anyhow!(
+                "MDF block at {} is past the end of the file",
+                offset
+            ))?;
+        if !header.starts_with(b"##") {
+            bail!("No MDF block at {}", offset);
+        }
+        let len = read_le(header, 8, 8).unwrap() as usize;
+        let link_count = read_le(header, 16, 8).unwrap() as usize;
+        let block = start
+            .checked_add(len)
+            .and_then(|end| self.buf.get(start..end))
+            .ok_or(anyhow!(
+                "MDF block at {} runs past the end of the file",
+                offset
+            ))?;
+        let links_end = link_count
+            .checked_mul(8)
+            .and_then(|len| len.checked_add(BLOCK_HEADER_LEN))
+            .filter(|end| *end <= block.len())
+            .ok_or(anyhow!(
+                "MDF block at {} is too short for its links",
+                offset
+            ))?;
+        Ok(Block {
+            id: &header[2..4],
+            links: (BLOCK_HEADER_LEN..links_end)
+                .step_by(8)
+                .map(|pos| read_le(block, pos, 8).unwrap())
+                .collect(),
+            data: &block[links_end..],
+        })
+    }
+
+    // Text of a TX block, or None if there's no block
+    fn text(&self, offset: u64) -> Result<Option<String>> {
+        if offset == 0 {
+            return Ok(None);
+        }
+        let block = self.block(offset)?;
+        let text = block.data.split(|b| *b == 0).next().unwrap_or_default();
+        Ok(Some(String::from_utf8_lossy(text).into_owned()))
+    }
+
+    fn conversion(&self, offset: u64) -> Result<Option<(f64, f64)>> {
+        if offset == 0 {
+            return Ok(None);
+        }
+        let block = self.block(offset)?;
+        match block.u8_at(0)? {
+            0 => Ok(None),
+            CC_TYPE_LINEAR => {
+                let b = block.f64_at(24)?;
+                let a = block.f64_at(32)?;
+                Ok(Some((a, b)))
+            }
+            cc_type => bail!("Unsupported MDF conversion type {}", cc_type),
+        }
+    }
+
+    // The channels in a list starting at offset, including any channels that
+    // they're composed of
+    fn channels(&self, mut offset: u64, channels: &mut Vec<Channel>) -> Result<()> {
+        while offset != 0 {
+            let block = self.block(offset)?;
+            if block.id != b"CN" {
+                // i.e. an array (CA) block, which bus logging doesn't use
+                return Ok(());
+            }
+            let channel = Channel {
+                name: self.text(block.link(2))?.unwrap_or_default(),
+                cn_type: block.u8_at(0)?,
+                sync_type: block.u8_at(1)?,
+                data_type: block.u8_at(2)?,
+                bit_offset: block.u8_at(3)? as u32,
+                byte_offset: block.u32_at(4)? as usize,
+                bit_count: block.u32_at(8)?,
+                // Conversions are only used for the time
+                conversion: match block.u8_at(0)? {
+                    CN_TYPE_MASTER => self.conversion(block.link(4))?,
+                    _ => None,
+                },
+                data_link: block.link(5),
+            };
+            channels.push(channel);
+            self.channels(block.link(1), channels)?;
+            offset = block.link(0);
+        }
+        Ok(())
+    }
+
+    fn channel_groups(&self, mut offset: u64) -> Result<Vec<ChannelGroup>> {
+        let mut groups = vec![];
+        while offset != 0 {
+            let block = self.block(offset)?;
+            let mut channels = vec![];
+            self.channels(block.link(1), &mut channels)?;
+            groups.push(ChannelGroup {
+                offset,
+                record_id: block.u64_at(0)?,
+                flags: block.u16_at(16)?,
+                record_len: block.u32_at(24)? as usize + block.u32_at(28)? as usize,
+                name: self.text(block.link(2))?,
+                channels,
+            });
+            offset = block.link(0);
+        }
+        Ok(groups)
+    }
+
+    // All the data of a data block, or a list of them, as one buffer
+    fn data(&self, offset: u64, result: &mut Vec<u8>) -> Result<()> {
+        if offset == 0 {
+            return Ok(());
+        }
+        let block = self.block(offset)?;
+        match block.id {
+            b"DT" | b"SD" | b"RD" => result.extend_from_slice(block.data),
+            b"DZ" => result.extend(inflate_dz(&block)?),
+            b"HL" => self.data(block.link(0), result)?,
+            b"DL" => {
+                let mut list = Some(block);
+                while let Some(block) = list {
+                    for link in block.links.iter().skip(1) {
+                        self.data(*link, result)?;
+                    }
+                    list = match block.link(0) {
+                        0 => None,
+                        next => Some(self.block(next)?),
+                    };
+                }
+            }
+            id => bail!(
+                "Unexpected MDF block ##{} for data",
+                String::from_utf8_lossy(id)
+            ),
+        }
+        Ok(())
+    }
+}
+
+// Decompress a DZ block, which is zlib compressed, and may have been transposed
+// (as columns of each byte of the records) first
+fn inflate_dz(block: &Block) -> Result<Vec<u8>> {
+    let zip_type = block.u8_at(2)?;
+    let columns = block.u32_at(4)? as usize;
+    let org_len = block.u64_at(8)? as usize;
+    let zip_len = block.u64_at(16)? as usize;
+    let compressed = zip_len
+        .checked_add(24)
+        .and_then(|end| block.data.get(24..end))
+        .ok_or(anyhow!("MDF DZ block is too short"))?;
+    let mut data = Vec::with_capacity(org_len);
+    flate2::read::ZlibDecoder::new(compressed)
+        .read_to_end(&mut data)
+        .context("Failed to decompress MDF DZ block")?;
+    match zip_type {
+        0 => Ok(data),
+        1 if columns > 0 => {
+            // Only whole rows were transposed, any bytes after those are as is
+            let rows = data.len() / columns;
+            let mut result = data.clone();
+            for row in 0..rows {
+                for column in 0..columns {
+                    result[row * columns + column] = data[column * rows + row];
+                }
+            }
+            Ok(result)
+        }
+        _ => bail!("Unsupported MDF DZ zip type {}", zip_type),
+    }
+}
+
+// Length of each variable length value, followed by the value
+fn vlsd_value(stream: &[u8], offset: u64) -> Option<&[u8]> {
+    let start = (offset as usize).checked_add(4)?;
+    let len = read_le(stream, offset as usize, 4)? as usize;
+    stream.get(start..start.checked_add(len)?)
+}
+
+// Read the CAN frames from one data group's records
+fn read_data_group(
+    file: &Mf4File,
+    dg: &Block,
+    start_ns: Nanos,
+    frames: &mut Vec<Result<Mf4Frame>>,
+) -> Result<()> {
+    let groups = file.channel_groups(dg.link(1))?;
+    if !groups.iter().any(ChannelGroup::is_can_frames) {
+        return Ok(());
+    }
+    let rec_id_size = dg.u8_at(0)? as usize;
+    let mut data = vec![];
+    file.data(dg.link(2), &mut data)?;
+
+    // Split the data into each group's records (without their record IDs). A VLSD
+    // group's records are each a length and a value, kept together so they're
+    // the same as the data of an SD block.
+    let mut records: HashMap<u64, Vec<&[u8]>> = HashMap::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let record_id = match rec_id_size {
+            0 => groups[0].record_id,
+            size => read_le(&data, pos, size).ok_or(anyhow!("MDF record ID is cut off"))?,
+        };
+        pos += rec_id_size;
+        let group = groups
+            .iter()
+            .find(|g| g.record_id == record_id)
+            .ok_or(anyhow!("Unknown MDF record ID {}", record_id))?;
+        let len = match group.flags & CG_FLAG_VLSD {
+            0 => group.record_len,
+            _ => 4 + read_le(&data, pos, 4).ok_or(anyhow!("MDF VLSD length is cut off"))? as usize,
+        };
+        let record = pos
+            .checked_add(len)
+            .and_then(|end| data.get(pos..end))
+            .ok_or(anyhow!("MDF record is cut off at the end of the data"))?;
+        records.entry(record_id).or_default().push(record);
+        pos += len;
+    }
+
+    for group in groups.iter().filter(|g| g.is_can_frames()) {
+        let time = group
+            .channels
+            .iter()
+            .find(|c| c.cn_type == CN_TYPE_MASTER && c.sync_type == CN_SYNC_TIME)
+            .ok_or(anyhow!("No time channel for CAN frames"))?;
+        let id = group
+            .channel("ID")
+            .ok_or(anyhow!("No ID channel for CAN frames"))?;
+        let data_bytes = group
+            .channel("DataBytes")
+            .ok_or(anyhow!("No DataBytes channel for CAN frames"))?;
+        let (bus, ide, dlc, data_len, dir, edl) = (
+            group.channel("BusChannel"),
+            group.channel("IDE"),
+            group.channel("DLC"),
+            group.channel("DataLength"),
+            group.channel("Dir"),
+            group.channel("EDL"),
+        );
+
+        // Variable length data is in an SD block, or in the records of a VLSD
+        // channel group
+        let vlsd = match data_bytes.cn_type {
+            CN_TYPE_VLSD => {
+                let mut stream = vec![];
+                match groups.iter().find(|g| g.offset == data_bytes.data_link) {
+                    Some(vlsd_group) => {
+                        for record in records.get(&vlsd_group.record_id).into_iter().flatten() {
+                            stream.extend_from_slice(record);
+                        }
+                    }
+                    None => file.data(data_bytes.data_link, &mut stream)?,
+                }
+                Some(stream)
+            }
+            _ => None,
+        };
+
+        for record in records.get(&group.record_id).into_iter().flatten() {
+            let frame = || -> Result<Mf4Frame> {
+                let secs = time.value(record).ok_or(anyhow!("Invalid time"))?;
+                let can_id = id.uint(record).ok_or(anyhow!("Invalid ID"))?;
+                let is_fd = edl.and_then(|c| c.uint(record)) == Some(1);
+                let dlc = dlc.and_then(|c| c.uint(record)).map(|dlc| dlc as u8);
+                let mut data = match &vlsd {
+                    Some(stream) => data_bytes
+                        .uint(record)
+                        .and_then(|offset| vlsd_value(stream, offset)),
+                    None => data_bytes.bytes(record),
+                }
+                .ok_or(anyhow!("Invalid DataBytes"))?
+                .to_vec();
+                // Fixed length data has room for the longest frame, so is cut to the
+                // frame's length
+                let len = match (data_len.and_then(|c| c.uint(record)), dlc) {
+                    (Some(len), _) => Some(len as usize),
+                    (None, Some(dlc)) => Some(len_for_dlc(dlc, is_fd)),
+                    (None, None) => None,
+                };
+                if let Some(len) = len {
+                    data.truncate(len);
+                }
+                // A time that can't be a timestamp is only an error for this frame,
+                // when it's parsed
+                let ns = secs * 1e9;
+                let timestamp = (ns.is_finite() && ns.abs() < Nanos::MAX as f64)
+                    .then(|| start_ns.checked_add(ns.round() as Nanos))
+                    .flatten();
+                Ok(Mf4Frame {
+                    secs,
+                    timestamp,
+                    can_id,
+                    is_extended_id: can_id & ID_EXTENDED != 0
+                        || ide.and_then(|c| c.uint(record)) == Some(1),
+                    // Bus channels are numbered from 1
+                    bus_no: bus
+                        .and_then(|c| c.uint(record))
+                        .map_or(0, |bus| bus.saturating_sub(1) as u8),
+                    dlc,
+                    data,
+                    is_fd,
+                    direction: dir.and_then(|c| c.uint(record)).map(|dir| match dir {
+                        0 => Direction::Rx,
+                        _ => Direction::Tx,
+                    }),
+                })
+            };
+            frames.push(frame());
+        }
+    }
+    Ok(())
+}
+
+pub fn read_can_messages_mf4(
+    log_path: &Path,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
+    info!("Opening MDF4 file {:?}...", log_path);
+
+    // The blocks can be anywhere in the file, so it's read into memory
+    let mut buf = vec![];
+    open_log_file(log_path, options.progress.as_ref())?
+        .read_to_end(&mut buf)
+        .with_context(|| format!("Failed to read MDF4 file {:?}", log_path))?;
+    if buf.len() < ID_BLOCK_LEN || !buf.starts_with(b"MDF     ") {
+        bail!("{:?} isn't an MDF file", log_path);
+    }
+    let version = read_le(&buf, 28, 2).unwrap();
+    if version < 400 {
+        bail!(
+            "{:?} is MDF version {}, only MDF 4 is supported",
+            log_path,
+            version
+        );
+    }
+    if read_le(&buf, 60, 2).unwrap() != 0 {
+        warn!(
+            "MDF4 file {:?} wasn't finalized by the logger, some frames may be missing",
+            log_path
+        );
+    }
+
+    let file = Mf4File { buf };
+    let frames = (|| -> Result<Vec<Result<Mf4Frame>>> {
+        let hd = file.block(ID_BLOCK_LEN as u64)?;
+        if hd.id != b"HD" {
+            bail!("No header block");
+        }
+        let start_ns = hd.u64_at(0)? as Nanos;
+        let mut frames = vec![];
+        let mut dg_offset = hd.link(0);
+        while dg_offset != 0 {
+            let dg = file.block(dg_offset)?;
+            read_data_group(&file, &dg, start_ns, &mut frames)?;
+            dg_offset = dg.link(0);
+        }
+        Ok(frames)
+    })()
+    .with_context(|| format!("Failed to read MDF4 file {:?}", log_path))?;
+
+    // The records of each channel group are in order, but the groups (i.e. one for
+    // each bus) are read one after another. Sort them so the first frame is the
+    // earliest, for the timestamp offset (any errors and invalid times go first,
+    // to be reported or skipped).
+    let mut frames = frames;
+    frames.sort_by_key(|frame| {
+        frame
+            .as_ref()
+            .map_or(Nanos::MIN, |f| f.timestamp.unwrap_or(Nanos::MIN))
+    });
+    let frames = frames
+        .into_iter()
+        .enumerate()
+        .map(|(idx, frame)| (idx + 1, frame));
+    collect_can_messages(
+        log_path,
+        frames,
+        can_ts_offs,
+        options,
+        CANMessage::parse_mf4_frame,
+    )
+}
@@ -0,0 +1,132 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Live CAN messages from Linux SocketCAN interfaces (i.e. can0), for recording a
+// route straight from the bus. Each interface is read on its own thread, and its
+// messages are given the bus number of the interface's position in the list.
+//
+// Messages are timestamped when they're read, from the monotonic clock, as the
+// time since the interfaces were opened. The kernel can timestamp frames as they
+// arrive (SO_TIMESTAMPING) but only from the wall clock, which can jump if the
+// clock is adjusted part way through a recording. The delay before a frame is
+// read is small compared to the 10ms that each CAN event in the route spans.
+use std::io::ErrorKind;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use socketcan::{CanAnyFrame, CanFdSocket, EmbeddedFrame, Frame, Socket};
+
+use super::{dlc_for_len, CANMessage, ReadOptions};
+use crate::interrupt::{handle_interrupt, interrupted, reset_interrupt};
+use crate::Nanos;
+
+// How often each reading thread checks if the recording has been stopped
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Returns None for error frames, which report problems on the bus rather than
+// carrying a message
+fn parse_socketcan_frame(frame: &CanAnyFrame, bus_no: u8, timestamp: Nanos) -> Option<CANMessage> {
+    let (can_id, is_extended_id, data, is_fd, is_rtr) = match frame {
+        CanAnyFrame::Normal(f) => (f.raw_id(), f.is_extended(), f.data().to_vec(), false, false),
+        CanAnyFrame::Remote(f) => (f.raw_id(), f.is_extended(), vec![], false, true),
+        CanAnyFrame::Fd(f) => (f.raw_id(), f.is_extended(), f.data().to_vec(), true, false),
+        CanAnyFrame::Error(_) => return None,
+    };
+    let dlc = match frame {
+        CanAnyFrame::Remote(f) => f.dlc() as u8,
+        _ => dlc_for_len(data.len()),
+    };
+    Some(CANMessage {
+        timestamp,
+        can_id,
+        is_extended_id,
+        bus_no,
+        data,
+        is_fd,
+        dlc,
+        is_rtr,
+        // Frames sent by other programs on this machine are looped back to the
+        // socket, so can't tell these apart from received frames
+        direction: None,
+    })
+}
+
+// Read frames from one interface until the recording is stopped, or there's an
+// error (which is sent on, to end the recording)
+fn read_interface(
+    socket: CanFdSocket,
+    interface: String,
+    bus_no: u8,
+    start: Instant,
+    tx: mpsc::Sender<Result<CANMessage>>,
+) {
+    while !interrupted() {
+        let frame = match socket.read_frame() {
+            Ok(frame) => frame,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                let _ = tx.send(Err(e).with_context(|| format!("Failed to read {}", interface)));
+                return;
+            }
+        };
+        let timestamp = start.elapsed().as_nanos() as Nanos;
+        if let Some(message) = parse_socketcan_frame(&frame, bus_no, timestamp) {
+            if tx.send(Ok(message)).is_err() {
+                // Nothing is reading the messages any more
+                return;
+            }
+        }
+    }
+}
+
+// Open the interfaces and start recording from them, until Ctrl-C is pressed
+// (pressing it again exits straight away, without finishing the route).
+//
+// Returns the wall clock time that the recording started, as nanoseconds since
+// the Unix epoch (the same as the can_ts_offs of a log with absolute
+// timestamps), and the messages as they arrive. Messages from different
+// interfaces may be slightly out of order, see ReorderCanMessages. The options'
+// bus_remap and filter are applied.
+pub fn record_can_messages_socketcan(
+    interfaces: &[String],
+    options: &ReadOptions,
+) -> Result<(Nanos, impl Iterator<Item = Result<CANMessage>>)> {
+    handle_interrupt();
+    reset_interrupt();
+
+    let mut sockets = vec![];
+    for interface in interfaces {
+        let socket = CanFdSocket::open(interface)
+            .with_context(|| format!("Failed to open SocketCAN interface {}", interface))?;
+        socket
+            .set_read_timeout(STOP_POLL_INTERVAL)
+            .with_context(|| format!("Failed to set read timeout for {}", interface))?;
+        sockets.push((socket, interface.clone()));
+    }
+
+    let start = Instant::now();
+    let start_ns = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .context("System clock is before the Unix epoch")?
+        .as_nanos() as Nanos;
+
+    let (tx, rx) = mpsc::channel();
+    for (bus_no, (socket, interface)) in sockets.into_iter().enumerate() {
+        let tx = tx.clone();
+        thread::spawn(move || read_interface(socket, interface, bus_no as u8, start, tx));
+    }
+
+    let options = options.clone();
+    let messages = rx.into_iter().filter_map(move |message| match message {
+        Ok(mut message) => {
+            if let Some(bus_no) = options.bus_remap.get(&message.bus_no) {
+                message.bus_no = *bus_no;
+            }
+            options.filter.matches(&message).then_some(Ok(message))
+        }
+        Err(e) => Some(Err(e)),
+    });
+    Ok((start_ns, messages))
+}
@@ -0,0 +1,232 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Parser for PEAK PCAN-View trace files (.trc). The header is a block of lines
+// starting with ';', including the file version and start time:
+//
+// ;$FILEVERSION=2.0
+// ;$STARTTIME=43285.4453402662
+// ;$COLUMNS=N,O,T,B,I,d,R,L,D
+//
+// followed by one line per message:
+//
+//      1        19.842 DT 1      0100 Rx - 8    01 02 03 04 05 06 07 08
+//
+// Files from version 2.1 declare the column layout in the header, older
+// versions (including 2.0) have a fixed layout for each version.
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
+use tracing::info;
+
+use super::{
+    check_data_len, collect_can_messages, dlc_for_len, open_log_file, parse_fixed_point,
+    us_to_timestamp, CANMessage, CanLog, ReadOptions,
+};
+use crate::Nanos;
+
+// Days between the OLE Automation date epoch (1899-12-30), used for STARTTIME,
+// and the Unix epoch
+const OLE_DATE_UNIX_EPOCH: f64 = 25569.0;
+
+const US_PER_DAY: f64 = 86_400_000_000.0;
+
+// Column layout and start time, as read from the file header
+struct TrcHeader {
+    columns: Vec<char>,
+    start_us: i64,
+}
+
+impl TrcHeader {
+    // Column layout used by each file version that doesn't have a $COLUMNS line
+    fn default_columns(version: &str) -> Result<Vec<char>> {
+        let columns = match version {
+            "1.0" => "NOILD",
+            "1.1" => "NOTILD",
+            "1.2" => "NOBTILD",
+            "1.3" => "NOBTIRLD",
+            "2.0" => "NOTIdLD",
+            _ => {
+                return Err(anyhow!(
+                    "TRC file version {} requires a $COLUMNS line",
+                    version
+                ))
+            }
+        };
+        Ok(columns.chars().collect())
+    }
+
+    fn column(&self, name: char) -> Option<usize> {
+        self.columns.iter().position(|c| *c == name)
+    }
+}
+
+fn parse_trc_line(line: &str, ts_offs: Nanos, header: &TrcHeader) -> Result<CANMessage> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let field = |name: char, desc: &str| -> Result<&str> {
+        header
+            .column(name)
+            .and_then(|idx| fields.get(idx).copied())
+            .ok_or(anyhow!("Missing {} field", desc))
+    };
+
+    let offset_us =
+        parse_fixed_point(field('O', "time offset")?, 3).context("Invalid time offset field")?;
+    let ts_us = header
+        .start_us
+        .checked_add(offset_us)
+        .ok_or(anyhow!("Timestamp out of range"))?;
+
+    let id = field('I', "can id")?;
+    let can_id = u32::from_str_radix(id, 16).context("Invalid can id field")?;
+    // Standard IDs are written as 4 hex digits, extended IDs as 8
+    let is_extended_id = id.len() > 4;
+
+    let bus_no = match header.column('B') {
+        Some(_) => field('B', "bus")?.parse().context("Invalid bus field")?,
+        None => 0,
+    };
+
+    let msg_type = header.column('T').map(|_| field('T', "type")).transpose()?;
+    let is_fd = matches!(msg_type, Some("FD" | "FB" | "FE" | "BI"));
+
+    // Version 2.x files have a direction column, in older versions Rx/Tx is
+    // the message type
+    let direction = match header.column('d') {
+        Some(_) => Some(field('d', "direction")?.parse()?),
+        None => msg_type.and_then(|t| t.parse().ok()),
+    };
+
+    let data_idx = header.column('D').ok_or(anyhow!("Missing data column"))?;
+    // Remote frames have a length but no data. Version 2.x files have a message
+    // type for them, older versions write "RTR" in place of the data.
+    let is_rtr = msg_type == Some("RR") || fields.get(data_idx) == Some(&"RTR");
+    let data = if is_rtr {
+        vec![]
+    } else {
+        fields
+            .iter()
+            .skip(data_idx)
+            .map(|d| u8::from_str_radix(d, 16))
+            .try_collect()
+            .context("Error parsing TRC data field")?
+    };
+    check_data_len(&data)?;
+
+    // Version 2.x files have the DLC in the L column (and the number of data
+    // bytes in l), older versions have the length in L
+    let dlc = match header.column('L') {
+        Some(_) => field('L', "length")?
+            .parse()
+            .context("Invalid length field")?,
+        None => dlc_for_len(data.len()),
+    };
+
+    Ok(CANMessage {
+        timestamp: us_to_timestamp(ts_us, ts_offs)?,
+        can_id,
+        is_extended_id,
+        bus_no,
+        data,
+        is_fd,
+        dlc,
+        is_rtr,
+        direction,
+    })
+}
+
+// Message types (where the file has a type column) that are CAN frames. Other
+// types are errors, status or other events which are skipped.
+fn is_trc_frame(line: &str, header: &TrcHeader) -> bool {
+    match header.column('T') {
+        Some(idx) => matches!(
+            line.split_whitespace().nth(idx),
+            Some("DT" | "FD" | "FB" | "FE" | "BI" | "RR" | "Rx" | "Tx")
+        ),
+        None => true,
+    }
+}
+
+pub fn read_can_messages_trc(
+    log_path: &Path,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
+    info!("Opening TRC log {:?}...", log_path);
+
+    let mut lines = open_log_file(log_path, options.progress.as_ref())?
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| {
+            (
+                idx + 1,
+                line.with_context(|| format!("Failed to read TRC file {:?}", log_path)),
+            )
+        })
+        .peekable();
+
+    // Read the header block
+    let mut version = "1.0".to_string();
+    let mut columns = None;
+    let mut start_days = OLE_DATE_UNIX_EPOCH;
+    while let Some((_, Ok(line))) = lines.peek() {
+        let line = line.trim();
+        let Some(comment) = line.strip_prefix(';') else {
+            break;
+        };
+        if let Some((key, value)) = comment.trim().split_once('=') {
+            let value = value.trim();
+            match key {
+                "$FILEVERSION" => version = value.to_string(),
+                "$STARTTIME" => {
+                    start_days = value
+                        .parse()
+                        .with_context(|| format!("Invalid TRC start time {}", value))?
+                }
+                "$COLUMNS" => {
+                    columns = Some(
+                        value
+                            .split(',')
+                            .filter_map(|c| c.trim().chars().next())
+                            .collect(),
+                    )
+                }
+                _ => (),
+            }
+        }
+        lines.next();
+    }
+
+    let columns = match columns {
+        Some(columns) => columns,
+        None => TrcHeader::default_columns(&version)?,
+    };
+    let header = TrcHeader {
+        columns,
+        start_us: ((start_days - OLE_DATE_UNIX_EPOCH) * US_PER_DAY).round() as i64,
+    };
+    if header.column('D').map(|idx| idx + 1) != Some(header.columns.len()) {
+        return Err(anyhow!(
+            "TRC file {:?} data column should be last",
+            log_path
+        ));
+    }
+
+    let messages = lines.filter(|(_, line)| match line {
+        Ok(l) => {
+            let l = l.trim();
+            !l.is_empty() && !l.starts_with(';') && is_trc_frame(l, &header)
+        }
+        _ => true,
+    });
+
+    collect_can_messages(
+        log_path,
+        messages,
+        can_ts_offs,
+        options,
+        |line: &String, ts_offs| parse_trc_line(line, ts_offs, &header),
+    )
+}
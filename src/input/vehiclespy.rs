@@ -0,0 +1,219 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Parser for CSV logs exported from Intrepid Control Systems Vehicle Spy. The
+// export starts with some lines of information about the log, followed by a
+// header row naming the columns and then one row per message:
+//
+// Line,Abs Time(Sec),Rel Time (Sec),Status,Er,Tx,Description,Network,Node,Arb ID,Remote,Xtd,B1,B2,B3,B4,B5,B6,B7,B8
+// 1,0.000125,0.000125,,F,F,,HS CAN,,3E9,F,F,01,02,03,04,05,06,07,08
+//
+// Which columns are included (and their order) is up to the user doing the
+// export, so the fields are found by their names in the header row.
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+
+use super::{
+    check_data_len, collect_can_messages, dlc_for_len, len_for_dlc, open_log_file,
+    parse_fixed_point, CANMessage, CanLog, Direction, ReadOptions,
+};
+use crate::Nanos;
+
+// The header row has to be within this many rows of the start of the file
+const MAX_INFO_ROWS: usize = 100;
+
+// Names of the Vehicle Spy CAN networks, numbered as buses in this order
+const NETWORK_BUSES: [&str; 10] = [
+    "HS CAN", "MS CAN", "HS CAN2", "HS CAN3", "HS CAN4", "HS CAN5", "HS CAN6", "HS CAN7", "SW CAN",
+    "LSFT CAN",
+];
+
+// Index of each field's column in the log, from the header row
+struct Columns {
+    time: usize,
+    arb_id: usize,
+    network: Option<usize>,
+    tx: Option<usize>,
+    remote: Option<usize>,
+    extended: Option<usize>,
+    dlc: Option<usize>,
+    // B1, B2, ... in order
+    data: Vec<usize>,
+}
+
+// Column name with the case, spaces and any "(units)" removed, i.e. "Abs Time(Sec)" is
+// "abstime"
+fn column_key(name: &str) -> String {
+    let name = name.split('(').next().unwrap_or_default();
+    name.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+impl Columns {
+    // Returns None if the record isn't the header row
+    fn from_header(record: &csv::StringRecord) -> Option<Self> {
+        let keys: Vec<String> = record.iter().map(column_key).collect();
+        let find = |names: &[&str]| keys.iter().position(|k| names.contains(&k.as_str()));
+
+        let mut data = vec![];
+        while let Some(idx) = find(&[format!("b{}", data.len() + 1).as_str()]) {
+            data.push(idx);
+        }
+
+        Some(Columns {
+            time: find(&["abstime", "time"])?,
+            arb_id: find(&["arbid", "id"])?,
+            network: find(&["network"]),
+            tx: find(&["tx"]),
+            remote: find(&["remote"]),
+            extended: find(&["xtd", "extended"]),
+            dlc: find(&["dlc", "len"]),
+            data,
+        })
+    }
+}
+
+// Vehicle Spy writes boolean columns as T or F
+fn is_true(value: &str) -> bool {
+    matches!(value.trim(), "T" | "TRUE" | "True" | "1")
+}
+
+// CAN networks have a name from NETWORK_BUSES, or possibly a number. Rows from
+// any other networks (i.e. LIN) are skipped.
+fn network_bus(network: &str) -> Option<Result<u8>> {
+    let network = network.trim();
+    if let Ok(bus) = network.parse() {
+        return Some(Ok(bus));
+    }
+    if !network.contains("CAN") {
+        return None;
+    }
+    Some(
+        NETWORK_BUSES
+            .iter()
+            .position(|n| *n == network)
+            .map(|bus| bus as u8)
+            .ok_or(anyhow!("Unknown CAN network '{}'", network)),
+    )
+}
+
+fn parse_vehiclespy_record(
+    record: &csv::StringRecord,
+    ts_offs: Nanos,
+    columns: &Columns,
+) -> Result<CANMessage> {
+    let field = |idx: usize, desc: &str| -> Result<&str> {
+        record
+            .get(idx)
+            .map(str::trim)
+            .ok_or(anyhow!("Missing {} field", desc))
+    };
+    let flag =
+        |idx: Option<usize>| matches!(idx.and_then(|idx| record.get(idx)), Some(v) if is_true(v));
+
+    let time = field(columns.time, "time")?;
+    let timestamp = parse_fixed_point(time, 9)
+        .context("Invalid time field")?
+        .checked_sub(ts_offs)
+        .ok_or(anyhow!("Timestamp {} out of range", time))?;
+
+    let arb_id = field(columns.arb_id, "arb id")?;
+    let can_id = u32::from_str_radix(arb_id.trim_start_matches("0x"), 16)
+        .with_context(|| format!("Invalid arb id field {}", arb_id))?;
+
+    let bus_no = match columns.network {
+        Some(idx) => network_bus(field(idx, "network")?).unwrap_or(Ok(0))?,
+        None => 0,
+    };
+
+    let is_rtr = flag(columns.remote);
+    let dlc: Option<u8> = match columns.dlc {
+        Some(idx) => Some(field(idx, "dlc")?.parse().context("Invalid dlc field")?),
+        None => None,
+    };
+    let mut data = vec![];
+    if !is_rtr {
+        for idx in &columns.data {
+            match record.get(*idx).map(str::trim) {
+                Some("") | None => break,
+                Some(byte) => data.push(
+                    u8::from_str_radix(byte, 16)
+                        .with_context(|| format!("Invalid data byte {}", byte))?,
+                ),
+            }
+        }
+        // The DLC column is the DLC code, i.e. 9 is 12 bytes of a CAN-FD frame
+        if let Some(dlc) = dlc {
+            data.truncate(len_for_dlc(dlc, data.len() > 8));
+        }
+    }
+    check_data_len(&data)?;
+
+    Ok(CANMessage {
+        timestamp,
+        can_id,
+        is_extended_id: flag(columns.extended) || can_id > 0x7FF,
+        bus_no,
+        is_fd: data.len() > 8,
+        dlc: dlc.unwrap_or(dlc_for_len(data.len())),
+        data,
+        is_rtr,
+        direction: columns.tx.map(|idx| match flag(Some(idx)) {
+            true => Direction::Tx,
+            false => Direction::Rx,
+        }),
+    })
+}
+
+pub fn read_can_messages_vehiclespy(
+    log_path: &Path,
+    can_ts_offs: Option<Nanos>,
+    options: &ReadOptions,
+) -> Result<CanLog> {
+    info!("Opening Vehicle Spy log {:?}...", log_path);
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(open_log_file(log_path, options.progress.as_ref())?);
+    let mut records = rdr.records().enumerate().map(|(row, rec)| {
+        (
+            row + 1,
+            rec.map_err(|e| anyhow!("Invalid CSV record in file {:?}: {}", log_path, e)),
+        )
+    });
+
+    // Skip the information about the log, up to the header row
+    let columns = records
+        .by_ref()
+        .take(MAX_INFO_ROWS)
+        .find_map(|(_, rec)| rec.ok().as_ref().and_then(Columns::from_header))
+        .ok_or(anyhow!(
+            "No header row with time and arb id columns found in {:?}",
+            log_path
+        ))?;
+
+    // Rows from other networks, and any blank rows, aren't CAN messages
+    let messages = records.filter(|(_, rec)| match rec {
+        Ok(rec) => {
+            let is_can = match columns.network.and_then(|idx| rec.get(idx)) {
+                Some(network) => network_bus(network).is_some(),
+                None => true,
+            };
+            is_can && !rec.iter().all(|f| f.trim().is_empty())
+        }
+        _ => true,
+    });
+
+    collect_can_messages(
+        log_path,
+        messages,
+        can_ts_offs,
+        options,
+        |record, ts_offs| parse_vehiclespy_record(record, ts_offs, &columns),
+    )
+}
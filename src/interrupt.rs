@@ -0,0 +1,36 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Ctrl-C handling. The first Ctrl-C only sets a flag, so that a recording or
+// conversion can stop cleanly and still leave a route that opens. Pressing it
+// again exits straight away.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+use tracing::warn;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static HANDLER: Once = Once::new();
+
+// Start handling Ctrl-C, if it isn't already handled
+pub fn handle_interrupt() {
+    HANDLER.call_once(|| {
+        let handler = ctrlc::set_handler(|| {
+            if INTERRUPTED.swap(true, Ordering::Relaxed) {
+                std::process::exit(130);
+            }
+        });
+        if let Err(e) = handler {
+            warn!("Failed to handle Ctrl-C: {}", e);
+        }
+    });
+}
+
+// Whether Ctrl-C has been pressed since the last reset_interrupt()
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+pub fn reset_interrupt() {
+    INTERRUPTED.store(false, Ordering::Relaxed);
+}
@@ -1,5 +1,14 @@
+pub mod bundle;
+pub mod cli;
+pub mod dbc;
 pub mod input;
+pub mod interrupt;
+#[cfg(feature = "python")]
+mod python;
 pub mod qlog;
+pub mod route;
+pub mod stats;
+pub mod sync;
 pub mod video;
 
 // Type for nanosecond timestamps
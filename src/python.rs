@@ -0,0 +1,97 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Python module (built with the "python" feature, see pyproject.toml), for
+// converting logs and reading CAN messages from Python without running the
+// command line tool.
+use std::path::PathBuf;
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+
+use crate::cli::{self, ConvertOptions, LogInfo};
+use crate::input::{read_can_logs, Direction, LogFormat, ReadOptions};
+
+fn to_py_err(e: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{:#}", e))
+}
+
+// Convert a CAN log (and optionally a video) to a route in out_dir, the same as
+// the command line tool. The keyword arguments are the fields of ConvertOptions,
+// i.e. the command line options without the leading dashes and with
+// underscores, such as no_qlog=True or bus=[0, 2]. car and fingerprint are the
+// same as in the YAML file.
+#[pyfunction]
+#[pyo3(signature = (can_path, video_path, out_dir, car = "", fingerprint = "", **opts))]
+fn convert(
+    py: Python,
+    can_path: PathBuf,
+    video_path: Option<PathBuf>,
+    out_dir: PathBuf,
+    car: &str,
+    fingerprint: &str,
+    opts: Option<&PyDict>,
+) -> PyResult<()> {
+    let mut options: ConvertOptions = match opts {
+        Some(opts) => pythonize::depythonize(opts)
+            .map_err(|e| PyValueError::new_err(format!("Invalid option: {}", e)))?,
+        None => ConvertOptions::default(),
+    };
+    options.data_dir = out_dir;
+    let log = LogInfo::new(car, fingerprint, can_path, video_path);
+    let base_dir = std::env::current_dir()?;
+
+    py.allow_threads(|| cli::convert(vec![log], &base_dir, &options))
+        .map_err(to_py_err)
+}
+
+// Read the CAN messages from one or more logs (merged in timestamp order), as a
+// list of dicts. Timestamps are in nanoseconds from the first message, the same
+// as in a route. format is the same as --format, guessed from the file
+// extension if it isn't set.
+#[pyfunction]
+#[pyo3(signature = (paths, format = None))]
+fn read_can_messages<'py>(
+    py: Python<'py>,
+    paths: Vec<PathBuf>,
+    format: Option<&str>,
+) -> PyResult<&'py PyList> {
+    let format: Option<LogFormat> = format.map(str::parse).transpose().map_err(to_py_err)?;
+    let log = py
+        .allow_threads(|| {
+            let options = ReadOptions {
+                allow_empty: true,
+                ..Default::default()
+            };
+            read_can_logs(&paths, format, None, &options)
+        })
+        .map_err(|e| to_py_err(e.into()))?;
+
+    let messages = PyList::empty(py);
+    for message in &log.messages {
+        let dict = PyDict::new(py);
+        dict.set_item("timestamp", message.timestamp)?;
+        dict.set_item("bus", message.bus_no)?;
+        dict.set_item("id", message.can_id)?;
+        dict.set_item("extended", message.is_extended_id)?;
+        dict.set_item("data", PyBytes::new(py, &message.data))?;
+        dict.set_item("dlc", message.dlc)?;
+        dict.set_item("fd", message.is_fd)?;
+        dict.set_item("rtr", message.is_rtr)?;
+        let direction = message.direction.map(|d| match d {
+            Direction::Rx => "rx",
+            Direction::Tx => "tx",
+        });
+        dict.set_item("direction", direction)?;
+        messages.append(dict)?;
+    }
+    Ok(messages)
+}
+
+#[pymodule]
+fn make_cabana_route(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(convert, m)?)?;
+    m.add_function(wrap_pyfunction!(read_can_messages, m)?)?;
+    Ok(())
+}
@@ -1,30 +1,167 @@
 // Copyright (c) 2023 Angus Gratton
 // SPDX-License-Identifier: GPL-2.0-or-later
-use crate::input::{Alert, AlertStatus, CANMessage};
+use crate::dbc::CarState;
+use crate::input::{Alert, AlertStatus, CANMessage, Direction, Location, Marker};
 use crate::log_capnp;
 use crate::log_capnp::sentinel::SentinelType;
+use crate::video::Camera;
 use crate::Nanos;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use bzip2::write::BzEncoder;
-use bzip2::Compression;
+use capnp::message::HeapAllocator;
+use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::SystemTime;
 
-// Struct to wrap writing an qlog.bz2 file
+// zstd level for LogCompression::Zstd, much faster than bzip2 for a similar size
+const ZSTD_LEVEL: i32 = 3;
+
+// How the rlog and qlog files are compressed as they're written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogCompression {
+    // Plain capnp stream, i.e. for debugging
+    None,
+    // The openpilot default
+    Bz2,
+    Zstd,
+}
+
+impl LogCompression {
+    // File name extension for logs with this compression, i.e. "rlog" + ".bz2"
+    pub fn extension(&self) -> &'static str {
+        match self {
+            LogCompression::None => "",
+            LogCompression::Bz2 => ".bz2",
+            LogCompression::Zstd => ".zst",
+        }
+    }
+}
+
+impl FromStr for LogCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(LogCompression::None),
+            "bz2" | "bzip2" => Ok(LogCompression::Bz2),
+            "zstd" | "zst" => Ok(LogCompression::Zstd),
+            _ => Err(anyhow!("Unknown log compression '{}'", s)),
+        }
+    }
+}
+
+// Kind of device that the route claims to be recorded on, for initData
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    Unknown,
+    Neo,
+    Tici,
+    Tizi,
+    // openpilot running on a computer, i.e. in the simulator
+    Pc,
+}
+
+impl From<DeviceType> for log_capnp::init_data::DeviceType {
+    fn from(device_type: DeviceType) -> Self {
+        match device_type {
+            DeviceType::Unknown => Self::Unknown,
+            DeviceType::Neo => Self::Neo,
+            DeviceType::Tici => Self::Tici,
+            DeviceType::Tizi => Self::Tizi,
+            DeviceType::Pc => Self::Pc,
+        }
+    }
+}
+
+impl FromStr for DeviceType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "unknown" => Ok(DeviceType::Unknown),
+            "neo" => Ok(DeviceType::Neo),
+            "tici" | "c3" => Ok(DeviceType::Tici),
+            "tizi" | "c3x" => Ok(DeviceType::Tizi),
+            "pc" => Ok(DeviceType::Pc),
+            _ => Err(anyhow!("Unknown device type '{}'", s)),
+        }
+    }
+}
+
+// The fields of initData that say which openpilot build wrote the route. Some
+// replay tools won't load a route without them.
+#[derive(Clone, Debug)]
+pub struct InitDataFields {
+    pub version: String,
+    pub git_commit: String,
+    pub git_branch: String,
+    pub git_remote: String,
+    pub device_type: DeviceType,
+    pub dongle_id: String,
+}
+
+// Struct to wrap writing an qlog.bz2 (or rlog.bz2) file. Events are compressed
+// as they're written, as per the LogCompression.
 pub struct QlogWriter {
     last_timestamp: Nanos,
-    writer: BzEncoder<File>,
+    writer: Box<dyn Write>,
+    // Bus numbers to write as a different CanData.src, see can_src()
+    src_map: HashMap<u8, u8>,
 }
 
 impl QlogWriter {
-    pub fn new(path: PathBuf) -> Result<Self> {
+    pub fn new(path: PathBuf, compression: LogCompression) -> Result<Self> {
         let f =
             File::create(&path).with_context(|| format!("Failed to create file {:?}", &path))?;
-        let writer = BzEncoder::new(f, Compression::new(6));
-        Ok(Self {
+        let writer: Box<dyn Write> = match compression {
+            LogCompression::None => Box::new(BufWriter::new(f)),
+            LogCompression::Bz2 => Box::new(BzEncoder::new(f, bzip2::Compression::new(6))),
+            // The zstd frame is finished when the writer is dropped, the same as
+            // the bzip2 encoder
+            LogCompression::Zstd => Box::new(
+                zstd::stream::write::Encoder::new(f, ZSTD_LEVEL)
+                    .context("Failed to create zstd encoder")?
+                    .auto_finish(),
+            ),
+        };
+        Ok(Self::from_writer(writer))
+    }
+
+    // Write events to any other writer, i.e. a stream instead of a file. Nothing
+    // is added to compress them.
+    pub fn from_writer(writer: Box<dyn Write>) -> Self {
+        Self {
             writer,
             last_timestamp: 0,
-        })
+            src_map: HashMap::new(),
+        }
+    }
+
+    // Flush any events still buffered by the writer
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush().context("Failed to write events")
+    }
+
+    // Write CAN messages from the buses in src_map with a different bus number.
+    // Other buses are written with their own number.
+    pub fn set_src_map(&mut self, src_map: HashMap<u8, u8>) {
+        self.src_map = src_map;
+    }
+
+    // CanData.src value for a message from this bus.
+    //
+    // In openpilot logs src is the panda bus number the frame was received on
+    // (0 to 127), plus 128 for a frame the panda sent and echoed back, or 192 for
+    // one it was asked to send and rejected. Frames sent by the logging device are
+    // written in sendcan events instead (see write_can()), so only the bus number
+    // is written here. Extended IDs aren't marked in src, or anywhere else: the
+    // address is the whole 29-bit ID, and any address above 0x7FF is extended.
+    fn can_src(&self, bus_no: u8) -> u8 {
+        *self.src_map.get(&bus_no).unwrap_or(&bus_no)
     }
 
     fn write_event(&mut self, mono_time: Nanos, fill_event_cb: impl Fn(log_capnp::event::Builder)) {
@@ -40,9 +177,34 @@ impl QlogWriter {
         capnp::serialize::write_message(&mut self.writer, &message).unwrap();
     }
 
-    pub fn write_init_data(&mut self, mono_time: Nanos) {
+    // Write an event that's already been built, i.e. one copied from another
+    // route's log. Its logMonoTime is written as it is.
+    pub fn write_message(&mut self, message: &capnp::message::Builder<HeapAllocator>) {
+        capnp::serialize::write_message(&mut self.writer, message).unwrap();
+    }
+
+    // wall_time is the real time at mono_time, if known
+    pub fn write_init_data(
+        &mut self,
+        mono_time: Nanos,
+        wall_time: Option<SystemTime>,
+        fields: &InitDataFields,
+    ) {
         self.write_event(mono_time, |event| {
-            let mut _init_data = event.init_init_data(); // Not setting any fields here for now
+            let mut init_data = event.init_init_data(); // Not setting most fields here for now
+            init_data.set_version(&fields.version);
+            init_data.set_git_commit(&fields.git_commit);
+            init_data.set_git_branch(&fields.git_branch);
+            init_data.set_git_remote(&fields.git_remote);
+            init_data.set_device_type(fields.device_type.into());
+            init_data.set_dongle_id(&fields.dongle_id);
+            if let Some(wall_time) = wall_time {
+                let wall_time_nanos = wall_time
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+                init_data.set_wall_time_nanos(wall_time_nanos as u64);
+            }
         });
     }
 
@@ -61,35 +223,90 @@ impl QlogWriter {
         });
     }
 
-    pub fn write_can(&mut self, can_msgs: &[CANMessage]) {
+    pub fn write_can<M: Borrow<CANMessage>>(&mut self, can_msgs: &[M]) {
+        // Frames sent by the logging device go in a sendcan event, the same as
+        // openpilot logs frames it transmits. Everything else is a can event.
+        let (sent, received): (Vec<&CANMessage>, Vec<&CANMessage>) = can_msgs
+            .iter()
+            .map(|m| m.borrow())
+            .partition(|m| m.direction == Some(Direction::Tx));
+        self.write_can_event(&received, false);
+        self.write_can_event(&sent, true);
+    }
+
+    fn write_can_event(&mut self, can_msgs: &[&CANMessage], sendcan: bool) {
         if can_msgs.is_empty() {
             return;
         }
 
+        let srcs: Vec<u8> = can_msgs.iter().map(|m| self.can_src(m.bus_no)).collect();
         self.write_event(can_msgs[0].timestamp(), |event| {
             let len = can_msgs.len().try_into().unwrap();
-            let mut can_evt = event.init_can(len);
+            let mut can_evt = if sendcan {
+                event.init_sendcan(len)
+            } else {
+                event.init_can(len)
+            };
             for (idx, msg) in can_msgs.iter().enumerate() {
                 let mut evt_msg = can_evt.reborrow().get(idx as u32);
+                // CanData has no flag for remote frames, so these are written
+                // with no data
                 evt_msg.set_address(msg.can_id);
                 evt_msg.set_dat(&msg.data);
-                evt_msg.set_src(msg.bus_no);
+                evt_msg.set_src(srcs[idx]);
                 evt_msg.set_bus_time(0);
             }
         });
     }
 
-    pub fn write_frame_encode_idx(&mut self, mono_time: Nanos, segment_num: i32, frame_id: u32) {
+    pub fn write_frame_encode_idx(
+        &mut self,
+        mono_time: Nanos,
+        camera: Camera,
+        segment_num: i32,
+        frame_id: u32,
+    ) {
+        self.write_event(mono_time, |event| {
+            let encode_idx = match camera {
+                Camera::Road => event.init_road_encode_idx(),
+                Camera::Wide => event.init_wide_road_encode_idx(),
+                Camera::Driver => event.init_driver_encode_idx(),
+            };
+            set_encode_idx(
+                encode_idx,
+                log_capnp::encode_index::Type::FullHEVC,
+                mono_time,
+                segment_num,
+                frame_id,
+            );
+        });
+    }
+
+    // Frame timing for a camera's frame, this doesn't have any of the exposure
+    // or sensor information that a real openpilot camera would have
+    pub fn write_camera_state(&mut self, mono_time: Nanos, camera: Camera, frame_id: u32) {
+        self.write_event(mono_time, |event| {
+            let mut state = match camera {
+                Camera::Road => event.init_road_camera_state(),
+                Camera::Wide => event.init_wide_road_camera_state(),
+                Camera::Driver => event.init_driver_camera_state(),
+            };
+            state.set_frame_id(frame_id);
+            state.set_timestamp_sof(mono_time as u64);
+            state.set_timestamp_eof(mono_time as u64);
+        });
+    }
+
+    // Same as write_frame_encode_idx, for the low resolution road qcamera.ts video
+    pub fn write_qcamera_encode_idx(&mut self, mono_time: Nanos, segment_num: i32, frame_id: u32) {
         self.write_event(mono_time, |event| {
-            let mut encode_idx = event.init_road_encode_idx();
-            encode_idx.set_frame_id(frame_id);
-            encode_idx.set_type(log_capnp::encode_index::Type::FullHEVC);
-            encode_idx.set_encode_id(frame_id); // Seems this can be same as Frame ID?
-            encode_idx.set_segment_num(segment_num);
-            encode_idx.set_segment_id(frame_id); // Appears to be the same(!)
-            encode_idx.set_segment_id_encode(frame_id); // Seems ignored?
-            encode_idx.set_timestamp_sof(mono_time as u64);
-            encode_idx.set_timestamp_eof(mono_time as u64); // TODO: set properly
+            set_encode_idx(
+                event.init_q_road_encode_idx(),
+                log_capnp::encode_index::Type::QcameraH264,
+                mono_time,
+                segment_num,
+                frame_id,
+            );
         });
     }
 
@@ -108,9 +325,54 @@ impl QlogWriter {
         });
     }
 
+    // GPS fix from an external GPS log, so the route can be shown on a map
+    pub fn write_gps_location(&mut self, location: &Location) {
+        self.write_event(location.timestamp, |event| {
+            let mut gps = event.init_gps_location();
+            gps.set_latitude(location.latitude);
+            gps.set_longitude(location.longitude);
+            gps.set_altitude(location.altitude.unwrap_or(0.0));
+            gps.set_speed(location.speed.unwrap_or(0.0) as f32);
+            gps.set_bearing_deg(location.bearing.unwrap_or(0.0) as f32);
+            gps.set_unix_timestamp_millis(location.unix_time_ns / 1_000_000);
+            // Bit 0 set means the fix is valid
+            gps.set_flags(1);
+            gps.set_source(log_capnp::gps_location_data::SensorSource::External);
+        });
+    }
+
+    // carState synthesized from decoded DBC signals, any fields which aren't
+    // known are left as zero
+    pub fn write_car_state(&mut self, car_state: &CarState) {
+        self.write_event(car_state.timestamp, |event| {
+            let mut state = event.init_car_state();
+            if let Some(v_ego) = car_state.v_ego {
+                state.set_v_ego(v_ego as f32);
+                state.set_v_ego_raw(v_ego as f32);
+                state.set_standstill(v_ego == 0.0);
+            }
+            if let Some(angle) = car_state.steering_angle_deg {
+                state.set_steering_angle_deg(angle as f32);
+            }
+            if let Some(gas) = car_state.gas {
+                state.set_gas(gas as f32);
+                state.set_gas_pressed(gas > 0.0);
+            }
+            if let Some(brake) = car_state.brake {
+                state.set_brake(brake as f32);
+                state.set_brake_pressed(brake > 0.0);
+            }
+        });
+    }
+
     // Insert an alert to appear on the video. Needs to be followed by
     // write_alert_end() with a later timestamp to show when the alert is
     // no longer visible.
+    //
+    // This is the same controlsState event that openpilot shows its own alerts
+    // from, and expand_alerts() repeats it every 100ms like openpilot does. There's
+    // no onroadEvents event, as car events only have a fixed set of names and no
+    // text.
     pub fn write_alert(&mut self, alert: &Alert) {
         self.write_event(alert.timestamp, |event| {
             // Abusing this quite comprehensive event type to only inject alert text
@@ -126,7 +388,12 @@ impl QlogWriter {
                 controls.set_alert_text1(text1);
                 controls.set_alert_text2(text2);
 
-                controls.set_alert_size(log_capnp::controls_state::AlertSize::Mid);
+                // Sized the same as openpilot alerts, small alerts only have one line
+                controls.set_alert_size(if text2.is_empty() {
+                    log_capnp::controls_state::AlertSize::Small
+                } else {
+                    log_capnp::controls_state::AlertSize::Mid
+                });
             } else {
                 controls.set_alert_size(log_capnp::controls_state::AlertSize::None);
             }
@@ -134,6 +401,22 @@ impl QlogWriter {
         });
     }
 
+    // Show a user marker's label the same way as an alert, or end it if the
+    // label is None
+    pub fn write_marker(&mut self, marker: &Marker) {
+        let Some(label) = &marker.label else {
+            self.write_alert_end(marker.timestamp);
+            return;
+        };
+        self.write_event(marker.timestamp, |event| {
+            let mut controls = event.init_controls_state();
+            controls.set_alert_type("make_route_marker");
+            controls.set_alert_text1(label);
+            controls.set_alert_size(log_capnp::controls_state::AlertSize::Small);
+            controls.set_alert_status(log_capnp::controls_state::AlertStatus::Normal);
+        });
+    }
+
     pub fn write_alert_end(&mut self, mono_time: Nanos) {
         self.write_event(mono_time, |event| {
             // Abusing this quite comprehensive event type to only inject alert text
@@ -152,3 +435,53 @@ impl From<AlertStatus> for log_capnp::controls_state::AlertStatus {
         }
     }
 }
+
+// Decimates CAN messages for a qlog, which only needs enough messages to scrub
+// through the route. Keeps at most one message per bus and CAN ID in each
+// interval.
+pub struct CanDecimator {
+    interval: Nanos,
+    last_kept: HashMap<(u8, u32), Nanos>,
+}
+
+impl CanDecimator {
+    pub fn new(interval: Nanos) -> Self {
+        Self {
+            interval,
+            last_kept: HashMap::new(),
+        }
+    }
+
+    pub fn decimate<'a>(&mut self, can_msgs: &'a [CANMessage]) -> Vec<&'a CANMessage> {
+        can_msgs
+            .iter()
+            .filter(|m| {
+                let key = (m.bus_no, m.can_id);
+                match self.last_kept.get(&key) {
+                    Some(last) if m.timestamp() - last < self.interval => false,
+                    _ => {
+                        self.last_kept.insert(key, m.timestamp());
+                        true
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+fn set_encode_idx(
+    mut encode_idx: log_capnp::encode_index::Builder,
+    encode_type: log_capnp::encode_index::Type,
+    mono_time: Nanos,
+    segment_num: i32,
+    frame_id: u32,
+) {
+    encode_idx.set_frame_id(frame_id);
+    encode_idx.set_type(encode_type);
+    encode_idx.set_encode_id(frame_id); // Seems this can be same as Frame ID?
+    encode_idx.set_segment_num(segment_num);
+    encode_idx.set_segment_id(frame_id); // Appears to be the same(!)
+    encode_idx.set_segment_id_encode(frame_id); // Seems ignored?
+    encode_idx.set_timestamp_sof(mono_time as u64);
+    encode_idx.set_timestamp_eof(mono_time as u64); // TODO: set properly
+}
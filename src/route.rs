@@ -0,0 +1,545 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Reading back routes that have already been written to a data directory. A
+// route is the set of segment directories named "<route>--<segment number>",
+// each with an rlog (and usually a qlog and videos).
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use capnp::message::{HeapAllocator, ReaderOptions};
+use capnp::serialize::OwnedSegments;
+use tracing::info;
+
+use crate::input::{check_data_len, open_log_file, TsUnit};
+use crate::log_capnp::event;
+use crate::log_capnp::sentinel::SentinelType;
+use crate::qlog::{LogCompression, QlogWriter};
+use crate::video::SegmentVideoKind;
+use crate::Nanos;
+
+// Default duration of a route segment, the same as real openpilot routes. Routes
+// can be written with other lengths (see --segment-seconds), the segment
+// directories and sentinels are all derived from the length used.
+pub const SEGMENT_NANOS: Nanos = Duration::from_secs(60).as_nanos() as Nanos;
+
+// Logs written in each segment directory, with any LogCompression extension
+const LOG_NAMES: [&str; 2] = ["rlog", "qlog"];
+
+pub struct Segment {
+    pub number: i64,
+    pub path: PathBuf,
+}
+
+// The segments of a route in data_dir, in order. Fails if there aren't any.
+pub fn route_segments(data_dir: &Path, route: &str) -> Result<Vec<Segment>> {
+    let prefix = format!("{route}--");
+    let mut segments = vec![];
+    for entry in std::fs::read_dir(data_dir)
+        .with_context(|| format!("Failed to read data directory {:?}", data_dir))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+        let number = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(&prefix))
+            .and_then(|n| n.parse().ok());
+        if let Some(number) = number {
+            if entry.file_type()?.is_dir() {
+                segments.push(Segment {
+                    number,
+                    path: entry.path(),
+                });
+            }
+        }
+    }
+    if segments.is_empty() {
+        bail!("No segments of route {} found in {:?}", route, data_dir);
+    }
+    segments.sort_by_key(|s| s.number);
+    Ok(segments)
+}
+
+// Path of the named log (i.e. "rlog") in a segment directory, and how it's
+// compressed. None if the segment doesn't have this log.
+pub fn find_log(segment_dir: &Path, name: &str) -> Option<(PathBuf, LogCompression)> {
+    [
+        LogCompression::Bz2,
+        LogCompression::Zstd,
+        LogCompression::None,
+    ]
+    .into_iter()
+    .map(|c| (segment_dir.join(format!("{name}{}", c.extension())), c))
+    .find(|(path, _)| path.is_file())
+}
+
+// Events in an rlog or qlog, in the order they were written
+pub fn read_log_events(
+    path: &Path,
+) -> Result<impl Iterator<Item = Result<capnp::message::Reader<OwnedSegments>>>> {
+    let mut reader = open_log_file(path, None)?;
+    let path = path.to_path_buf();
+    let mut failed = false;
+    Ok(std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let message = capnp::serialize::try_read_message(&mut reader, ReaderOptions::new())
+            .with_context(|| format!("Failed to read event from {:?}", path));
+        failed = message.is_err();
+        message.transpose()
+    }))
+}
+
+// Copy of an event moved to a later segment, with its timestamps shifted by
+// shift_ns and any segment numbers by shift_segments
+fn shift_event(
+    event: event::Reader,
+    shift_ns: Nanos,
+    shift_segments: i32,
+) -> Result<capnp::message::Builder<HeapAllocator>> {
+    let mut message = capnp::message::Builder::new_default();
+    message.set_root(event)?;
+    let mut shifted = message.get_root::<event::Builder>()?;
+    let shift = |ts: u64| (ts as Nanos + shift_ns) as u64;
+
+    // The builder's getters take it by value, so the old values are read from the
+    // original event
+    shifted.set_log_mono_time(shift(event.get_log_mono_time()));
+    match (event.which()?, shifted.which()?) {
+        (event::RoadEncodeIdx(idx), event::RoadEncodeIdx(new_idx))
+        | (event::WideRoadEncodeIdx(idx), event::WideRoadEncodeIdx(new_idx))
+        | (event::DriverEncodeIdx(idx), event::DriverEncodeIdx(new_idx))
+        | (event::QRoadEncodeIdx(idx), event::QRoadEncodeIdx(new_idx)) => {
+            let (idx, mut new_idx) = (idx?, new_idx?);
+            new_idx.set_segment_num(idx.get_segment_num() + shift_segments);
+            new_idx.set_timestamp_sof(shift(idx.get_timestamp_sof()));
+            new_idx.set_timestamp_eof(shift(idx.get_timestamp_eof()));
+        }
+        (event::RoadCameraState(state), event::RoadCameraState(new_state))
+        | (event::WideRoadCameraState(state), event::WideRoadCameraState(new_state))
+        | (event::DriverCameraState(state), event::DriverCameraState(new_state)) => {
+            let (state, mut new_state) = (state?, new_state?);
+            new_state.set_timestamp_sof(shift(state.get_timestamp_sof()));
+            new_state.set_timestamp_eof(shift(state.get_timestamp_eof()));
+        }
+        (event::Thumbnail(thumbnail), event::Thumbnail(new_thumbnail)) => {
+            new_thumbnail?.set_timestamp_eof(shift(thumbnail?.get_timestamp_eof()));
+        }
+        _ => (),
+    }
+    Ok(message)
+}
+
+// Copy a segment's log to out_path with the same compression, shifting each
+// event (see shift_event()) by shift_segments segments of segment_nanos each.
+// A sentinel of the type in replace_sentinel is changed to the other type, or
+// left out if that's None, so the joined route only has one start and end.
+fn copy_log(
+    path: &Path,
+    compression: LogCompression,
+    out_path: PathBuf,
+    shift_segments: i64,
+    segment_nanos: Nanos,
+    replace_sentinel: Option<(SentinelType, Option<SentinelType>)>,
+) -> Result<()> {
+    let mut writer = QlogWriter::new(out_path, compression)?;
+    for message in read_log_events(path)? {
+        let message = message?;
+        let event = message.get_root::<event::Reader>()?;
+        let mut new_sentinel = None;
+        if let (event::Sentinel(sentinel), Some((from, to))) = (event.which()?, replace_sentinel) {
+            if sentinel?.get_type()? == from {
+                match to {
+                    Some(to) => new_sentinel = Some(to),
+                    None => continue,
+                }
+            }
+        }
+        let mut shifted = shift_event(event, shift_segments * segment_nanos, shift_segments as i32)
+            .with_context(|| format!("Failed to copy event from {:?}", path))?;
+        if let Some(sentinel_type) = new_sentinel {
+            let event = shifted.get_root::<event::Builder>()?;
+            event.init_sentinel().set_type(sentinel_type);
+        }
+        writer.write_message(&shifted);
+    }
+    Ok(())
+}
+
+// Copy a whole segment directory to out_dir as segment number + shift_segments.
+// Logs are rewritten, everything else (i.e. the videos) is copied as it is.
+fn copy_segment(
+    segment: &Segment,
+    out_dir: &Path,
+    shift_segments: i64,
+    segment_nanos: Nanos,
+    replace_sentinel: Option<(SentinelType, Option<SentinelType>)>,
+) -> Result<()> {
+    std::fs::create_dir_all(out_dir)?;
+    let mut logs = vec![];
+    for name in LOG_NAMES {
+        if let Some((path, compression)) = find_log(&segment.path, name) {
+            let out_path = out_dir.join(path.file_name().unwrap());
+            copy_log(
+                &path,
+                compression,
+                out_path,
+                shift_segments,
+                segment_nanos,
+                replace_sentinel,
+            )?;
+            logs.push(path);
+        }
+    }
+    if logs.is_empty() {
+        bail!("Segment {:?} has no rlog", segment.path);
+    }
+
+    for entry in std::fs::read_dir(&segment.path)? {
+        let path = entry?.path();
+        if path.is_file() && !logs.contains(&path) {
+            let out_path = out_dir.join(path.file_name().unwrap());
+            std::fs::copy(&path, &out_path)
+                .with_context(|| format!("Failed to copy {:?} to {:?}", path, out_path))?;
+        }
+    }
+    Ok(())
+}
+
+// Join two routes in data_dir into a new route named output. The first route's
+// segments are copied as they are, then the second route's segments are
+// renumbered to carry on straight after the first's last segment, with all
+// their timestamps moved later to match. Any gaps between segments within each
+// route are kept, as each segment's timestamps have to match its number.
+//
+// Only the logs' monotonic timestamps are moved: the wall clock times in the
+// second route's initData events are still the real time it was recorded. Both
+// routes must have segment_nanos long segments.
+pub fn concat_routes(
+    data_dir: &Path,
+    first: &str,
+    second: &str,
+    output: &str,
+    segment_nanos: Nanos,
+) -> Result<()> {
+    let first_segments = route_segments(data_dir, first)?;
+    let second_segments = route_segments(data_dir, second)?;
+    if route_segments(data_dir, output).is_ok() {
+        bail!("Route {} already exists in {:?}", output, data_dir);
+    }
+
+    let last_number = first_segments.last().unwrap().number;
+    let shift_segments = last_number + 1 - second_segments[0].number;
+    info!(
+        "Joining routes {} and {} as {}, with segments {}-{}",
+        first,
+        second,
+        output,
+        first_segments[0].number,
+        second_segments.last().unwrap().number + shift_segments
+    );
+
+    let out_dir = |number: i64| data_dir.join(format!("{output}--{number}"));
+    for segment in &first_segments {
+        // The first route's end is now just the end of a segment
+        let replace_sentinel = (segment.number == last_number)
+            .then_some((SentinelType::EndOfRoute, Some(SentinelType::EndOfSegment)));
+        info!("Copying segment {:?}...", segment.path);
+        copy_segment(
+            segment,
+            &out_dir(segment.number),
+            0,
+            segment_nanos,
+            replace_sentinel,
+        )?;
+    }
+    for segment in &second_segments {
+        let number = segment.number + shift_segments;
+        info!(
+            "Copying segment {:?} as segment {}...",
+            segment.path, number
+        );
+        copy_segment(
+            segment,
+            &out_dir(number),
+            shift_segments,
+            segment_nanos,
+            Some((SentinelType::StartOfRoute, None)),
+        )?;
+    }
+    Ok(())
+}
+
+// Result of checking one segment of a route, see verify_route()
+pub struct SegmentCheck {
+    pub number: i64,
+    pub events: usize,
+    // Video files in the segment, i.e. "fcamera.hevc"
+    pub videos: Vec<&'static str>,
+    pub problems: Vec<String>,
+}
+
+// What's been found in a segment's rlog, see RlogSummary::add()
+#[derive(Default)]
+struct RlogSummary {
+    events: usize,
+    last_ts: u64,
+    // Number of events earlier than the one before them, and the first of these
+    backwards: Option<(usize, u64)>,
+    init_data: bool,
+    sentinels: Vec<SentinelType>,
+    // CAN frames with a src value that QlogWriter never writes, and with an
+    // invalid data length
+    bad_src: usize,
+    bad_len: usize,
+    // Videos with frames in the rlog, and the number of frames with the wrong
+    // segment number
+    encoded: Vec<SegmentVideoKind>,
+    wrong_segment_num: usize,
+}
+
+impl RlogSummary {
+    fn add(&mut self, event: event::Reader, segment_num: i64) -> Result<()> {
+        let ts = event.get_log_mono_time();
+        if self.events > 0 && ts < self.last_ts {
+            self.backwards.get_or_insert((0, ts)).0 += 1;
+        }
+        self.last_ts = ts;
+        self.events += 1;
+
+        let encode_idx = match event.which()? {
+            event::InitData(_) => {
+                self.init_data = true;
+                None
+            }
+            event::Sentinel(sentinel) => {
+                self.sentinels.push(sentinel?.get_type()?);
+                None
+            }
+            event::Can(msgs) | event::Sendcan(msgs) => {
+                for msg in msgs? {
+                    // Values of 128 and up are frames sent (or rejected) by a panda
+                    if msg.get_src() >= 128 {
+                        self.bad_src += 1;
+                    }
+                    if check_data_len(msg.get_dat()?).is_err() {
+                        self.bad_len += 1;
+                    }
+                }
+                None
+            }
+            event::RoadEncodeIdx(idx) => Some((SegmentVideoKind::FCamera, idx?)),
+            event::QRoadEncodeIdx(idx) => Some((SegmentVideoKind::QCamera, idx?)),
+            event::WideRoadEncodeIdx(idx) => Some((SegmentVideoKind::ECamera, idx?)),
+            event::DriverEncodeIdx(idx) => Some((SegmentVideoKind::DCamera, idx?)),
+            _ => None,
+        };
+        if let Some((kind, idx)) = encode_idx {
+            if !self.encoded.contains(&kind) {
+                self.encoded.push(kind);
+            }
+            if idx.get_segment_num() as i64 != segment_num {
+                self.wrong_segment_num += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn problems(&self, first: bool, last: bool) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some((count, ts)) = self.backwards {
+            problems.push(format!(
+                "{} events have an earlier timestamp than the event before them (first at {:.3}s)",
+                count,
+                ts as f64 / 1e9
+            ));
+        }
+        if !self.init_data {
+            problems.push("No initData event".to_string());
+        }
+
+        // The first segment also starts the route, and the last segment ends it
+        // instead of having an EndOfSegment
+        let mut expected = vec![];
+        if first {
+            expected.push(SentinelType::StartOfRoute);
+        }
+        expected.push(SentinelType::StartOfSegment);
+        expected.push(match last {
+            true => SentinelType::EndOfRoute,
+            false => SentinelType::EndOfSegment,
+        });
+        if self.sentinels != expected {
+            problems.push(format!(
+                "Sentinels are {:?}, expected {:?}",
+                self.sentinels, expected
+            ));
+        }
+
+        if self.bad_src > 0 {
+            problems.push(format!(
+                "{} CAN frames have a src value of 128 or more",
+                self.bad_src
+            ));
+        }
+        if self.bad_len > 0 {
+            problems.push(format!(
+                "{} CAN frames have an invalid data length",
+                self.bad_len
+            ));
+        }
+        if self.wrong_segment_num > 0 {
+            problems.push(format!(
+                "{} video frames have the wrong segment number",
+                self.wrong_segment_num
+            ));
+        }
+        problems
+    }
+}
+
+fn verify_segment(segment: &Segment, first: bool, last: bool) -> SegmentCheck {
+    let mut rlog = RlogSummary::default();
+    let mut problems = vec![];
+    match find_log(&segment.path, "rlog") {
+        Some((path, _)) => {
+            let result = read_log_events(&path).and_then(|events| {
+                for message in events {
+                    rlog.add(message?.get_root()?, segment.number)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                problems.push(format!(
+                    "Failed to read rlog after {} events: {:#}",
+                    rlog.events, e
+                ));
+            }
+        }
+        None => problems.push("No rlog".to_string()),
+    }
+    problems.extend(rlog.problems(first, last));
+
+    // Each video should have its frames in the rlog, and the other way around
+    let mut videos = vec![];
+    for kind in SegmentVideoKind::ALL {
+        let name = kind.file_name();
+        let has_file = segment.path.join(name).is_file();
+        let has_frames = rlog.encoded.contains(&kind);
+        if has_file {
+            videos.push(name);
+        }
+        if has_file && !has_frames {
+            problems.push(format!("Has {} but no frames for it in the rlog", name));
+        } else if has_frames && !has_file {
+            problems.push(format!("Has frames in the rlog for {} but no video", name));
+        }
+    }
+
+    SegmentCheck {
+        number: segment.number,
+        events: rlog.events,
+        videos,
+        problems,
+    }
+}
+
+// Check that each segment of a route in data_dir is well formed, as far as
+// Cabana and openpilot's tools are concerned: the rlog can be read, its
+// timestamps never go backwards, it has initData and the expected sentinels,
+// its CAN frames are valid, and its video frames match the videos in the
+// segment directory. This is meant for routes written by make_cabana_route, so
+// a CAN src value for a frame sent by a panda is reported as a problem.
+pub fn verify_route(data_dir: &Path, route: &str) -> Result<Vec<SegmentCheck>> {
+    let segments = route_segments(data_dir, route)?;
+    let last_idx = segments.len() - 1;
+    let mut checks: Vec<SegmentCheck> = segments
+        .iter()
+        .enumerate()
+        .map(|(idx, segment)| verify_segment(segment, idx == 0, idx == last_idx))
+        .collect();
+    if segments[0].number != 0 {
+        checks[0]
+            .problems
+            .push("Route doesn't start from segment 0".to_string());
+    }
+    Ok(checks)
+}
+
+// Write the CAN frames in a route's rlogs (or only one segment's rlog) to a
+// SavvyCAN CSV file, as read by read_can_messages(). Each frame's timestamp is
+// its time in the route plus ts_offset, written in ts_unit. The timestamps are
+// those of the can events, which group the frames in each 10ms. Returns the
+// number of frames written.
+pub fn dump_route_can(
+    data_dir: &Path,
+    route: &str,
+    segment: Option<i64>,
+    ts_offset: Nanos,
+    ts_unit: TsUnit,
+    writer: impl Write,
+) -> Result<usize> {
+    let mut segments = route_segments(data_dir, route)?;
+    if let Some(number) = segment {
+        segments.retain(|s| s.number == number);
+        if segments.is_empty() {
+            bail!("Route {} has no segment {}", route, number);
+        }
+    }
+
+    let mut csv = csv::WriterBuilder::new().flexible(true).from_writer(writer);
+    csv.write_record([
+        "Time Stamp",
+        "ID",
+        "Extended",
+        "Dir",
+        "Bus",
+        "LEN",
+        "D1",
+        "D2",
+        "D3",
+        "D4",
+        "D5",
+        "D6",
+        "D7",
+        "D8",
+    ])?;
+
+    let mut count = 0;
+    for segment in &segments {
+        let Some((rlog, _)) = find_log(&segment.path, "rlog") else {
+            bail!("No rlog in {:?}", segment.path);
+        };
+        for message in read_log_events(&rlog)? {
+            let message = message?;
+            let event = message.get_root::<event::Reader>()?;
+            let (msgs, dir) = match event.which()? {
+                event::Can(msgs) => (msgs?, "Rx"),
+                event::Sendcan(msgs) => (msgs?, "Tx"),
+                _ => continue,
+            };
+            let timestamp = ts_unit.format_nanos(event.get_log_mono_time() as Nanos + ts_offset);
+            for msg in msgs {
+                let address = msg.get_address();
+                let data = msg.get_dat()?;
+                let mut row = vec![
+                    timestamp.clone(),
+                    format!("{:08X}", address),
+                    (address > 0x7FF).to_string(),
+                    dir.to_string(),
+                    // Remove the panda's sent or rejected flags, if any
+                    (msg.get_src() % 64).to_string(),
+                    data.len().to_string(),
+                ];
+                row.extend(data.iter().map(|d| format!("{:02X}", d)));
+                csv.write_record(&row)?;
+                count += 1;
+            }
+        }
+    }
+    csv.flush()?;
+    Ok(count)
+}
@@ -0,0 +1,154 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Summary statistics for a route's CAN messages, written as JSON so quality
+// checks can be scripted across many converted logs.
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::input::{Alert, CANMessage, LogInput};
+use crate::Nanos;
+
+#[derive(Serialize, Debug)]
+pub struct RouteStats {
+    pub duration_s: f64,
+    pub messages: usize,
+    pub buses: Vec<BusStats>,
+    pub ids: Vec<IdStats>,
+    // Alerts for gaps in the CAN messages, as per find_missing_can_messages()
+    pub gaps: Vec<Alert>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BusStats {
+    pub bus: u8,
+    pub messages: usize,
+    pub rate_hz: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IdStats {
+    pub bus: u8,
+    pub can_id: u32,
+    pub messages: usize,
+    pub rate_hz: f64,
+    // Shortest and longest time between two messages, None if only one message
+    pub min_interval_ms: Option<f64>,
+    pub max_interval_ms: Option<f64>,
+}
+
+// Rate of count messages over the route, 0 if the route has no duration
+fn rate(count: usize, duration_s: f64) -> f64 {
+    if duration_s > 0.0 {
+        count as f64 / duration_s
+    } else {
+        0.0
+    }
+}
+
+// Messages must be sorted by timestamp
+pub fn route_stats(messages: &[CANMessage], gaps: &[Alert]) -> RouteStats {
+    let duration_s = messages.last().map(|m| m.timestamp()).unwrap_or(0) as f64 / 1e9;
+
+    let mut timestamps: BTreeMap<(u8, u32), Vec<Nanos>> = BTreeMap::new();
+    for m in messages {
+        timestamps
+            .entry((m.bus_no, m.can_id))
+            .or_default()
+            .push(m.timestamp());
+    }
+
+    let mut bus_counts: BTreeMap<u8, usize> = BTreeMap::new();
+    let ids = timestamps
+        .into_iter()
+        .map(|((bus, can_id), timestamps)| {
+            *bus_counts.entry(bus).or_default() += timestamps.len();
+            let intervals = || timestamps.windows(2).map(|w| w[1] - w[0]);
+            IdStats {
+                bus,
+                can_id,
+                messages: timestamps.len(),
+                rate_hz: rate(timestamps.len(), duration_s),
+                min_interval_ms: intervals().min().map(|i| i as f64 / 1e6),
+                max_interval_ms: intervals().max().map(|i| i as f64 / 1e6),
+            }
+        })
+        .collect();
+
+    RouteStats {
+        duration_s,
+        messages: messages.len(),
+        buses: bus_counts
+            .into_iter()
+            .map(|(bus, messages)| BusStats {
+                bus,
+                messages,
+                rate_hz: rate(messages, duration_s),
+            })
+            .collect(),
+        ids,
+        gaps: gaps.to_vec(),
+    }
+}
+
+// Counts of CAN messages and video frames in each second of a route, to check
+// that the video and CAN logs line up. A second with frames but no CAN
+// messages (or the other way around) usually means one of them is offset.
+#[derive(Debug, Default)]
+pub struct AlignmentReport {
+    // (CAN messages, frames) for each second with any inputs
+    seconds: BTreeMap<Nanos, (usize, usize)>,
+    can_messages: usize,
+    frames: usize,
+}
+
+impl AlignmentReport {
+    pub fn add(&mut self, input: &LogInput) {
+        let counts = match input {
+            LogInput::CAN(_) => (1, 0),
+            LogInput::Frame(_) => (0, 1),
+            _ => return,
+        };
+        let second = input.timestamp().div_euclid(1_000_000_000);
+        let (can_messages, frames) = self.seconds.entry(second).or_default();
+        *can_messages += counts.0;
+        *frames += counts.1;
+        self.can_messages += counts.0;
+        self.frames += counts.1;
+    }
+
+    // Write the report as CSV, with a row for every second from the first input
+    // to the last. Returns the number of seconds with a problem. These are only
+    // reported if the route has both CAN messages and frames.
+    pub fn write_csv<W: Write>(&self, writer: W) -> Result<usize> {
+        let mut csv = csv::Writer::from_writer(writer);
+        csv.write_record(["second", "can_messages", "frames", "problem"])?;
+        let check = self.can_messages > 0 && self.frames > 0;
+        let mut problems = 0;
+        let first = self.seconds.keys().next().copied().unwrap_or(0);
+        let last = self.seconds.keys().last().copied().unwrap_or(-1);
+        for second in first..=last {
+            let (can_messages, frames) = self.seconds.get(&second).copied().unwrap_or_default();
+            let problem = match (can_messages, frames) {
+                (0, 0) => "",
+                (0, _) if check => "frames without CAN",
+                (_, 0) if check => "CAN without frames",
+                _ => "",
+            };
+            if !problem.is_empty() {
+                problems += 1;
+            }
+            csv.write_record([
+                second.to_string(),
+                can_messages.to_string(),
+                frames.to_string(),
+                problem.to_string(),
+            ])?;
+        }
+        csv.flush().context("Failed to write alignment report")?;
+        Ok(problems)
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Automatic alignment of a video with a CAN log, by finding the time lag where a
+// series of values from the video (i.e. amount of motion in each frame) best
+// matches a series of values from the log (i.e. decoded vehicle speed).
+use crate::Nanos;
+
+// Fewer overlapping samples than this aren't enough to trust a correlation
+const MIN_OVERLAP_BINS: usize = 10;
+
+// Average the samples in each bin of step length from start, None for any bin
+// with no samples
+fn resample(samples: &[(Nanos, f64)], start: Nanos, step: Nanos, bins: usize) -> Vec<Option<f64>> {
+    let mut sums = vec![(0.0, 0usize); bins];
+    for (ts, value) in samples {
+        let bin = ((ts - start) / step) as usize;
+        if let Some((sum, count)) = sums.get_mut(bin) {
+            *sum += value;
+            *count += 1;
+        }
+    }
+    sums.into_iter()
+        .map(|(sum, count)| (count > 0).then(|| sum / count as f64))
+        .collect()
+}
+
+// Pearson correlation coefficient of the pairs where both values are present
+fn correlation(pairs: impl Iterator<Item = (f64, f64)> + Clone) -> Option<f64> {
+    let n = pairs.clone().count();
+    if n < MIN_OVERLAP_BINS {
+        return None;
+    }
+    let (sum_a, sum_b) = pairs
+        .clone()
+        .fold((0.0, 0.0), |(sa, sb), (a, b)| (sa + a, sb + b));
+    let (mean_a, mean_b) = (sum_a / n as f64, sum_b / n as f64);
+
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (a, b) in pairs {
+        cov += (a - mean_a) * (b - mean_b);
+        var_a += (a - mean_a) * (a - mean_a);
+        var_b += (b - mean_b) * (b - mean_b);
+    }
+    if var_a == 0.0 || var_b == 0.0 {
+        // One series is constant, i.e. the car never moved
+        return None;
+    }
+    Some(cov / (var_a * var_b).sqrt())
+}
+
+// Find how far video_samples should be moved (a multiple of step, up to
+// max_lag in either direction) to best match can_samples. Returns the lag and
+// its correlation, or None if the two series don't overlap enough to tell.
+pub fn find_sync_lag(
+    can_samples: &[(Nanos, f64)],
+    video_samples: &[(Nanos, f64)],
+    max_lag: Nanos,
+    step: Nanos,
+) -> Option<(Nanos, f64)> {
+    let all_ts = || can_samples.iter().chain(video_samples).map(|(ts, _)| *ts);
+    let start = all_ts().min()?;
+    let bins = ((all_ts().max()? - start) / step) as usize + 1;
+
+    let can_bins = resample(can_samples, start, step, bins);
+    let video_bins = &resample(video_samples, start, step, bins);
+
+    let max_shift = (max_lag / step) as isize;
+    (-max_shift..=max_shift)
+        .filter_map(|shift| {
+            // Compare each CAN bin with the video bin that would move into it
+            let pairs = can_bins.iter().enumerate().filter_map(move |(idx, can)| {
+                let video_idx = usize::try_from(idx as isize - shift).ok()?;
+                Some(((*can)?, (*video_bins.get(video_idx)?)?))
+            });
+            correlation(pairs).map(|c| (shift as Nanos * step, c))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+}
@@ -1,6 +1,6 @@
 // Copyright (c) 2023 Angus Gratton
 // SPDX-License-Identifier: GPL-2.0-or-later
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use ffmpeg::ffi::AVPixelFormat;
 use ffmpeg::filter;
 use ffmpeg::format::Pixel;
@@ -8,7 +8,12 @@ use ffmpeg::{
     codec, decoder, encoder, format, frame, media, software::scaling, Dictionary, Packet, Rational,
 };
 use jpeg_encoder;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::{debug, info, warn};
 
 const TARGET_FPS: u32 = 20;
 
@@ -16,6 +21,9 @@ const TARGET_FRAME_NS: i64 = 1_000_000_000i64 / TARGET_FPS as i64;
 
 const JPEG_QUALITY: u8 = 80;
 
+// Frames are scaled down to this width to estimate motion
+const MOTION_WIDTH: u32 = 64;
+
 // TODO: consider making these runtime configurable
 const JPEG_MAX_WIDTH: u32 = 640;
 /// Maximum width of an embedded JPEG thumbnail
@@ -26,76 +34,260 @@ struct FilterGraph {
     graph: ffmpeg::filter::graph::Graph,
 }
 
+// Size of openpilot's low resolution qcamera video
+const QCAMERA_WIDTH: u32 = 526;
+const QCAMERA_HEIGHT: u32 = 330;
+
+// The cameras that a route can have video from, the same as openpilot's
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Camera {
+    Road,
+    Wide,
+    Driver,
+}
+
+// Video files written for each route segment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentVideoKind {
+    // Road camera video in HEVC, up to VIDEO_MAX_WIDTH wide, used by native Cabana
+    FCamera,
+    // Low resolution H.264 road camera video in MPEG-TS, used by comma connect's web replay
+    QCamera,
+    // Wide road camera video in HEVC
+    ECamera,
+    // Driver camera video in HEVC
+    DCamera,
+}
+
+impl SegmentVideoKind {
+    pub const ALL: [SegmentVideoKind; 4] = [
+        SegmentVideoKind::FCamera,
+        SegmentVideoKind::QCamera,
+        SegmentVideoKind::ECamera,
+        SegmentVideoKind::DCamera,
+    ];
+
+    // Videos to write for a camera. qcamera is only for the road camera.
+    pub fn for_camera(camera: Camera, qcamera: bool) -> Vec<Self> {
+        match camera {
+            Camera::Road if qcamera => vec![SegmentVideoKind::FCamera, SegmentVideoKind::QCamera],
+            Camera::Road => vec![SegmentVideoKind::FCamera],
+            Camera::Wide => vec![SegmentVideoKind::ECamera],
+            Camera::Driver => vec![SegmentVideoKind::DCamera],
+        }
+    }
+
+    pub fn camera(&self) -> Camera {
+        match self {
+            SegmentVideoKind::FCamera | SegmentVideoKind::QCamera => Camera::Road,
+            SegmentVideoKind::ECamera => Camera::Wide,
+            SegmentVideoKind::DCamera => Camera::Driver,
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            SegmentVideoKind::FCamera => "fcamera.hevc",
+            SegmentVideoKind::QCamera => "qcamera.ts",
+            SegmentVideoKind::ECamera => "ecamera.hevc",
+            SegmentVideoKind::DCamera => "dcamera.hevc",
+        }
+    }
+}
+
 pub struct SegmentVideoEncoder {
     octx: format::context::Output,
-    encoder: encoder::Video,
+    // None if the source video's packets are copied instead, see
+    // SourceVideo::set_remux()
+    encoder: Option<encoder::Video>,
     video_stream_index: usize,
+    // Time base of the packets written, the encoder's or the source stream's
+    time_base: Rational,
+    // Scales source frames to the output size, if it's different
+    scaler: Option<scaling::Context>,
     frame_count: usize,
     pkt_count: usize,
 }
 
 impl SegmentVideoEncoder {
-    pub fn new(path: &Path, properties: &VideoProperties, dump_info: bool) -> Result<Self> {
+    pub fn new(
+        path: &Path,
+        properties: &VideoProperties,
+        kind: SegmentVideoKind,
+        dump_info: bool,
+    ) -> Result<Self> {
         let mut octx = format::output(path)
             .with_context(|| format!("Failed to create output context for {:?}", path))?;
+        let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
 
         let mut ost = octx.add_stream()?;
         let video_stream_index = ost.index();
 
-        let codec = encoder::find(codec::Id::HEVC).context("Failed to find HEVC codec")?;
+        // The HEVC videos can have the source video's packets copied into them
+        // as-is, but qcamera is always a smaller H.264 video
+        let remux = properties.remux.as_ref();
+        if let (Some(parameters), false) = (remux, kind == SegmentVideoKind::QCamera) {
+            ost.set_parameters(parameters.clone());
+            info!("Copying segment video to {}...", path.display());
+            if dump_info {
+                format::context::output::dump(&octx, 0, path.to_str());
+            }
+            octx.write_header()
+                .with_context(|| format!("Failed to write {:?} header", path))?;
+            return Ok(Self {
+                octx,
+                encoder: None,
+                video_stream_index,
+                time_base: properties.stream_time_base,
+                scaler: None,
+                frame_count: 0,
+                pkt_count: 0,
+            });
+        }
+
+        let (codec_id, width, height, pixel_format) = match kind {
+            SegmentVideoKind::FCamera | SegmentVideoKind::ECamera | SegmentVideoKind::DCamera => (
+                codec::Id::HEVC,
+                properties.out_width,
+                properties.out_height,
+                properties.format,
+            ),
+            SegmentVideoKind::QCamera => (
+                codec::Id::H264,
+                QCAMERA_WIDTH,
+                QCAMERA_HEIGHT,
+                Pixel::YUV420P,
+            ),
+        };
+
+        let codec = encoder::find(codec_id)
+            .with_context(|| format!("Failed to find {:?} codec", codec_id))?;
         let mut video = codec::Encoder::new(codec)
-            .context("Failed to instantiate HEVC Codec")?
+            .with_context(|| format!("Failed to instantiate {:?} Codec", codec_id))?
             .video()
             .context("Failed to get video from Codec")?;
 
-        video.set_width(properties.out_width);
-        video.set_height(properties.out_height);
-        video.set_format(properties.format);
+        video.set_width(width);
+        video.set_height(height);
+        video.set_format(pixel_format);
         video.set_frame_rate(Some(Rational::new(TARGET_FPS as i32, 1)));
         video.set_colorspace(properties.color_space);
         video.set_color_range(properties.color_range);
 
-        // This time base seems to be required by HEVC, but unsure how it's supposed
-        // to be set
-        if let Some(time_base) = properties.time_base {
-            video.set_time_base(Some(time_base.invert()));
-        } else {
-            video.set_time_base(Some(Rational::new(30000, 1)));
-        }
-        video.set_flags(codec::Flags::GLOBAL_HEADER);
+        let mut opts = Dictionary::new();
+        let time_base = match kind {
+            SegmentVideoKind::FCamera | SegmentVideoKind::ECamera | SegmentVideoKind::DCamera => {
+                opts.set("preset", "medium"); // default is medium. TODO: make configurable?
 
-        eprintln!("Writing segment video to {}...", path.display());
+                // Disabling b-frames and setting keyframe interval to 30
+                // frames produces videos with the same properties as comma.ai's camera
+                // videos: one packet per frame and keyframes every 1.5 seconds. One
+                // packet per frame is necessary for openpilot compatibility.
+                opts.set("x265-params", "keyint=30:bframes=0:open-gop=0");
 
-        let mut x265_opts = Dictionary::new();
-        x265_opts.set("preset", "medium"); // default is medium. TODO: make configurable?
+                // This time base seems to be required by HEVC, but unsure how it's supposed
+                // to be set
+                properties
+                    .time_base
+                    .map(|time_base| time_base.invert())
+                    .unwrap_or(Rational::new(30000, 1))
+            }
+            SegmentVideoKind::QCamera => {
+                // Same GOP structure as the HEVC video, but quick to encode as
+                // quality doesn't matter much at this size
+                opts.set("preset", "veryfast");
+                opts.set("x264-params", "keyint=30:bframes=0:open-gop=0");
+
+                // Source frames keep the pts from the source video stream
+                properties.stream_time_base
+            }
+        };
+        video.set_time_base(Some(time_base));
+        // Keyframes forced by send_frame() are IDR frames, so nothing before them is
+        // needed to decode the segment
+        opts.set("forced-idr", "1");
+        // Only set if the container needs them, otherwise the codec headers are
+        // repeated in the stream (needed for raw .hevc files)
+        if global_header {
+            video.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+
+        info!("Writing segment video to {}...", path.display());
 
-        // Disabling b-frames and setting keyframe interval to 30
-        // frames produces videos with the same properties as comma.ai's camera
-        // videos: one packet per frame and keyframes every 1.5 seconds. One
-        // packet per frame is necessary for openpilot compatibility.
-        x265_opts.set("x265-params", "keyint=30:bframes=0");
         let encoder = video
-            .open_with(x265_opts)
-            .expect("error opening HEVC encoder");
+            .open_with(opts)
+            .with_context(|| format!("Failed to open {:?} encoder", codec_id))?;
         ost.set_parameters(encoder.parameters());
 
+        let scaler = (width != properties.out_width
+            || height != properties.out_height
+            || pixel_format != properties.format)
+            .then(|| {
+                scaling::Context::get(
+                    properties.format,
+                    properties.out_width,
+                    properties.out_height,
+                    pixel_format,
+                    width,
+                    height,
+                    scaling::Flags::BILINEAR,
+                )
+            })
+            .transpose()
+            .context("Failed to initialize video scaler context")?;
+
         if dump_info {
             format::context::output::dump(&octx, 0, path.to_str());
         }
-        octx.write_header().context("Failed to write HEVC header")?;
+        octx.write_header()
+            .with_context(|| format!("Failed to write {:?} header", codec_id))?;
 
         Ok(Self {
             octx,
-            encoder,
+            encoder: Some(encoder),
             video_stream_index,
+            time_base,
+            scaler,
             frame_count: 0,
             pkt_count: 0,
         })
     }
 
     pub fn send_frame(&mut self, frame: &SourceFrame) -> Result<()> {
-        self.encoder
-            .send_frame(&frame.frame)
+        let Some(encoder) = &mut self.encoder else {
+            // Remuxing, the frame's packet is written with its pts (which is moved
+            // the same as the frame's for any padding) in the source time base
+            let mut packet = frame
+                .packet
+                .clone()
+                .context("Source frame has no packet to copy")?;
+            packet.set_pts(frame.frame.pts());
+            packet.set_dts(frame.frame.pts());
+            self.write_packet(&mut packet)?;
+            self.frame_count += 1;
+            return Ok(());
+        };
+        let mut scaled = match &mut self.scaler {
+            Some(scaler) => {
+                let mut scaled = frame::Video::empty();
+                scaler
+                    .run(&frame.frame, &mut scaled)
+                    .context("Failed to scale frame for encoder")?;
+                scaled.set_pts(frame.frame.pts());
+                Some(scaled)
+            }
+            None => None,
+        };
+        // Each segment starts with a keyframe, otherwise seeking to the start of a
+        // segment shows garbage until the next keyframe
+        if self.frame_count == 0 {
+            scaled
+                .get_or_insert_with(|| frame.frame.clone())
+                .set_kind(ffmpeg::picture::Type::I);
+        }
+        encoder
+            .send_frame(scaled.as_ref().unwrap_or(&frame.frame))
             .context("Failed to send frame to encoder")?;
         self.receive_packets()
             .context("Failed to read input video packets")?;
@@ -105,18 +297,35 @@ impl SegmentVideoEncoder {
 
     fn receive_packets(&mut self) -> Result<()> {
         let mut encoded = Packet::empty();
-        while self.encoder.receive_packet(&mut encoded).is_ok() {
-            self.pkt_count += 1;
-            encoded.set_stream(self.video_stream_index);
-            encoded.write(&mut self.octx)
-                .context("failed to write to encoder")?;
+        while let Some(encoder) = &mut self.encoder {
+            if encoder.receive_packet(&mut encoded).is_err() {
+                break;
+            }
+            self.write_packet(&mut encoded)?;
         }
 
         Ok(())
     }
 
+    fn write_packet(&mut self, packet: &mut Packet) -> Result<()> {
+        let stream_time_base = self
+            .octx
+            .stream(self.video_stream_index)
+            .context("Missing output video stream")?
+            .time_base();
+        self.pkt_count += 1;
+        packet.set_stream(self.video_stream_index);
+        // The muxer may have chosen a different time base when writing the header
+        packet.rescale_ts(self.time_base, stream_time_base);
+        packet.write(&mut self.octx)
+            .context("failed to write to encoder")?;
+        Ok(())
+    }
+
     pub fn finish(mut self) -> Result<()> {
-        self.encoder.send_eof().context("Failed to send EOF")?;
+        if let Some(encoder) = &mut self.encoder {
+            encoder.send_eof().context("Failed to send EOF")?;
+        }
         self.receive_packets()
             .context("Failed to receive final packets")?;
         self.octx
@@ -130,6 +339,17 @@ pub struct SourceVideo {
     video_file: PathBuf,
     ictx: format::context::Input,
     video_stream_index: usize,
+    // Time base of the video stream's packet and frame timestamps
+    time_base: Rational,
+    camera: Camera,
+    // Any more ffmpeg filters to apply to each frame, after it's scaled and rotated
+    filters: Vec<String>,
+    // If set, frames are timestamped at this constant rate instead of from their pts
+    fps: Option<f64>,
+    // See TimestampCheck
+    monotonize: bool,
+    // See set_remux()
+    remux: bool,
 }
 
 // It's hard to borrow the source ffmpeg Video struct for each encoding session, as
@@ -137,23 +357,53 @@ pub struct SourceVideo {
 //
 // Hence, make this little wrapper struct to copy around the key properties of
 // the source video and use for each segment.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct VideoProperties {
     out_height: u32,
     out_width: u32,
     format: format::Pixel,
     time_base: Option<Rational>,
+    // Time base of the source frames' pts
+    stream_time_base: Rational,
     color_space: ffmpeg::color::Space,
     color_range: ffmpeg::color::Range,
+    // Source stream's codec parameters, if its packets are copied into the segment
+    // videos
+    remux: Option<codec::Parameters>,
+}
+
+// Summary of a video stream, as recorded in its container
+#[derive(Clone, Debug)]
+pub struct VideoInfo {
+    // 0 if the container doesn't record the number of frames
+    pub frames: i64,
+    pub fps: f64,
+    pub duration_ns: i64,
+}
+
+// Where a segment's video starts when the source packets are copied into it.
+// A copied video has to start with a keyframe, so the frames in the segment
+// before its first keyframe are left out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemuxSegmentStart {
+    pub segment: i64,
+    // Timestamp of the segment video's first frame, None if the segment has no
+    // keyframe at all
+    pub ts_ns: Option<i64>,
+    pub skipped_frames: usize,
 }
 
 pub struct SourceFrame {
     pub frame: frame::Video,
     pub ts_ns: i64,
+    pub camera: Camera,
+    // The frame's packet from the source video, if it's being remuxed (None for
+    // padding frames)
+    pub packet: Option<Packet>,
 }
 
 impl SourceVideo {
-    pub fn new(video_file: &Path) -> Result<Self> {
+    pub fn new(video_file: &Path, camera: Camera) -> Result<Self> {
         let ictx = format::input(video_file)
             .with_context(|| format!("Failed to open video file {:?}", video_file))?;
         let input = ictx
@@ -162,14 +412,124 @@ impl SourceVideo {
             .ok_or(ffmpeg::Error::StreamNotFound)
             .with_context(|| format!("Video file {:?} contained no video streams", video_file))?;
         let video_stream_index = input.index();
+        let time_base = input.time_base();
+        if time_base.numerator() <= 0 || time_base.denominator() <= 0 {
+            return Err(anyhow!(
+                "Video file {:?} has invalid time base {}/{}",
+                video_file,
+                time_base.numerator(),
+                time_base.denominator()
+            ));
+        }
 
         Ok(Self {
             ictx,
             video_stream_index,
             video_file: video_file.to_path_buf(),
+            time_base,
+            camera,
+            filters: vec![],
+            fps: None,
+            monotonize: false,
+            remux: false,
         })
     }
 
+    // Timestamp each frame as if the video has a constant frame rate of fps, for
+    // a video with pts that are missing or wrong (i.e. a camera that misreports
+    // its frame rate, so the video drifts against the CAN log)
+    pub fn set_fps(&mut self, fps: f64) {
+        self.fps = Some(fps);
+    }
+
+    // Correct any frame timestamps that go backwards, by moving the rest of
+    // the video later, instead of only warning about them
+    pub fn set_monotonize(&mut self) {
+        self.monotonize = true;
+    }
+
+    // Whether the video's packets can be copied into the segment videos as they
+    // are, instead of decoding and re-encoding every frame (see set_remux()). It
+    // has to be HEVC already, no wider than the segment videos, not rotated or
+    // filtered, and without B-frames, as openpilot needs each packet to be one
+    // frame in display order.
+    pub fn can_remux(&self) -> Result<bool> {
+        let decoder = self.video_decoder()?;
+        Ok(decoder.id() == codec::Id::HEVC
+            && decoder.width() <= VIDEO_MAX_WIDTH
+            && !decoder.has_b_frames()
+            && self.display_rotation()? == 0
+            && self.filters.is_empty())
+    }
+
+    // Copy the video's packets into the segment videos (qcamera is still
+    // encoded). The frames are still decoded for their timestamps and the
+    // thumbnails, but every frame is kept rather than dropping frames down to
+    // TARGET_FPS, as a frame can't be left out without re-encoding the frames
+    // after it. Only call this if can_remux() is true.
+    pub fn set_remux(&mut self) {
+        self.remux = true;
+    }
+
+    // Where each segment's video would start if the packets were copied, for a
+    // video that starts start_ns into the route (see remux_segment_starts()).
+    // This only reads the packets without decoding them, so the timestamps are
+    // from the packets' pts (or set_fps()), without any set_monotonize()
+    // corrections.
+    pub fn remux_segment_starts(
+        &mut self,
+        start_ns: i64,
+        segment_ns: i64,
+    ) -> Result<Vec<RemuxSegmentStart>> {
+        let declared_frame_ns = match self.info()?.fps {
+            fps if fps > 0.0 => 1e9 / fps,
+            _ => TARGET_FRAME_NS as f64,
+        };
+        let mut frames = vec![];
+        for res in self.ictx.packets() {
+            let (stream, packet) =
+                res.with_context(|| format!("Failed to read packets from {:?}", self.video_file))?;
+            if stream.index() != self.video_stream_index {
+                continue;
+            }
+            let index = frames.len() as f64;
+            let ts_ns = match (self.fps, packet.pts()) {
+                (Some(fps), _) => (index * 1e9 / fps).round() as i64,
+                (None, Some(pts)) => pts_to_ns(pts, self.time_base),
+                (None, None) => (index * declared_frame_ns).round() as i64,
+            };
+            frames.push((ts_ns + start_ns, packet.is_key()));
+        }
+        Ok(remux_segment_starts(frames, segment_ns))
+    }
+
+    // Add an ffmpeg filter (i.e. "boxblur=20:3") for video_frames() to apply to
+    // each frame, after any others
+    pub fn add_filter(&mut self, filter_spec: &str) {
+        self.filters.push(filter_spec.to_string());
+    }
+
+    // Open a video that's in memory (i.e. a &[u8]) or some other reader. ffmpeg
+    // can only read from a file, so it's copied to a temporary file which is
+    // removed again as soon as ffmpeg has opened it.
+    pub fn from_reader(mut reader: impl Read, camera: Camera) -> Result<Self> {
+        static TEMP_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+        let temp_path = std::env::temp_dir().join(format!(
+            "make_cabana_route-{}-{}.video",
+            std::process::id(),
+            TEMP_FILE_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut temp_file = std::fs::File::create(&temp_path)
+            .with_context(|| format!("Failed to create temporary video file {:?}", temp_path))?;
+        let copied = std::io::copy(&mut reader, &mut temp_file)
+            .with_context(|| format!("Failed to write temporary video file {:?}", temp_path));
+        drop(temp_file);
+        let result = copied.and_then(|_| Self::new(&temp_path, camera));
+        // ffmpeg keeps the file open, so it can be read after it's removed
+        let _ = std::fs::remove_file(&temp_path);
+        result
+    }
+
     pub fn video_decoder(&self) -> Result<decoder::Video> {
         self.ictx
             .streams()
@@ -185,8 +545,9 @@ impl SourceVideo {
     }
 
     // Didn't have any luck implementing IntoIter for this, but this is kind of better
-    // as more flexible
-    pub fn video_frames(&mut self) -> Result<SourceFrameIterator<'_>> {
+    // as more flexible. Frame timestamps are moved later by start_ns, i.e. if the
+    // route starts before the video.
+    pub fn video_frames(&mut self, start_ns: i64) -> Result<SourceFrameIterator<'_>> {
         let decoder = self.video_decoder()?;
         let props = self.properties()?;
 
@@ -196,9 +557,18 @@ impl SourceVideo {
         if rotate != 0 {
             filter_spec = format!("{},rotate={}*PI/180", filter_spec, rotate);
         }
-        eprintln!("Filter spec: {}", filter_spec);
+        for filter in &self.filters {
+            filter_spec = format!("{},{}", filter_spec, filter);
+        }
+        debug!("Filter spec: {}", filter_spec);
 
         let filter_graph = FilterGraph::new(&decoder, &filter_spec)?;
+        // Frames with no pts are timestamped at the rate the container declares,
+        // or at the target rate if it doesn't declare one
+        let declared_fps = match self.info()?.fps {
+            fps if fps > 0.0 => fps,
+            _ => TARGET_FPS as f64,
+        };
         let packets = self.ictx.packets();
 
         Ok(SourceFrameIterator {
@@ -207,6 +577,92 @@ impl SourceVideo {
             video_stream_index: self.video_stream_index,
             next_frame_ts: 0,
             filter_graph,
+            time_base: self.time_base,
+            start_ns,
+            camera: self.camera,
+            fixed_frame_ns: self.fps.map(|fps| 1e9 / fps),
+            declared_frame_ns: 1e9 / declared_fps,
+            frame_index: 0,
+            timestamp_check: TimestampCheck {
+                monotonize: self.monotonize,
+                last_ts_ns: None,
+                correction_ns: 0,
+            },
+            remux: self.remux,
+            sent_packets: VecDeque::new(),
+        })
+    }
+
+    // Decode all the (rate limited) frames of the video into memory. This
+    // needs a lot of memory for a long video, video_frames() is better if the
+    // frames can be processed one at a time.
+    pub fn read_frames(&mut self) -> Result<Vec<SourceFrame>> {
+        Ok(self.video_frames(0)?.collect())
+    }
+
+    // Estimate how much the picture changes at each frame, as the mean absolute
+    // difference in brightness from the previous frame (on a small greyscale copy
+    // of each frame). This is a cheap stand-in for optical flow magnitude, which
+    // roughly follows the vehicle speed for a forward facing camera.
+    pub fn frame_motion(&mut self, start_ns: i64) -> Result<Vec<(i64, f64)>> {
+        let mut result = vec![];
+        let mut prev: Option<Vec<u8>> = None;
+        for frame in self.video_frames(start_ns)? {
+            let grey = frame.grey_pixels(MOTION_WIDTH)?;
+            if let Some(prev) = &prev {
+                let diff: u64 = grey
+                    .iter()
+                    .zip(prev)
+                    .map(|(a, b)| a.abs_diff(*b) as u64)
+                    .sum();
+                result.push((frame.ts_ns, diff as f64 / grey.len() as f64));
+            }
+            prev = Some(grey);
+        }
+        Ok(result)
+    }
+
+    // Black frames at the target frame rate from timestamp 0 until duration_ns,
+    // to fill the start of the route before the video starts
+    pub fn padding_frames(&self, duration_ns: i64) -> Result<impl Iterator<Item = SourceFrame>> {
+        let time_base = self.time_base;
+        let camera = self.camera;
+        let black = self.properties()?.black_frame()?;
+
+        Ok((0..duration_ns)
+            .step_by(TARGET_FRAME_NS as usize)
+            .map(move |ts_ns| {
+                let mut frame = black.clone();
+                frame.set_pts(Some(ns_to_pts(ts_ns, time_base)));
+                SourceFrame {
+                    frame,
+                    ts_ns,
+                    camera,
+                    packet: None,
+                }
+            }))
+    }
+
+    pub fn info(&self) -> Result<VideoInfo> {
+        let stream = self
+            .ictx
+            .streams()
+            .best(media::Type::Video)
+            .ok_or(ffmpeg::Error::StreamNotFound)?;
+        let rate = stream.avg_frame_rate();
+        let fps = match rate.denominator() {
+            0 => 0.0,
+            den => rate.numerator() as f64 / den as f64,
+        };
+        let duration_ns = match stream.duration() {
+            // Container duration is in microseconds
+            d if d <= 0 => self.ictx.duration().max(0) * 1000,
+            d => pts_to_ns(d, self.time_base),
+        };
+        Ok(VideoInfo {
+            frames: stream.frames(),
+            fps,
+            duration_ns,
         })
     }
 
@@ -237,12 +693,93 @@ impl SourceVideo {
             out_height,
             format,
             time_base: decoder.time_base(),
+            stream_time_base: self.time_base,
             color_space: decoder.color_space(),
             color_range: decoder.color_range(),
+            remux: match self.remux {
+                true => Some(
+                    self.ictx
+                        .stream(self.video_stream_index)
+                        .context("Missing video stream")?
+                        .parameters(),
+                ),
+                false => None,
+            },
         })
     }
 }
 
+impl VideoProperties {
+    // A black frame in the output size and format, made by converting an all-zero
+    // RGB frame so it works for any pixel format
+    fn black_frame(&self) -> Result<frame::Video> {
+        let mut rgb_frame = frame::Video::new(Pixel::RGB24, self.out_width, self.out_height);
+        rgb_frame.data_mut(0).fill(0);
+
+        let mut scaler = scaling::Context::get(
+            Pixel::RGB24,
+            self.out_width,
+            self.out_height,
+            self.format,
+            self.out_width,
+            self.out_height,
+            scaling::Flags::BILINEAR,
+        )
+        .context("Failed to initialize black frame scaler context")?;
+        let mut frame = frame::Video::empty();
+        scaler
+            .run(&rgb_frame, &mut frame)
+            .context("Failed to convert black frame")?;
+        frame.set_color_space(self.color_space);
+        frame.set_color_range(self.color_range);
+        Ok(frame)
+    }
+}
+
+// Where each segment's video starts when the packets are copied, from the
+// timestamp of each frame in the route (in order) and whether it's a keyframe.
+// Frames before the start of the route are left out of the route anyway.
+pub fn remux_segment_starts(
+    frames: impl IntoIterator<Item = (i64, bool)>,
+    segment_ns: i64,
+) -> Vec<RemuxSegmentStart> {
+    let mut starts: Vec<RemuxSegmentStart> = vec![];
+    for (ts_ns, is_key) in frames.into_iter().filter(|(ts_ns, _)| *ts_ns >= 0) {
+        let segment = ts_ns / segment_ns;
+        match starts.last_mut() {
+            Some(start) if start.segment == segment => {
+                if start.ts_ns.is_none() {
+                    if is_key {
+                        start.ts_ns = Some(ts_ns);
+                    } else {
+                        start.skipped_frames += 1;
+                    }
+                }
+            }
+            _ => starts.push(RemuxSegmentStart {
+                segment,
+                ts_ns: is_key.then_some(ts_ns),
+                skipped_frames: usize::from(!is_key),
+            }),
+        }
+    }
+    starts
+}
+
+// Convert a timestamp in the stream's time base to nanoseconds. This is done in
+// one step rather than multiplying by the (rounded) length of one tick, as MP4
+// and MOV time bases like 1/90000 aren't a whole number of nanoseconds and the
+// error adds up over a long video.
+fn pts_to_ns(pts: i64, time_base: Rational) -> i64 {
+    (pts as i128 * time_base.numerator() as i128 * 1_000_000_000 / time_base.denominator() as i128)
+        as i64
+}
+
+fn ns_to_pts(ns: i64, time_base: Rational) -> i64 {
+    (ns as i128 * time_base.denominator() as i128 / (time_base.numerator() as i128 * 1_000_000_000))
+        as i64
+}
+
 // YUVJnnn formats are "deprecated in favour of YUVnnn and setting color_range",
 // so do that to reduce warnings. Note color_range has to be set when using these.
 fn fix_deprecated_pixel_format(format: Pixel) -> Pixel {
@@ -261,6 +798,63 @@ pub struct SourceFrameIterator<'a> {
     video_stream_index: usize,
     filter_graph: FilterGraph,
     next_frame_ts: i64,
+    time_base: Rational,
+    start_ns: i64,
+    camera: Camera,
+    // Interval between frames set with SourceVideo::set_fps(), if any
+    fixed_frame_ns: Option<f64>,
+    // Interval between frames for timestamping any frames with no pts
+    declared_frame_ns: f64,
+    // Number of frames decoded so far, including any dropped ones
+    frame_index: i64,
+    timestamp_check: TimestampCheck,
+    // Keep every frame, with its packet, see SourceVideo::set_remux()
+    remux: bool,
+    // Packets sent to the decoder that haven't come out as frames yet, in order
+    // (there are no B-frames when remuxing)
+    sent_packets: VecDeque<Packet>,
+}
+
+// Finds frame timestamps that go backwards, as some dashcams write at the
+// boundaries between their recordings. Frames from before the previous frame
+// would be dropped until the timestamps catch up again, losing that part of the
+// video.
+struct TimestampCheck {
+    // If set, a timestamp that goes backwards is moved to one frame after the
+    // previous frame, and all the frames after it are moved by the same amount
+    monotonize: bool,
+    last_ts_ns: Option<i64>,
+    // Amount added to the timestamps, for the jumps corrected so far
+    correction_ns: i64,
+}
+
+impl TimestampCheck {
+    // Returns the timestamp to use for the frame
+    fn check(&mut self, ts_ns: i64, frame_ns: f64, frame_index: i64, camera: Camera) -> i64 {
+        let mut ts_ns = ts_ns + self.correction_ns;
+        if let Some(last) = self.last_ts_ns.filter(|last| ts_ns < *last) {
+            let jump = format!(
+                "{:?} camera video timestamps go back {:.3}s at frame {}",
+                camera,
+                (last - ts_ns) as f64 / 1e9,
+                frame_index
+            );
+            if self.monotonize {
+                warn!("{}, moving the rest of the video later", jump);
+                let next = last + frame_ns.round() as i64;
+                self.correction_ns += next - ts_ns;
+                ts_ns = next;
+            } else {
+                warn!(
+                    "{}, frames are dropped until they catch up (pass --monotonize-video to \
+                     move the rest of the video later instead)",
+                    jump
+                );
+            }
+        }
+        self.last_ts_ns = Some(ts_ns);
+        ts_ns
+    }
 }
 
 impl<'a> Iterator for SourceFrameIterator<'a> {
@@ -268,20 +862,42 @@ impl<'a> Iterator for SourceFrameIterator<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut receive_frames = |decoder: &mut decoder::Video| -> Option<Self::Item> {
-            let time_base = decoder.time_base().expect("Video must have time base");
-            let timebase_ns =
-                (time_base.numerator() as i64 * 1_000_000_000) / time_base.denominator() as i64;
             let mut frame = frame::Video::empty();
             for res in self.packets.by_ref() {
                 let (stream, packet) = res.expect("Failed to iterate frames");
                 if stream.index() == self.video_stream_index {
+                    if self.remux {
+                        self.sent_packets.push_back(packet.clone());
+                    }
                     decoder
                         .send_packet(&packet)
                         .expect("Failed to decode frames");
                     if decoder.receive_frame(&mut frame).is_ok() {
-                        let ts_ns = frame.pts().unwrap() * timebase_ns;
+                        let source_packet = self.sent_packets.pop_front();
+                        // Use the timestamp of each frame, as phone videos are often
+                        // variable frame rate. Otherwise, count frames at a constant rate.
+                        let pts = match self.fixed_frame_ns {
+                            Some(_) => None,
+                            None => frame.pts().or(frame.timestamp()),
+                        };
+                        let frame_ns = self.fixed_frame_ns.unwrap_or(self.declared_frame_ns);
+                        let pts_ns = match pts {
+                            Some(pts) => pts_to_ns(pts, self.time_base),
+                            None => (self.frame_index as f64 * frame_ns).round() as i64,
+                        };
+                        let ts_ns = self.timestamp_check.check(
+                            pts_ns,
+                            frame_ns,
+                            self.frame_index,
+                            self.camera,
+                        );
+                        let pts = match pts {
+                            Some(pts) if ts_ns == pts_ns => pts,
+                            _ => ns_to_pts(ts_ns, self.time_base),
+                        };
+                        self.frame_index += 1;
                         // Drop frames as needed to meet the target FPS rate
-                        if ts_ns >= self.next_frame_ts + TARGET_FRAME_NS {
+                        if self.remux || ts_ns >= self.next_frame_ts + TARGET_FRAME_NS {
                             self.next_frame_ts = if self.next_frame_ts == 0 {
                                 ts_ns + TARGET_FRAME_NS
                             } else {
@@ -292,7 +908,14 @@ impl<'a> Iterator for SourceFrameIterator<'a> {
                                 .filter_frame(&mut frame)
                                 .expect("Failed to filter frame");
                             frame.set_format(fix_deprecated_pixel_format(frame.format()));
-                            return Some(Self::Item { frame, ts_ns });
+                            // Keep the frame pts after any padding frames
+                            frame.set_pts(Some(pts + ns_to_pts(self.start_ns, self.time_base)));
+                            return Some(Self::Item {
+                                frame,
+                                ts_ns: ts_ns + self.start_ns,
+                                camera: self.camera,
+                                packet: source_packet,
+                            });
                         }
                     }
                 }
@@ -352,9 +975,42 @@ impl SourceFrame {
     }
 }
 
+impl SourceFrame {
+    // Pixels of a greyscale copy of the frame scaled to width
+    fn grey_pixels(&self, width: u32) -> Result<Vec<u8>> {
+        let width = width.min(self.frame.width());
+        let height = (width * self.frame.height() / self.frame.width()).max(1);
+        let mut scaler = scaling::Context::get(
+            self.frame.format(),
+            self.frame.width(),
+            self.frame.height(),
+            format::Pixel::GRAY8,
+            width,
+            height,
+            scaling::Flags::BILINEAR,
+        )
+        .context("Failed to initialize greyscale scaler context")?;
+
+        let mut grey_frame = frame::Video::empty();
+        scaler
+            .run(&self.frame, &mut grey_frame)
+            .context("Failed to scale video frame to greyscale")?;
+
+        // Rows of the frame data may be padded
+        let stride = grey_frame.stride(0);
+        Ok(grey_frame
+            .data(0)
+            .chunks(stride)
+            .take(height as usize)
+            .flat_map(|row| &row[..width as usize])
+            .copied()
+            .collect())
+    }
+}
+
 impl PartialEq for SourceFrame {
     fn eq(&self, other: &Self) -> bool {
-        self.ts_ns == other.ts_ns && self.frame == other.frame
+        self.ts_ns == other.ts_ns && self.camera == other.camera && self.frame == other.frame
     }
 }
 
@@ -0,0 +1,142 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for alerts shown on the timeline
+use make_cabana_route::input::{
+    expand_alerts, find_silent_can_ids, read_alerts, Alert, AlertStatus, LogInput,
+};
+
+mod common;
+
+use common::{message, test_dir};
+
+// Identical alerts in a row are repeated as one, without starting again
+#[test]
+fn expand_alerts_coalesces_identical() {
+    let alert = |timestamp, message: Option<&str>| Alert {
+        timestamp,
+        status: AlertStatus::Critical,
+        message: message.map(str::to_string),
+    };
+    let alerts = vec![
+        alert(0, Some("gap")),
+        alert(250, Some("gap")),
+        alert(400, None),
+        alert(500, Some("gap")),
+        alert(550, Some("gap")),
+    ];
+    let expanded: Vec<_> = expand_alerts(alerts, 100)
+        .iter()
+        .map(|input| match input {
+            LogInput::Alert(a) => (a.timestamp, a.message.is_some()),
+            _ => panic!("not an alert"),
+        })
+        .collect();
+    assert_eq!(
+        expanded,
+        vec![
+            (0, true),
+            (100, true),
+            (200, true),
+            (300, true),
+            (400, false),
+            (500, true)
+        ]
+    );
+}
+
+// While two IDs are silent at the same time the alert stays active until the
+// second one returns, rather than clearing when the first one does
+#[test]
+fn overlapping_silent_ids_clear_once() {
+    const PERIOD: i64 = 10_000_000;
+    let mut messages = vec![];
+    for i in 0..40 {
+        let timestamp = i * PERIOD;
+        // 0x100 is silent from 100ms to 200ms, 0x200 from 150ms to 300ms
+        if !(11..20).contains(&i) {
+            messages.push(message(timestamp, 0x100, 0, &[1]));
+        }
+        if !(16..30).contains(&i) {
+            messages.push(message(timestamp, 0x200, 0, &[2]));
+        }
+    }
+    let alerts: Vec<_> = find_silent_can_ids(&messages)
+        .into_iter()
+        .map(|a| (a.timestamp, a.status, a.message.is_some()))
+        .collect();
+    assert_eq!(
+        alerts,
+        vec![
+            (100_000_000, AlertStatus::Critical, true),
+            (150_000_000, AlertStatus::Critical, true),
+            (300_000_000, AlertStatus::Normal, false),
+        ]
+    );
+}
+
+fn alert(timestamp: i64, status: AlertStatus, message: Option<&str>) -> Alert {
+    Alert {
+        timestamp,
+        status,
+        message: message.map(str::to_string),
+    }
+}
+
+// CSV timestamps are seconds with a decimal point or whole nanoseconds, the
+// status can be any case, and an empty or missing message ends the alert
+#[test]
+fn read_alerts_csv() {
+    let path = test_dir("read_alerts_csv").join("alerts.csv");
+    std::fs::write(
+        &path,
+        "timestamp,status,message\n\
+         12.5,critical,Overheating\n\
+         5.0,Critical,Before the route\n\
+         20000000000,NORMAL,\n\
+         25.25,user_prompt, Check tyres \n\
+         10000000020,UserPrompt,\n\
+         31.0,Normal\n",
+    )
+    .unwrap();
+
+    // Sorted, with the offset taken off and the alert before it dropped
+    let alerts = read_alerts(&path, 10_000_000_000).unwrap();
+    assert_eq!(
+        alerts,
+        vec![
+            alert(20, AlertStatus::UserPrompt, None),
+            alert(2_500_000_000, AlertStatus::Critical, Some("Overheating")),
+            alert(10_000_000_000, AlertStatus::Normal, None),
+            alert(15_250_000_000, AlertStatus::UserPrompt, Some("Check tyres")),
+            alert(21_000_000_000, AlertStatus::Normal, None),
+        ]
+    );
+
+    std::fs::write(&path, "1.0,Warning,Unknown status\n").unwrap();
+    assert!(read_alerts(&path, 0).is_err());
+}
+
+// JSON timestamps are always nanoseconds, and a null message ends the alert
+#[test]
+fn read_alerts_json() {
+    let path = test_dir("read_alerts_json").join("alerts.json");
+    std::fs::write(
+        &path,
+        r#"[{"timestamp": 20000000000, "status": "Normal", "message": null},
+            {"timestamp": 12500000000, "status": "Critical", "message": "Overheating"},
+            {"timestamp": 15000000000, "status": "UserPrompt", "message": "Check tyres"},
+            {"timestamp": 5000000000, "status": "Critical", "message": "Before the route"}]"#,
+    )
+    .unwrap();
+
+    let alerts = read_alerts(&path, 10_000_000_000).unwrap();
+    assert_eq!(
+        alerts,
+        vec![
+            alert(2_500_000_000, AlertStatus::Critical, Some("Overheating")),
+            alert(5_000_000_000, AlertStatus::UserPrompt, Some("Check tyres")),
+            alert(10_000_000_000, AlertStatus::Normal, None),
+        ]
+    );
+}
@@ -0,0 +1,71 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for reading Vector ASCII logs
+use common::test_dir;
+use make_cabana_route::input::{read_can_log, Direction, LogFormat, ReadOptions};
+
+mod common;
+
+// The same frames logged with each number base
+const HEX_LOG: &str = "date Wed Mar 1 12:00:00.000 pm 2023\n\
+                       base hex  timestamps absolute\n\
+                       internal events logged\n\
+                       // version 13.0.0\n\
+                       Begin Triggerblock Wed Mar 1 12:00:00.000 pm 2023\n\
+                       \x20  0.000000 Start of measurement\n\
+                       \x20  0.001000 1  123             Rx   d 2 DE AD\n\
+                       \x20  0.002000 2  18DAF110x       Tx   d 1 0F\n\
+                       \x20  0.003000 1  7DF             Rx   r 8\n\
+                       \x20  0.004000 CAN 1 Status:chip status error active\n\
+                       End TriggerBlock\n";
+
+const DEC_LOG: &str = "date Wed Mar 1 12:00:00.000 pm 2023\n\
+                       base dec  timestamps absolute\n\
+                       internal events logged\n\
+                       // version 13.0.0\n\
+                       Begin Triggerblock Wed Mar 1 12:00:00.000 pm 2023\n\
+                       \x20  0.000000 Start of measurement\n\
+                       \x20  0.001000 1  291             Rx   d 2 222 173\n\
+                       \x20  0.002000 2  417001744x      Tx   d 1 15\n\
+                       \x20  0.003000 1  2015            Rx   r 8\n\
+                       \x20  0.004000 CAN 1 Status:chip status error active\n\
+                       End TriggerBlock\n";
+
+#[test]
+fn header_and_number_base() {
+    for (name, log) in [("asc-hex", HEX_LOG), ("asc-dec", DEC_LOG)] {
+        let path = test_dir(name).join("log.asc");
+        std::fs::write(&path, log).unwrap();
+        let log = read_can_log(&path, Some(LogFormat::Asc), None, &ReadOptions::default()).unwrap();
+        let messages: Vec<_> = log
+            .messages
+            .iter()
+            .map(|m| {
+                (
+                    m.timestamp,
+                    m.bus_no,
+                    m.can_id,
+                    m.is_extended_id,
+                    m.is_rtr,
+                    m.direction,
+                    m.dlc,
+                    m.data.clone(),
+                )
+            })
+            .collect();
+        let rx = Some(Direction::Rx);
+        let tx = Some(Direction::Tx);
+        assert_eq!(
+            messages,
+            vec![
+                (0, 1, 0x123, false, false, rx, 2, vec![0xDE, 0xAD]),
+                (1_000_000, 2, 0x18DA_F110, true, false, tx, 1, vec![0x0F]),
+                // Remote frames have a length but no data
+                (2_000_000, 1, 0x7DF, false, true, rx, 8, vec![]),
+            ],
+            "{}",
+            name
+        );
+    }
+}
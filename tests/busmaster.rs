@@ -0,0 +1,96 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for reading BUSMASTER logs
+use chrono::{Local, NaiveDate, TimeZone};
+use make_cabana_route::input::{
+    read_can_log, CANMessage, Direction, LogDate, LogFormat, ReadOptions,
+};
+use make_cabana_route::Nanos;
+
+mod common;
+
+use common::test_dir;
+
+// The columns are in a different order to BUSMASTER's, the first two times have
+// 4 and 3 digits of fractional seconds, and the last is after midnight
+const LOG: &str = "***BUSMASTER Ver 3.2.2***\n\
+                   ***PROTOCOL CAN***\n\
+                   ***<Time><Channel><CAN ID><Tx/Rx><DLC><Type><DataBytes>***\n\
+                   23:59:59:9900 2 0x0C1 Rx 2 s 01 02\n\
+                   23:59:59.995 1 0x18DAF110 Tx 1 x 03\n\
+                   00:00:00:0100 1 0x123 Rx 0 sr\n";
+
+const SECOND: Nanos = 1_000_000_000;
+
+fn read(test: &str, options: &ReadOptions) -> Vec<CANMessage> {
+    let path = test_dir(test).join("log.txt");
+    std::fs::write(&path, LOG).unwrap();
+    read_can_log(&path, Some(LogFormat::Busmaster), Some(0), options)
+        .unwrap()
+        .messages
+}
+
+#[test]
+fn columns_from_header_and_midnight() {
+    let messages: Vec<_> = read("busmaster-columns", &ReadOptions::default())
+        .into_iter()
+        .map(|m| (m.timestamp, m.can_id, m.bus_no, m.direction, m.data))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (
+                86_399 * SECOND + 990_000_000,
+                0x0c1,
+                1,
+                Some(Direction::Rx),
+                vec![1, 2]
+            ),
+            (
+                86_399 * SECOND + 995_000_000,
+                0x18daf110,
+                0,
+                Some(Direction::Tx),
+                vec![3]
+            ),
+            // The next day
+            (
+                86_400 * SECOND + 10_000_000,
+                0x123,
+                0,
+                Some(Direction::Rx),
+                vec![]
+            ),
+        ]
+    );
+}
+
+// With a log date the times of day are from midnight of that date, in the local
+// time zone
+#[test]
+fn log_date_makes_timestamps_absolute() {
+    let date = NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+    let midnight = Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .earliest()
+        .unwrap()
+        .timestamp()
+        * SECOND;
+    let options = ReadOptions {
+        log_date: Some(LogDate::Date(date)),
+        ..Default::default()
+    };
+    let timestamps: Vec<_> = read("busmaster-log-date", &options)
+        .iter()
+        .map(|m| m.timestamp)
+        .collect();
+    assert_eq!(
+        timestamps,
+        vec![
+            midnight + 86_399 * SECOND + 990_000_000,
+            midnight + 86_399 * SECOND + 995_000_000,
+            midnight + 86_400 * SECOND + 10_000_000,
+        ]
+    );
+}
@@ -0,0 +1,103 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for reading Linux SocketCAN 'candump -L' logs
+use common::test_dir;
+use make_cabana_route::input::{read_can_log, CanLog, InputError, LogFormat, ReadOptions};
+
+mod common;
+
+fn read(test: &str, log: &str) -> Result<CanLog, InputError> {
+    let path = test_dir(test).join("log.log");
+    std::fs::write(&path, log).unwrap();
+    read_can_log(
+        &path,
+        Some(LogFormat::Candump),
+        None,
+        &ReadOptions::default(),
+    )
+}
+
+#[test]
+fn frame_kinds() {
+    let log = read(
+        "candump-frames",
+        "(1678901234.000000) can0 123#DEADBEEF\n\
+         (1678901234.000100) can1 18DAF110##1000102030405060708090A0B\n\
+         (1678901234.000200) can0 123#0011223344556677_F\n\
+         (1678901234.000300) can0 7DF#R\n\
+         (1678901234.000400) can0 7DF#R8\n",
+    )
+    .unwrap();
+    let messages: Vec<_> = log
+        .messages
+        .iter()
+        .map(|m| {
+            (
+                m.timestamp,
+                m.bus_no,
+                m.can_id,
+                m.is_extended_id,
+                m.is_fd,
+                m.is_rtr,
+                m.dlc,
+                m.data.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (
+                0,
+                0,
+                0x123,
+                false,
+                false,
+                false,
+                4,
+                vec![0xDE, 0xAD, 0xBE, 0xEF]
+            ),
+            // The digit after ## is the CAN-FD flags, not data
+            (
+                100_000,
+                1,
+                0x18DA_F110,
+                true,
+                true,
+                false,
+                9,
+                (0..12).collect()
+            ),
+            // A classic frame with a DLC above 8
+            (
+                200_000,
+                0,
+                0x123,
+                false,
+                false,
+                false,
+                15,
+                vec![0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]
+            ),
+            (300_000, 0, 0x7DF, false, false, true, 0, vec![]),
+            (400_000, 0, 0x7DF, false, false, true, 8, vec![]),
+        ]
+    );
+}
+
+// Row numbers are line numbers, including any blank lines that were skipped
+#[test]
+fn malformed_line() {
+    let result = read(
+        "candump-malformed",
+        "(1678901234.000000) can0 123#DEADBEEF\n\
+         \n\
+         (1678901234.000100) can0 123DEADBEEF\n",
+    );
+    assert!(
+        matches!(&result, Err(InputError::InvalidRow { row: 3, .. })),
+        "{:?}",
+        result
+    );
+}
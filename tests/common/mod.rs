@@ -0,0 +1,39 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Helpers shared by the integration tests. Each test file only uses some of them.
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+
+use csv::StringRecord;
+use make_cabana_route::input::{dlc_for_len, CANMessage};
+use make_cabana_route::Nanos;
+
+// A standard ID CAN message received on bus_no
+pub fn message(timestamp: Nanos, can_id: u32, bus_no: u8, data: &[u8]) -> CANMessage {
+    CANMessage {
+        timestamp,
+        can_id,
+        is_extended_id: false,
+        bus_no,
+        data: data.to_vec(),
+        is_fd: false,
+        dlc: dlc_for_len(data.len()),
+        is_rtr: false,
+        direction: None,
+    }
+}
+
+pub fn record(fields: &[&str]) -> StringRecord {
+    StringRecord::from(fields.to_vec())
+}
+
+// An empty directory for a test's files, named after the test
+pub fn test_dir(test: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("make_cabana_route-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,60 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for filling in ConvertOptions without the command line, as the Python
+// module does
+use make_cabana_route::cli::ConvertOptions;
+use make_cabana_route::qlog::LogCompression;
+use serde_json::json;
+
+fn options(value: serde_json::Value) -> serde_json::Result<ConvertOptions> {
+    serde_json::from_value(value)
+}
+
+// Options that aren't set have the command line defaults
+#[test]
+fn defaults_match_command_line() {
+    let options = options(json!({})).unwrap();
+    assert_eq!(options.segment_seconds, 60);
+    assert_eq!(options.gap_threshold_ms, 500);
+    assert_eq!(options.compression, LogCompression::Bz2);
+    assert_eq!(options.dongle_id, "0000000000000000");
+    assert_eq!(options.data_dir.to_str(), Some("data_dir"));
+    assert!(!options.no_qlog);
+}
+
+#[test]
+fn typed_values() {
+    let options = options(json!({
+        "segment_seconds": 10,
+        "compression": "zstd",
+        "start": 1.5,
+        "bus": [0, 2],
+        "remap_bus": [[1, 0]],
+        "decimate": [[0x123, 10.0]],
+        "no_qlog": true,
+    }))
+    .unwrap();
+    assert_eq!(options.segment_seconds, 10);
+    assert_eq!(options.compression, LogCompression::Zstd);
+    assert_eq!(options.start, Some(1_500_000_000));
+    assert_eq!(options.bus, vec![0, 2]);
+    assert_eq!(options.remap_bus, vec![(1, 0)]);
+    assert_eq!(options.decimate, vec![(0x123, 10.0)]);
+    assert!(options.no_qlog);
+}
+
+// Unknown options and values of the wrong type are errors, rather than being
+// ignored
+#[test]
+fn invalid_options() {
+    for value in [
+        json!({"segment_secs": 10}),
+        json!({"segment_seconds": "10"}),
+        json!({"compression": "gzip"}),
+        json!({"start": -1.0}),
+        json!({"no_qlog": 1}),
+    ] {
+        assert!(options(value.clone()).is_err(), "{}", value);
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for decoding signals with a DBC file
+use make_cabana_route::dbc::{find_signal_alerts, AlertRule, Comparison, Dbc};
+use make_cabana_route::input::AlertStatus;
+
+mod common;
+
+use common::{message, test_dir};
+
+const DBC: &str = r#"VERSION ""
+
+NS_ :
+
+BS_:
+
+BU_: XXX
+
+BO_ 256 TEST: 8 XXX
+ SG_ INTEL : 8|12@1+ (0.5,10) [0|0] "" XXX
+ SG_ MOTOROLA : 39|12@0+ (1,0) [0|0] "" XXX
+ SG_ SIGNED : 48|8@1- (1,0) [0|0] "" XXX
+"#;
+
+fn load_dbc(test: &str) -> Dbc {
+    let path = test_dir(test).join("test.dbc");
+    std::fs::write(&path, DBC).unwrap();
+    Dbc::from_path(&path).unwrap()
+}
+
+// Data for the TEST message with the given MOTOROLA and SIGNED raw values
+fn test_data(motorola: u16, signed: i8) -> [u8; 8] {
+    let mut data = [0; 8];
+    data[4] = (motorola >> 4) as u8;
+    data[5] = ((motorola & 0xf) << 4) as u8;
+    data[6] = signed as u8;
+    data
+}
+
+#[test]
+fn decode_byte_orders_and_sign() {
+    let dbc = load_dbc("decode_byte_orders_and_sign");
+    let data = [0x00, 0x34, 0x12, 0x00, 0xab, 0xc0, 0xf6, 0x00];
+    let decode = |name| dbc.find_signal(name).unwrap().1.decode(&data);
+
+    // Intel, 0x234 from the low bits of bytes 1 and 2, then scaled
+    assert_eq!(decode("INTEL"), Some(564.0 * 0.5 + 10.0));
+    // Motorola, from the most significant bit of byte 4 into the top of byte 5
+    assert_eq!(decode("MOTOROLA"), Some(0xabc as f64));
+    assert_eq!(decode("SIGNED"), Some(-10.0));
+    // Too short for the signal
+    assert_eq!(
+        dbc.find_signal("SIGNED").unwrap().1.decode(&data[..6]),
+        None
+    );
+}
+
+// Encoding a value and decoding it again gives the same value, as long as it's
+// in the signal's range, and leaves the other signals' bits alone
+#[test]
+fn encode_round_trips() {
+    let dbc = load_dbc("encode_round_trips");
+    let signal = |name| dbc.find_signal(name).unwrap().1;
+    let mut data = [0xff; 8];
+
+    for (name, value) in [("INTEL", 292.0), ("MOTOROLA", 2748.0), ("SIGNED", -10.0)] {
+        signal(name).encode(&mut data, value).unwrap();
+        assert_eq!(signal(name).decode(&data), Some(value), "{}", name);
+    }
+    assert_eq!(data, [0xff, 0x34, 0xf2, 0xff, 0xab, 0xcf, 0xf6, 0xff]);
+
+    // Out of range values are clamped
+    signal("INTEL").encode(&mut data, 1e6).unwrap();
+    assert_eq!(signal("INTEL").decode(&data), Some(4095.0 * 0.5 + 10.0));
+    signal("SIGNED").encode(&mut data, -200.0).unwrap();
+    assert_eq!(signal("SIGNED").decode(&data), Some(-128.0));
+    // Too short for the signal
+    assert_eq!(signal("SIGNED").encode(&mut data[..6], 0.0), None);
+}
+
+// Each rule is tracked separately, and the alert is only cleared when none of
+// them are true
+#[test]
+fn overlapping_alert_rules() {
+    let dbc = load_dbc("overlapping_alert_rules");
+    let rules = vec![
+        AlertRule {
+            signal: "SIGNED".to_string(),
+            condition: Comparison::Less,
+            value: 0.0,
+            status: AlertStatus::Critical,
+            message: "{signal} negative".to_string(),
+        },
+        AlertRule {
+            signal: "TEST.MOTOROLA".to_string(),
+            condition: Comparison::Greater,
+            value: 2000.0,
+            status: AlertStatus::UserPrompt,
+            message: "{signal} {value}".to_string(),
+        },
+    ];
+    let messages = vec![
+        message(0, 0x100, 0, &test_data(0, 0)),
+        message(100, 0x100, 0, &test_data(0, -1)),
+        message(200, 0x100, 0, &test_data(2748, -1)),
+        message(300, 0x100, 0, &test_data(2748, 0)),
+        message(400, 0x100, 0, &test_data(0, 0)),
+    ];
+    let alerts: Vec<_> = find_signal_alerts(&dbc, &rules, &messages)
+        .unwrap()
+        .into_iter()
+        .map(|a| (a.timestamp, a.status, a.message))
+        .collect();
+    assert_eq!(
+        alerts,
+        vec![
+            (
+                100,
+                AlertStatus::Critical,
+                Some("SIGNED negative".to_string())
+            ),
+            (
+                200,
+                AlertStatus::UserPrompt,
+                Some("TEST.MOTOROLA 2748".to_string())
+            ),
+            (
+                300,
+                AlertStatus::UserPrompt,
+                Some("TEST.MOTOROLA 2748".to_string())
+            ),
+            (400, AlertStatus::Normal, None),
+        ]
+    );
+}
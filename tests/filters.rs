@@ -0,0 +1,68 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for dropping and thinning out CAN messages
+use std::collections::HashMap;
+
+use common::message;
+use make_cabana_route::input::{drop_static_can_ids, DecimateCanMessages};
+
+mod common;
+
+// Decimated IDs keep the last message in each window, other IDs and the order of
+// all messages are unchanged
+#[test]
+fn decimate_keeps_latest_in_window() {
+    let msg = |timestamp_us: i64, can_id| message(timestamp_us * 1000, can_id, 0, &[]);
+    let messages = vec![
+        msg(0, 0x100),
+        msg(10, 0x200),
+        msg(40, 0x100),
+        msg(50, 0x200),
+        msg(90, 0x100),
+        msg(120, 0x100),
+        msg(130, 0x200),
+    ];
+    let intervals = HashMap::from([(0x100, 100_000)]);
+    let kept: Vec<_> = DecimateCanMessages::new(messages.into_iter(), intervals)
+        .map(|m| (m.timestamp / 1000, m.can_id))
+        .collect();
+    assert_eq!(
+        kept,
+        vec![
+            (10, 0x200),
+            (50, 0x200),
+            (90, 0x100),
+            (120, 0x100),
+            (130, 0x200)
+        ]
+    );
+}
+
+// IDs with unchanging data keep only their first message, on each bus
+#[test]
+fn only_ids_with_changes() {
+    let msg = |timestamp_us: i64, can_id, bus_no, data| {
+        message(timestamp_us * 1000, can_id, bus_no, &[data])
+    };
+    let mut messages = vec![
+        msg(0, 0x100, 0, 0x01),
+        msg(0, 0x100, 1, 0x01),
+        msg(10, 0x200, 0, 0x01),
+        msg(20, 0x100, 0, 0x01),
+        msg(20, 0x100, 1, 0x02),
+        msg(30, 0x200, 0, 0x01),
+    ];
+    assert_eq!(
+        drop_static_can_ids(&mut messages),
+        vec![(0, 0x100), (0, 0x200)]
+    );
+    let kept: Vec<_> = messages
+        .iter()
+        .map(|m| (m.timestamp / 1000, m.can_id, m.bus_no))
+        .collect();
+    assert_eq!(
+        kept,
+        vec![(0, 0x100, 0), (0, 0x100, 1), (10, 0x200, 0), (20, 0x100, 1)]
+    );
+}
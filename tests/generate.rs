@@ -0,0 +1,42 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for generated CAN messages
+use make_cabana_route::input::{generate_can_messages, GeneratorSpec, Waveform};
+
+// Generated bytes follow their waveforms, at the generator's rate
+#[test]
+fn generate_byte_waveforms() {
+    let spec = GeneratorSpec {
+        id: Some(0x0B4),
+        bus: 1,
+        extended: false,
+        rate: 4.0,
+        start: 2.0,
+        duration: 1.0,
+        len: 3,
+        bytes: vec![
+            Waveform::Counter { max: 2 },
+            Waveform::Sine {
+                amplitude: 100.0,
+                offset: 100.0,
+                period: 1.0,
+            },
+        ],
+        signals: vec![],
+    };
+    let messages = generate_can_messages(&[spec], None).unwrap();
+    let generated: Vec<_> = messages
+        .iter()
+        .map(|m| (m.timestamp, m.can_id, m.bus_no, m.data.clone()))
+        .collect();
+    assert_eq!(
+        generated,
+        vec![
+            (2_000_000_000, 0x0B4, 1, vec![0, 100, 0]),
+            (2_250_000_000, 0x0B4, 1, vec![1, 200, 0]),
+            (2_500_000_000, 0x0B4, 1, vec![2, 100, 0]),
+            (2_750_000_000, 0x0B4, 1, vec![0, 0, 0]),
+        ]
+    );
+}
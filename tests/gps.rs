@@ -0,0 +1,79 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for reading GPS logs
+use make_cabana_route::input::read_locations;
+
+mod common;
+
+use common::test_dir;
+
+// 2022-12-17T09:35:30Z in nanoseconds since the Unix epoch
+const START: i64 = 1_671_269_730_000_000_000;
+
+fn assert_near(actual: f64, expected: f64) {
+    assert!(
+        (actual - expected).abs() < 1e-9,
+        "{} != {}",
+        actual,
+        expected
+    );
+}
+
+// Route points and track points are both read, in the order they come
+#[test]
+fn gpx_route_and_track_points() {
+    let path = test_dir("gpx_route_and_track_points").join("drive.gpx");
+    std::fs::write(
+        &path,
+        r#"<?xml version="1.0"?>
+<gpx version="1.1">
+<rte><rtept lat="-37.8135" lon="144.9630"><time>2022-12-17T09:35:30Z</time></rtept></rte>
+<trk><trkseg>
+<trkpt lat="-37.8136" lon="144.9631"><ele>31.0</ele><time>2022-12-17T09:35:31Z</time></trkpt>
+<trkpt lat='-37.8137' lon='144.9632'><time>2022-12-17T09:35:32.5Z</time></trkpt>
+</trkseg></trk>
+</gpx>
+"#,
+    )
+    .unwrap();
+
+    let locations = read_locations(&path, START).unwrap();
+    let timestamps: Vec<_> = locations.iter().map(|l| l.timestamp).collect();
+    assert_eq!(timestamps, vec![0, 1_000_000_000, 2_500_000_000]);
+    assert_near(locations[0].latitude, -37.8135);
+    assert_near(locations[2].longitude, 144.9632);
+    let altitudes: Vec<_> = locations.iter().map(|l| l.altitude).collect();
+    assert_eq!(altitudes, vec![None, Some(31.0), None]);
+}
+
+// RMC sentences are matched with the GGA sentence for the same fix to get the
+// altitude, and sentences with a bad checksum or no fix are skipped
+#[test]
+fn nmea_rmc_and_gga() {
+    let path = test_dir("nmea_rmc_and_gga").join("drive.nmea");
+    std::fs::write(
+        &path,
+        "$GPRMC,093531.00,A,3748.81600,S,14457.78600,E,12.3,45.6,171222,,,A*41\n\
+         $GPGGA,093531.00,3748.81600,S,14457.78600,E,1,08,1.0,31.0,M,,M,,*54\n\
+         $GPRMC,093532.00,V,,,,,,,171222,,,N*76\n\
+         $GPRMC,093532.50,A,not,a,valid,sentence*00\n\
+         $GPRMC,093533.00,A,3748.82200,S,14457.79200,E,,,171222,,,A*46\n",
+    )
+    .unwrap();
+
+    // The offset is a second into the log, the first fix is at the start
+    let locations = read_locations(&path, START + 1_000_000_000).unwrap();
+    let timestamps: Vec<_> = locations.iter().map(|l| l.timestamp).collect();
+    assert_eq!(timestamps, vec![0, 2_000_000_000]);
+
+    assert_near(locations[0].latitude, -37.8136);
+    assert_near(locations[0].longitude, 144.9631);
+    assert_eq!(locations[0].altitude, Some(31.0));
+    assert_near(locations[0].speed.unwrap(), 12.3 * 1852.0 / 3600.0);
+    assert_eq!(locations[0].bearing, Some(45.6));
+
+    assert_near(locations[1].latitude, -37.8137);
+    assert_eq!(locations[1].altitude, None);
+    assert_eq!(locations[1].speed, None);
+}
@@ -0,0 +1,60 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for reading GVRET binary logs
+use common::test_dir;
+use make_cabana_route::input::{read_can_log, LogFormat, ReadOptions};
+
+mod common;
+
+// A GVRET CAN frame, see src/input/gvret.rs
+fn frame(ts_us: u32, id: u32, bus: u8, len: u8, data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0xF1, 0x00];
+    frame.extend(ts_us.to_le_bytes());
+    frame.extend(id.to_le_bytes());
+    frame.push(bus << 4 | len);
+    frame.extend(data);
+    // Checksum
+    frame.push(0);
+    frame
+}
+
+// The timestamp counter wraps around between the first two frames, the second
+// frame has an extended ID and the third is a remote frame. Bytes that aren't a
+// CAN frame (i.e. replies to other GVRET commands) are skipped.
+#[test]
+fn frames_across_timestamp_wrap() {
+    let mut log = frame(0xFFFF_FF00, 0x123, 0, 2, &[0x01, 0x02]);
+    log.extend([0x55, 0xF1, 0x09, 0x01, 0x02]);
+    log.extend(frame(0x100, 1 << 31 | 0x18DA_F110, 1, 1, &[0x03]));
+    log.extend([0x00, 0xAA]);
+    log.extend(frame(0x200, 1 << 30 | 0x7DF, 0, 2, &[0x00, 0x00]));
+    let path = test_dir("gvret-wrap").join("log.gvret");
+    std::fs::write(&path, log).unwrap();
+
+    let log = read_can_log(&path, Some(LogFormat::Gvret), None, &ReadOptions::default()).unwrap();
+    let messages: Vec<_> = log
+        .messages
+        .iter()
+        .map(|m| {
+            (
+                m.timestamp,
+                m.can_id,
+                m.is_extended_id,
+                m.is_rtr,
+                m.bus_no,
+                m.dlc,
+                m.data.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (0, 0x123, false, false, 0, 2, vec![0x01, 0x02]),
+            (512_000, 0x18DA_F110, true, false, 1, 1, vec![0x03]),
+            // Remote frames have a length but no data
+            (768_000, 0x7DF, false, true, 0, 2, vec![]),
+        ]
+    );
+}
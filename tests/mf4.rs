@@ -0,0 +1,323 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for reading CAN frames from MDF4 files. The files are built here with
+// only the blocks and fields that the reader uses, the same as a bus logger
+// writes them.
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use make_cabana_route::input::{
+    read_can_log, CANMessage, Direction, InputError, LogFormat, ReadOptions,
+};
+use make_cabana_route::Nanos;
+
+mod common;
+
+use common::{message, test_dir};
+
+// Start time in the header, as nanoseconds since the Unix epoch
+const START: Nanos = 1_700_000_000_000_000_000;
+
+// Each fixed length record is Timestamp (f64), BusChannel (u8), ID (u32), DLC
+// (u8), DataLength (u8), Dir (u8) and then 8 bytes of DataBytes (or the offset
+// of the value, for VLSD data)
+const RECORD_LEN: usize = 24;
+
+// cn_type, cn_data_type and cg_flags values
+const CN_TYPE_FIXED: u8 = 0;
+const CN_TYPE_VLSD: u8 = 1;
+const CN_TYPE_MASTER: u8 = 2;
+const UINT_LE: u8 = 0;
+const FLOAT_LE: u8 = 4;
+const BYTE_ARRAY: u8 = 10;
+const CG_FLAG_VLSD: u16 = 1;
+
+// Builds an MDF4 file a block at a time
+struct Mf4Builder {
+    buf: Vec<u8>,
+}
+
+impl Mf4Builder {
+    // The identification block, then the header block with no data groups yet
+    fn new() -> Self {
+        let mut buf = b"MDF     4.10    make_cab".to_vec();
+        buf.resize(28, 0);
+        buf.extend(410u16.to_le_bytes());
+        buf.resize(64, 0);
+        let mut builder = Mf4Builder { buf };
+        let mut hd = START.to_le_bytes().to_vec();
+        hd.resize(32, 0);
+        builder.block(b"HD", &[0; 6], &hd);
+        builder
+    }
+
+    // Append a block, returning its offset
+    fn block(&mut self, id: &[u8; 2], links: &[u64], data: &[u8]) -> u64 {
+        let offset = self.buf.len() as u64;
+        let len = 24 + links.len() * 8 + data.len();
+        self.buf.extend(b"##");
+        self.buf.extend(id);
+        self.buf.extend([0; 4]);
+        self.buf.extend((len as u64).to_le_bytes());
+        self.buf.extend((links.len() as u64).to_le_bytes());
+        for link in links {
+            self.buf.extend(link.to_le_bytes());
+        }
+        self.buf.extend(data);
+        // Blocks are 8 byte aligned
+        self.buf.resize(self.buf.len().next_multiple_of(8), 0);
+        offset
+    }
+
+    fn text(&mut self, text: &str) -> u64 {
+        self.block(b"TX", &[], format!("{}\0", text).as_bytes())
+    }
+
+    // A CN block, linked to the channel after it
+    fn channel(&mut self, name: &str, next: u64, layout: (u8, u8, u32, u32), data: u64) -> u64 {
+        let (cn_type, data_type, byte_offset, bit_count) = layout;
+        let name = self.text(name);
+        let sync_type = (cn_type == CN_TYPE_MASTER) as u8;
+        let mut cn = vec![cn_type, sync_type, data_type, 0];
+        cn.extend(byte_offset.to_le_bytes());
+        cn.extend(bit_count.to_le_bytes());
+        cn.resize(72, 0);
+        self.block(b"CN", &[next, 0, name, 0, 0, data, 0, 0], &cn)
+    }
+
+    // A CG block for the CAN frames, with its channels. data_bytes is the
+    // DataBytes channel's cn_type and data link.
+    fn can_group(&mut self, record_id: u64, next: u64, data_bytes: (u8, u64)) -> u64 {
+        let mut cn = self.channel(
+            "CAN_DataFrame.DataBytes",
+            0,
+            (data_bytes.0, BYTE_ARRAY, 16, 64),
+            data_bytes.1,
+        );
+        for (name, byte_offset, bit_count) in [
+            ("CAN_DataFrame.Dir", 15, 8),
+            ("CAN_DataFrame.DataLength", 14, 8),
+            ("CAN_DataFrame.DLC", 13, 8),
+            ("CAN_DataFrame.ID", 9, 32),
+            ("CAN_DataFrame.BusChannel", 8, 8),
+        ] {
+            cn = self.channel(
+                name,
+                cn,
+                (CN_TYPE_FIXED, UINT_LE, byte_offset, bit_count),
+                0,
+            );
+        }
+        cn = self.channel("Timestamp", cn, (CN_TYPE_MASTER, FLOAT_LE, 0, 64), 0);
+        let name = self.text("CAN_DataFrame");
+        self.channel_group(record_id, next, cn, name, 0, RECORD_LEN)
+    }
+
+    fn channel_group(
+        &mut self,
+        record_id: u64,
+        next: u64,
+        channels: u64,
+        name: u64,
+        flags: u16,
+        record_len: usize,
+    ) -> u64 {
+        let mut cg = record_id.to_le_bytes().to_vec();
+        cg.extend(0u64.to_le_bytes());
+        cg.extend(flags.to_le_bytes());
+        cg.extend([0; 6]);
+        cg.extend((record_len as u32).to_le_bytes());
+        cg.extend(0u32.to_le_bytes());
+        self.block(b"CG", &[next, channels, name, 0, 0, 0], &cg)
+    }
+
+    // Add the only data group, and link the header to it
+    fn data_group(&mut self, groups: u64, data: u64, rec_id_size: u8) {
+        let mut dg_data = vec![rec_id_size];
+        dg_data.resize(8, 0);
+        let dg = self.block(b"DG", &[0, groups, data, 0], &dg_data);
+        // First link of the header block
+        self.buf[64 + 24..64 + 32].copy_from_slice(&dg.to_le_bytes());
+    }
+
+    fn write(&self, path: &Path) {
+        std::fs::write(path, &self.buf).unwrap();
+    }
+}
+
+// A record for a frame, data_bytes is the data (or the offset of the VLSD value)
+fn record(secs: f64, bus: u8, id: u32, dlc: u8, len: u8, dir: u8, data_bytes: [u8; 8]) -> Vec<u8> {
+    let mut record = secs.to_le_bytes().to_vec();
+    record.push(bus);
+    record.extend(id.to_le_bytes());
+    record.extend([dlc, len, dir]);
+    record.extend(data_bytes);
+    record
+}
+
+// The two frames in the DT and DZ files
+fn fixed_records() -> Vec<u8> {
+    [
+        record(0.5, 1, 0x123, 2, 2, 0, [1, 2, 0, 0, 0, 0, 0, 0]),
+        record(
+            1.25,
+            2,
+            0x8000_0000 | 0x18da_f110,
+            8,
+            8,
+            1,
+            [1, 2, 3, 4, 5, 6, 7, 8],
+        ),
+    ]
+    .concat()
+}
+
+fn fixed_messages() -> Vec<CANMessage> {
+    vec![
+        CANMessage {
+            direction: Some(Direction::Rx),
+            ..message(START + 500_000_000, 0x123, 0, &[1, 2])
+        },
+        CANMessage {
+            is_extended_id: true,
+            direction: Some(Direction::Tx),
+            ..message(
+                START + 1_250_000_000,
+                0x18da_f110,
+                1,
+                &[1, 2, 3, 4, 5, 6, 7, 8],
+            )
+        },
+    ]
+}
+
+fn read(path: &Path) -> Vec<CANMessage> {
+    read_can_log(path, Some(LogFormat::Mf4), Some(0), &ReadOptions::default())
+        .unwrap()
+        .messages
+}
+
+// Records in a DT block, with the data cut to each frame's DataLength
+#[test]
+fn dt_block() {
+    let path = test_dir("mf4-dt").join("log.mf4");
+    let mut mf4 = Mf4Builder::new();
+    let cg = mf4.can_group(0, 0, (CN_TYPE_FIXED, 0));
+    let dt = mf4.block(b"DT", &[], &fixed_records());
+    mf4.data_group(cg, dt, 0);
+    mf4.write(&path);
+
+    assert_eq!(read(&path), fixed_messages());
+}
+
+// The same records in a DZ block, transposed so each column of bytes is together
+// before they're compressed
+#[test]
+fn dz_block_transposed() {
+    let path = test_dir("mf4-dz").join("log.mf4");
+    let records = fixed_records();
+    let rows = records.len() / RECORD_LEN;
+    let transposed: Vec<u8> = (0..RECORD_LEN)
+        .flat_map(|column| (0..rows).map(move |row| (row, column)))
+        .map(|(row, column)| records[row * RECORD_LEN + column])
+        .collect();
+    let mut zlib = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+    zlib.write_all(&transposed).unwrap();
+    let compressed = zlib.finish().unwrap();
+
+    let mut dz = b"DT".to_vec();
+    dz.extend([1, 0]);
+    dz.extend((RECORD_LEN as u32).to_le_bytes());
+    dz.extend((records.len() as u64).to_le_bytes());
+    dz.extend((compressed.len() as u64).to_le_bytes());
+    dz.extend(compressed);
+
+    let mut mf4 = Mf4Builder::new();
+    let cg = mf4.can_group(0, 0, (CN_TYPE_FIXED, 0));
+    let dz = mf4.block(b"DZ", &[], &dz);
+    mf4.data_group(cg, dz, 0);
+    mf4.write(&path);
+
+    assert_eq!(read(&path), fixed_messages());
+}
+
+// DataBytes in a VLSD channel group, with the records of both groups mixed in
+// the same data block. Each frame's DataBytes is the offset of its value in the
+// VLSD group's records.
+#[test]
+fn vlsd_data_bytes() {
+    let path = test_dir("mf4-vlsd").join("log.mf4");
+    let fd_data: Vec<u8> = (0..12).collect();
+    let vlsd = |data: &[u8]| [&[2], &(data.len() as u32).to_le_bytes()[..], data].concat();
+    let frame = |record: Vec<u8>| [vec![1], record].concat();
+    let data = [
+        vlsd(&[0xaa, 0xbb, 0xcc]),
+        frame(record(0.5, 1, 0x123, 3, 3, 0, 0u64.to_le_bytes())),
+        vlsd(&fd_data),
+        frame(record(0.75, 1, 0x456, 9, 12, 0, 7u64.to_le_bytes())),
+    ]
+    .concat();
+
+    let mut mf4 = Mf4Builder::new();
+    let vlsd_cg = mf4.channel_group(2, 0, 0, 0, CG_FLAG_VLSD, 0);
+    let cg = mf4.can_group(1, vlsd_cg, (CN_TYPE_VLSD, vlsd_cg));
+    let dt = mf4.block(b"DT", &[], &data);
+    mf4.data_group(cg, dt, 1);
+    mf4.write(&path);
+
+    assert_eq!(
+        read(&path),
+        vec![
+            CANMessage {
+                direction: Some(Direction::Rx),
+                ..message(START + 500_000_000, 0x123, 0, &[0xaa, 0xbb, 0xcc])
+            },
+            CANMessage {
+                is_fd: true,
+                direction: Some(Direction::Rx),
+                ..message(START + 750_000_000, 0x456, 0, &fd_data)
+            },
+        ]
+    );
+}
+
+// Frames with a time that isn't finite, or is too far from the start time to be
+// a timestamp, are invalid rows (so can be skipped)
+#[test]
+fn invalid_times() {
+    let path = test_dir("mf4-invalid-times").join("log.mf4");
+    let records = [
+        record(0.5, 1, 0x123, 2, 2, 0, [1, 2, 0, 0, 0, 0, 0, 0]),
+        record(f64::NAN, 1, 0x123, 2, 2, 0, [3, 4, 0, 0, 0, 0, 0, 0]),
+        record(f64::INFINITY, 1, 0x123, 2, 2, 0, [5, 6, 0, 0, 0, 0, 0, 0]),
+        record(1e12, 1, 0x123, 2, 2, 0, [7, 8, 0, 0, 0, 0, 0, 0]),
+    ]
+    .concat();
+    let mut mf4 = Mf4Builder::new();
+    let cg = mf4.can_group(0, 0, (CN_TYPE_FIXED, 0));
+    let dt = mf4.block(b"DT", &[], &records);
+    mf4.data_group(cg, dt, 0);
+    mf4.write(&path);
+
+    let result = read_can_log(
+        &path,
+        Some(LogFormat::Mf4),
+        Some(0),
+        &ReadOptions::default(),
+    );
+    assert!(
+        matches!(&result, Err(InputError::InvalidRow { .. })),
+        "{:?}",
+        result
+    );
+
+    let options = ReadOptions {
+        skip_bad_rows: true,
+        ..Default::default()
+    };
+    let log = read_can_log(&path, Some(LogFormat::Mf4), Some(0), &options).unwrap();
+    assert_eq!(log.messages, fixed_messages()[..1]);
+    assert_eq!(options.skipped_rows.load(Ordering::Relaxed), 3);
+}
@@ -0,0 +1,281 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Table-driven tests for parsing SavvyCAN CSV records
+use common::record;
+use make_cabana_route::input::{CANMessage, Direction, FlagProblem, InputError, TsUnit};
+
+mod common;
+
+struct Case {
+    name: &'static str,
+    fields: &'static [&'static str],
+    expected: CANMessage,
+}
+
+fn valid_cases() -> Vec<Case> {
+    vec![
+        Case {
+            name: "no Tx/Rx column",
+            fields: &["1000", "123", "false", "0", "2", "DE", "AD"],
+            expected: CANMessage {
+                timestamp: 1_000_000,
+                can_id: 0x123,
+                is_extended_id: false,
+                bus_no: 0,
+                data: vec![0xde, 0xad],
+                is_fd: false,
+                dlc: 2,
+                is_rtr: false,
+                direction: None,
+            },
+        },
+        Case {
+            name: "Rx column",
+            fields: &["1000", "123", "false", "Rx", "1", "2", "DE", "AD"],
+            expected: CANMessage {
+                timestamp: 1_000_000,
+                can_id: 0x123,
+                is_extended_id: false,
+                bus_no: 1,
+                data: vec![0xde, 0xad],
+                is_fd: false,
+                dlc: 2,
+                is_rtr: false,
+                direction: Some(Direction::Rx),
+            },
+        },
+        Case {
+            name: "Tx column",
+            fields: &["1000", "123", "false", "Tx", "2", "1", "FF"],
+            expected: CANMessage {
+                timestamp: 1_000_000,
+                can_id: 0x123,
+                is_extended_id: false,
+                bus_no: 2,
+                data: vec![0xff],
+                is_fd: false,
+                dlc: 1,
+                is_rtr: false,
+                direction: Some(Direction::Tx),
+            },
+        },
+        Case {
+            name: "extended ID",
+            fields: &["5", "18DAF110", "true", "0", "1", "01"],
+            expected: CANMessage {
+                timestamp: 5_000,
+                can_id: 0x18daf110,
+                is_extended_id: true,
+                bus_no: 0,
+                data: vec![0x01],
+                is_fd: false,
+                dlc: 1,
+                is_rtr: false,
+                direction: None,
+            },
+        },
+        Case {
+            name: "fractional microseconds",
+            fields: &["1000.25", "123", "false", "0", "1", "01"],
+            expected: CANMessage {
+                timestamp: 1_000_250,
+                can_id: 0x123,
+                is_extended_id: false,
+                bus_no: 0,
+                data: vec![0x01],
+                is_fd: false,
+                dlc: 1,
+                is_rtr: false,
+                direction: None,
+            },
+        },
+        Case {
+            name: "0-byte data",
+            fields: &["0", "7FF", "false", "0", "0"],
+            expected: CANMessage {
+                timestamp: 0,
+                can_id: 0x7ff,
+                is_extended_id: false,
+                bus_no: 0,
+                data: vec![],
+                is_fd: false,
+                dlc: 0,
+                is_rtr: false,
+                direction: None,
+            },
+        },
+        Case {
+            name: "full 8-byte data",
+            fields: &[
+                "42", "1A0", "false", "Rx", "0", "8", "00", "11", "22", "33", "44", "55", "66",
+                "77",
+            ],
+            expected: CANMessage {
+                timestamp: 42_000,
+                can_id: 0x1a0,
+                is_extended_id: false,
+                bus_no: 0,
+                data: vec![0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
+                is_fd: false,
+                dlc: 8,
+                is_rtr: false,
+                direction: Some(Direction::Rx),
+            },
+        },
+        Case {
+            name: "dlen field doesn't match data",
+            fields: &["1000", "123", "false", "0", "4", "DE", "AD"],
+            expected: CANMessage {
+                timestamp: 1_000_000,
+                can_id: 0x123,
+                is_extended_id: false,
+                bus_no: 0,
+                data: vec![0xde, 0xad],
+                is_fd: false,
+                dlc: 4,
+                is_rtr: false,
+                direction: None,
+            },
+        },
+    ]
+}
+
+#[test]
+fn parse_valid_records() {
+    for case in valid_cases() {
+        let msg = CANMessage::parse_from(&record(case.fields), 0)
+            .unwrap_or_else(|e| panic!("{}: {:#}", case.name, e));
+        assert_eq!(msg, case.expected, "{}", case.name);
+    }
+}
+
+#[test]
+fn parse_applies_timestamp_offset() {
+    let msg =
+        CANMessage::parse_from(&record(&["1000", "123", "false", "0", "0"]), 400_000).unwrap();
+    assert_eq!(msg.timestamp, 600_000);
+}
+
+// More than 8 data bytes is a CAN-FD frame, up to 64 bytes
+#[test]
+fn parse_64_byte_fd_frame() {
+    let data: Vec<String> = (0..64).map(|b| format!("{:02X}", b)).collect();
+    let mut fields = vec!["1000", "18DAF110", "true", "Rx", "1", "64"];
+    fields.extend(data.iter().map(String::as_str));
+    let msg = CANMessage::parse_from(&record(&fields), 0).unwrap();
+    assert_eq!(
+        msg,
+        CANMessage {
+            timestamp: 1_000_000,
+            can_id: 0x18daf110,
+            is_extended_id: true,
+            bus_no: 1,
+            data: (0..64).collect(),
+            is_fd: true,
+            dlc: 15,
+            is_rtr: false,
+            direction: Some(Direction::Rx),
+        }
+    );
+    assert_eq!(msg.flag_problems(), vec![]);
+}
+
+// A DLC that disagrees with the data is kept, and reported by check_can_flags()
+#[test]
+fn dlc_mismatch_is_flagged() {
+    let msg = CANMessage::parse_from(&record(&["1000", "123", "false", "0", "4", "DE", "AD"]), 0)
+        .unwrap();
+    assert_eq!(msg.flag_problems(), vec![FlagProblem::DlcMismatch]);
+
+    let msg = CANMessage::parse_from(&record(&["1000", "123", "false", "0", "2", "DE", "AD"]), 0)
+        .unwrap();
+    assert_eq!(msg.flag_problems(), vec![]);
+}
+
+// With strict_fields, the data fields have to match the dlen field exactly
+#[test]
+fn strict_fields_checks_data_len() {
+    let parse = |fields: &[&str], strict_fields| {
+        CANMessage::parse_from_unit(&record(fields), 0, TsUnit::Micros, strict_fields)
+    };
+    let exact = ["1000", "123", "false", "0", "2", "DE", "AD"];
+    let extra = ["1000", "123", "false", "0", "2", "DE", "AD", "00"];
+    let extra_text = ["1000", "123", "false", "0", "2", "DE", "AD", "Rx"];
+    let missing = ["1000", "123", "false", "0", "2", "DE"];
+    let no_dlen = ["1000", "123", "false", "0"];
+
+    assert!(parse(&exact, true).is_ok());
+    for fields in [&extra[..], &extra_text, &missing, &no_dlen] {
+        assert!(parse(fields, false).is_ok(), "{:?}", fields);
+        assert!(parse(fields, true).is_err(), "{:?}", fields);
+    }
+
+    // Without strict_fields, columns after the data are ignored
+    for fields in [&extra, &extra_text] {
+        assert_eq!(parse(fields, false).unwrap().data, vec![0xDE, 0xAD]);
+    }
+}
+
+// Library users can tell the kinds of parse error apart
+#[test]
+fn parse_error_kinds() {
+    let parse = |fields: &[&str]| CANMessage::parse_from(&record(fields), 0).unwrap_err();
+    assert!(matches!(
+        parse(&["1000", "123", "false"]),
+        InputError::MissingField("bus")
+    ));
+    assert!(matches!(
+        parse(&["1000", "12G", "false", "0", "0"]),
+        InputError::InvalidHex {
+            field: "can id",
+            ..
+        }
+    ));
+    assert!(matches!(
+        parse(&["1000", "123", "false", "0", "1", "100"]),
+        InputError::InvalidHex { field: "data", .. }
+    ));
+    assert!(matches!(
+        parse(&["1.5x", "123", "false", "0", "0"]),
+        InputError::InvalidTimestamp(_)
+    ));
+}
+
+#[test]
+fn parse_invalid_records() {
+    let cases: &[(&str, &[&str])] = &[
+        ("empty record", &[]),
+        ("missing can id", &["1000"]),
+        ("missing bus", &["1000", "123", "false"]),
+        ("malformed ts", &["1.5x", "123", "false", "0", "0"]),
+        ("malformed can id", &["1000", "12G", "false", "0", "0"]),
+        ("malformed bus", &["1000", "123", "false", "bus0", "0"]),
+        (
+            "malformed hex data",
+            &["1000", "123", "false", "0", "1", "ZZ"],
+        ),
+        (
+            "data byte too large",
+            &["1000", "123", "false", "0", "1", "100"],
+        ),
+        (
+            "invalid data length",
+            &[
+                "1000", "123", "false", "0", "9", "00", "01", "02", "03", "04", "05", "06", "07",
+                "08",
+            ],
+        ),
+        (
+            "timestamp overflow",
+            &["9223372036854775807", "123", "false", "0", "0"],
+        ),
+    ];
+    for (name, fields) in cases {
+        assert!(
+            CANMessage::parse_from(&record(fields), 0).is_err(),
+            "{} should fail to parse",
+            name
+        );
+    }
+}
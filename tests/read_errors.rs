@@ -0,0 +1,105 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for the InputError returned when a whole log can't be read
+use std::path::{Path, PathBuf};
+
+use make_cabana_route::input::{
+    read_can_log, read_can_logs, read_can_messages_from_reader, CanFilter, InputError, LogFormat,
+    ReadOptions,
+};
+
+mod common;
+
+use common::test_dir;
+
+const HEADER: &str = "Time Stamp,ID,Extended,Bus,LEN,D1\n";
+
+#[test]
+fn missing_log_file() {
+    let path = test_dir("missing_log_file").join("missing.csv");
+    let result = read_can_log(&path, None, None, &ReadOptions::default());
+    assert!(
+        matches!(&result, Err(InputError::Io { path: p, .. }) if *p == path),
+        "{:?}",
+        result
+    );
+}
+
+// The row number counts from the first row after the header
+#[test]
+fn invalid_row() {
+    let log = format!("{}1000,100,false,0,1,01\n2000,100,false,0,1,XY\n", HEADER);
+    let result = read_can_messages_from_reader(log.as_bytes(), None, &ReadOptions::default());
+    assert!(
+        matches!(&result, Err(InputError::InvalidRow { row: 2, .. })),
+        "{:?}",
+        result
+    );
+}
+
+// A log with only a header, or with every message filtered out, is an error
+// unless allow_empty is set
+#[test]
+fn empty_log() {
+    let reader_path = vec![PathBuf::from("<reader>")];
+    let result = read_can_messages_from_reader(HEADER.as_bytes(), None, &ReadOptions::default());
+    assert!(
+        matches!(&result, Err(InputError::EmptyLog(paths)) if *paths == reader_path),
+        "{:?}",
+        result
+    );
+
+    let log = format!("{}1000,100,false,0,1,01\n", HEADER);
+    let filtered = ReadOptions {
+        filter: CanFilter {
+            buses: Some(vec![1]),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let result = read_can_messages_from_reader(log.as_bytes(), None, &filtered);
+    assert!(
+        matches!(&result, Err(InputError::EmptyLog(_))),
+        "{:?}",
+        result
+    );
+
+    let allow_empty = ReadOptions {
+        allow_empty: true,
+        ..filtered
+    };
+    let log = read_can_messages_from_reader(log.as_bytes(), None, &allow_empty).unwrap();
+    assert!(log.messages.is_empty());
+}
+
+// When reading more than one file, only all of them being empty is an error
+#[test]
+fn empty_log_files() {
+    let dir = test_dir("empty_log_files");
+    let write = |name: &str, contents: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    };
+    let empty = write("empty.csv", HEADER);
+    let other = write("other.csv", &format!("{}1000,100,false,0,1,01\n", HEADER));
+    let read = |paths: &[&Path]| {
+        let paths: Vec<_> = paths.iter().map(|p| p.to_path_buf()).collect();
+        read_can_logs(
+            &paths,
+            Some(LogFormat::SavvyCan),
+            None,
+            &ReadOptions::default(),
+        )
+    };
+
+    assert_eq!(read(&[&empty, &other]).unwrap().messages.len(), 1);
+    let result = read(&[&empty, &empty]);
+    let both = vec![empty.clone(), empty];
+    assert!(
+        matches!(&result, Err(InputError::EmptyLog(paths)) if *paths == both),
+        "{:?}",
+        result
+    );
+}
@@ -0,0 +1,72 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for streaming a CAN log and putting it back in timestamp order
+use anyhow::{anyhow, Result};
+use make_cabana_route::input::{iter_can_messages, CANMessage, ReadOptions, ReorderCanMessages};
+
+mod common;
+
+use common::{message, test_dir};
+
+fn reorder(timestamps: &[i64], window: usize) -> Vec<i64> {
+    let messages = timestamps.iter().map(|ts| Ok(message(*ts, 0x100, 0, &[])));
+    ReorderCanMessages::new(messages, window)
+        .map(|m| m.unwrap().timestamp)
+        .collect()
+}
+
+// Messages that are out of order by less than the window come out sorted, and
+// the messages still in the buffer at the end of the stream are all yielded
+#[test]
+fn reorder_within_window() {
+    assert_eq!(reorder(&[3, 1, 2, 5, 4], 3), vec![1, 2, 3, 4, 5]);
+    assert_eq!(reorder(&[2, 1], 10), vec![1, 2]);
+}
+
+// A message further out of order than the window is yielded late, rather than
+// holding back the whole stream
+#[test]
+fn reorder_outside_window() {
+    assert_eq!(reorder(&[5, 1, 2, 3, 4, 0], 2), vec![1, 2, 3, 4, 0, 5]);
+}
+
+// An error is passed on as soon as it's read
+#[test]
+fn reorder_error() {
+    let messages: Vec<Result<CANMessage>> = vec![
+        Ok(message(2, 0x100, 0, &[])),
+        Err(anyhow!("bad row")),
+        Ok(message(1, 0x100, 0, &[])),
+    ];
+    let mut reordered = ReorderCanMessages::new(messages.into_iter(), 10);
+    assert!(reordered.next().unwrap().is_err());
+}
+
+// iter_can_messages() yields the messages in the order they're in the log (with
+// the filter applied), which ReorderCanMessages sorts
+#[test]
+fn stream_log() {
+    let path = test_dir("reorder-stream").join("log.csv");
+    std::fs::write(
+        &path,
+        "Time Stamp,ID,Extended,Bus,LEN,D1\n\
+         1000,100,false,0,1,01\n\
+         3000,100,false,0,1,03\n\
+         2000,200,false,1,1,02\n\
+         5000,100,false,0,1,05\n\
+         4000,200,false,1,1,04\n",
+    )
+    .unwrap();
+    let options = ReadOptions::default();
+    let data = |messages: Vec<Result<CANMessage>>| -> Vec<u8> {
+        messages.into_iter().map(|m| m.unwrap().data[0]).collect()
+    };
+
+    let messages = iter_can_messages(&path, None, &options).unwrap();
+    assert_eq!(data(messages.collect()), vec![1, 3, 2, 5, 4]);
+
+    let messages = iter_can_messages(&path, None, &options).unwrap();
+    let reordered = ReorderCanMessages::new(messages, 2);
+    assert_eq!(data(reordered.collect()), vec![1, 2, 3, 4, 5]);
+}
@@ -0,0 +1,198 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Round trip tests for the rlog written by QlogWriter, read back with the cereal
+// schema to check the events match what was written
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use bzip2::read::BzDecoder;
+use capnp::message::ReaderOptions;
+use common::{message, test_dir};
+use make_cabana_route::input::{CANMessage, Direction};
+use make_cabana_route::log_capnp::sentinel::SentinelType;
+use make_cabana_route::log_capnp::{can_data, event};
+use make_cabana_route::qlog::{LogCompression, QlogWriter};
+use make_cabana_route::video::Camera;
+use make_cabana_route::Nanos;
+
+mod common;
+
+// Path for a test's rlog, in a directory of its own
+fn rlog_path(test: &str) -> PathBuf {
+    test_dir(test).join("rlog.bz2")
+}
+
+// An event as read back from the rlog, with only the fields the tests check
+#[derive(Debug, PartialEq)]
+enum ReadEvent {
+    Sentinel(Nanos),
+    // Timestamp, sendcan and each message's (address, src, data)
+    Can(Nanos, bool, Vec<(u32, u8, Vec<u8>)>),
+    // Timestamp, segment number and frame ID
+    RoadEncodeIdx(Nanos, i32, u32),
+    Other(Nanos),
+}
+
+fn read_rlog(path: &Path) -> Vec<ReadEvent> {
+    let mut reader = BufReader::new(BzDecoder::new(File::open(path).unwrap()));
+    let mut result = vec![];
+    while let Some(message) =
+        capnp::serialize::try_read_message(&mut reader, ReaderOptions::new()).unwrap()
+    {
+        let event = message.get_root::<event::Reader>().unwrap();
+        let ts = event.get_log_mono_time() as Nanos;
+        result.push(match event.which().unwrap() {
+            event::Sentinel(_) => ReadEvent::Sentinel(ts),
+            event::Can(msgs) => ReadEvent::Can(ts, false, can(msgs.unwrap())),
+            event::Sendcan(msgs) => ReadEvent::Can(ts, true, can(msgs.unwrap())),
+            event::RoadEncodeIdx(idx) => {
+                let idx = idx.unwrap();
+                ReadEvent::RoadEncodeIdx(ts, idx.get_segment_num(), idx.get_frame_id())
+            }
+            _ => ReadEvent::Other(ts),
+        });
+    }
+    result
+}
+
+fn can(msgs: capnp::struct_list::Reader<can_data::Owned>) -> Vec<(u32, u8, Vec<u8>)> {
+    msgs.iter()
+        .map(|m| (m.get_address(), m.get_src(), m.get_dat().unwrap().to_vec()))
+        .collect()
+}
+
+#[test]
+fn can_and_frame_events_round_trip() {
+    let path = rlog_path("round-trip");
+    let mut tx = message(2_000_000, 0x7e0, 1, &[0x02, 0x10, 0x03]);
+    tx.direction = Some(Direction::Tx);
+    let can_msgs = vec![
+        message(1_000_000, 0x123, 0, &[0xde, 0xad, 0xbe, 0xef]),
+        tx,
+        message(3_000_000, 0x1a0, 2, &[]),
+    ];
+
+    let mut rlog = QlogWriter::new(path.clone(), LogCompression::Bz2).unwrap();
+    rlog.write_sentinel(500, SentinelType::StartOfRoute);
+    rlog.write_can(&can_msgs);
+    rlog.write_frame_encode_idx(50_000_000, Camera::Road, 0, 7);
+    drop(rlog);
+
+    assert_eq!(
+        read_rlog(&path),
+        vec![
+            ReadEvent::Sentinel(500),
+            ReadEvent::Can(
+                1_000_000,
+                false,
+                vec![(0x123, 0, vec![0xde, 0xad, 0xbe, 0xef]), (0x1a0, 2, vec![])]
+            ),
+            // Transmitted messages are written separately, as sendcan
+            ReadEvent::Can(2_000_000, true, vec![(0x7e0, 1, vec![0x02, 0x10, 0x03])]),
+            ReadEvent::RoadEncodeIdx(50_000_000, 0, 7),
+        ]
+    );
+}
+
+// CAN-FD frames keep all 64 data bytes
+#[test]
+fn fd_frame_round_trips() {
+    let path = rlog_path("fd-frame");
+    let data: Vec<u8> = (0..64).collect();
+    let fd = CANMessage {
+        is_fd: true,
+        ..message(1_000_000, 0x1a0, 1, &data)
+    };
+    assert_eq!(fd.dlc, 15);
+
+    let mut rlog = QlogWriter::new(path.clone(), LogCompression::Bz2).unwrap();
+    rlog.write_can(&[fd]);
+    drop(rlog);
+
+    assert_eq!(
+        read_rlog(&path),
+        vec![ReadEvent::Can(1_000_000, false, vec![(0x1a0, 1, data)])]
+    );
+}
+
+// Each event is written with a later timestamp than the one before
+#[test]
+fn timestamps_are_monotonic() {
+    let path = rlog_path("monotonic");
+    let mut rlog = QlogWriter::new(path.clone(), LogCompression::Bz2).unwrap();
+    rlog.write_sentinel(5_000, SentinelType::StartOfSegment);
+    rlog.write_can(&[message(1_000, 0x100, 0, &[0x01])]);
+    rlog.write_sentinel(5_000, SentinelType::EndOfSegment);
+    drop(rlog);
+
+    assert_eq!(
+        read_rlog(&path),
+        vec![
+            ReadEvent::Sentinel(5_000),
+            ReadEvent::Can(5_001, false, vec![(0x100, 0, vec![0x01])]),
+            ReadEvent::Sentinel(5_002),
+        ]
+    );
+}
+
+// CanData has no extended ID or remote frame flags, so the address is the whole
+// ID (Cabana shows any address above 0x7FF as extended) and src is only the bus
+#[test]
+fn standard_and_extended_ids_keep_address_and_bus() {
+    let path = rlog_path("extended-ids");
+    let extended = |can_id, bus_no| CANMessage {
+        is_extended_id: true,
+        ..message(1_000_000, can_id, bus_no, &[0x11, 0x22])
+    };
+    let mut rtr = message(1_000_000, 0x7ff, 0, &[]);
+    rtr.is_rtr = true;
+    rtr.dlc = 4;
+    let mut extended_rtr = extended(0x1fff_ffff, 3);
+    extended_rtr.is_rtr = true;
+    extended_rtr.data = vec![];
+    let can_msgs = vec![
+        message(1_000_000, 0x000, 0, &[0x01]),
+        message(1_000_000, 0x7ff, 1, &[0x02]),
+        extended(0x800, 1),
+        extended(0x18da_f110, 2),
+        extended(0x1fff_ffff, 127),
+        rtr,
+        extended_rtr,
+    ];
+
+    let mut rlog = QlogWriter::new(path.clone(), LogCompression::Bz2).unwrap();
+    rlog.write_can(&can_msgs);
+    drop(rlog);
+
+    let events = read_rlog(&path);
+    assert_eq!(
+        events,
+        vec![ReadEvent::Can(
+            1_000_000,
+            false,
+            vec![
+                (0x000, 0, vec![0x01]),
+                (0x7ff, 1, vec![0x02]),
+                (0x800, 1, vec![0x11, 0x22]),
+                (0x18da_f110, 2, vec![0x11, 0x22]),
+                (0x1fff_ffff, 127, vec![0x11, 0x22]),
+                (0x7ff, 0, vec![]),
+                (0x1fff_ffff, 3, vec![]),
+            ]
+        )]
+    );
+    let ReadEvent::Can(_, _, read) = &events[0] else {
+        unreachable!();
+    };
+    for (msg, (address, src, _)) in can_msgs.iter().zip(read) {
+        assert_eq!(
+            *address > 0x7ff,
+            msg.is_extended_id,
+            "ID 0x{:x}",
+            msg.can_id
+        );
+        assert!(*src < 128, "ID 0x{:x} has a panda src {}", msg.can_id, src);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for joining and verifying routes already written to a data directory
+use std::path::Path;
+
+use common::{message, test_dir};
+use make_cabana_route::input::{
+    read_can_messages_from_reader, CANMessage, Direction, ReadOptions, TsUnit,
+};
+use make_cabana_route::log_capnp::event;
+use make_cabana_route::log_capnp::sentinel::SentinelType;
+use make_cabana_route::qlog::{DeviceType, InitDataFields, LogCompression, QlogWriter};
+use make_cabana_route::route::{
+    concat_routes, dump_route_can, read_log_events, route_segments, verify_route, SEGMENT_NANOS,
+};
+use make_cabana_route::video::Camera;
+use make_cabana_route::Nanos;
+
+mod common;
+
+// Write a route's segments with only initData, the sentinels and one road
+// camera frame each. As for a converted route, the last segment ends with
+// EndOfRoute instead of EndOfSegment.
+fn write_route(data_dir: &Path, route: &str, segments: i64) {
+    let init_data = InitDataFields {
+        version: "test".to_string(),
+        git_commit: String::new(),
+        git_branch: String::new(),
+        git_remote: String::new(),
+        device_type: DeviceType::Pc,
+        dongle_id: "0000000000000000".to_string(),
+    };
+    for number in 0..segments {
+        let dir = data_dir.join(format!("{route}--{number}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("fcamera.hevc"), route).unwrap();
+
+        let ts = number * SEGMENT_NANOS;
+        let mut rlog = QlogWriter::new(dir.join("rlog.bz2"), LogCompression::Bz2).unwrap();
+        rlog.write_init_data(ts, None, &init_data);
+        if number == 0 {
+            rlog.write_sentinel(ts, SentinelType::StartOfRoute);
+        }
+        rlog.write_sentinel(ts, SentinelType::StartOfSegment);
+        rlog.write_frame_encode_idx(ts + 1_000, Camera::Road, number as i32, 0);
+        let end = match number == segments - 1 {
+            true => SentinelType::EndOfRoute,
+            false => SentinelType::EndOfSegment,
+        };
+        rlog.write_sentinel(ts + 2_000, end);
+    }
+}
+
+// Each event's timestamp, and its sentinel type or encode index segment number
+fn read_segment(dir: &Path) -> Vec<(Nanos, String)> {
+    read_log_events(&dir.join("rlog.bz2"))
+        .unwrap()
+        .map(|message| {
+            let message = message.unwrap();
+            let event = message.get_root::<event::Reader>().unwrap();
+            let desc = match event.which().unwrap() {
+                event::Sentinel(s) => format!("{:?}", s.unwrap().get_type().unwrap()),
+                event::RoadEncodeIdx(idx) => format!("segment {}", idx.unwrap().get_segment_num()),
+                _ => "other".to_string(),
+            };
+            (event.get_log_mono_time() as Nanos, desc)
+        })
+        .collect()
+}
+
+#[test]
+fn second_route_follows_first() {
+    let dir = test_dir("concat");
+    write_route(&dir, "first", 2);
+    write_route(&dir, "second", 2);
+
+    concat_routes(&dir, "first", "second", "joined", SEGMENT_NANOS).unwrap();
+
+    let segments = route_segments(&dir, "joined").unwrap();
+    let numbers: Vec<i64> = segments.iter().map(|s| s.number).collect();
+    assert_eq!(numbers, vec![0, 1, 2, 3]);
+
+    // Videos are copied as they are
+    let video = std::fs::read_to_string(segments[2].path.join("fcamera.hevc")).unwrap();
+    assert_eq!(video, "second");
+
+    // Only the first route's start and the second route's end are kept, the
+    // first route's end becomes the end of a segment
+    let end = |ts: Nanos| (ts, "EndOfSegment".to_string());
+    assert_eq!(
+        read_segment(&segments[1].path),
+        vec![
+            (SEGMENT_NANOS, "other".to_string()),
+            (SEGMENT_NANOS + 1, "StartOfSegment".to_string()),
+            (SEGMENT_NANOS + 1_000, "segment 1".to_string()),
+            end(SEGMENT_NANOS + 2_000),
+        ]
+    );
+    let ts = 2 * SEGMENT_NANOS;
+    assert_eq!(
+        read_segment(&segments[2].path),
+        vec![
+            (ts, "other".to_string()),
+            (ts + 1, "StartOfSegment".to_string()),
+            (ts + 1_000, "segment 2".to_string()),
+            end(ts + 2_000),
+        ]
+    );
+    let ts = 3 * SEGMENT_NANOS;
+    assert_eq!(
+        read_segment(&segments[3].path),
+        vec![
+            (ts, "other".to_string()),
+            (ts + 1, "StartOfSegment".to_string()),
+            (ts + 1_000, "segment 3".to_string()),
+            (ts + 2_000, "EndOfRoute".to_string()),
+        ]
+    );
+
+    // The joined route can't be written over
+    assert!(concat_routes(&dir, "first", "second", "joined", SEGMENT_NANOS).is_err());
+}
+
+#[test]
+fn verify_finds_problems() {
+    let dir = test_dir("verify");
+    write_route(&dir, "good", 2);
+    let checks = verify_route(&dir, "good").unwrap();
+    assert_eq!(checks.len(), 2);
+    for check in &checks {
+        assert_eq!(
+            check.problems,
+            Vec::<String>::new(),
+            "segment {}",
+            check.number
+        );
+        assert_eq!(check.videos, vec!["fcamera.hevc"]);
+    }
+
+    // A segment missing its video, and one whose log was cut short
+    write_route(&dir, "bad", 3);
+    std::fs::remove_file(dir.join("bad--1/fcamera.hevc")).unwrap();
+    let rlog = dir.join("bad--2/rlog.bz2");
+    let events = std::fs::read(&rlog).unwrap();
+    std::fs::write(&rlog, &events[..events.len() / 2]).unwrap();
+
+    let checks = verify_route(&dir, "bad").unwrap();
+    assert!(checks[0].problems.is_empty());
+    assert_eq!(
+        checks[1].problems,
+        vec!["Has frames in the rlog for fcamera.hevc but no video"]
+    );
+    assert!(checks[2].problems[0].starts_with("Failed to read rlog"));
+}
+
+// CAN frames dumped from a route read back the same as the frames written to it
+#[test]
+fn dump_can_reads_back() {
+    let dir = test_dir("dump-can");
+    write_route(&dir, "route", 2);
+    let message = |timestamp, can_id, bus_no, direction| CANMessage {
+        is_extended_id: can_id > 0x7FF,
+        direction: Some(direction),
+        ..message(timestamp, can_id, bus_no, &[0x12, 0xAB])
+    };
+    let messages = vec![
+        message(5_000_000, 0x123, 0, Direction::Rx),
+        message(5_000_000, 0x18DAF110, 2, Direction::Rx),
+        message(6_000_000, 0x7E0, 1, Direction::Tx),
+    ];
+    let mut rlog = QlogWriter::new(dir.join("route--1/rlog.bz2"), LogCompression::Bz2).unwrap();
+    rlog.write_can(&messages);
+    drop(rlog);
+
+    let ts_offset = 1_671_269_730_000_000_000;
+    let mut csv = vec![];
+    let count =
+        dump_route_can(&dir, "route", Some(1), ts_offset, TsUnit::Micros, &mut csv).unwrap();
+    assert_eq!(count, 3);
+
+    let options = ReadOptions {
+        ts_unit: Some(TsUnit::Micros),
+        ..Default::default()
+    };
+    let log = read_can_messages_from_reader(csv.as_slice(), Some(ts_offset), &options).unwrap();
+    assert_eq!(log.messages, messages);
+
+    assert!(dump_route_can(&dir, "route", Some(2), 0, TsUnit::Micros, std::io::sink()).is_err());
+}
@@ -0,0 +1,202 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for the timestamps of messages read from a whole log
+use make_cabana_route::input::{
+    dedup_timestamps, merge_sorted_can, read_can_logs, read_can_messages_from_reader, CANMessage,
+    LogFormat, ReadOptions, TsMode, TsUnit,
+};
+
+mod common;
+
+use common::{message, test_dir};
+
+// Delta timestamps are added up from the start of the log, in the log's unit
+#[test]
+fn delta_timestamps_accumulate() {
+    let log = "Time Stamp,ID,Extended,Bus,LEN,D1\n\
+               0.5,100,false,0,1,01\n\
+               0.25,100,false,0,1,02\n\
+               1.125,100,false,0,1,03\n";
+    let options = ReadOptions {
+        ts_unit: Some(TsUnit::Seconds),
+        ts_mode: TsMode::Delta,
+        ..Default::default()
+    };
+    let log = read_can_messages_from_reader(log.as_bytes(), Some(0), &options).unwrap();
+    let timestamps: Vec<_> = log.messages.iter().map(|m| m.timestamp).collect();
+    assert_eq!(timestamps, vec![500_000_000, 750_000_000, 1_875_000_000]);
+}
+
+// The unit is guessed from the average interval between the first messages,
+// and a quiet bus logged in milliseconds isn't mistaken for a busy one in
+// microseconds
+#[test]
+fn ts_unit_is_detected() {
+    let log = |interval: f64| {
+        let mut log = "Time Stamp,ID,Extended,Bus,LEN,D1\n".to_string();
+        for i in 0..100 {
+            log += &format!("{},100,false,0,1,01\n", i as f64 * interval);
+        }
+        log
+    };
+    for (interval, unit) in [
+        (200.0, TsUnit::Micros),
+        (0.2, TsUnit::Millis),
+        (20.0, TsUnit::Millis),
+        (0.0002, TsUnit::Seconds),
+        (0.02, TsUnit::Seconds),
+    ] {
+        let log = read_can_messages_from_reader(
+            log(interval).as_bytes(),
+            Some(0),
+            &ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(log.ts_unit, Some(unit), "interval {}", interval);
+    }
+}
+
+// Messages from before the offset are moved to 0 in order, instead of dropped
+#[test]
+fn clamp_early_keeps_early_messages() {
+    let log = "Time Stamp,ID,Extended,Bus,LEN,D1\n\
+               1000,100,false,0,1,01\n\
+               1500,200,false,0,1,02\n\
+               3000,100,false,0,1,03\n";
+    let options = ReadOptions {
+        ts_unit: Some(TsUnit::Micros),
+        clamp_early: true,
+        ..Default::default()
+    };
+    let log = read_can_messages_from_reader(log.as_bytes(), Some(2_000_000), &options).unwrap();
+    let messages: Vec<_> = log
+        .messages
+        .iter()
+        .map(|m| (m.timestamp, m.data[0]))
+        .collect();
+    assert_eq!(messages, vec![(0, 1), (0, 2), (1_000_000, 3)]);
+}
+
+// Two logs of the same drive that overlap in time are interleaved, and share
+// the offset of the earliest message in either of them
+#[test]
+fn overlapping_logs_are_merged() {
+    let dir = test_dir("overlapping_logs_are_merged");
+    let first = dir.join("first.csv");
+    let second = dir.join("second.csv");
+    std::fs::write(
+        &first,
+        "Time Stamp,ID,Extended,Bus,LEN,D1\n\
+         2000,100,false,0,1,01\n\
+         4000,100,false,0,1,03\n\
+         6000,100,false,0,1,05\n",
+    )
+    .unwrap();
+    std::fs::write(
+        &second,
+        "Time Stamp,ID,Extended,Bus,LEN,D1\n\
+         3000,200,false,1,1,02\n\
+         5000,200,false,1,1,04\n\
+         7000,200,false,1,1,06\n",
+    )
+    .unwrap();
+    let options = ReadOptions {
+        ts_unit: Some(TsUnit::Micros),
+        ..Default::default()
+    };
+
+    // The order of the files doesn't matter
+    for paths in [[&first, &second], [&second, &first]] {
+        let paths: Vec<_> = paths.into_iter().cloned().collect();
+        let log = read_can_logs(&paths, Some(LogFormat::SavvyCan), None, &options).unwrap();
+        assert_eq!(log.ts_offset, 2_000_000);
+        let messages: Vec<_> = log
+            .messages
+            .iter()
+            .map(|m| (m.timestamp, m.bus_no, m.data[0]))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (0, 0, 1),
+                (1_000_000, 1, 2),
+                (2_000_000, 0, 3),
+                (3_000_000, 1, 4),
+                (4_000_000, 0, 5),
+                (5_000_000, 1, 6),
+            ]
+        );
+    }
+}
+
+// Sorted sources are merged in timestamp order. Messages with the same
+// timestamp come out in the order of their sources, and an empty source is
+// the same as no source.
+#[test]
+fn merge_sorted_sources() {
+    let source = |bus_no, timestamps: &[i64]| -> Vec<_> {
+        timestamps
+            .iter()
+            .map(|ts| message(*ts, 0x100, bus_no, &[]))
+            .collect()
+    };
+    let sources = vec![
+        source(0, &[0, 20, 20, 50]),
+        source(1, &[]),
+        source(2, &[10, 20, 60]),
+        source(3, &[20, 30]),
+    ];
+    let merged: Vec<_> = merge_sorted_can(sources.into_iter().map(Vec::into_iter).collect())
+        .map(|m| (m.timestamp, m.bus_no))
+        .collect();
+    assert_eq!(
+        merged,
+        vec![
+            (0, 0),
+            (10, 2),
+            (20, 0),
+            (20, 0),
+            (20, 2),
+            (20, 3),
+            (30, 3),
+            (50, 0),
+            (60, 2),
+        ]
+    );
+    assert_eq!(
+        merge_sorted_can(Vec::<std::vec::IntoIter<_>>::new()).count(),
+        0
+    );
+}
+
+// Messages with the same timestamp as the one before are counted whatever bus
+// they're on. With spread set, each run is spread evenly up to the next
+// timestamp (or 1ns apart at the end of the log), and the timestamps after it
+// are unchanged.
+#[test]
+fn dedup_timestamp_runs() {
+    let messages = |timestamps: &[i64]| -> Vec<_> {
+        timestamps
+            .iter()
+            .enumerate()
+            .map(|(idx, ts)| message(*ts, 0x100, (idx % 2) as u8, &[]))
+            .collect()
+    };
+    let timestamps =
+        |messages: &[CANMessage]| -> Vec<_> { messages.iter().map(|m| m.timestamp).collect() };
+    let log = [0, 10, 10, 10, 40, 50, 50];
+
+    let mut counted = messages(&log);
+    assert_eq!(dedup_timestamps(&mut counted, false), 3);
+    assert_eq!(timestamps(&counted), log);
+
+    let mut spread = messages(&log);
+    assert_eq!(dedup_timestamps(&mut spread, true), 3);
+    assert_eq!(timestamps(&spread), vec![0, 10, 20, 30, 40, 50, 51]);
+
+    // A run with no room before the next timestamp is left as it is
+    let mut no_room = messages(&[0, 0, 0, 1]);
+    assert_eq!(dedup_timestamps(&mut no_room, true), 2);
+    assert_eq!(timestamps(&no_room), vec![0, 0, 0, 1]);
+}
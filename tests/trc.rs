@@ -0,0 +1,83 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for reading PEAK PCAN-View trace files
+use common::test_dir;
+use make_cabana_route::input::{read_can_log, Direction, LogFormat, ReadOptions};
+
+mod common;
+
+// Version 2.0 files have no $COLUMNS line, the columns are always the same
+#[test]
+fn version_2_0_has_fixed_columns() {
+    let path = test_dir("trc-2.0").join("log.trc");
+    std::fs::write(
+        &path,
+        ";$FILEVERSION=2.0\n\
+         ;$STARTTIME=25569.5\n\
+         ;   Message   Time    Type ID     Rx/Tx\n\
+         ;   Number    Offset  |    [hex]  |  Data Length\n\
+         ;---+-- ------+------ +- --+----- +- +- +- -- -- --\n\
+         \x20    1)      1059.900 DT 0300 Rx 3 00 11 22\n\
+         \x20    2)      1060.100 ST Rx 00 00 00 08\n\
+         \x20    3)      1061.250 DT 18EFFF00 Tx 2 01 02\n",
+    )
+    .unwrap();
+
+    let log = read_can_log(
+        &path,
+        Some(LogFormat::Trc),
+        Some(0),
+        &ReadOptions::default(),
+    )
+    .unwrap();
+    let messages: Vec<_> = log
+        .messages
+        .iter()
+        .map(|m| {
+            (
+                m.timestamp,
+                m.can_id,
+                m.is_extended_id,
+                m.direction,
+                m.data.clone(),
+            )
+        })
+        .collect();
+    // STARTTIME is half a day after the Unix epoch
+    let start = 43_200_000_000_000;
+    assert_eq!(
+        messages,
+        vec![
+            (
+                start + 1_059_900_000,
+                0x300,
+                false,
+                Some(Direction::Rx),
+                vec![0x00, 0x11, 0x22]
+            ),
+            (
+                start + 1_061_250_000,
+                0x18ef_ff00,
+                true,
+                Some(Direction::Tx),
+                vec![0x01, 0x02]
+            ),
+        ]
+    );
+
+    // From version 2.1 the columns have to be in the header
+    let path = test_dir("trc-2.1").join("log.trc");
+    std::fs::write(
+        &path,
+        ";$FILEVERSION=2.1\n     1      1.000 DT 0300 Rx 1 00\n",
+    )
+    .unwrap();
+    assert!(read_can_log(
+        &path,
+        Some(LogFormat::Trc),
+        Some(0),
+        &ReadOptions::default()
+    )
+    .is_err());
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for reading Vehicle Spy CSV exports
+use common::test_dir;
+use make_cabana_route::input::{
+    read_can_log, CanFilter, CanLog, Direction, LogFormat, ReadOptions,
+};
+
+mod common;
+
+fn read(test: &str, log: &str, options: &ReadOptions) -> CanLog {
+    let path = test_dir(test).join("log.csv");
+    std::fs::write(&path, log).unwrap();
+    read_can_log(&path, Some(LogFormat::VehicleSpy), Some(0), options).unwrap()
+}
+
+// The columns are found from the header row, in any order and after the
+// information about the log
+#[test]
+fn columns_from_header() {
+    let log = read(
+        "vehiclespy-columns",
+        "Vehicle Spy Export\n\
+         Start Time,2023-03-01 12:00:00\n\
+         \n\
+         Arb ID,B1,B2,Tx,Network,Abs Time(Sec),Xtd,DLC\n\
+         3E9,01,02,F,HS CAN,0.000125,F,2\n\
+         18DAF110,0F,,T,HS CAN,0.000250,T,1\n",
+        &ReadOptions::default(),
+    );
+    let messages: Vec<_> = log
+        .messages
+        .iter()
+        .map(|m| {
+            (
+                m.timestamp,
+                m.can_id,
+                m.is_extended_id,
+                m.direction,
+                m.data.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            (125_000, 0x3E9, false, Some(Direction::Rx), vec![0x01, 0x02]),
+            (250_000, 0x18DA_F110, true, Some(Direction::Tx), vec![0x0F]),
+        ]
+    );
+}
+
+// Rows from networks that aren't CAN are skipped, CAN networks are numbered as
+// buses and then filtered the same as any other log
+#[test]
+fn networks_and_bus_filter() {
+    let log = "Line,Abs Time(Sec),Network,Arb ID,B1\n\
+               1,0.000100,HS CAN,100,01\n\
+               2,0.000200,LIN 1,3C,02\n\
+               3,0.000300,MS CAN,200,03\n\
+               4,0.000400,HS CAN2,300,04\n";
+    let buses =
+        |log: &CanLog| -> Vec<_> { log.messages.iter().map(|m| (m.bus_no, m.can_id)).collect() };
+
+    let all = read("vehiclespy-networks", log, &ReadOptions::default());
+    assert_eq!(buses(&all), vec![(0, 0x100), (1, 0x200), (2, 0x300)]);
+
+    let options = ReadOptions {
+        filter: CanFilter {
+            buses: Some(vec![1]),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let filtered = read("vehiclespy-bus-filter", log, &options);
+    assert_eq!(buses(&filtered), vec![(1, 0x200)]);
+}
+
+// The DLC column is a DLC code, so a CAN-FD row with DLC 9 has 12 data bytes
+// (and any data columns after them are ignored)
+#[test]
+fn fd_dlc() {
+    let data_columns: Vec<_> = (1..=16).map(|n| format!("B{}", n)).collect();
+    let data: Vec<_> = (0..16).map(|b| format!("{:02X}", b)).collect();
+    let log = format!(
+        "Abs Time(Sec),Arb ID,DLC,{}\n0.000100,123,9,{}\n",
+        data_columns.join(","),
+        data.join(",")
+    );
+    let log = read("vehiclespy-fd", &log, &ReadOptions::default());
+    let message = &log.messages[0];
+    assert!(message.is_fd);
+    assert_eq!(message.dlc, 9);
+    assert_eq!(message.data, (0..12).collect::<Vec<u8>>());
+}
@@ -0,0 +1,77 @@
+// Copyright (c) 2023 Angus Gratton
+// SPDX-License-Identifier: GPL-2.0-or-later
+//
+// Tests for where each segment's video starts when the source video's packets
+// are copied into it
+use make_cabana_route::video::{remux_segment_starts, RemuxSegmentStart};
+use make_cabana_route::Nanos;
+
+const SECOND: Nanos = 1_000_000_000;
+
+// 20 fps frames from start until end, with a keyframe every gop frames
+fn frames(start: Nanos, end: Nanos, gop: usize) -> Vec<(Nanos, bool)> {
+    (start..end)
+        .step_by(50_000_000)
+        .enumerate()
+        .map(|(i, ts)| (ts, i % gop == 0))
+        .collect()
+}
+
+fn start(segment: i64, ts_ns: Option<Nanos>, skipped_frames: usize) -> RemuxSegmentStart {
+    RemuxSegmentStart {
+        segment,
+        ts_ns,
+        skipped_frames,
+    }
+}
+
+// With a keyframe interval that divides the segment length, segment N's video
+// starts at N times the segment length, for any segment length
+#[test]
+fn keyframes_on_segment_boundaries() {
+    for segment_seconds in [1, 10, 60] {
+        let segment_ns = segment_seconds * SECOND;
+        let starts = remux_segment_starts(frames(0, 3 * segment_ns, 20), segment_ns);
+        assert_eq!(
+            starts,
+            (0..3)
+                .map(|n| start(n, Some(n * segment_ns), 0))
+                .collect::<Vec<_>>(),
+            "{}s segments",
+            segment_seconds
+        );
+    }
+}
+
+// With a 7 second keyframe interval and 10 second segments, the frames before
+// the first keyframe in each segment would be left out
+#[test]
+fn keyframes_off_segment_boundaries() {
+    let starts = remux_segment_starts(frames(0, 30 * SECOND, 140), 10 * SECOND);
+    assert_eq!(
+        starts,
+        vec![
+            start(0, Some(0), 0),
+            start(1, Some(14 * SECOND), 80),
+            start(2, Some(21 * SECOND), 20),
+        ]
+    );
+
+    // A segment with no keyframe at all
+    let starts = remux_segment_starts(frames(0, 3 * SECOND, 100), SECOND);
+    assert_eq!(
+        starts,
+        vec![start(0, Some(0), 0), start(1, None, 20), start(2, None, 20)]
+    );
+}
+
+// Frames before the start of the route (i.e. from a negative --video-offset-ms)
+// are left out of the route anyway, so they don't count
+#[test]
+fn frames_before_route_start() {
+    let frames = frames(-SECOND, 2 * SECOND, 20);
+    assert_eq!(
+        remux_segment_starts(frames, SECOND),
+        vec![start(0, Some(0), 0), start(1, Some(SECOND), 0)]
+    );
+}